@@ -1,23 +1,61 @@
 use std::cmp::{max, min};
 use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::output::output;
+use crate::compress::StoreCompression;
+use crate::download::EmptyFiles;
+use crate::extract::ArchiveType;
+use crate::fallback::GzipPair;
+use crate::hash::HashType;
+use crate::output::{output, Verbosity};
+use crate::policy::LinkAction;
+use crate::publish::PublishOrder;
+use crate::redirects::RecordRedirects;
+use crate::resolve::Resolve;
+use crate::shard::Shard;
+use crate::status::StatusSet;
+use crate::url::Url;
 
 #[derive(Parser, Clone, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_precedence_over_arg = true)]
 pub struct Args {
-    /// URL to mirror
-    pub url: String,
+    /// Command to run instead of mirroring
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// URLs to mirror followed by the target directory
+    #[clap(value_name = "ARG", required = true)]
+    pub positional: Vec<String>,
+
+    /// URLs to mirror
+    #[clap(skip)]
+    pub urls: Vec<String>,
 
     /// Target directory
+    #[clap(skip)]
     pub target: String,
 
+    /// File listing additional URLs to mirror, one per line
+    #[clap(long = "url-file")]
+    pub url_file: Option<String>,
+
+    /// RSS or Atom feed to mirror the entries of published since the last run (may be repeated).
+    /// Feed URLs ending in .rss, .atom, feed.xml, rss.xml, atom.xml, /feed or /rss are found
+    /// without this
+    #[clap(long = "feed", value_name = "URL")]
+    pub feed: Vec<String>,
+
     /// Maximum number of concurrent requests to the web server
     #[clap(short = 'c', long = "concurrent", default_value_t = default_concurrent_requests(), value_parser = clamp_concurrent)]
     pub concurrent_fetch: usize,
 
+    /// Maximum number of concurrent requests to each web server
+    #[clap(long = "concurrent-per-host")]
+    pub concurrent_per_host: Option<usize>,
+
     /// Maximum number of worker threads to run
     #[clap(short = 't', long = "threads", default_value_t = default_threads(), value_parser = clamp_threads)]
     pub threads: usize,
@@ -26,6 +64,40 @@ pub struct Args {
     #[clap(short = 'u', long = "unnamed", default_value_t = default_unnamed())]
     pub unnamed: String,
 
+    /// File name to use for directory style URLs (ending in /)
+    #[clap(long = "index-name", default_value_t = default_index_name())]
+    pub index_name: String,
+
+    /// Maximum local directory depth. Deeper path components are joined in to the file name
+    #[clap(long = "flatten-depth")]
+    pub flatten_depth: Option<usize>,
+
+    /// Save all files directly in the target directory instead of recreating the URL directory
+    /// structure. Files with the same name are numbered (eg. report-1.pdf)
+    #[clap(long = "flatten", conflicts_with = "flatten_depth")]
+    pub flatten: bool,
+
+    /// Drop this many leading directories from local paths (eg. a/b/file is saved as b/file
+    /// with 1)
+    #[clap(long = "cut-dirs", value_name = "N", default_value_t = 0)]
+    pub cut_dirs: usize,
+
+    /// Decode percent escapes in URL paths when building local file names
+    #[clap(long = "decode-filenames")]
+    pub decode_filenames: bool,
+
+    /// Map file names to names which are legal on Windows (always on when running on Windows)
+    #[clap(long = "windows-names")]
+    pub windows_names: bool,
+
+    /// Maximum length of a local file name in bytes. Longer names are shortened
+    #[clap(long = "max-name-length", default_value_t = default_max_name_length())]
+    pub max_name_length: usize,
+
+    /// Maximum length of a local path in bytes. Longer paths are shortened
+    #[clap(long = "max-path-length", default_value_t = default_max_path_length())]
+    pub max_path_length: usize,
+
     /// Connection timout in seconds
     #[clap(long = "connect-timeout", default_value_t = default_connect_timeout())]
     pub connect_timeout: u64,
@@ -34,6 +106,109 @@ pub struct Args {
     #[clap(long = "fetch-timeout", default_value_t = default_fetch_timeout())]
     pub fetch_timeout: u64,
 
+    /// Longest Retry-After time in seconds to wait for when a server responds with status 429 or
+    /// 503 (longer waits are treated as errors)
+    #[clap(long = "max-retry-after", value_name = "SECS", default_value_t = default_max_retry_after())]
+    pub max_retry_after: u64,
+
+    /// Skip URLs returning these statuses instead of counting them as errors (comma separated
+    /// codes or classes, eg. 403,404 or 4xx)
+    #[clap(long = "ignore-status", value_name = "STATUSES", value_parser = StatusSet::parse)]
+    pub ignore_status: Option<StatusSet>,
+
+    /// Abort the run when a URL returns one of these statuses (comma separated codes or classes,
+    /// eg. 5xx)
+    #[clap(long = "fail-on", value_name = "STATUSES", value_parser = StatusSet::parse)]
+    pub fail_on: Option<StatusSet>,
+
+    /// Exit with an error if any file failed to download
+    #[clap(long = "strict")]
+    pub strict: bool,
+
+    /// Exit with an error if less than this percentage of the files fetched succeeded (skipped
+    /// files aren't counted)
+    #[clap(long = "min-success-rate", value_name = "PERCENT", value_parser = parse_percent)]
+    pub min_success_rate: Option<f64>,
+
+    /// Print a status line with the fetches in progress and waiting, URLs processed and the
+    /// transfer rate at this interval in seconds (0 disables)
+    #[clap(long = "status-interval", value_name = "SECS", default_value_t = 0)]
+    pub status_interval: u64,
+
+    /// Run this shell command when the run finishes, passing a JSON summary of the result on
+    /// its standard input
+    #[clap(long = "on-complete-cmd", value_name = "CMD")]
+    pub on_complete_cmd: Option<String>,
+
+    /// Post a JSON summary of the result to this URL when the run finishes
+    #[clap(long = "webhook-url", value_name = "URL")]
+    pub webhook_url: Option<Url>,
+
+    /// Keep running, mirroring again at this interval (seconds, or with an s, m, h or d suffix)
+    #[clap(long = "watch", value_name = "INTERVAL", value_parser = parse_duration)]
+    pub watch: Option<Duration>,
+
+    /// Add a random delay of up to this long to each watch interval (seconds, or with an s, m,
+    /// h or d suffix)
+    #[clap(long = "watch-jitter", value_name = "DURATION", value_parser = parse_duration, requires = "watch")]
+    pub watch_jitter: Option<Duration>,
+
+    /// Stop watching after this many runs
+    #[clap(long = "max-runs", value_name = "N", requires = "watch")]
+    pub max_runs: Option<u64>,
+
+    /// Serve Prometheus metrics on this address (eg. 127.0.0.1:9090) while running
+    #[clap(long = "metrics-listen", value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Write Prometheus metrics to this file while running, for a textfile collector
+    #[clap(long = "metrics-file", value_name = "FILE")]
+    pub metrics_file: Option<String>,
+
+    /// Print the files and bytes downloaded in each top level directory and for each file
+    /// extension with the totals
+    #[clap(long = "stats-detail")]
+    pub stats_detail: bool,
+
+    /// Print the changes in the totals since the last run, warning about error spikes and
+    /// missing files
+    #[clap(long = "compare-last-run")]
+    pub compare_last_run: bool,
+
+    /// Print sizes as exact byte counts instead of in KiB, MiB, GiB etc.
+    #[clap(long = "bytes")]
+    pub bytes: bool,
+
+    /// Print response and transfer time percentiles with the totals
+    #[clap(long = "timings")]
+    pub timings: bool,
+
+    /// Don't ask servers to compress responses (gzip, brotli and deflate are accepted and stored
+    /// decoded by default)
+    #[clap(long = "no-compression")]
+    pub no_compression: bool,
+
+    /// Only use HTTP/1
+    #[clap(long = "http1-only", conflicts_with = "http2_prior_knowledge")]
+    pub http1_only: bool,
+
+    /// Use HTTP/2 without negotiating it first
+    #[clap(long = "http2-prior-knowledge")]
+    pub http2_prior_knowledge: bool,
+
+    /// Maximum number of idle connections kept open to each host
+    #[clap(long = "pool-max-idle-per-host")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Send TCP keepalive probes on idle connections after this many seconds
+    #[clap(long = "tcp-keepalive", value_name = "SECS")]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Download large files from servers which accept range requests over up to this many
+    /// connections at once. Each extra connection takes a free download slot
+    #[clap(long = "segments", default_value_t = default_segments(), value_parser = clamp_concurrent)]
+    pub segments: usize,
+
     /// Skip list file (JSON array file containing URLs or relative file paths to skip)
     #[clap(short = 's', long = "skip-file")]
     pub skip_file: Option<String>,
@@ -42,33 +217,475 @@ pub struct Args {
     #[clap(short = 'e', long = "no-etags")]
     pub no_etags: bool,
 
+    /// Save the etags collected so far at this interval in seconds while running (0 saves them
+    /// only at the end)
+    #[clap(long = "etags-save-interval", value_name = "SECS", default_value_t = default_etags_save_interval())]
+    pub etags_save_interval: u64,
+
+    /// Use the etags file in the target directory even if it was saved mirroring a different URL
+    #[clap(long = "force")]
+    pub force: bool,
+
+    /// Drop etags for URLs not seen in this run from the etags file
+    #[clap(long = "prune-etags", conflicts_with = "metadata_store")]
+    pub prune_etags: bool,
+
+    /// Save crawl progress to .mirrorurl-state.json and resume an interrupted crawl from it
+    #[clap(long = "resume")]
+    pub resume: bool,
+
+    /// Lock the target directory with .mirrorurl.lock so runs on other machines sharing it don't
+    /// mirror in to it at the same time
+    #[clap(long = "lock")]
+    pub lock: bool,
+
+    /// Take over a lock on the target directory whose holder has stopped updating it
+    #[clap(long = "steal-lock", requires = "lock")]
+    pub steal_lock: bool,
+
+    /// Record the URL, path, size, SHA-256 and time of each saved file in .manifest.json
+    #[clap(long = "manifest")]
+    pub manifest: bool,
+
+    /// Mirror in to a staging directory next to the target and only swap it in once the run
+    /// completes without errors (the target becomes a link to the latest copy)
+    #[clap(long = "stage")]
+    pub stage: bool,
+
+    /// Hard link downloaded files to identical files already in the mirror (uses the manifest)
+    #[clap(long = "dedupe", requires = "manifest")]
+    pub dedupe: bool,
+
+    /// When a URL redirects to another inside the mirror, put a symbolic link (or a stub HTML
+    /// page redirecting) to the final URL's file at the original URL's path
+    #[clap(long = "record-redirects", value_name = "HOW", value_enum, num_args = 0..=1, default_missing_value = "symlink")]
+    pub record_redirects: Option<RecordRedirects>,
+
+    /// Store files compressed, appending the compression extension to their names (the
+    /// manifest records the original name)
+    #[clap(
+        long = "store-compressed",
+        value_enum,
+        conflicts_with_all = ["dedupe", "skip_existing", "auto_extract", "audit"]
+    )]
+    pub store_compressed: Option<StoreCompression>,
+
+    /// How to handle downloads with an empty body
+    #[clap(long = "empty-files", value_enum, default_value_t)]
+    pub empty_files: EmptyFiles,
+
+    /// Shared SQLite metadata store to use instead of the etags file (may be shared between processes)
+    #[clap(long = "metadata-store")]
+    pub metadata_store: Option<String>,
+
+    /// Only follow links within this sub-tree of the base URL (may be repeated, eg. to shard a mirror)
+    #[clap(long = "only")]
+    pub only: Vec<String>,
+
+    /// Only download files with these extensions, comma separated (pages and directories are still crawled)
+    #[clap(short = 'A', long = "accept", value_delimiter = ',')]
+    pub accept: Vec<String>,
+
+    /// Don't download files with these extensions, comma separated
+    #[clap(short = 'R', long = "reject", value_delimiter = ',')]
+    pub reject: Vec<String>,
+
+    /// Only download files with these MIME types, comma separated (eg. application/pdf,image/*)
+    #[clap(long = "accept-type", value_delimiter = ',')]
+    pub accept_type: Vec<String>,
+
+    /// Don't download files with these MIME types, comma separated
+    #[clap(long = "reject-type", value_delimiter = ',')]
+    pub reject_type: Vec<String>,
+
+    /// Don't download files smaller than this size (bytes, or with a K, M, G or T suffix)
+    #[clap(long = "min-size", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+
+    /// Don't download files larger than this size (bytes, or with a K, M, G or T suffix)
+    #[clap(long = "max-size", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Don't download files modified less than this long ago (seconds, or with an s, m, h or d suffix)
+    #[clap(long = "min-age", value_parser = parse_duration)]
+    pub min_age: Option<Duration>,
+
+    /// When a file is not found fetch its gzip compressed or plain copy instead and convert it,
+    /// matching file names by suffixes in PLAIN=COMPRESSED form (may be repeated, eg. =.gz)
+    #[clap(long = "gzip-fallback", value_name = "PLAIN=COMPRESSED")]
+    pub gzip_fallback: Vec<GzipPair>,
+
+    /// Only download files in shard i of n (1 based, eg. 2/4), assigned by a hash of the path
+    #[clap(long = "shard")]
+    pub shard: Option<Shard>,
+
+    /// Follow links to this host as well as the base URL host (may be repeated)
+    #[clap(long = "allow-host")]
+    pub allow_host: Vec<String>,
+
+    /// Follow links to any host
+    #[clap(long = "span-hosts")]
+    pub span_hosts: bool,
+
+    /// Save the files from the base URL host in a directory named after it (eg. example.com/file)
+    /// as well as those from other hosts followed with --allow-host and --span-hosts
+    #[clap(long = "host-directories", overrides_with = "no_host_directories")]
+    pub host_directories: bool,
+
+    /// Don't save the files from any host in a directory named after it. Files from the base URL
+    /// host are saved without one by default
+    #[clap(long = "no-host-directories", overrides_with = "host_directories")]
+    pub no_host_directories: bool,
+
+    /// How to treat links with a fragment (overrides the policy file)
+    #[clap(long = "fragments", value_enum)]
+    pub fragments: Option<LinkAction>,
+
+    /// How to treat links with a query string (overrides the policy file)
+    #[clap(long = "queries", value_enum)]
+    pub queries: Option<LinkAction>,
+
+    /// Follow links with a query string, encoding the query in to the file name (same as --queries follow)
+    #[clap(long = "allow-query", conflicts_with = "queries")]
+    pub allow_query: bool,
+
+    /// Fetch paths matching this pattern (relative to the base URL) ahead of others, eg.
+    /// dists/*/InRelease (may be repeated)
+    #[clap(long = "hot", value_name = "GLOB")]
+    pub hot: Vec<String>,
+
+    /// Order in which downloaded files replace the files in the mirror
+    #[clap(long = "publish-order", value_enum, default_value_t)]
+    pub publish_order: PublishOrder,
+
+    /// Index or metadata files to publish last with --publish-order data-first, eg.
+    /// dists/*/InRelease (may be repeated, default dists/** and repodata/**)
+    #[clap(long = "metadata-glob", value_name = "GLOB")]
+    pub metadata_glob: Vec<String>,
+
+    /// Crawl policy file (JSON object with fragment, query, span_hosts and allow_hosts keys)
+    #[clap(long = "policy-file")]
+    pub policy_file: Option<String>,
+
+    /// Exclude file (wget exclude_directories and reject settings or rsync filter patterns)
+    #[clap(long = "exclude-from")]
+    pub exclude_from: Option<String>,
+
+    /// Don't fetch and obey robots.txt
+    #[clap(long = "ignore-robots")]
+    pub ignore_robots: bool,
+
+    /// Issue a HEAD request before each GET so the size, age and MIME type filters are applied
+    /// first, and files without an etag whose local copy has the same length and is no older are
+    /// not fetched (falls back to GET for hosts which don't handle HEAD)
+    #[clap(long = "head-first")]
+    pub head_first: bool,
+
+    /// Pause for re-authentication when a file download returns a login or interstitial page
+    #[clap(long = "pause-on-interstitial")]
+    pub pause_on_interstitial: bool,
+
+    /// Save files under the name given in a Content-Disposition header instead of the URL name
+    #[clap(long = "content-disposition")]
+    pub content_disposition: bool,
+
+    /// Skip files whose local copy matches the size and modification time from a HEAD request
+    /// (downloaded files are given the server's modification time)
+    #[clap(long = "skip-existing")]
+    pub skip_existing: bool,
+
+    /// Never replace a file which already exists locally
+    #[clap(long = "no-clobber")]
+    pub no_clobber: bool,
+
+    /// Also download images, stylesheets, scripts and media referenced by HTML pages
+    #[clap(short = 'p', long = "page-requisites")]
+    pub page_requisites: bool,
+
+    /// Save parsed HTML documents in the mirror
+    #[clap(long = "save-html")]
+    pub save_html: bool,
+
+    /// After the run re-parse the saved documents and report linked files missing from the
+    /// mirror or with the wrong size
+    #[clap(long = "audit", requires = "save_html")]
+    pub audit: bool,
+
+    /// Obey nofollow and noindex directives in robots meta tags
+    #[clap(long = "respect-robots-meta")]
+    pub respect_robots_meta: bool,
+
+    /// Synthesize etags from file details for servers which provide no validators, checking them with HEAD requests
+    #[clap(long = "synth-etags")]
+    pub synth_etags: bool,
+
+    /// Unpack downloaded archives of these types in to a sibling directory (comma separated, eg. tar.gz,zip)
+    #[clap(long = "auto-extract", value_enum, value_delimiter = ',')]
+    pub auto_extract: Vec<ArchiveType>,
+
+    /// Write an index page listing to each directory in the mirror after the run
+    #[clap(long = "generate-index")]
+    pub generate_index: bool,
+
+    /// Write a sitemap of the mirror to this file after the run
+    #[clap(long = "emit-sitemap", requires = "publish_base")]
+    pub emit_sitemap: Option<String>,
+
+    /// Base URL the mirror is published under (used by --emit-sitemap)
+    #[clap(long = "publish-base")]
+    pub publish_base: Option<Url>,
+
+    /// Verify downloaded files against checksum files published on the server (eg. SHA256SUMS or file.sha256)
+    #[clap(long = "verify", value_enum)]
+    pub verify: Option<HashType>,
+
+    /// Check downloaded files against the sizes given by directory listings (Apache and nginx
+    /// index pages and nginx JSON listings), failing downloads which don't match
+    #[clap(long = "check-listed-sizes")]
+    pub check_listed_sizes: bool,
+
+    /// Don't request files whose date in the directory listing is the same as when they were
+    /// downloaded (for servers without etags). Dates are recorded in .listing-dates.json
+    #[clap(long = "listing-dates")]
+    pub listing_dates: bool,
+
+    /// File of SHA-256 digests of content which must not be mirrored (sha256sum format).
+    /// Matching downloads are deleted and reported
+    #[clap(long = "blocklist-hashes", value_name = "FILE")]
+    pub blocklist_hashes: Option<String>,
+
+    /// Move downloads matching the hash blocklist in to this directory instead of deleting them
+    #[clap(
+        long = "quarantine-dir",
+        value_name = "DIR",
+        requires = "blocklist_hashes"
+    )]
+    pub quarantine_dir: Option<String>,
+
+    /// Move downloads which fail checksum or length verification to .mirrorurl/quarantine in
+    /// the target directory with a file giving the reason, rather than deleting them
+    #[clap(long = "quarantine-failed")]
+    pub quarantine_failed: bool,
+
     /// Maximum number of redirects
     #[clap(short = 'r', long = "max-redirects", default_value_t = default_max_redirects())]
     pub max_redirects: usize,
 
-    /// Increase debug message level
+    /// Additional root CA certificate file (PEM) to trust
+    #[clap(long = "cacert")]
+    pub cacert: Option<String>,
+
+    /// Client certificate file (PEM) for mutual TLS authentication
+    #[clap(long = "client-cert", requires = "client_key")]
+    pub client_cert: Option<String>,
+
+    /// Client private key file (PKCS#8 PEM) for mutual TLS authentication
+    #[clap(long = "client-key", requires = "client_cert")]
+    pub client_key: Option<String>,
+
+    /// Don't verify server TLS certificates
+    #[clap(long = "insecure")]
+    pub insecure: bool,
+
+    /// S3 compatible endpoint to fetch s3:// URLs from, addressing buckets by path (default
+    /// Amazon S3, addressing buckets by host name)
+    #[clap(long = "s3-endpoint")]
+    pub s3_endpoint: Option<Url>,
+
+    /// Mirror the Wayback Machine snapshot of the site nearest this timestamp (YYYYMMDDhhmmss
+    /// or a prefix of it)
+    #[clap(long = "via-wayback", value_name = "TIMESTAMP", value_parser = parse_timestamp)]
+    pub via_wayback: Option<String>,
+
+    /// Wayback Machine address (default https://web.archive.org/)
+    #[clap(long = "wayback-endpoint", requires = "via_wayback")]
+    pub wayback_endpoint: Option<Url>,
+
+    /// Connect to this address for a host name instead of looking it up (host:addr, may be repeated)
+    #[clap(long = "resolve")]
+    pub resolve: Vec<Resolve>,
+
+    /// Virtual host name to present (Host header and TLS SNI) whilst connecting to the URL's address
+    #[clap(long = "host-header")]
+    pub host_header: Option<String>,
+
+    /// Only output errors
+    #[clap(short = 'q', long = "quiet", conflicts_with_all = ["no_progress", "verbose"])]
+    pub quiet: bool,
+
+    /// Don't output a line for each URL fetched, downloaded or skipped
+    #[clap(long = "no-progress", conflicts_with = "verbose")]
+    pub no_progress: bool,
+
+    /// Also output redirects and the number of links followed from each document
+    #[clap(short = 'v', long = "verbose")]
+    pub verbose: bool,
+
+    /// Increase debug message level (send SIGHUP to cycle the level whilst running)
     #[clap(short = 'd', long = "debug", action = clap::ArgAction::Count)]
     pub debug: u8,
 
+    /// Only log debug messages from these modules or crates (comma separated, eg. walk,html,reqwest)
+    #[clap(long = "debug-target", value_delimiter = ',')]
+    pub debug_target: Vec<String>,
+
     /// Insert an artificial delay in the data fetch for debugging
     #[clap(long = "debug-delay", default_value_t = 0)]
     pub debug_delay: u64,
 }
 
+/// Commands
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Check the configuration, files, target directory and URLs, reporting all problems found
+    Check,
+    /// Show which rule applies to each URL (or path relative to the first URL) and the local
+    /// path it would be saved to, without fetching anything
+    TestRules {
+        /// URLs or paths to test
+        #[clap(value_name = "URL_OR_PATH", required = true)]
+        items: Vec<String>,
+    },
+    /// Write the etags and manifest of the target directory to a portable state file. Only the
+    /// target directory needs to be given
+    ExportState {
+        /// State file to write
+        file: String,
+    },
+    /// Merge the etags and manifest from a state file in to the target directory so an
+    /// incremental sync can continue. Only the target directory needs to be given
+    ImportState {
+        /// State file to read
+        file: String,
+    },
+    /// Replace this binary with the latest release once its signature has been verified
+    #[cfg(feature = "self-update")]
+    SelfUpdate {
+        /// Only report whether a newer release is available
+        #[clap(long = "check")]
+        check: bool,
+    },
+}
+
 impl Default for Args {
     fn default() -> Self {
         Self {
-            url: Default::default(),
+            command: Default::default(),
+            positional: Default::default(),
+            urls: Default::default(),
+            url_file: Default::default(),
+            feed: Default::default(),
             target: Default::default(),
             concurrent_fetch: default_concurrent_requests(),
+            concurrent_per_host: Default::default(),
             threads: default_threads(),
             unnamed: default_unnamed(),
+            index_name: default_index_name(),
+            flatten_depth: Default::default(),
+            flatten: Default::default(),
+            cut_dirs: Default::default(),
+            decode_filenames: Default::default(),
+            windows_names: Default::default(),
+            max_name_length: default_max_name_length(),
+            max_path_length: default_max_path_length(),
             connect_timeout: default_connect_timeout(),
             fetch_timeout: default_fetch_timeout(),
+            max_retry_after: default_max_retry_after(),
+            ignore_status: Default::default(),
+            fail_on: Default::default(),
+            strict: Default::default(),
+            min_success_rate: Default::default(),
+            status_interval: Default::default(),
+            on_complete_cmd: Default::default(),
+            webhook_url: Default::default(),
+            watch: Default::default(),
+            watch_jitter: Default::default(),
+            max_runs: Default::default(),
+            metrics_listen: Default::default(),
+            metrics_file: Default::default(),
+            stats_detail: Default::default(),
+            compare_last_run: Default::default(),
+            bytes: Default::default(),
+            timings: Default::default(),
+            no_compression: Default::default(),
+            http1_only: Default::default(),
+            http2_prior_knowledge: Default::default(),
+            pool_max_idle_per_host: Default::default(),
+            tcp_keepalive: Default::default(),
+            segments: default_segments(),
             skip_file: Default::default(),
             no_etags: Default::default(),
+            etags_save_interval: default_etags_save_interval(),
+            force: Default::default(),
+            prune_etags: Default::default(),
+            resume: Default::default(),
+            lock: Default::default(),
+            steal_lock: Default::default(),
+            manifest: Default::default(),
+            stage: Default::default(),
+            dedupe: Default::default(),
+            record_redirects: Default::default(),
+            store_compressed: Default::default(),
+            empty_files: Default::default(),
+            metadata_store: Default::default(),
+            only: Default::default(),
+            accept: Default::default(),
+            reject: Default::default(),
+            accept_type: Default::default(),
+            reject_type: Default::default(),
+            min_size: Default::default(),
+            max_size: Default::default(),
+            min_age: Default::default(),
+            shard: Default::default(),
+            gzip_fallback: Default::default(),
+            allow_host: Default::default(),
+            span_hosts: Default::default(),
+            host_directories: Default::default(),
+            no_host_directories: Default::default(),
+            fragments: Default::default(),
+            queries: Default::default(),
+            allow_query: Default::default(),
+            hot: Default::default(),
+            publish_order: Default::default(),
+            metadata_glob: Default::default(),
+            policy_file: Default::default(),
+            exclude_from: Default::default(),
+            ignore_robots: Default::default(),
+            head_first: Default::default(),
+            pause_on_interstitial: Default::default(),
+            content_disposition: Default::default(),
+            skip_existing: Default::default(),
+            no_clobber: Default::default(),
+            page_requisites: Default::default(),
+            synth_etags: Default::default(),
+            save_html: Default::default(),
+            audit: Default::default(),
+            respect_robots_meta: Default::default(),
+            auto_extract: Default::default(),
+            generate_index: Default::default(),
+            emit_sitemap: Default::default(),
+            publish_base: Default::default(),
+            verify: Default::default(),
+            check_listed_sizes: Default::default(),
+            listing_dates: Default::default(),
+            blocklist_hashes: Default::default(),
+            quarantine_dir: Default::default(),
+            quarantine_failed: Default::default(),
             max_redirects: default_max_redirects(),
+            cacert: Default::default(),
+            client_cert: Default::default(),
+            client_key: Default::default(),
+            insecure: Default::default(),
+            s3_endpoint: Default::default(),
+            via_wayback: Default::default(),
+            wayback_endpoint: Default::default(),
+            resolve: Default::default(),
+            host_header: Default::default(),
+            quiet: Default::default(),
+            no_progress: Default::default(),
+            verbose: Default::default(),
             debug: Default::default(),
+            debug_target: Default::default(),
             debug_delay: Default::default(),
         }
     }
@@ -77,10 +694,38 @@ impl Default for Args {
 impl Args {
     /// Parse command line arguments and return an error on failure
     pub fn parse() -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let args = Args::try_parse()?;
+        let mut args = Args::try_parse()?;
+
+        // The last positional argument is the target directory
+        args.target = args.positional.pop().unwrap_or_default();
+        args.urls = std::mem::take(&mut args.positional);
+
+        let needs_url = match args.command {
+            Some(Command::ExportState { .. } | Command::ImportState { .. }) => false,
+            #[cfg(feature = "self-update")]
+            Some(Command::SelfUpdate { .. }) => false,
+            _ => true,
+        };
+
+        if needs_url && args.urls.is_empty() && args.url_file.is_none() && args.feed.is_empty() {
+            Err("At least one URL, --url-file or --feed must be given")?
+        }
 
         Ok(args)
     }
+
+    /// Returns how much output is wanted
+    pub fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.no_progress {
+            Verbosity::NoProgress
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
 }
 
 fn default_concurrent_requests() -> usize {
@@ -95,6 +740,22 @@ fn default_unnamed() -> String {
     String::from("__file.dat")
 }
 
+fn default_index_name() -> String {
+    String::from("index.html")
+}
+
+fn default_max_name_length() -> usize {
+    255
+}
+
+fn default_max_path_length() -> usize {
+    if cfg!(windows) {
+        260
+    } else {
+        4096
+    }
+}
+
 fn default_connect_timeout() -> u64 {
     60
 }
@@ -103,6 +764,14 @@ fn default_fetch_timeout() -> u64 {
     5
 }
 
+fn default_etags_save_interval() -> u64 {
+    60
+}
+
+fn default_max_retry_after() -> u64 {
+    300
+}
+
 fn clamp_concurrent(s: &str) -> Result<usize, String> {
     Ok(max(
         1,
@@ -125,6 +794,64 @@ fn clamp_threads(s: &str) -> Result<usize, String> {
     Ok(act_threads)
 }
 
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, mult) = match s.char_indices().last() {
+        Some((pos, 'k' | 'K')) => (&s[..pos], 1 << 10),
+        Some((pos, 'm' | 'M')) => (&s[..pos], 1 << 20),
+        Some((pos, 'g' | 'G')) => (&s[..pos], 1 << 30),
+        Some((pos, 't' | 'T')) => (&s[..pos], 1 << 40),
+        _ => (s, 1),
+    };
+
+    let num: u64 = num.parse().map_err(|_| format!("'{s}' is not a size"))?;
+
+    num.checked_mul(mult)
+        .ok_or_else(|| format!("'{s}' is too large"))
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let num: f64 = s
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("'{s}' is not a percentage"))?;
+
+    if !(0.0..=100.0).contains(&num) {
+        Err(format!("'{s}' is not between 0 and 100"))?
+    }
+
+    Ok(num)
+}
+
+fn parse_timestamp(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > 14 || !s.chars().all(|c| c.is_ascii_digit()) {
+        Err(format!("'{s}' is not a timestamp in YYYYMMDDhhmmss form"))?
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, mult) = match s.char_indices().last() {
+        Some((pos, 's' | 'S')) => (&s[..pos], 1),
+        Some((pos, 'm' | 'M')) => (&s[..pos], 60),
+        Some((pos, 'h' | 'H')) => (&s[..pos], 60 * 60),
+        Some((pos, 'd' | 'D')) => (&s[..pos], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("'{s}' is not a duration"))?;
+
+    num.checked_mul(mult)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("'{s}' is too long"))
+}
+
+fn default_segments() -> usize {
+    1
+}
+
 fn default_max_redirects() -> usize {
     10
 }
@@ -0,0 +1,151 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::path::PathBuf;
+
+use tokio::fs::{metadata, read_to_string};
+use tokio::sync::Mutex;
+
+use crate::html::parse_links;
+use crate::output::output;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::State;
+use crate::url::Url;
+
+/// Details recorded during a run for the post-run audit
+#[derive(Default)]
+pub struct Audit {
+    /// Saved documents and their local paths
+    documents: Mutex<Vec<(Url, PathBuf)>>,
+    /// Sizes of saved files by URL
+    sizes: Mutex<HashMap<String, u64>>,
+    /// Reasons URLs were skipped
+    skips: Mutex<HashMap<String, String>>,
+}
+
+impl Audit {
+    /// Records a saved document
+    pub async fn add_document(&self, url: &Url, path: PathBuf) {
+        self.documents.lock().await.push((url.clone(), path));
+    }
+
+    /// Records the size of a saved file
+    pub async fn add_size(&self, url: &Url, size: u64) {
+        self.sizes.lock().await.insert(url.to_string(), size);
+    }
+
+    /// Records the reason a URL was skipped
+    pub async fn add_skip(&self, skip: &SkipReasonErr) {
+        self.skips
+            .lock()
+            .await
+            .insert(skip.url().to_string(), skip.reason().to_string());
+    }
+}
+
+/// Re-parses the documents saved during the run and checks every file they link to is in the
+/// mirror with the size it was downloaded with, reporting the problems found
+pub async fn audit(state: &State) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(audit) = state.audit() else {
+        return Ok(());
+    };
+
+    let documents = audit.documents.lock().await.clone();
+    let sizes = audit.sizes.lock().await;
+    let skips = audit.skips.lock().await;
+
+    let mut problems = BTreeSet::new();
+    let mut checked = BTreeSet::new();
+
+    for (doc_url, doc_path) in documents {
+        let html = read_to_string(&doc_path)
+            .await
+            .map_err(|e| format!("Unable to read {}: {e}", doc_path.display()))?;
+
+        for href in parse_links(&html, state.args().page_requisites) {
+            // Only check links the crawl would follow to files
+            let Ok(mut url) = doc_url.join(&href) else {
+                continue;
+            };
+
+            url.set_fragment(None);
+
+            if url.path().ends_with('/') || !checked.insert(url.to_string()) {
+                continue;
+            }
+
+            let url = match state.check_link(url) {
+                Ok(url) => url,
+                Err(skip) if is_filter(skip.reason()) => {
+                    problems.insert(format!(
+                        "{} linked from {doc_url} is not mirrored: {}",
+                        skip.url(),
+                        skip.reason()
+                    ));
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+            // Reason a linked file is missing
+            let missing = || {
+                let reason = skips
+                    .get(url.as_str())
+                    .map(String::as_str)
+                    .unwrap_or("Not downloaded");
+
+                format!("{url} linked from {doc_url} is missing from the mirror: {reason}")
+            };
+
+            // Find the local file without claiming a path for files which weren't downloaded
+            let path = match state.saved_path_for_url(&url).await {
+                Ok(Some(path)) => path,
+                Ok(None) => {
+                    problems.insert(missing());
+                    continue;
+                }
+                Err(skip) => {
+                    problems.insert(format!(
+                        "{url} linked from {doc_url} is not mirrored: {}",
+                        skip.reason()
+                    ));
+                    continue;
+                }
+            };
+
+            match metadata(&path).await {
+                Ok(meta) => match sizes.get(url.as_str()) {
+                    Some(size) if *size != meta.len() => {
+                        problems.insert(format!(
+                            "{url} linked from {doc_url} is {} bytes, expected {size}",
+                            meta.len()
+                        ));
+                    }
+                    _ => (),
+                },
+                Err(_) => {
+                    problems.insert(missing());
+                }
+            }
+        }
+    }
+
+    for problem in &problems {
+        output!("Audit: {problem}");
+    }
+
+    if problems.is_empty() {
+        output!("Audit found no problems");
+    } else {
+        output!("Audit found {} problems", problems.len());
+    }
+
+    Ok(())
+}
+
+/// Returns true if a link was skipped by a download filter rather than being outside the crawl
+fn is_filter(reason: &SkipReason) -> bool {
+    matches!(
+        reason,
+        SkipReason::SkipList | SkipReason::NotOnly | SkipReason::Extension | SkipReason::Excluded
+    )
+}
@@ -0,0 +1,46 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::read_to_string;
+
+/// List of SHA-256 digests of content which must not be mirrored
+#[derive(Default)]
+pub struct HashBlocklist {
+    hashes: HashSet<String>,
+}
+
+impl HashBlocklist {
+    /// Loads a blocklist file. Each line holds a hex SHA-256 digest, optionally followed by a
+    /// file name as written by sha256sum. Blank lines and lines starting with # are ignored
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let content = read_to_string(file)
+            .map_err(|e| format!("Failed to open hash blocklist file {file}: {e}"))?;
+
+        let mut hashes = HashSet::new();
+
+        for (num, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let hash = line.split_whitespace().next().unwrap_or_default();
+
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                Err(format!(
+                    "Error in hash blocklist file {file} line {}: '{hash}' is not a SHA-256 digest",
+                    num + 1
+                ))?
+            }
+
+            hashes.insert(hash.to_ascii_lowercase());
+        }
+
+        Ok(Self { hashes })
+    }
+
+    /// Returns true if a hex SHA-256 digest is in the list
+    pub fn contains(&self, sha256: &str) -> bool {
+        self.hashes.contains(&sha256.to_ascii_lowercase())
+    }
+}
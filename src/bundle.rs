@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::args::Args;
+use crate::etags::ETags;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::output::output;
+use crate::store::MetadataStore;
+
+/// Version of the state bundle format
+const BUNDLE_VERSION: u32 = 1;
+
+/// Portable copy of the mirror state
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Bundle {
+    /// Bundle format version
+    version: u32,
+    /// URL to etag mapping
+    etags: BTreeMap<String, String>,
+    /// Manifest entries
+    manifest: Vec<ManifestEntry>,
+}
+
+/// Writes the etags (from the etags file or metadata store) and manifest of the target
+/// directory to a bundle file
+pub async fn export_state(args: &Args, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let target = Path::new(&args.target);
+    let target_file = |name: &str| target.join(name).to_string_lossy().into_owned();
+
+    // Load the etags
+    let etags = match &args.metadata_store {
+        Some(store) => MetadataStore::open(store)?.load_etags().await?,
+        None => ETags::new_from_file(&target_file(".etags.json"))?,
+    };
+
+    // Load the manifest
+    let manifest = Manifest::new_from_file(&target_file(".manifest.json"))?;
+
+    let bundle = Bundle {
+        version: BUNDLE_VERSION,
+        etags: etags
+            .iter()
+            .map(|(url, etag)| (url.to_string(), etag.to_string()))
+            .collect(),
+        manifest: manifest.entries().cloned().collect(),
+    };
+
+    // Write the bundle
+    let fh = File::create(file).map_err(|e| format!("Error creating {file}: {e}"))?;
+
+    serde_json::to_writer_pretty(BufWriter::new(fh), &bundle)
+        .map_err(|e| format!("Error writing {file}: {e}"))?;
+
+    output!(
+        "Exported {} etags and {} manifest entries to {file}",
+        bundle.etags.len(),
+        bundle.manifest.len()
+    );
+
+    Ok(())
+}
+
+/// Merges the etags and manifest from a bundle file in to the target directory (and metadata
+/// store if one is being used)
+pub async fn import_state(args: &Args, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Load the bundle
+    let fh = File::open(file).map_err(|e| format!("Failed to open state file {file}: {e}"))?;
+
+    let bundle: Bundle = serde_json::from_reader(BufReader::new(fh))
+        .map_err(|e| format!("Failed to load state file {file}: {e}"))?;
+
+    if bundle.version != BUNDLE_VERSION {
+        Err(format!(
+            "State file {file} has unsupported version {}",
+            bundle.version
+        ))?
+    }
+
+    let target = Path::new(&args.target);
+    let target_file = |name: &str| target.join(name).to_string_lossy().into_owned();
+
+    create_dir_all(target)
+        .map_err(|e| format!("Unable to create target directory {}: {e}", args.target))?;
+
+    // Merge the etags
+    let mut etags = ETags::default();
+
+    for (url, etag) in &bundle.etags {
+        etags.add(url.clone(), etag.clone());
+    }
+
+    match &args.metadata_store {
+        Some(store) => MetadataStore::open(store)?.save_etags(etags).await?,
+        None => {
+            let etags_file = target_file(".etags.json");
+
+            let mut merged = ETags::new_from_file(&etags_file)?;
+            merged.extend(&etags).save_to_file(&etags_file)?
+        }
+    }
+
+    // Merge the manifest
+    let manifest_file = target_file(".manifest.json");
+    let mut manifest = Manifest::new_from_file(&manifest_file)?;
+
+    for entry in &bundle.manifest {
+        manifest.add(entry.clone());
+    }
+
+    manifest.save_to_file(&manifest_file)?;
+
+    output!(
+        "Imported {} etags and {} manifest entries from {file}",
+        bundle.etags.len(),
+        bundle.manifest.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::fs::{remove_file, File};
+use std::path::Path;
+
+use reqwest::StatusCode;
+
+use crate::args::Args;
+use crate::blocklist::HashBlocklist;
+use crate::checkpoint::Checkpoint;
+use crate::etags::ETags;
+use crate::exclude::ExcludeList;
+use crate::feed::FeedCursors;
+use crate::hosts::HostCapabilities;
+use crate::lastrun::RunSummary;
+use crate::listdates::ListingDates;
+use crate::manifest::Manifest;
+use crate::namemap::NameMap;
+use crate::output::{error, output};
+use crate::policy::CrawlPolicy;
+use crate::redirects::RedirectLog;
+use crate::skip::SkipList;
+use crate::state::{load_cacert, load_identity, parse_root_url, root_urls, State};
+use crate::store::MetadataStore;
+use crate::url::{HostScope, Url};
+
+/// Checks the configuration, reporting all of the problems found
+pub async fn check(args: Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut problems = Vec::new();
+
+    let mut report = |result: Result<(), Box<dyn Error + Send + Sync>>| {
+        if let Err(e) = result {
+            error!("{e}");
+            problems.push(e.to_string());
+        }
+    };
+
+    // Check the URLs
+    let mut urls = Vec::new();
+
+    match root_urls(&args) {
+        Ok(roots) => {
+            for url in roots {
+                match parse_root_url(&url) {
+                    Ok(url) => urls.push(url),
+                    Err(e) => report(Err(e)),
+                }
+            }
+        }
+        Err(e) => report(Err(e)),
+    }
+
+    // Check the configuration files
+    if let Some(file) = &args.skip_file {
+        report(SkipList::new_from_file(file).map(|_| ()));
+    }
+
+    if let Some(file) = &args.policy_file {
+        report(CrawlPolicy::check_file(file));
+    }
+
+    if let Some(file) = &args.exclude_from {
+        report(ExcludeList::new_from_file(file).map(|_| ()));
+    }
+
+    if let Some(file) = &args.blocklist_hashes {
+        report(HashBlocklist::new_from_file(file).map(|_| ()));
+    }
+
+    if let Some(file) = &args.metadata_store {
+        report(MetadataStore::open(file).map(|_| ()));
+    }
+
+    // Check the credentials
+    let mut credentials_ok = true;
+
+    if let Some(cacert) = &args.cacert {
+        let result = load_cacert(cacert).map(|_| ());
+        credentials_ok &= result.is_ok();
+        report(result);
+    }
+
+    if let (Some(client_cert), Some(client_key)) = (&args.client_cert, &args.client_key) {
+        let result = load_identity(client_cert, client_key).map(|_| ());
+        credentials_ok &= result.is_ok();
+        report(result);
+    }
+
+    // Check the target directory and the state files in it
+    let target = Path::new(&args.target);
+
+    report(check_writable(target));
+
+    if target.is_dir() {
+        let file = |name: &str| target.join(name).to_string_lossy().into_owned();
+
+        if !args.no_etags && args.metadata_store.is_none() {
+            report(ETags::new_from_file(&file(".etags.json")).map(|_| ()));
+        }
+
+        if args.manifest {
+            report(Manifest::new_from_file(&file(".manifest.json")).map(|_| ()));
+        }
+
+        if args.resume {
+            report(Checkpoint::new_from_file(&file(".mirrorurl-state.json")).map(|_| ()));
+        }
+
+        report(HostCapabilities::new_from_file(&file(".hosts.json")).map(|_| ()));
+        report(NameMap::new_from_file(&file(".names.json")).map(|_| ()));
+
+        if args.listing_dates {
+            report(ListingDates::new_from_file(&file(".listing-dates.json")).map(|_| ()));
+        }
+
+        report(FeedCursors::new_from_file(&file(".feed-cursors.json")).map(|_| ()));
+
+        if args.compare_last_run {
+            report(RunSummary::new_from_file(&file(".last-run.json")).map(|_| ()));
+        }
+    }
+
+    // Check the URLs can be reached
+    if credentials_ok {
+        if let Some(first) = urls.first() {
+            let scope = HostScope::new(first.clone(), true, Vec::new());
+            let client =
+                State::create_http_client(&args, scope, &args.resolve, &RedirectLog::default())?;
+
+            for url in &urls {
+                report(check_reachable(&client, url).await);
+            }
+        }
+    }
+
+    match problems.len() {
+        0 => {
+            output!("No problems found");
+            Ok(())
+        }
+        1 => Err("1 problem found")?,
+        n => Err(format!("{n} problems found"))?,
+    }
+}
+
+/// Checks files can be created in a directory, or in its nearest existing parent if it
+/// doesn't exist yet
+fn check_writable(target: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dir = target
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+
+    if !dir.is_dir() {
+        Err(format!("{} is not a directory", dir.display()))?
+    }
+
+    let probe = dir.join(".mirrorurl-check");
+
+    File::create(&probe)
+        .map_err(|e| format!("Target directory {} is not writable: {e}", dir.display()))?;
+
+    let _ = remove_file(&probe);
+
+    Ok(())
+}
+
+/// Checks a URL can be fetched
+async fn check_reachable(
+    client: &reqwest::Client,
+    url: &Url,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if url.scheme() == "file" {
+        return match url.to_file_path() {
+            Ok(path) if path.exists() => Ok(()),
+            _ => Err(format!("{url} does not exist"))?,
+        };
+    }
+
+    if url.scheme() == "ftp" {
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port().unwrap_or(21);
+
+        tokio::net::TcpStream::connect((host, port))
+            .await
+            .map_err(|e| format!("Unable to reach {url}: {e}"))?;
+
+        return Ok(());
+    }
+
+    let response = client
+        .head(url.clone())
+        .send()
+        .await
+        .map_err(|e| format!("Unable to reach {url}: {e}"))?;
+
+    let status = response.status();
+
+    if status.is_success()
+        || matches!(
+            status,
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        )
+    {
+        Ok(())
+    } else {
+        Err(format!("Status {status} from {url}"))?
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::savefile::save_file;
+
+/// Crawl progress saved so an interrupted run can be resumed
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Checkpoint {
+    /// URLs which have been fully processed
+    completed: BTreeSet<String>,
+    /// URLs which have been queued but not completed
+    frontier: BTreeSet<String>,
+}
+
+impl Checkpoint {
+    /// Load the crawl state from a JSON file. If the file does not exist, start a new crawl
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let checkpoint = match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load crawl state file {file}: {e}"))?
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Checkpoint::default(),
+                _ => Err(format!("Failed to open crawl state file {file}: {e}"))?,
+            },
+        };
+
+        Ok(checkpoint)
+    }
+
+    /// Save the crawl state to a JSON file
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = PathBuf::from(file);
+
+        let write = if let Some(parent) = path.parent() {
+            parent.is_dir()
+        } else {
+            true
+        };
+
+        if write {
+            save_file(file, |writer| {
+                Ok(serde_json::to_writer_pretty(writer, self)?)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a URL which is about to be processed
+    pub fn queue(&mut self, url: &str) {
+        if !self.completed.contains(url) {
+            self.frontier.insert(url.to_string());
+        }
+    }
+
+    /// Records a URL which has been fully processed
+    pub fn complete(&mut self, url: &str) {
+        self.frontier.remove(url);
+        self.completed.insert(url.to_string());
+    }
+
+    /// Returns true if a URL has been fully processed
+    pub fn is_completed(&self, url: &str) -> bool {
+        self.completed.contains(url)
+    }
+
+    /// Returns the URLs which have been queued but not completed
+    pub fn frontier(&self) -> impl Iterator<Item = &str> {
+        self.frontier.iter().map(String::as_str)
+    }
+
+    /// Returns true if there are no URLs left to process
+    pub fn is_finished(&self) -> bool {
+        self.frontier.is_empty()
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use percent_encoding::percent_decode_str;
+use tokio::fs::read_to_string;
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::ftp::read_text;
+use crate::hash::{ExpectedHash, HashType};
+use crate::output::debug;
+use crate::state::ArcState;
+use crate::url::Url;
+
+/// Checksum file listing for a directory (file name to hex digest)
+type SumsList = HashMap<String, String>;
+
+/// Checksums published on the server alongside downloaded files
+pub struct Checksums {
+    /// Hash algorithm to verify with
+    hash_type: HashType,
+    /// Checksum file listings by directory URL, fetched once per directory
+    dirs: Mutex<HashMap<Url, Arc<OnceCell<SumsList>>>>,
+}
+
+impl Checksums {
+    /// Creates an empty checksum cache
+    pub fn new(hash_type: HashType) -> Self {
+        Self {
+            hash_type,
+            dirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the name of the checksum list file for a directory (eg. SHA256SUMS)
+    fn sums_name(&self) -> String {
+        format!("{}SUMS", self.hash_type.name().to_ascii_uppercase())
+    }
+
+    /// Returns true if a file name is one of the checksum files
+    fn is_checksum_file(&self, name: &str) -> bool {
+        name == self.sums_name() || name.ends_with(&format!(".{}", self.hash_type))
+    }
+
+    /// Looks for a published checksum for a URL, first in the directory checksum list and
+    /// then in a sibling checksum file (eg. file.sha256)
+    pub async fn expected(
+        &self,
+        state: &ArcState,
+        url: &Url,
+    ) -> Result<Option<ExpectedHash>, Box<dyn Error + Send + Sync>> {
+        // Get the file name as it appears in checksum files
+        let name = match url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|name| percent_decode_str(name).decode_utf8_lossy())
+        {
+            Some(name) if !name.is_empty() && !self.is_checksum_file(&name) => name,
+            _ => return Ok(None),
+        };
+
+        // Look in the directory checksum list
+        let dir = url.join("./")?;
+        let sums_url = dir.join(&self.sums_name())?;
+
+        let cell = self
+            .dirs
+            .lock()
+            .await
+            .entry(dir.clone())
+            .or_default()
+            .clone();
+
+        let sums = cell
+            .get_or_init(|| async {
+                match fetch_text(state, &sums_url).await {
+                    Some(text) => parse_sums(&text),
+                    None => SumsList::new(),
+                }
+            })
+            .await;
+
+        let hex = match sums.get(name.as_ref()) {
+            Some(hex) => Some(hex.clone()),
+            None => {
+                // Look for a sibling checksum file
+                let mut sibling_url = url.clone();
+                sibling_url.set_path(&format!("{}.{}", url.path(), self.hash_type));
+                sibling_url.set_query(None);
+
+                fetch_text(state, &sibling_url).await.and_then(|text| {
+                    let sums = parse_sums(&text);
+
+                    match sums.get(name.as_ref()) {
+                        Some(hex) => Some(hex.clone()),
+                        None if sums.len() == 1 => sums.into_values().next(),
+                        None => None,
+                    }
+                })
+            }
+        };
+
+        Ok(hex.and_then(|hex| ExpectedHash::new(self.hash_type, &hex)))
+    }
+}
+
+/// Fetches a checksum file as text. Returns None if it doesn't exist
+async fn fetch_text(state: &ArcState, url: &Url) -> Option<String> {
+    let text = match url.scheme() {
+        "ftp" => read_text(state, url).await,
+        "file" => match url.to_file_path() {
+            Ok(path) => read_to_string(path).await.map_err(|e| e.into()),
+            Err(()) => Err("Not a local path".into()),
+        },
+        _ => return fetch_http_text(state, url).await,
+    };
+
+    match text {
+        Ok(text) => Some(text),
+        Err(e) => {
+            debug!(state, 1, "Failed to fetch checksum file {url}: {e}");
+            None
+        }
+    }
+}
+
+/// Fetches a checksum file from an HTTP server as text. Returns None if it doesn't exist
+async fn fetch_http_text(state: &ArcState, url: &Url) -> Option<String> {
+    match state.client().get(state.request_url(url)).send().await {
+        Ok(response) if response.status().is_success() => match response.text().await {
+            Ok(text) => Some(text),
+            Err(e) => {
+                debug!(state, 1, "Failed to read checksum file {url}: {e}");
+                None
+            }
+        },
+        Ok(response) => {
+            debug!(
+                state,
+                1,
+                "Status {} fetching checksum file {url}",
+                response.status()
+            );
+            None
+        }
+        Err(e) => {
+            debug!(state, 1, "Failed to fetch checksum file {url}: {e}");
+            None
+        }
+    }
+}
+
+/// Parses a checksum list in GNU (digest  name) or BSD (ALG (name) = digest) format
+fn parse_sums(text: &str) -> SumsList {
+    let mut sums = SumsList::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let entry = if let Some((head, hex)) = line.rsplit_once(" = ") {
+            // BSD format
+            head.split_once(" (")
+                .and_then(|(_, name)| name.strip_suffix(')'))
+                .map(|name| (name, hex))
+        } else {
+            // GNU format - a '*' marks binary mode
+            line.split_once(char::is_whitespace).map(|(hex, name)| {
+                let name = name.trim_start();
+                (name.strip_prefix('*').unwrap_or(name), hex)
+            })
+        };
+
+        if let Some((name, hex)) = entry {
+            let name = name.trim_start_matches("./");
+            sums.insert(name.to_string(), hex.trim().to_string());
+        }
+    }
+
+    sums
+}
@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Compression applied to files stored in the mirror
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum StoreCompression {
+    /// Zstandard (.zst)
+    Zstd,
+    /// Gzip (.gz)
+    Gzip,
+}
+
+impl StoreCompression {
+    /// Returns the extension appended to the names of stored files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zstd => ".zst",
+            Self::Gzip => ".gz",
+        }
+    }
+
+    /// Creates a streaming compressor
+    pub fn encoder(&self) -> io::Result<Encoder> {
+        Ok(match self {
+            Self::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+            Self::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+        })
+    }
+
+    /// Opens a stored file for reading its original contents
+    pub fn decoder(&self, file: File) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+            Self::Gzip => Box::new(GzDecoder::new(BufReader::new(file))),
+        })
+    }
+}
+
+/// Streaming compressor collecting its output in memory until it is taken
+pub enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Compresses a chunk of data, returning the compressed data produced so far
+    pub fn compress(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Self::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+
+        Ok(std::mem::take(out))
+    }
+
+    /// Ends the compressed stream, returning the remaining compressed data
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
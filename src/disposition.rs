@@ -0,0 +1,94 @@
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION};
+
+/// Returns the sanitised file name from a Content-Disposition header if there is one
+pub fn disposition_file_name(headers: &HeaderMap) -> Option<String> {
+    let value = header_text(headers.get(CONTENT_DISPOSITION)?.as_bytes());
+
+    parse_file_name(&value).and_then(|name| sanitise(&name))
+}
+
+/// Converts a header value to text. Servers sometimes send file names as raw UTF-8, and
+/// anything else is taken to be ISO-8859-1
+fn header_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().copied().map(char::from).collect(),
+    }
+}
+
+/// Parses the file name from a Content-Disposition header value. An RFC 5987 encoded
+/// filename* parameter is preferred over a plain filename parameter
+fn parse_file_name(value: &str) -> Option<String> {
+    let mut plain = None;
+
+    for param in value.split(';').skip(1) {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // charset'language'percent-encoded-value
+                if let Some((charset, rest)) = value.trim().split_once('\'') {
+                    if let Some((_, encoded)) = rest.split_once('\'') {
+                        let bytes: Vec<u8> = percent_decode_str(encoded).collect();
+
+                        let decoded = if charset.eq_ignore_ascii_case("utf-8") {
+                            String::from_utf8(bytes).ok()
+                        } else {
+                            // ISO-8859-1
+                            Some(bytes.into_iter().map(char::from).collect())
+                        };
+
+                        if decoded.is_some() {
+                            return decoded;
+                        }
+                    }
+                }
+            }
+            "filename" => plain = Some(unquote(value.trim())),
+            _ => {}
+        }
+    }
+
+    plain
+}
+
+/// Removes the quotes and escapes from a quoted string
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => {
+            let mut result = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => result.extend(chars.next()),
+                    c => result.push(c),
+                }
+            }
+
+            result
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Makes a file name from the server safe to use. Any directory part is removed along with
+/// control characters. Returns None if nothing usable is left
+fn sanitise(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
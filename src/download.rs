@@ -1,25 +1,309 @@
 use std::error::Error;
 use std::ffi::OsString;
+use std::fmt::Display;
 use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 
-use reqwest::header::ETAG;
-use tokio::fs::{create_dir_all, remove_file, rename, File};
-use tokio::io::AsyncWriteExt;
+use bytes::Bytes;
+use clap::ValueEnum;
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use tokio::fs::{copy, create_dir_all, hard_link, remove_file, rename, File};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::task::spawn_blocking;
 
-use crate::output::{debug, error, output};
+use crate::disposition::disposition_file_name;
+use crate::etags::{etag_to_string, SyntheticETag};
+use crate::extract::auto_extract;
+use crate::hash::{file_digests, DigestHasher, Digests};
+use crate::outcome::{check, Outcome};
+use crate::output::{debug, error, output, progress};
+use crate::publish::Deferred;
+use crate::redirects::record_redirect;
 use crate::response::Response;
+use crate::segment::Segments;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::stats::{human_size, Stats};
 use crate::url::Url;
 use crate::ArcState;
 
+/// Size of the buffer downloads are written through
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Error for a download which failed checksum or length verification
+#[derive(Debug)]
+pub struct VerifyErr(String);
+
+impl Display for VerifyErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for VerifyErr {}
+
+/// Handling of downloads with an empty body
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EmptyFiles {
+    /// Save the empty file
+    #[default]
+    Keep,
+    /// Skip the file, keeping any previous copy
+    Skip,
+    /// Treat the download as an error, keeping any previous copy
+    Error,
+}
+
+/// Source of data to save to a file
+pub trait Body {
+    /// Returns the length of the data if known
+    fn content_length(&self) -> Option<u64>;
+
+    /// Returns the next chunk of data, or None at the end of the data
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>>;
+
+    /// Returns the segments to download the data in if the rest of it can be fetched over
+    /// other connections
+    fn segments(&self, _state: &ArcState) -> Option<Segments> {
+        None
+    }
+}
+
+impl Body for Response {
+    fn content_length(&self) -> Option<u64> {
+        Response::content_length(self)
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+        Ok(self.chunk().await?)
+    }
+
+    fn segments(&self, state: &ArcState) -> Option<Segments> {
+        Segments::plan(state, self)
+    }
+}
+
+/// Body already held in memory
+pub struct BytesBody {
+    bytes: Option<Bytes>,
+    len: u64,
+}
+
+impl BytesBody {
+    /// Creates a new in memory body
+    pub fn new(bytes: Bytes) -> Self {
+        Self {
+            len: bytes.len() as u64,
+            bytes: Some(bytes),
+        }
+    }
+}
+
+impl Body for BytesBody {
+    fn content_length(&self) -> Option<u64> {
+        Some(self.len)
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+        Ok(self.bytes.take())
+    }
+}
+
+/// Response body with the start of it already read
+pub struct PrefixedBody {
+    prefix: Option<Bytes>,
+    response: Response,
+    len: Option<u64>,
+}
+
+impl PrefixedBody {
+    /// Creates a body from the start already read from a response and the rest of the response.
+    /// The length is that of the whole body
+    pub fn new(prefix: Bytes, response: Response, len: Option<u64>) -> Self {
+        Self {
+            prefix: Some(prefix),
+            response,
+            len,
+        }
+    }
+}
+
+impl Body for PrefixedBody {
+    fn content_length(&self) -> Option<u64> {
+        self.len
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+        match self.prefix.take() {
+            Some(prefix) => Ok(Some(prefix)),
+            None => Ok(self.response.chunk().await?),
+        }
+    }
+}
+
+/// Details of a file saved to the mirror
+pub struct Saved {
+    /// Number of bytes written
+    pub bytes: usize,
+    /// Path of the file (the temporary file if publishing is deferred)
+    pub path: PathBuf,
+    /// SHA-256 of the file contents
+    pub sha256: String,
+}
+
 /// Downloads a URL to a file
 pub async fn download(
     state: &ArcState,
     url: &Url,
     final_url: &Url,
     mut response: Response,
-) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    let headers = response.headers().clone();
+
+    download_body(state, url, final_url, &headers, &mut response, stats).await
+}
+
+/// Downloads a body with a set of response headers to a file
+pub async fn download_body<B>(
+    state: &ArcState,
+    url: &Url,
+    final_url: &Url,
+    headers: &HeaderMap,
+    body: &mut B,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>>
+where
+    B: Body,
+{
+    // Use the file name given by the server if enabled
+    let file_name = if state.args().content_disposition {
+        disposition_file_name(headers)
+    } else {
+        None
+    };
+
+    // Save the body, verifying it before any previous copy is replaced
+    let saved = check!(
+        save_body_checked(
+            state,
+            Some(url),
+            final_url,
+            file_name.as_deref(),
+            body,
+            stats,
+        )
+        .await?
+    );
+
+    // Give the file the server's modification time so later runs can compare it
+    if state.args().skip_existing {
+        if let Some(modified) = headers
+            .get(LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+        {
+            set_mtime(&saved.path, modified).await?;
+        }
+    }
+
+    // Unpack archives before recording the etag so a failed unpack is retried
+    if !state.args().auto_extract.is_empty() {
+        if let Some(members) = auto_extract(state, headers, &saved.path).await? {
+            state.set_manifest_members(final_url, &members).await;
+        }
+    }
+
+    // Get response etag
+    match headers.get(ETAG) {
+        Some(value) => {
+            // Add etag for original and final url (if different). Etags which aren't printable
+            // ASCII are stored encoded
+            let etag = etag_to_string(value);
+            debug!(state, 1, "etag for {url} (final {final_url}): {etag}");
+            state.add_etags(vec![url, final_url], &etag);
+        }
+        None if state.args().synth_etags && !headers.contains_key(LAST_MODIFIED) => {
+            // No validators received - synthesize an etag from the file details
+            let etag = SyntheticETag::new_from_file(&saved.path, &saved.sha256)
+                .await?
+                .to_string();
+
+            debug!(
+                state,
+                1, "Synthesized etag for {url} (final {final_url}): {etag}"
+            );
+            state.add_etags(vec![url, final_url], &etag);
+        }
+        None => {
+            // No etag received
+            debug!(state, 1, "No etag header received");
+        }
+    }
+
+    // Remember the date the directory listing gave so the file isn't fetched again until it changes
+    state.record_listed_date(url).await;
+
+    Ok(Outcome::Downloaded { bytes: saved.bytes })
+}
+
+/// Saves a body to the file for a URL via a temporary file. The inner error gives the reason if
+/// the body is skipped
+pub async fn save_body<B>(
+    state: &ArcState,
+    final_url: &Url,
+    body: &mut B,
+    stats: &mut Stats,
+) -> Result<Result<Saved, SkipReasonErr>, Box<dyn Error + Send + Sync>>
+where
+    B: Body,
+{
+    save_body_checked(state, None, final_url, None, body, stats).await
+}
+
+/// Saves a body fetched from a URL without redirects to the file for the URL via a temporary
+/// file, verifying it before it replaces the file. The inner error gives the reason if the body
+/// is skipped
+pub async fn save_verified_body<B>(
+    state: &ArcState,
+    url: &Url,
+    body: &mut B,
+    stats: &mut Stats,
+) -> Result<Result<Saved, SkipReasonErr>, Box<dyn Error + Send + Sync>>
+where
+    B: Body,
+{
+    save_body_checked(state, Some(url), url, None, body, stats).await
+}
+
+/// Saves a body to the file for a URL via a temporary file. If the original URL is given the
+/// download is verified before it replaces the file. A previous copy of the file is kept if
+/// the download fails. A file name replaces the name of the file for the URL if given. The inner
+/// error gives the reason if the body is skipped
+async fn save_body_checked<B>(
+    state: &ArcState,
+    url: Option<&Url>,
+    final_url: &Url,
+    file_name: Option<&str>,
+    body: &mut B,
+    stats: &mut Stats,
+) -> Result<Result<Saved, SkipReasonErr>, Box<dyn Error + Send + Sync>>
+where
+    B: Body,
+{
     // Build full download path
-    let path = state.path_for_url(final_url).await?;
+    let mut path = match state.path_for_url(final_url).await {
+        Ok(path) => path,
+        Err(skip) => return Ok(Err(skip)),
+    };
+
+    if let Some(file_name) = file_name {
+        match state.args().store_compressed {
+            Some(compression) => {
+                path.set_file_name(format!("{file_name}{}", compression.extension()))
+            }
+            None => path.set_file_name(file_name),
+        }
+    }
 
     // Build temp file name
     let mut tmp_file_name = match path.file_name() {
@@ -31,53 +315,363 @@ pub async fn download(
     // Build temp path
     let tmp_path = path.with_file_name(tmp_file_name);
 
-    // Download to temp file
-    let bytes = match download_to_path(state, final_url, &mut response, &path, &tmp_path).await {
-        Ok(bytes) => {
-            // Try and rename the file
-            match rename(&tmp_path, path).await {
-                Ok(_) => bytes,
-                Err(e) => {
-                    // Failed - try and remove temp file
+    // Download to temp file and check it
+    let started = Instant::now();
+    let downloaded = download_to_path(state, final_url, body, &path, &tmp_path).await;
+
+    if state.args().timings && downloaded.is_ok() {
+        stats.add_transfer_time(started.elapsed());
+    }
+
+    // Skip empty bodies, keeping any previous copy
+    if matches!(downloaded, Ok((0, _))) && state.args().empty_files == EmptyFiles::Skip {
+        let _ = remove_file(&tmp_path).await;
+
+        return Ok(Err(SkipReasonErr::new(
+            final_url.to_string(),
+            SkipReason::Empty,
+        )));
+    }
+
+    let result = match downloaded {
+        Ok((0, _)) if state.args().empty_files == EmptyFiles::Error => {
+            Err(format!("Empty response body from {final_url}").into())
+        }
+        Ok((bytes, digests)) => match url {
+            Some(url) => verify_download(state, url, &tmp_path, &path, bytes, &digests)
+                .await
+                .map(|()| (bytes, digests.sha256().to_string())),
+            None => Ok((bytes, digests.sha256().to_string())),
+        },
+        Err(e) => Err(e),
+    };
+
+    // Refuse content on the hash blocklist
+    let result = match result {
+        Ok((_, sha256)) if state.is_blocked(&sha256) => {
+            match block_download(state, final_url, &tmp_path, &path, &sha256).await {
+                Ok(skip) => {
                     let _ = remove_file(&tmp_path).await;
-                    Err(e)?
+
+                    return Ok(Err(skip));
                 }
+                Err(e) => Err(e),
             }
         }
+        result => result,
+    };
+
+    // Metadata files are published once the data files they reference have been downloaded
+    let deferred = result.is_ok() && state.defers_publish(final_url);
+
+    // Rename the temp file over the file
+    let result = match result {
+        Ok(result) if deferred => Ok(result),
+        Ok(result) => rename(&tmp_path, &path)
+            .await
+            .map(|()| result)
+            .map_err(|e| e.into()),
+        Err(e) => Err(e),
+    };
+
+    let (bytes, sha256) = match result {
+        Ok(result) => result,
         Err(e) => {
+            // Keep downloads which failed verification for inspection
+            if state.args().quarantine_failed && e.is::<VerifyErr>() {
+                quarantine_failed(state, final_url, &tmp_path, &path, &*e).await;
+            }
+
             // Failed - try and remove temp file
             let _ = remove_file(&tmp_path).await;
+
+            // Note if the previous copy has been kept
+            if path.is_file() {
+                debug!(state, 1, "Keeping previous copy of {}", path.display());
+                stats.add_kept_stale();
+                state.set_manifest_stale(final_url).await;
+            }
+
             Err(e)?
         }
     };
 
-    // Get response etag
-    match response.headers().get(ETAG).map(|value| value.to_str()) {
-        Some(Ok(etag)) => {
-            // Add etag for original and final url (if different)
-            debug!(state, 1, "etag for {url} (final {final_url}): {etag}");
-            state.add_etags(vec![url, final_url], etag).await;
+    // Break the downloads down by directory and extension
+    if state.args().stats_detail {
+        if let Ok(rel_path) = path.strip_prefix(&state.args().target) {
+            stats.add_detail(rel_path, bytes);
         }
-        Some(_) => {
-            // Etag is invalid
-            error!("Invalid etag header received from {url}");
+    }
+
+    // Make the original URL's path lead to the file
+    if let (Some(how), Some(url)) = (state.args().record_redirects, url) {
+        if url != final_url {
+            record_redirect(state, how, url, final_url, &path).await;
         }
-        None => {
-            // No etag received
-            debug!(state, 1, "No etag header received");
+    }
+
+    if deferred {
+        debug!(state, 1, "Deferring publishing {}", path.display());
+
+        state
+            .defer_publish(Deferred {
+                urls: url.into_iter().chain([final_url]).cloned().collect(),
+                final_url: final_url.clone(),
+                tmp_path: tmp_path.clone(),
+                path: path.clone(),
+                size: bytes as u64,
+                sha256: sha256.clone(),
+            })
+            .await;
+
+        state.events().on_download_complete(final_url, &path, bytes);
+
+        return Ok(Ok(Saved {
+            bytes,
+            path: tmp_path,
+            sha256,
+        }));
+    }
+
+    // Remember the size for the post-run audit
+    if let Some(audit) = state.audit() {
+        audit.add_size(final_url, bytes as u64).await;
+    }
+
+    // Replace with a hard link to an identical file already in the mirror
+    if state.args().dedupe {
+        if let Some(existing) = state.find_duplicate(&path, bytes as u64, &sha256).await {
+            dedupe(state, &existing, &path, &tmp_path).await;
+        }
+    }
+
+    // Record the file in the manifest
+    state
+        .add_manifest_entry(final_url, &path, bytes as u64, &sha256)
+        .await;
+
+    state.events().on_download_complete(final_url, &path, bytes);
+
+    Ok(Ok(Saved {
+        bytes,
+        path,
+        sha256,
+    }))
+}
+
+/// Verifies a downloaded file against the size given by a directory listing, the digest given
+/// by a link hash fragment and the checksums published on the server
+async fn verify_download(
+    state: &ArcState,
+    url: &Url,
+    tmp_path: &Path,
+    path: &Path,
+    bytes: usize,
+    digests: &Digests,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Check the size given by the directory listing
+    if let Some(listed_size) = state.listed_size(url).await {
+        if !listed_size.matches(bytes as u64) {
+            Err(VerifyErr(format!(
+                "Size mismatch for {}: listed as {listed_size}, got {bytes} bytes",
+                path.display()
+            )))?
+        }
+    }
+
+    // Verify the digest given by a link hash fragment
+    if let Some(expected_hash) = state.expected_hash(url).await {
+        expected_hash
+            .verify(tmp_path, path, digests, state.args().store_compressed)
+            .await
+            .map_err(|e| VerifyErr(e.to_string()))?;
+
+        debug!(state, 1, "{} of {url} verified", expected_hash.hash_type);
+    }
+
+    // Verify against the checksums published on the server
+    if let Some(checksums) = state.checksums() {
+        match checksums.expected(state, url).await? {
+            Some(expected_hash) => {
+                expected_hash
+                    .verify(tmp_path, path, digests, state.args().store_compressed)
+                    .await
+                    .map_err(|e| VerifyErr(e.to_string()))?;
+
+                debug!(state, 1, "{} of {url} verified", expected_hash.hash_type);
+            }
+            None => debug!(state, 1, "No published checksum found for {url}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves a download whose content is on the hash blocklist to the quarantine directory if one
+/// is given, returning the reason to skip it with. Otherwise the caller removes the download
+async fn block_download(
+    state: &ArcState,
+    final_url: &Url,
+    tmp_path: &Path,
+    path: &Path,
+    sha256: &str,
+) -> Result<SkipReasonErr, Box<dyn Error + Send + Sync>> {
+    if let Some(dir) = &state.args().quarantine_dir {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let quarantine_path = Path::new(dir).join(format!("{sha256}-{name}"));
+
+        match quarantine(tmp_path, &quarantine_path).await {
+            Ok(()) => output!("Quarantined {final_url} as {}", quarantine_path.display()),
+            Err(e) => Err(format!("Unable to quarantine {final_url}: {e}"))?,
+        }
+    }
+
+    Ok(SkipReasonErr::new(
+        final_url.to_string(),
+        SkipReason::Blocked(sha256.to_string()),
+    ))
+}
+
+/// Moves a download which failed verification to .mirrorurl/quarantine/<path> in the target
+/// directory and writes the reason next to it in <path>.reason
+async fn quarantine_failed(
+    state: &ArcState,
+    final_url: &Url,
+    tmp_path: &Path,
+    path: &Path,
+    reason: &(dyn Error + Send + Sync),
+) {
+    let target = Path::new(&state.args().target);
+    let rel_path = path.strip_prefix(target).unwrap_or(path);
+
+    let quarantine_path = target.join(".mirrorurl").join("quarantine").join(rel_path);
+
+    let mut reason_name = quarantine_path.as_os_str().to_owned();
+    reason_name.push(".reason");
+    let reason_path = PathBuf::from(reason_name);
+
+    let result = match quarantine(tmp_path, &quarantine_path).await {
+        Ok(()) => tokio::fs::write(&reason_path, format!("{final_url}\n{reason}\n")).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => output!("Quarantined {final_url} as {}", quarantine_path.display()),
+        Err(e) => error!("Unable to quarantine {final_url}: {e}"),
+    }
+}
+
+/// Moves a file in to the quarantine directory, copying it if it can't be renamed
+async fn quarantine(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    if rename(from, to).await.is_err() {
+        copy(from, to).await?;
+        remove_file(from).await?;
+    }
+
+    Ok(())
+}
+
+/// Sets the modification time of a file
+async fn set_mtime(path: &Path, modified: SystemTime) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let file = File::options()
+        .write(true)
+        .open(path)
+        .await
+        .map_err(|e| format!("Unable to open {}: {e}", path.display()))?
+        .into_std()
+        .await;
+
+    spawn_blocking(move || file.set_modified(modified))
+        .await?
+        .map_err(|e| format!("Unable to set modification time of {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+/// Replaces a file with a hard link to an identical file via a temporary link
+async fn dedupe(state: &ArcState, existing: &Path, path: &Path, tmp_path: &Path) {
+    let result = match hard_link(existing, tmp_path).await {
+        Ok(()) => rename(tmp_path, path).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => progress!(
+            "Linked {} to identical file {}",
+            path.display(),
+            existing.display()
+        ),
+        Err(e) => {
+            // Failed - keep the copy
+            let _ = remove_file(tmp_path).await;
+            debug!(
+                state,
+                1,
+                "Unable to link {} to {}: {e}",
+                path.display(),
+                existing.display()
+            );
+        }
+    }
+}
+
+/// Allocates the disk space for a file before it is written so running out of space shows up
+/// before the download and the file isn't fragmented. Where the filesystem can't allocate space
+/// up front the length of the file is set, leaving it sparse until written
+pub async fn preallocate(
+    file: &File,
+    length: u64,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    #[cfg(target_os = "linux")]
+    if length > 0 {
+        use rustix::fs::{fallocate, FallocateFlags};
+        use rustix::io::Errno;
+
+        let std_file = file
+            .try_clone()
+            .await
+            .map_err(|e| format!("Unable to allocate {}: {e}", path.display()))?
+            .into_std()
+            .await;
+
+        match spawn_blocking(move || fallocate(&std_file, FallocateFlags::empty(), 0, length))
+            .await?
+        {
+            Ok(()) => return Ok(()),
+            Err(Errno::OPNOTSUPP) => (),
+            Err(e) => Err(format!("Unable to allocate {}: {e}", path.display()))?,
         }
     }
 
-    Ok(bytes)
+    file.set_len(length)
+        .await
+        .map_err(|e| format!("Unable to allocate {}: {e}", path.display()))?;
+
+    Ok(())
 }
 
-pub async fn download_to_path(
+/// Downloads a body to a path returning the number of bytes received and the digests of the
+/// contents, worked out as the contents are written. The file is compressed if files are stored
+/// compressed
+pub async fn download_to_path<B>(
     state: &ArcState,
     final_url: &Url,
-    response: &mut Response,
+    body: &mut B,
     final_path: &Path,
     tmp_path: &PathBuf,
-) -> Result<usize, Box<dyn Error + Send + Sync>> {
+) -> Result<(usize, Digests), Box<dyn Error + Send + Sync>>
+where
+    B: Body,
+{
     // Create directories if necessary
     if let Some(parent) = tmp_path.parent() {
         if !parent.is_dir() {
@@ -88,35 +682,83 @@ pub async fn download_to_path(
     }
 
     // Calculate size string
-    let size = response
+    let size = body
         .content_length()
-        .map(|s| format!("{s}"))
+        .map(|s| match human_size(s) {
+            Some(size) if !state.args().bytes => size,
+            _ => format!("{s}"),
+        })
         .unwrap_or(String::from("unknown"));
 
-    output!(
+    progress!(
         "Downloading {final_url} to {} (size {size})",
         final_path.display()
     );
 
     // Open the file
-    let mut file = File::create(&tmp_path)
+    let file = File::create(&tmp_path)
         .await
         .map_err(|e| format!("Unable to create file {}: {e}", tmp_path.display()))?;
 
     // Debug delay
     state.debug_delay().await;
 
+    // Remember the expected length
+    let expected_length = body.content_length();
+
+    // Fetch large files over several connections
+    if let Some(segments) = body.segments(state) {
+        let bytes = segments.download(state, body, file, tmp_path).await?;
+
+        // The segments arrive out of order so the file is read back once to hash it
+        let digests = file_digests(tmp_path, state.args().verify).await?;
+
+        return Ok((bytes, digests));
+    }
+
+    // Compress the data if files are stored compressed
+    let mut encoder = match state.args().store_compressed {
+        Some(compression) => Some(
+            compression
+                .encoder()
+                .map_err(|e| format!("Unable to create compressor: {e}"))?,
+        ),
+        None => None,
+    };
+
+    // Preallocate the file if the length is known
+    if let (Some(length), None) = (expected_length, &encoder) {
+        preallocate(&file, length, tmp_path).await?;
+    }
+
+    // Buffer the writes so servers sending small chunks don't cause a write per chunk
+    let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
+
     // Read next chunk
     let mut bytes = 0;
+    let mut hasher = DigestHasher::new(state.args().verify);
 
-    while let Some(chunk) = response
-        .chunk()
+    while let Some(chunk) = body
+        .next_chunk()
         .await
         .map_err(|e| format!("Error downloading chunk: {e}"))?
     {
         bytes += chunk.len();
         debug!(state, 2, "Read {} bytes", chunk.len());
 
+        // Add chunk to the hash
+        hasher.update(&chunk);
+
+        // Compress the chunk
+        let chunk = match &mut encoder {
+            Some(encoder) => Bytes::from(
+                encoder
+                    .compress(&chunk)
+                    .map_err(|e| format!("Error compressing {}: {e}", tmp_path.display()))?,
+            ),
+            None => chunk,
+        };
+
         // Write chunk to the file
         file.write_all(&chunk)
             .await
@@ -126,5 +768,31 @@ pub async fn download_to_path(
         state.debug_delay().await;
     }
 
-    Ok(bytes)
+    // Write the end of the compressed stream
+    if let Some(encoder) = encoder {
+        let chunk = encoder
+            .finish()
+            .map_err(|e| format!("Error compressing {}: {e}", tmp_path.display()))?;
+
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| format!("Error writing to {}: {e}", tmp_path.display()))?;
+    }
+
+    // Write out the rest of the buffer
+    file.flush()
+        .await
+        .map_err(|e| format!("Error writing to {}: {e}", tmp_path.display()))?;
+
+    // Check the length
+    if let Some(expected_length) = expected_length {
+        if bytes as u64 != expected_length {
+            Err(VerifyErr(format!(
+                "Length mismatch for {}: expected {expected_length} bytes, got {bytes}",
+                final_path.display()
+            )))?
+        }
+    }
+
+    Ok((bytes, hasher.finalize()))
 }
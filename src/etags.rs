@@ -1,15 +1,47 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::UNIX_EPOCH;
+
+use reqwest::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+
+use crate::savefile::save_file;
 
 /// Map of URLs to etags
 #[derive(Default)]
 pub struct ETags {
+    /// Base URL of the mirror the etags were recorded for
+    base: Option<String>,
     etags: HashMap<String, String>,
 }
 
+/// Contents of an etags file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ETagsFile {
+    /// Etags with the base URL of the mirror
+    WithBase {
+        base: String,
+        etags: HashMap<String, String>,
+    },
+    /// Etags only, as written by earlier versions
+    Plain(HashMap<String, String>),
+}
+
+/// Etags with the base URL of the mirror for writing
+#[derive(Serialize)]
+struct ETagsWithBase<'a> {
+    base: &'a str,
+    etags: &'a HashMap<String, String>,
+}
+
 impl ETags {
     /// Load mapping from a JSON file. If the file does not exist, create an empty list
     pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
@@ -17,10 +49,16 @@ impl ETags {
             Ok(fh) => {
                 let reader = BufReader::new(fh);
 
-                let map = serde_json::from_reader(reader)
+                let contents = serde_json::from_reader(reader)
                     .map_err(|e| format!("Failed to load etags file {file}: {e}"))?;
 
-                Self { etags: map }
+                match contents {
+                    ETagsFile::WithBase { base, etags } => Self {
+                        base: Some(base),
+                        etags,
+                    },
+                    ETagsFile::Plain(etags) => Self { base: None, etags },
+                }
             }
             Err(e) => match e.kind() {
                 std::io::ErrorKind::NotFound => ETags::default(),
@@ -31,7 +69,8 @@ impl ETags {
         Ok(etags)
     }
 
-    /// Save mapping to a JSON file
+    /// Save mapping to a JSON file. The mapping is written to a temporary file which is then
+    /// renamed over the file, so an interrupted save leaves the previous file intact
     pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
         let path = PathBuf::from(file);
 
@@ -42,12 +81,7 @@ impl ETags {
         };
 
         if write {
-            let fh = File::create(path).map_err(|e| format!("Error creating {file}: {e}"))?;
-
-            let writer = BufWriter::new(fh);
-
-            self.write(writer)
-                .map_err(|e| format!("Error writing {file}: {e}"))?;
+            save_file(file, |writer| self.write(writer))?;
         }
 
         Ok(())
@@ -58,7 +92,26 @@ impl ETags {
     where
         W: Write,
     {
-        Ok(serde_json::to_writer_pretty(writer, &self.etags)?)
+        match &self.base {
+            Some(base) => Ok(serde_json::to_writer_pretty(
+                writer,
+                &ETagsWithBase {
+                    base,
+                    etags: &self.etags,
+                },
+            )?),
+            None => Ok(serde_json::to_writer_pretty(writer, &self.etags)?),
+        }
+    }
+
+    /// Returns the base URL of the mirror the etags were recorded for if known
+    pub fn base(&self) -> Option<&str> {
+        self.base.as_deref()
+    }
+
+    /// Sets the base URL of the mirror the etags are recorded for
+    pub fn set_base(&mut self, base: String) {
+        self.base = Some(base);
     }
 
     /// Looks for a URL in the mapping and returns the etag if present
@@ -71,6 +124,17 @@ impl ETags {
         self.etags.insert(url, etag);
     }
 
+    /// Removes the mappings for URLs not matching a predicate, returning the number removed
+    pub fn retain<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let before = self.etags.len();
+        self.etags.retain(|url, _| keep(url));
+
+        before - self.etags.len()
+    }
+
     /// Extends the map with another map
     pub fn extend(&mut self, other: &ETags) -> &Self {
         self.etags.extend(
@@ -83,8 +147,218 @@ impl ETags {
         self
     }
 
+    /// Returns an iterator over the URL to etag mappings
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.etags
+            .iter()
+            .map(|(url, etag)| (url.as_str(), etag.as_str()))
+    }
+
     /// Returns true if the collection is empty
     pub fn is_empty(&self) -> bool {
         self.etags.is_empty()
     }
 }
+
+/// Number of independently locked shards in a shared etag map
+const SHARDS: usize = 16;
+
+/// Map of URLs to etags which can be added to concurrently. Entries are spread over a number
+/// of independently locked shards so concurrent downloads rarely contend, and no lock is held
+/// across an await
+pub struct SharedETags {
+    shards: Vec<Mutex<ETags>>,
+    /// Set when the mappings change
+    changed: AtomicBool,
+}
+
+impl Default for SharedETags {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(ETags::default())).collect(),
+            changed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SharedETags {
+    /// Adds a URL to etag mapping
+    pub fn add(&self, url: String, etag: String) {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+
+        let shard = &self.shards[hasher.finish() as usize % SHARDS];
+
+        shard
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .add(url, etag);
+
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Removes a URL to etag mapping
+    pub fn remove(&self, url: &str) {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+
+        let shard = &self.shards[hasher.finish() as usize % SHARDS];
+
+        shard
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .etags
+            .remove(url);
+
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if the mappings have changed since the last call
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns a copy of all of the mappings as a single map
+    pub fn snapshot(&self) -> ETags {
+        let mut etags = ETags::default();
+
+        for shard in &self.shards {
+            etags.extend(&shard.lock().unwrap_or_else(PoisonError::into_inner));
+        }
+
+        etags
+    }
+
+    /// Removes all of the mappings returning them as a single map
+    pub fn take(&self) -> ETags {
+        let mut etags = ETags::default();
+
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            etags.etags.extend(shard.etags.drain());
+        }
+
+        etags
+    }
+}
+
+/// Prefix for etags received with bytes which aren't printable ASCII. The raw bytes follow hex
+/// encoded
+const RAW_PREFIX: &str = "mirrorurl-raw:";
+
+/// Converts an etag header value to the string stored for it. Values which aren't printable
+/// ASCII are hex encoded so they can be sent back to the server unchanged
+pub fn etag_to_string(value: &HeaderValue) -> String {
+    match value.to_str() {
+        Ok(etag) if !etag.starts_with(RAW_PREFIX) => etag.to_string(),
+        _ => {
+            let hex: String = value
+                .as_bytes()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            format!("{RAW_PREFIX}{hex}")
+        }
+    }
+}
+
+/// Converts a stored etag back to the header value received from the server
+pub fn etag_to_header(etag: &str) -> Option<HeaderValue> {
+    match etag.strip_prefix(RAW_PREFIX) {
+        Some(hex) => {
+            if hex.len() % 2 != 0 || !hex.is_ascii() {
+                return None;
+            }
+
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+
+            HeaderValue::from_bytes(&bytes).ok()
+        }
+        None => HeaderValue::from_str(etag).ok(),
+    }
+}
+
+/// Compares two etags using the weak comparison of RFC 7232, which ignores the W/ prefix of weak
+/// etags
+pub fn weak_match(a: &str, b: &str) -> bool {
+    fn opaque(etag: &str) -> &str {
+        etag.strip_prefix("W/").unwrap_or(etag)
+    }
+
+    opaque(a) == opaque(b)
+}
+
+/// Prefix for etags synthesized from downloaded file details
+const SYNTHETIC_PREFIX: &str = "mirrorurl:";
+
+/// Etag synthesized for a file from a server which provides no validators
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticETag {
+    /// File size
+    pub size: u64,
+    /// Local file modification time in nanoseconds since the epoch
+    pub mtime: u128,
+    /// SHA-256 of the file contents
+    pub sha256: String,
+}
+
+impl SyntheticETag {
+    /// Builds a synthetic etag from a downloaded file
+    pub async fn new_from_file(
+        path: &Path,
+        sha256: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let meta = tokio::fs::metadata(path).await?;
+
+        Ok(Self {
+            size: meta.len(),
+            mtime: Self::file_mtime(&meta),
+            sha256: sha256.to_string(),
+        })
+    }
+
+    /// Parses a synthetic etag. Returns None if this is not a synthetic etag
+    pub fn parse(etag: &str) -> Option<Self> {
+        let mut parts = etag.strip_prefix(SYNTHETIC_PREFIX)?.split(':');
+
+        let size = parts.next()?.parse().ok()?;
+        let mtime = parts.next()?.parse().ok()?;
+        let sha256 = parts.next()?.to_string();
+
+        Some(Self {
+            size,
+            mtime,
+            sha256,
+        })
+    }
+
+    /// Returns true if a local file still has the recorded size and modification time
+    pub async fn matches_file(&self, path: &Path) -> bool {
+        match tokio::fs::metadata(path).await {
+            Ok(meta) => meta.len() == self.size && Self::file_mtime(&meta) == self.mtime,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the modification time of a file in nanoseconds since the epoch
+    fn file_mtime(meta: &std::fs::Metadata) -> u128 {
+        meta.modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for SyntheticETag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{SYNTHETIC_PREFIX}{}:{}:{}",
+            self.size, self.mtime, self.sha256
+        )
+    }
+}
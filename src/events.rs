@@ -0,0 +1,36 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::output::{error, progress, Logger};
+use crate::skipreason::SkipReasonErr;
+use crate::url::Url;
+
+/// Receives notifications as URLs are processed. All methods default to doing nothing
+pub trait EventSink: Send + Sync {
+    /// A URL is about to be fetched
+    fn on_fetch_start(&self, _url: &Url) {}
+
+    /// A file has been saved to the mirror
+    fn on_download_complete(&self, _url: &Url, _path: &Path, _bytes: usize) {}
+
+    /// A URL has been skipped
+    fn on_skip(&self, _skip: &SkipReasonErr) {}
+
+    /// Processing a URL failed
+    fn on_error(&self, _url: &Url, _error: &dyn Display) {}
+}
+
+/// The logger reports events on the console
+impl EventSink for Logger {
+    fn on_fetch_start(&self, url: &Url) {
+        progress!("Fetching {url}");
+    }
+
+    fn on_skip(&self, skip: &SkipReasonErr) {
+        progress!("{skip}");
+    }
+
+    fn on_error(&self, _url: &Url, error: &dyn Display) {
+        error!("{error}");
+    }
+}
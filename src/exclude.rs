@@ -0,0 +1,372 @@
+use std::error::Error;
+use std::fs::read_to_string;
+
+use percent_encoding::percent_decode_str;
+
+use crate::url::{Url, UrlExt};
+
+/// Path a rule is matched against
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Root {
+    /// Path relative to the base URL (rsync patterns)
+    Base,
+    /// Absolute path on the host (wget --exclude-directories)
+    Host,
+}
+
+/// A single exclude or include rule
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Rule includes rather than excludes matching paths
+    include: bool,
+    /// Glob pattern
+    pattern: String,
+    /// Path the pattern is matched against
+    root: Root,
+    /// Pattern must match from the start of the path
+    anchored: bool,
+    /// Pattern only matches directories
+    dir_only: bool,
+    /// Pattern is matched against the whole path rather than just the last component
+    full_path: bool,
+}
+
+impl Rule {
+    /// Converts an rsync filter pattern to a rule
+    fn rsync(include: bool, pattern: &str) -> Self {
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.starts_with('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        Self {
+            include,
+            pattern: pattern.to_string(),
+            root: Root::Base,
+            anchored,
+            dir_only,
+            full_path: anchored || pattern.contains('/') || pattern.contains("**"),
+        }
+    }
+
+    /// Converts a wget --exclude-directories entry to a rule
+    fn wget_dir(dir: &str) -> Self {
+        Self {
+            include: false,
+            pattern: dir.trim_matches('/').to_string(),
+            root: Root::Host,
+            anchored: true,
+            dir_only: true,
+            full_path: true,
+        }
+    }
+
+    /// Converts a wget --reject entry to a rule. Entries without wildcards are file name suffixes
+    fn wget_reject(reject: &str) -> Self {
+        let pattern = if reject.contains(['*', '?', '[']) {
+            reject.to_string()
+        } else {
+            format!("*{reject}")
+        };
+
+        Self {
+            include: false,
+            pattern,
+            root: Root::Base,
+            anchored: false,
+            dir_only: false,
+            full_path: false,
+        }
+    }
+
+    /// Returns true if the rule matches a path (without leading or trailing slashes)
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if !self.full_path {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            return glob_match(&self.pattern, name);
+        }
+
+        if glob_match(&self.pattern, path) {
+            return true;
+        }
+
+        // Unanchored patterns can match the end of the path on a directory boundary
+        !self.anchored
+            && path
+                .match_indices('/')
+                .any(|(i, _)| glob_match(&self.pattern, &path[i + 1..]))
+    }
+}
+
+/// List of wget and rsync style exclude patterns
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeList {
+    rules: Vec<Rule>,
+}
+
+impl ExcludeList {
+    /// Loads an exclude list file. Each line is a wgetrc style exclude_directories or reject
+    /// setting, a wget --exclude-directories or --reject option, or an rsync filter pattern
+    /// optionally prefixed with '- ' or '+ '. Blank lines and lines starting with # or ; are
+    /// ignored
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let content =
+            read_to_string(file).map_err(|e| format!("Failed to open exclude file {file}: {e}"))?;
+
+        let mut rules = Vec::new();
+
+        for (num, line) in content.lines().enumerate() {
+            Self::parse_line(line, &mut rules)
+                .map_err(|e| format!("Error in exclude file {file} line {}: {e}", num + 1))?;
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Converts a line of the exclude file to rules
+    fn parse_line(line: &str, rules: &mut Vec<Rule>) -> Result<(), String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            return Ok(());
+        }
+
+        // wget settings
+        if let Some((setting, list)) = wget_setting(trimmed)? {
+            let list = list.split(',').map(str::trim).filter(|v| !v.is_empty());
+
+            match setting {
+                WgetSetting::ExcludeDirectories => rules.extend(list.map(Rule::wget_dir)),
+                WgetSetting::Reject => rules.extend(list.map(Rule::wget_reject)),
+            }
+
+            return Ok(());
+        }
+
+        // rsync filter rules
+        let (include, pattern) = match line.split_once(' ') {
+            Some(("-", pattern)) => (false, pattern),
+            Some(("+", pattern)) => (true, pattern),
+            _ => (false, line),
+        };
+
+        if pattern.is_empty() {
+            Err("Empty pattern")?
+        }
+
+        rules.push(Rule::rsync(include, pattern));
+
+        Ok(())
+    }
+
+    /// Returns true if a URL is excluded. A URL is excluded if it, or any directory leading to
+    /// it, is excluded by the first rule which matches it
+    pub fn is_excluded(&self, url: &Url, base_url: &Url) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+
+        let rel = match url.relative_path(base_url) {
+            Some(rel) => decode(rel.split(['?', '#']).next().unwrap_or_default()),
+            None => return false,
+        };
+
+        // Host path leading to the relative path
+        let host_path = decode(url.path());
+        let host_root = host_path
+            .strip_suffix(rel.as_str())
+            .unwrap_or_default()
+            .trim_start_matches('/');
+
+        // Check each directory leading to the URL and then the URL itself
+        let mut prefix = String::new();
+        let mut components = rel.split('/').peekable();
+
+        while let Some(component) = components.next() {
+            if component.is_empty() {
+                continue;
+            }
+
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+
+            let is_dir = components.peek().is_some();
+            let host_prefix = format!("{host_root}{prefix}");
+
+            if self.first_match_excludes(&prefix, &host_prefix, is_dir) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns true if the first rule matching a path is an exclude rule
+    fn first_match_excludes(&self, rel: &str, host_path: &str, is_dir: bool) -> bool {
+        for rule in &self.rules {
+            let path = match rule.root {
+                Root::Base => rel,
+                Root::Host => host_path,
+            };
+
+            if rule.matches(path, is_dir) {
+                return !rule.include;
+            }
+        }
+
+        false
+    }
+}
+
+/// Returns true if the path of a URL relative to the base URL matches an rsync style pattern
+pub fn matches_pattern(pattern: &str, url: &Url, base_url: &Url) -> bool {
+    match url.relative_path(base_url) {
+        Some(rel) => {
+            let rel = decode(rel.split(['?', '#']).next().unwrap_or_default());
+            let is_dir = rel.ends_with('/');
+
+            Rule::rsync(false, pattern).matches(rel.trim_end_matches('/'), is_dir)
+        }
+        None => false,
+    }
+}
+
+/// wget settings which exclude paths
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WgetSetting {
+    /// exclude_directories / --exclude-directories / -X
+    ExcludeDirectories,
+    /// reject / --reject / -R
+    Reject,
+}
+
+/// Parses a wgetrc setting (name = value) or wget command line option (--name=value, -X value).
+/// Returns None if the line is not a wget setting
+fn wget_setting(line: &str) -> Result<Option<(WgetSetting, &str)>, String> {
+    let long_name = |name: &str| match name.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+        "exclude_directories" | "excludedirectories" => Some(WgetSetting::ExcludeDirectories),
+        "reject" => Some(WgetSetting::Reject),
+        _ => None,
+    };
+
+    if let Some(option) = line.strip_prefix("--") {
+        let (name, value) = option.split_once(['=', ' ']).unwrap_or((option, ""));
+
+        match long_name(name) {
+            Some(setting) => Ok(Some((setting, value.trim()))),
+            None => Err(format!("Unsupported wget option --{name}")),
+        }
+    } else if let Some(option) = line.strip_prefix('-').filter(|o| !o.starts_with(' ')) {
+        let mut chars = option.chars();
+        let name = chars.next().unwrap_or_default();
+        let value = chars.as_str().trim_start_matches('=').trim();
+
+        match name {
+            'X' => Ok(Some((WgetSetting::ExcludeDirectories, value))),
+            'R' => Ok(Some((WgetSetting::Reject, value))),
+            _ => Err(format!("Unsupported wget option -{name}")),
+        }
+    } else {
+        // Anything else which isn't a known wgetrc setting is an rsync pattern
+        Ok(line
+            .split_once('=')
+            .and_then(|(name, value)| long_name(name).map(|setting| (setting, value.trim()))))
+    }
+}
+
+/// Percent decodes a path
+fn decode(path: &str) -> String {
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+/// Matches a glob pattern against a path. '*' matches anything except '/', '**' matches
+/// anything, '?' matches any character except '/' and [...] matches a character class
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    glob_match_chars(&pattern, &text)
+}
+
+/// Matches a glob pattern against text, as characters
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            (0..=text.len()).any(|i| glob_match_chars(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+
+            for i in 0..=text.len() {
+                if glob_match_chars(rest, &text[i..]) {
+                    return true;
+                }
+
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+
+            false
+        }
+        Some('?') => {
+            matches!(text.first(), Some(c) if *c != '/')
+                && glob_match_chars(&pattern[1..], &text[1..])
+        }
+        Some('[') => match (text.first(), class_match(&pattern[1..], text.first())) {
+            (Some(_), Some((true, len))) => glob_match_chars(&pattern[len + 1..], &text[1..]),
+            (_, None) => {
+                // Unterminated class - match '[' literally
+                text.first() == Some(&'[') && glob_match_chars(&pattern[1..], &text[1..])
+            }
+            _ => false,
+        },
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match_chars(&pattern[2..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob_match_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Matches a character against a character class (after the opening '['). Returns whether it
+/// matched and the length of the class including the closing ']', or None if the class is not
+/// terminated
+fn class_match(class: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let (negate, start) = match class.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut matched = false;
+    let mut i = start;
+
+    loop {
+        match class.get(i) {
+            None => return None,
+            Some(']') if i > start => break,
+            Some(&lo) => {
+                if class.get(i + 1) == Some(&'-') && class.get(i + 2).is_some_and(|c| *c != ']') {
+                    let hi = class[i + 2];
+                    matched |= c.is_some_and(|c| (lo..=hi).contains(c));
+                    i += 3;
+                } else {
+                    matched |= c == Some(&lo);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    let matched = matched != negate && c != Some(&'/');
+
+    Some((matched, i + 1))
+}
@@ -0,0 +1,231 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{create_dir_all, File};
+use std::io::{self, BufReader};
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use tokio::fs::{remove_dir_all, rename};
+use tokio::task::spawn_blocking;
+use zip::ZipArchive;
+
+use crate::mime::Mime;
+use crate::output::{debug, output};
+use crate::ArcState;
+
+/// Archive types which can be unpacked after download
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveType {
+    /// Gzip compressed tar archive
+    #[value(name = "tar.gz")]
+    TarGz,
+    /// Zip archive
+    Zip,
+}
+
+impl ArchiveType {
+    /// Works out the archive type from the response content type, falling back to the file name
+    /// for generic content types
+    pub fn detect(headers: &HeaderMap, path: &Path) -> Option<Self> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let tar_name = name.ends_with(".tar.gz") || name.ends_with(".tgz");
+
+        let mime_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Mime>().ok());
+
+        match mime_type.as_ref().map(|m| m.essence_str()) {
+            Some("application/x-gtar" | "application/x-tgz" | "application/x-compressed-tar") => {
+                Some(Self::TarGz)
+            }
+            Some("application/gzip" | "application/x-gzip") if tar_name => Some(Self::TarGz),
+            Some("application/zip" | "application/x-zip-compressed") => Some(Self::Zip),
+            None | Some("application/octet-stream") => {
+                if tar_name {
+                    Some(Self::TarGz)
+                } else if name.ends_with(".zip") {
+                    Some(Self::Zip)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Unpacks a downloaded archive in to a sibling directory if it is one of the configured types.
+/// Returns the paths of the extracted members relative to the directory
+pub async fn auto_extract(
+    state: &ArcState,
+    headers: &HeaderMap,
+    path: &Path,
+) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>> {
+    // Is this an archive type we're configured to unpack?
+    let archive_type = match ArchiveType::detect(headers, path) {
+        Some(archive_type) if state.args().auto_extract.contains(&archive_type) => archive_type,
+        _ => return Ok(None),
+    };
+
+    // Build directory path
+    let dir = extract_dir(path);
+
+    // Build temp directory path
+    let mut tmp_dir_name = dir.file_name().map(OsString::from).unwrap_or_default();
+    tmp_dir_name.push(".mirrorurl");
+    let tmp_dir = dir.with_file_name(tmp_dir_name);
+
+    // Remove any previous partial unpack
+    if tmp_dir.is_dir() {
+        remove_dir_all(&tmp_dir)
+            .await
+            .map_err(|e| format!("Unable to remove directory {}: {e}", tmp_dir.display()))?;
+    }
+
+    // Unpack the archive in to the temp directory
+    let archive = path.to_path_buf();
+    let unpack_dir = tmp_dir.clone();
+
+    let result = spawn_blocking(move || match archive_type {
+        ArchiveType::TarGz => extract_tar_gz(&archive, &unpack_dir),
+        ArchiveType::Zip => extract_zip(&archive, &unpack_dir),
+    })
+    .await?;
+
+    let members = match result {
+        Ok(members) => members,
+        Err(e) => {
+            // Failed - try and remove temp directory
+            let _ = remove_dir_all(&tmp_dir).await;
+            Err(format!("Unable to extract {}: {e}", path.display()))?
+        }
+    };
+
+    // Replace the previous unpack
+    if dir.is_dir() {
+        remove_dir_all(&dir)
+            .await
+            .map_err(|e| format!("Unable to remove directory {}: {e}", dir.display()))?;
+    }
+
+    rename(&tmp_dir, &dir)
+        .await
+        .map_err(|e| format!("Unable to rename {}: {e}", tmp_dir.display()))?;
+
+    for member in &members {
+        debug!(state, 1, "Extracted {}", dir.join(member).display());
+    }
+
+    output!(
+        "Extracted {} members from {} to {}",
+        members.len(),
+        path.display(),
+        dir.display()
+    );
+
+    Ok(Some(members))
+}
+
+/// Builds the directory name for an unpacked archive
+fn extract_dir(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".d");
+
+    path.with_file_name(name)
+}
+
+/// Streams a gzipped tar archive in to a directory
+fn extract_tar_gz(
+    archive: &Path,
+    dir: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(archive)?;
+    let mut tar = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+
+    create_dir_all(dir)?;
+
+    let mut members = Vec::new();
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+
+        // Member name including any GNU long name, PAX path or ustar prefix
+        let name = entry.path()?.into_owned();
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_file() {
+            let target = member_path(dir, &name)?;
+
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent)?;
+            }
+
+            io::copy(&mut entry, &mut File::create(&target)?)?;
+            members.push(name);
+        } else if entry_type.is_dir() {
+            create_dir_all(member_path(dir, &name)?)?;
+        }
+
+        // Links, devices etc. are not unpacked
+    }
+
+    Ok(members)
+}
+
+/// Unpacks a zip archive in to a directory
+fn extract_zip(archive: &Path, dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(archive)?))?;
+
+    create_dir_all(dir)?;
+
+    let mut members = Vec::new();
+
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        let name = PathBuf::from(entry.name());
+
+        if entry.is_dir() {
+            create_dir_all(member_path(dir, &name)?)?;
+            continue;
+        }
+
+        let target = member_path(dir, &name)?;
+
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+
+        // The CRC is checked once the member has been read to the end
+        io::copy(&mut entry, &mut File::create(&target)?)
+            .map_err(|e| format!("Unable to extract zip member {}: {e}", name.display()))?;
+
+        members.push(name);
+    }
+
+    Ok(members)
+}
+
+/// Builds the path for an archive member, refusing members which would escape the directory
+fn member_path(dir: &Path, name: &Path) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let mut path = dir.to_path_buf();
+
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            _ => Err(format!(
+                "Archive member {} is outside the archive",
+                name.display()
+            ))?,
+        }
+    }
+
+    Ok(path)
+}
@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION, ETAG};
+use tokio::task::spawn_blocking;
+
+use crate::download::{download_body, BytesBody};
+use crate::http::header_modified;
+use crate::outcome::{check, Outcome};
+use crate::output::{debug, progress};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::url::Url;
+
+/// File name suffixes of the plain and gzip compressed copies of a file published in both forms
+#[derive(Debug, Clone, PartialEq)]
+pub struct GzipPair {
+    /// Suffix of the plain file (may be empty)
+    plain: String,
+    /// Suffix of the compressed file
+    compressed: String,
+}
+
+impl GzipPair {
+    /// Returns the URL of the other copy of a file if the URL matches the pair, and whether the
+    /// other copy needs compressing to give the requested file
+    fn other(&self, url: &Url) -> Option<(Url, bool)> {
+        let path = url.path();
+
+        if path.ends_with('/') {
+            return None;
+        }
+
+        let (other_path, compress) = match path.strip_suffix(&self.compressed) {
+            Some(stem) => (format!("{stem}{}", self.plain), true),
+            None => (
+                format!("{}{}", path.strip_suffix(&self.plain)?, self.compressed),
+                false,
+            ),
+        };
+
+        let mut other_url = url.clone();
+        other_url.set_path(&other_path);
+        other_url.set_fragment(None);
+
+        Some((other_url, compress))
+    }
+}
+
+impl FromStr for GzipPair {
+    type Err = String;
+
+    /// Parses a pair in PLAIN=COMPRESSED form, eg. =.gz or .tar=.tgz
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (plain, compressed) = s
+            .split_once('=')
+            .ok_or_else(|| format!("'{s}' is not in the form PLAIN=COMPRESSED"))?;
+
+        if compressed.is_empty() || plain == compressed {
+            Err(format!(
+                "'{s}' needs a compressed suffix different to the plain one"
+            ))?
+        }
+
+        Ok(Self {
+            plain: plain.to_string(),
+            compressed: compressed.to_string(),
+        })
+    }
+}
+
+/// Fetches the other copy of a file which was not found and compresses or decompresses it to give
+/// the requested file. Returns None if no pair matches the URL or the other copy isn't found
+pub async fn fetch_other(
+    state: &ArcState,
+    url: &Url,
+    stats: &mut Stats,
+) -> Result<Option<Outcome>, Box<dyn Error + Send + Sync>> {
+    for pair in &state.args().gzip_fallback {
+        let Some((other_url, compress)) = pair.other(url) else {
+            continue;
+        };
+
+        debug!(state, 1, "{url} not found, trying {other_url}");
+
+        let response = state
+            .client()
+            .get(state.request_url(&other_url))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            debug!(
+                state,
+                1,
+                "Status {} fetching {other_url}",
+                response.status()
+            );
+            continue;
+        }
+
+        // The validators and file name belong to the other copy
+        let mut headers = response.headers().clone();
+        headers.remove(ETAG);
+        headers.remove(CONTENT_DISPOSITION);
+
+        let data = response.bytes().await?;
+
+        let data = spawn_blocking(move || convert(&data, compress))
+            .await?
+            .map_err(|e| format!("Unable to convert {other_url}: {e}"))?;
+
+        progress!("Converting {other_url} to {url}");
+
+        let outcome = save_converted(state, url, &headers, data, stats).await?;
+
+        return Ok(Some(outcome));
+    }
+
+    Ok(None)
+}
+
+/// Saves the converted copy of a file if it is in our shard, size and age limits
+async fn save_converted(
+    state: &ArcState,
+    url: &Url,
+    headers: &HeaderMap,
+    data: Bytes,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    // Check the file is in our shard, size and age limits
+    check!(state.check_shard(url));
+    check!(state.check_size(url, Some(data.len() as u64)));
+    check!(state.check_age(url, header_modified(headers)));
+
+    // Save the converted file
+    let mut body = BytesBody::new(data);
+
+    download_body(state, url, url, headers, &mut body, stats).await
+}
+
+/// Gzip compresses or decompresses data
+fn convert(data: &[u8], compress: bool) -> std::io::Result<Bytes> {
+    let mut out = Vec::new();
+
+    if compress {
+        let mut encoder = GzEncoder::new(&mut out, Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()?;
+    } else {
+        GzDecoder::new(data).read_to_end(&mut out)?;
+    }
+
+    Ok(Bytes::from(out))
+}
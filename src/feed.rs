@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use chrono::{DateTime, NaiveDate};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+use crate::output::debug;
+use crate::savefile::save_file;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::url::Url;
+use crate::walk::{follow_links, join_tasks};
+
+/// Entry in an RSS or Atom feed
+#[derive(Debug, PartialEq)]
+pub struct FeedEntry {
+    /// Enclosure URL, or the entry link if there is no enclosure
+    pub link: String,
+    /// Publication time in seconds since the epoch
+    pub published: Option<u64>,
+}
+
+/// Returns true if the URL path looks like a feed
+pub fn is_feed_path(url: &Url) -> bool {
+    let path = url.path();
+
+    [
+        ".rss", ".atom", "feed.xml", "rss.xml", "atom.xml", "/feed", "/rss",
+    ]
+    .iter()
+    .any(|suffix| path.ends_with(suffix))
+}
+
+/// Field of a feed entry being read from element text
+enum Capture {
+    /// Entry link
+    Link,
+    /// Publication date, indexed by preference
+    Date(usize),
+}
+
+/// Feed entry being read
+#[derive(Default)]
+struct PartialEntry {
+    /// Enclosure URL
+    enclosure: Option<String>,
+    /// Entry link
+    link: Option<String>,
+    /// Publication dates, indexed by preference
+    dates: [Option<u64>; 2],
+}
+
+impl PartialEntry {
+    /// Returns the finished entry, or None if it has no link
+    fn finish(self) -> Option<FeedEntry> {
+        Some(FeedEntry {
+            link: self.enclosure.or(self.link)?,
+            published: self.dates.into_iter().flatten().next(),
+        })
+    }
+}
+
+/// Parses an RSS or Atom feed returning the entries. Returns None if the document is not a feed
+pub fn parse_feed(xml: &str) -> Option<Vec<FeedEntry>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut root = None;
+    let mut entries = Vec::new();
+    let mut entry: Option<PartialEntry> = None;
+    let mut capture = None;
+    let mut text = String::new();
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            // Keep the entries read before the error
+            Err(_) => break,
+        };
+
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                let name = element.local_name();
+                let name = name.as_ref();
+
+                // The root element decides the flavour of feed
+                let (entry_name, date_names): (&[u8], [&[u8]; 2]) = match root {
+                    Some(root) => root,
+                    None => {
+                        let flavour: (&[u8], [&[u8]; 2]) = match name {
+                            b"rss" | b"RDF" => (b"item", [b"pubDate", b"date"]),
+                            b"feed" => (b"entry", [b"published", b"updated"]),
+                            _ => return None,
+                        };
+
+                        root = Some(flavour);
+                        continue;
+                    }
+                };
+
+                let attribute = |name: &str| {
+                    element
+                        .try_get_attribute(name)
+                        .ok()
+                        .flatten()
+                        .and_then(|attr| attr.unescape_value().ok())
+                        .map(|value| value.into_owned())
+                };
+
+                if name == entry_name {
+                    entry = match event {
+                        Event::Start(_) => Some(PartialEntry::default()),
+                        _ => None,
+                    };
+                } else if let Some(entry) = &mut entry {
+                    match name {
+                        b"enclosure" if entry_name == b"item" => {
+                            entry.enclosure = entry.enclosure.take().or_else(|| attribute("url"));
+                        }
+                        b"link" if entry_name == b"item" => capture = Some(Capture::Link),
+                        b"link" => match attribute("rel").as_deref() {
+                            Some("enclosure") => {
+                                entry.enclosure =
+                                    entry.enclosure.take().or_else(|| attribute("href"))
+                            }
+                            None | Some("alternate") => {
+                                entry.link = entry.link.take().or_else(|| attribute("href"))
+                            }
+                            _ => (),
+                        },
+                        _ => {
+                            capture = date_names
+                                .iter()
+                                .position(|date_name| *date_name == name)
+                                .map(Capture::Date)
+                        }
+                    }
+
+                    if matches!(event, Event::Empty(_)) {
+                        capture = None;
+                    }
+
+                    text.clear();
+                }
+            }
+            Event::Text(content) => {
+                if capture.is_some() {
+                    if let Ok(content) = content.unescape() {
+                        text.push_str(&content);
+                    }
+                }
+            }
+            Event::CData(content) => {
+                if capture.is_some() {
+                    text.push_str(&String::from_utf8_lossy(&content));
+                }
+            }
+            Event::End(element) => {
+                if let Some(partial) = &mut entry {
+                    match capture.take() {
+                        Some(Capture::Link) => {
+                            let link = text.trim();
+
+                            if partial.link.is_none() && !link.is_empty() {
+                                partial.link = Some(link.to_string());
+                            }
+                        }
+                        Some(Capture::Date(index)) => {
+                            partial.dates[index] = partial.dates[index].or(parse_date(&text));
+                        }
+                        None => {
+                            if root.is_some_and(|(entry_name, _)| {
+                                element.local_name().as_ref() == entry_name
+                            }) {
+                                entries.extend(entry.take().and_then(PartialEntry::finish));
+                            }
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    root.map(|_| entries)
+}
+
+/// Parses an RFC 3339 (Atom) or RFC 2822 (RSS) date returning seconds since the epoch
+pub fn parse_date(date: &str) -> Option<u64> {
+    let date = date.trim();
+
+    let time = match DateTime::parse_from_rfc3339(date) {
+        Ok(time) => time.timestamp(),
+        Err(_) => match DateTime::parse_from_rfc2822(date) {
+            Ok(time) => time.timestamp(),
+            Err(_) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)?
+                .and_utc()
+                .timestamp(),
+        },
+    };
+
+    u64::try_from(time).ok()
+}
+
+/// Process the entries of a feed published since the last run, waiting for the downloads to
+/// finish and returning the number of links followed. Entries without a date are always followed
+pub async fn process_feed(
+    state: &ArcState,
+    url: &Url,
+    entries: Vec<FeedEntry>,
+    stats: &mut Stats,
+) -> usize {
+    let cursor = state.feed_cursor(url).await;
+    let mut newest = cursor.clone();
+
+    let links = entries
+        .iter()
+        .map(|entry| -> Result<Url, SkipReasonErr> {
+            let link = url
+                .join(&entry.link)
+                .map_err(|e| SkipReasonErr::new(entry.link.clone(), SkipReason::NotValid(e)))?;
+
+            if let Some(published) = entry.published {
+                // Entries published at the same time as the newest are all kept in the cursor
+                match &mut newest {
+                    Some(newest) if newest.published > published => (),
+                    Some(newest) if newest.published == published => {
+                        newest.links.insert(link.to_string());
+                    }
+                    _ => {
+                        newest = Some(FeedCursor {
+                            published,
+                            links: BTreeSet::from([link.to_string()]),
+                        })
+                    }
+                }
+
+                if cursor
+                    .as_ref()
+                    .is_some_and(|cursor| cursor.seen(published, &link))
+                {
+                    Err(SkipReasonErr::new(link.to_string(), SkipReason::FeedSeen))?
+                }
+            }
+
+            debug!(state, 2, "Feed entry {} of {url} -> {link}", entry.link);
+
+            Ok(link)
+        })
+        .collect();
+
+    let failed = stats.failed() + state.get_stats().failed();
+
+    // Process all of the links
+    let join_handles = follow_links(state, links, stats).await;
+    let followed = join_handles.len();
+
+    // Join the threads
+    join_tasks(join_handles).await;
+
+    // Move the cursor on to the newest entry once all of the entries have been fetched, so
+    // entries which failed are retried on the next run. Failures elsewhere in the crawl while
+    // waiting also hold the cursor back
+    if let Some(newest) = newest {
+        if state.is_interrupted() || stats.failed() + state.get_stats().failed() > failed {
+            debug!(state, 1, "Not moving the cursor for {url} on");
+        } else {
+            state.set_feed_cursor(url, newest).await;
+        }
+    }
+
+    followed
+}
+
+/// Newest entries seen in a feed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeedCursor {
+    /// Publication time in seconds since the epoch of the newest entry
+    pub published: u64,
+    /// Links of the entries published at that time
+    pub links: BTreeSet<String>,
+}
+
+impl FeedCursor {
+    /// Returns true if an entry was seen by the last run
+    fn seen(&self, published: u64, link: &Url) -> bool {
+        published < self.published
+            || (published == self.published && self.links.contains(link.as_str()))
+    }
+}
+
+/// Newest entries seen in each feed, keyed by feed URL
+#[derive(Default)]
+pub struct FeedCursors {
+    cursors: BTreeMap<String, FeedCursor>,
+    changed: bool,
+}
+
+impl FeedCursors {
+    /// Load the feed cursors from a JSON file. If the file does not exist, create an empty map
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let cursors = match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                let cursors = serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load feed cursors file {file}: {e}"))?;
+
+                Self {
+                    cursors,
+                    changed: false,
+                }
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => FeedCursors::default(),
+                _ => Err(format!("Failed to open feed cursors file {file}: {e}"))?,
+            },
+        };
+
+        Ok(cursors)
+    }
+
+    /// Save the feed cursors to a JSON file if they have changed
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = PathBuf::from(file);
+
+        let write = self.changed
+            && if let Some(parent) = path.parent() {
+                parent.is_dir()
+            } else {
+                true
+            };
+
+        if write {
+            save_file(file, |writer| {
+                Ok(serde_json::to_writer_pretty(writer, &self.cursors)?)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if any cursor has moved
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Returns the cursor for a feed
+    pub fn get(&self, url: &str) -> Option<FeedCursor> {
+        self.cursors.get(url).cloned()
+    }
+
+    /// Moves the cursor for a feed
+    pub fn set(&mut self, url: &str, cursor: FeedCursor) {
+        if self.cursors.get(url) != Some(&cursor) {
+            self.cursors.insert(url.to_string(), cursor);
+            self.changed = true;
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use tokio::fs::{metadata, read_dir, File};
+use tokio::io::AsyncReadExt;
+
+use crate::download::{save_verified_body, Body};
+use crate::limiter::Slot;
+use crate::outcome::{check, Outcome};
+use crate::output::{debug, progress};
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::transport::Transport;
+use crate::url::Url;
+use crate::walk::{follow_links, join_tasks};
+
+/// Size of chunks to read from local files
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Local file transport
+pub struct FileTransport;
+
+impl Transport for FileTransport {
+    fn walk<'a>(
+        &'a self,
+        state: &'a ArcState,
+        url: &'a Url,
+        sem: Slot,
+        stats: &'a mut Stats,
+    ) -> BoxFuture<'a, Result<Outcome, Box<dyn Error + Send + Sync>>> {
+        walk_file(state, url, sem, stats).boxed()
+    }
+}
+
+/// Processes a file:// URL. Directories are listed and their entries followed, files are copied.
+/// A synthesized etag built from the file size and modification time is used to skip unchanged files.
+async fn walk_file(
+    state: &ArcState,
+    url: &Url,
+    sem: Slot,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    // Convert the URL to a local path
+    let path = check!(url
+        .to_file_path()
+        .map_err(|_| SkipReasonErr::new(url.to_string(), SkipReason::Transport)));
+
+    state.events().on_fetch_start(url);
+
+    let meta = metadata(&path)
+        .await
+        .map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+
+    let outcome = if meta.is_dir() {
+        // List the directory
+        let links = list_dir(&path).await?;
+
+        // Release the download slot
+        drop(sem);
+
+        // Process the directory entries
+        let join_handles = follow_links(state, links, stats).await;
+        let links = join_handles.len();
+
+        // Join the threads
+        join_tasks(join_handles).await;
+
+        Outcome::Parsed { bytes: 0, links }
+    } else {
+        // Check the file is in our shard, size and age limits
+        check!(state.check_shard(url));
+        check!(state.check_size(url, Some(meta.len())));
+        check!(state.check_age(url, meta.modified().ok()));
+
+        // Build etag from the file size and modification time
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        let etag = format!("\"{}-{mtime}\"", meta.len());
+
+        debug!(state, 2, "Synthesized etag value: {etag}");
+
+        if state.find_etag(url) == Some(&etag) {
+            progress!("{url} is not modified");
+
+            return Ok(Outcome::NotModified);
+        }
+
+        // Copy the file
+        let file = File::open(&path)
+            .await
+            .map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+
+        let mut body = FileBody {
+            file,
+            len: meta.len(),
+        };
+
+        let bytes = check!(save_verified_body(state, url, &mut body, stats).await?).bytes;
+
+        // Record the etag
+        state.add_etags(vec![url], &etag);
+
+        // Release the download slot
+        drop(sem);
+
+        Outcome::Downloaded { bytes }
+    };
+
+    Ok(outcome)
+}
+
+/// Lists a local directory returning file:// URLs for each entry in name order
+async fn list_dir(
+    path: &Path,
+) -> Result<Vec<Result<Url, SkipReasonErr>>, Box<dyn Error + Send + Sync>> {
+    let mut entries: Vec<(PathBuf, bool)> = Vec::new();
+
+    let mut dir = read_dir(path)
+        .await
+        .map_err(|e| format!("Unable to read directory {}: {e}", path.display()))?;
+
+    while let Some(entry) = dir
+        .next_entry()
+        .await
+        .map_err(|e| format!("Unable to read directory {}: {e}", path.display()))?
+    {
+        let is_dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
+
+        entries.push((entry.path(), is_dir));
+    }
+
+    entries.sort();
+
+    Ok(entries
+        .into_iter()
+        .map(|(path, is_dir)| {
+            let url = if is_dir {
+                Url::from_directory_path(&path)
+            } else {
+                Url::from_file_path(&path)
+            };
+
+            url.map_err(|_| SkipReasonErr::new(path.display().to_string(), SkipReason::Transport))
+        })
+        .collect())
+}
+
+/// Local file body
+struct FileBody {
+    /// Open file
+    file: File,
+    /// File length
+    len: u64,
+}
+
+impl Body for FileBody {
+    fn content_length(&self) -> Option<u64> {
+        Some(self.len)
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+        let mut buf = vec![0; CHUNK_SIZE];
+
+        let len = self.file.read(&mut buf).await?;
+
+        if len == 0 {
+            Ok(None)
+        } else {
+            buf.truncate(len);
+            Ok(Some(Bytes::from(buf)))
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use percent_encoding::{percent_decode_str, percent_encode_byte};
+
+/// Decodes the percent escapes in each component of a relative path. Characters which can't
+/// be used in a local file name are left encoded
+pub fn decode_path(path: &str) -> String {
+    path.split('/')
+        .map(decode_component)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Decodes a single path component. Components which don't decode to valid UTF-8 or which
+/// decode to a relative directory name are left as they are
+fn decode_component(component: &str) -> String {
+    let decoded = match percent_decode_str(component).decode_utf8() {
+        Ok(decoded) if decoded != "." && decoded != ".." => decoded,
+        _ => return component.to_string(),
+    };
+
+    let mut result = String::with_capacity(decoded.len());
+
+    for c in decoded.chars() {
+        if is_unsafe(c) {
+            let mut buf = [0; 4];
+
+            result.extend(c.encode_utf8(&mut buf).bytes().map(percent_encode_byte));
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Returns true if a character can't be used in a file name on this platform
+fn is_unsafe(c: char) -> bool {
+    c == '/' || c.is_control() || (cfg!(windows) && "\\:*?\"<>|".contains(c))
+}
@@ -0,0 +1,148 @@
+use std::borrow::Cow;
+
+use sha2::{Digest, Sha256};
+
+/// Characters which can't be used in Windows file names
+const ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// Device names reserved by Windows, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Maps each segment of a relative local path to a name which is legal on Windows
+pub fn legal_path(path: &str) -> String {
+    path.split('/')
+        .map(legal_name)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Maps a path segment to a name which is legal on Windows. Illegal characters are replaced,
+/// trailing dots and spaces are removed and reserved device names are changed. Changed names
+/// are given a suffix derived from the original name so they can't collide with each other
+pub fn legal_name(segment: &str) -> Cow<'_, str> {
+    if segment.is_empty() {
+        return Cow::Borrowed(segment);
+    }
+
+    let name: String = segment
+        .chars()
+        .map(|c| {
+            if ILLEGAL_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    let name = name.trim_end_matches(['.', ' ']);
+
+    let stem = name.split('.').next().unwrap_or_default();
+    let reserved = RESERVED_NAMES.contains(&stem.trim_end().to_ascii_uppercase().as_str());
+
+    if name == segment && !reserved {
+        return Cow::Borrowed(segment);
+    }
+
+    // Add the suffix before the extension
+    let suffix = name_hash(segment);
+
+    Cow::Owned(match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem}~{suffix}.{ext}"),
+        _ => format!("{name}~{suffix}"),
+    })
+}
+
+/// Returns a short hash of a name
+pub fn name_hash(name: &str) -> String {
+    Sha256::digest(name.as_bytes())
+        .iter()
+        .take(4)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Longest extension kept when shortening a file name
+const MAX_EXT_LEN: usize = 16;
+
+/// Shortens a relative local path whose components are longer than max_name bytes or which
+/// is longer than max_path bytes when added to a base path of base_len bytes. Long names are
+/// truncated and given a suffix derived from the original name. If the path is still too long
+/// the deepest directories are replaced by a single hashed file name. Returns None if the
+/// path doesn't need shortening
+pub fn shorten_path(
+    path: &str,
+    base_len: usize,
+    max_name: usize,
+    max_path: usize,
+) -> Option<String> {
+    let mut changed = false;
+
+    let mut segments: Vec<String> = path
+        .split('/')
+        .map(|segment| {
+            if segment.len() > max_name {
+                changed = true;
+                truncate_name(segment, max_name)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect();
+
+    let path_len =
+        |segments: &[String]| base_len + segments.iter().map(|s| s.len() + 1).sum::<usize>();
+
+    if path_len(&segments) > max_path {
+        changed = true;
+
+        // Replace the file name with a hash of the whole path, keeping its extension
+        let file = segments.pop().unwrap_or_default();
+
+        let name = match extension(&file) {
+            Some(ext) => format!("~{}.{ext}", name_hash(path)),
+            None => format!("~{}", name_hash(path)),
+        };
+
+        // Keep as many leading directories as possible
+        while !segments.is_empty() && path_len(&segments) + name.len() + 1 > max_path {
+            segments.pop();
+        }
+
+        segments.push(name);
+    }
+
+    changed.then(|| segments.join("/"))
+}
+
+/// Truncates a file name to at most max bytes, keeping the extension and adding a suffix
+/// derived from the original name
+fn truncate_name(name: &str, max: usize) -> String {
+    let suffix = name_hash(name);
+
+    let (stem, ext) = match extension(name) {
+        Some(ext) => (&name[..name.len() - ext.len() - 1], format!(".{ext}")),
+        None => (name, String::new()),
+    };
+
+    let mut keep = max.saturating_sub(suffix.len() + 1 + ext.len());
+
+    while !stem.is_char_boundary(keep) {
+        keep -= 1;
+    }
+
+    format!("{}~{suffix}{ext}", &stem[..keep])
+}
+
+/// Returns the extension of a file name if it's short enough to keep
+fn extension(name: &str) -> Option<&str> {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() && ext.len() <= MAX_EXT_LEN => {
+            Some(ext)
+        }
+        _ => None,
+    }
+}
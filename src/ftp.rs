@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::download::{save_verified_body, Body};
+use crate::limiter::Slot;
+use crate::outcome::{check, Outcome};
+use crate::output::{debug, progress};
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::transport::Transport;
+use crate::url::Url;
+use crate::walk::{follow_links, join_tasks};
+
+/// Size of chunks to read from data connections
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// FTP transport
+pub struct FtpTransport;
+
+impl Transport for FtpTransport {
+    fn walk<'a>(
+        &'a self,
+        state: &'a ArcState,
+        url: &'a Url,
+        sem: Slot,
+        stats: &'a mut Stats,
+    ) -> BoxFuture<'a, Result<Outcome, Box<dyn Error + Send + Sync>>> {
+        walk_ftp(state, url, sem, stats).boxed()
+    }
+}
+
+/// Processes an ftp:// URL. Directories are listed and their entries followed, files are
+/// downloaded. A synthesized etag built from the file size and modification time is used to
+/// skip unchanged files.
+async fn walk_ftp(
+    state: &ArcState,
+    url: &Url,
+    sem: Slot,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    let path = percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .into_owned();
+
+    check_arg(&path, "Path", url)?;
+
+    state.events().on_fetch_start(url);
+
+    let mut conn = FtpConnection::open(state, url).await?;
+
+    // Paths without a trailing slash may still be directories
+    let size = if path.ends_with('/') {
+        None
+    } else {
+        conn.size(&path).await?
+    };
+
+    let is_dir = path.ends_with('/') || (size.is_none() && conn.is_dir(&path).await?);
+
+    let outcome = if is_dir {
+        // List the directory
+        let mut dir_url = url.clone();
+
+        if !dir_url.path().ends_with('/') {
+            dir_url.set_path(&format!("{}/", url.path()));
+        }
+
+        let entries = conn.list(&path).await?;
+        state.ftp_pool().put(conn).await;
+
+        // Release the download slot
+        drop(sem);
+
+        let links = entries
+            .into_iter()
+            .map(|(name, is_dir)| entry_url(&dir_url, &name, is_dir))
+            .collect();
+
+        // Process the directory entries
+        let join_handles = follow_links(state, links, stats).await;
+        let links = join_handles.len();
+
+        // Join the threads
+        join_tasks(join_handles).await;
+
+        Outcome::Parsed { bytes: 0, links }
+    } else {
+        let modified = conn.modified(&path).await?;
+
+        // Check the file is in our shard, size and age limits
+        check!(state.check_shard(url));
+        check!(state.check_size(url, size));
+        check!(state.check_age(url, modified.as_ref().map(|(time, _)| *time)));
+
+        // Build etag from the file size and modification time
+        let etag = format!(
+            "\"{}-{}\"",
+            size.unwrap_or_default(),
+            modified.map(|(_, mdtm)| mdtm).unwrap_or_default()
+        );
+
+        debug!(state, 2, "Synthesized etag value: {etag}");
+
+        if state.find_etag(url) == Some(&etag) {
+            progress!("{url} is not modified");
+            state.ftp_pool().put(conn).await;
+
+            return Ok(Outcome::NotModified);
+        }
+
+        // Download the file
+        let data = conn.retrieve(&path).await?;
+
+        let mut body = FtpBody { data, len: size };
+
+        let bytes = check!(save_verified_body(state, url, &mut body, stats).await?).bytes;
+
+        conn.finish_transfer(&path).await?;
+        state.ftp_pool().put(conn).await;
+
+        // Record the etag
+        state.add_etags(vec![url], &etag);
+
+        // Release the download slot
+        drop(sem);
+
+        Outcome::Downloaded { bytes }
+    };
+
+    Ok(outcome)
+}
+
+/// Reads a file from an FTP server as text
+pub async fn read_text(
+    state: &ArcState,
+    url: &Url,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let path = percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .into_owned();
+
+    check_arg(&path, "Path", url)?;
+
+    let mut conn = FtpConnection::open(state, url).await?;
+
+    let mut data = conn.retrieve(&path).await?;
+
+    let mut text = Vec::new();
+    data.read_to_end(&mut text).await?;
+    drop(data);
+
+    conn.finish_transfer(&path).await?;
+    state.ftp_pool().put(conn).await;
+
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+/// Builds the URL for a directory entry
+fn entry_url(dir_url: &Url, name: &str, is_dir: bool) -> Result<Url, SkipReasonErr> {
+    let mut rel = name
+        .replace('%', "%25")
+        .replace('#', "%23")
+        .replace('?', "%3F");
+
+    if is_dir {
+        rel.push('/');
+    }
+
+    dir_url
+        .join(&format!("./{rel}"))
+        .map_err(|e| SkipReasonErr::new(name.to_string(), SkipReason::NotValid(e)))
+}
+
+/// Returns an error if a command argument contains control characters, which would let it end
+/// the command and send another
+fn check_arg(arg: &str, what: &str, url: &Url) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if arg.contains(char::is_control) {
+        Err(format!("{what} of {url} contains control characters"))?
+    }
+
+    Ok(())
+}
+
+/// Idle FTP control connections kept for the next URL on the same server
+#[derive(Default)]
+pub struct FtpPool {
+    /// Logged in connections keyed by user, host and port
+    idle: Mutex<HashMap<String, Vec<FtpConnection>>>,
+}
+
+impl FtpPool {
+    /// Takes an idle connection for a server
+    async fn take(&self, key: &str) -> Option<FtpConnection> {
+        self.idle.lock().await.get_mut(key)?.pop()
+    }
+
+    /// Keeps a connection which has finished its commands for reuse
+    async fn put(&self, conn: FtpConnection) {
+        self.idle
+            .lock()
+            .await
+            .entry(conn.key.clone())
+            .or_default()
+            .push(conn);
+    }
+
+    /// Logs out of all of the idle connections
+    pub async fn close(&self) {
+        let idle = std::mem::take(&mut *self.idle.lock().await);
+
+        for mut conn in idle.into_values().flatten() {
+            conn.quit().await;
+        }
+    }
+}
+
+/// FTP control connection
+struct FtpConnection {
+    /// Control stream
+    control: BufReader<TcpStream>,
+    /// Address of the server
+    peer: SocketAddr,
+    /// Connection timeout
+    connect_timeout: Duration,
+    /// Pool key of the server and user logged in as
+    key: String,
+    /// URL being processed for error messages
+    url: String,
+}
+
+impl FtpConnection {
+    /// Returns an idle connection to the server for a URL, or connects and logs in if there are
+    /// none which are still open
+    async fn open(state: &ArcState, url: &Url) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let key = format!(
+            "{}@{}:{}",
+            url.username(),
+            url.host_str().unwrap_or_default(),
+            url.port().unwrap_or(21)
+        );
+
+        while let Some(mut conn) = state.ftp_pool().take(&key).await {
+            // The server may have closed the connection whilst it was idle
+            if matches!(conn.command("NOOP").await, Ok((200, _))) {
+                debug!(state, 2, "Reusing FTP connection to {key} for {url}");
+                conn.url = url.to_string();
+                return Ok(conn);
+            }
+        }
+
+        Self::connect(state, url, key).await
+    }
+
+    /// Connects and logs in to the server for a URL. Logs in anonymously unless the URL has a
+    /// user name
+    async fn connect(
+        state: &ArcState,
+        url: &Url,
+        key: String,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("URL {url} has no host"))?;
+        let port = url.port().unwrap_or(21);
+
+        let connect_timeout = Duration::from_secs(state.args().connect_timeout);
+
+        let stream = timeout(connect_timeout, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| format!("Timed out connecting to {host}:{port}"))?
+            .map_err(|e| format!("Unable to connect to {host}:{port}: {e}"))?;
+
+        let peer = stream.peer_addr()?;
+
+        let mut conn = Self {
+            control: BufReader::new(stream),
+            peer,
+            connect_timeout,
+            key,
+            url: url.to_string(),
+        };
+
+        conn.expect_reply(220).await?;
+
+        // Log in
+        let user = match url.username() {
+            "" => "anonymous".to_string(),
+            user => percent_decode_str(user).decode_utf8_lossy().into_owned(),
+        };
+
+        let password = match url.password() {
+            Some(password) => percent_decode_str(password)
+                .decode_utf8_lossy()
+                .into_owned(),
+            None => "anonymous@".to_string(),
+        };
+
+        check_arg(&user, "User name", url)?;
+        check_arg(&password, "Password", url)?;
+
+        match conn.command(&format!("USER {user}")).await? {
+            (230, _) => {}
+            (331, _) => {
+                conn.command(&format!("PASS {password}"))
+                    .await
+                    .and_then(|reply| conn.check(reply, 230))?;
+            }
+            reply => conn.check(reply, 230)?,
+        }
+
+        // Binary transfers
+        let reply = conn.command("TYPE I").await?;
+        conn.check(reply, 200)?;
+
+        Ok(conn)
+    }
+
+    /// Returns the size of a file, or None if it is not a file
+    async fn size(&mut self, path: &str) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+        match self.command(&format!("SIZE {path}")).await? {
+            (213, size) => Ok(size.trim().parse().ok()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the modification time of a file if the server reports it, along with the time
+    /// as sent by the server
+    async fn modified(
+        &mut self,
+        path: &str,
+    ) -> Result<Option<(SystemTime, String)>, Box<dyn Error + Send + Sync>> {
+        match self.command(&format!("MDTM {path}")).await? {
+            (213, mdtm) => {
+                let mdtm = mdtm.trim().to_string();
+                Ok(parse_mdtm(&mdtm).map(|time| (time, mdtm)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns true if a path is a directory
+    async fn is_dir(&mut self, path: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self.command(&format!("CWD {path}")).await?.0 == 250)
+    }
+
+    /// Lists a directory returning the names of the entries and whether they are directories,
+    /// in name order. MLSD is used if the server supports it, otherwise LIST
+    async fn list(
+        &mut self,
+        path: &str,
+    ) -> Result<Vec<(String, bool)>, Box<dyn Error + Send + Sync>> {
+        let mut entries = match self.transfer(&format!("MLSD {path}")).await? {
+            Some(listing) => parse_mlsd(&listing),
+            None => match self.transfer(&format!("LIST {path}")).await? {
+                Some(listing) => parse_list(&listing),
+                None => Err(format!("Unable to list directory {}", self.url))?,
+            },
+        };
+
+        entries.sort();
+
+        Ok(entries)
+    }
+
+    /// Runs a command which transfers text over a data connection. Returns None if the server
+    /// doesn't support the command
+    async fn transfer(
+        &mut self,
+        command: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let mut data = self.open_data().await?;
+
+        match self.command(command).await? {
+            (125 | 150, _) => {}
+            (500 | 501 | 502 | 504, _) => return Ok(None),
+            reply => self.check(reply, 150)?,
+        }
+
+        let mut text = Vec::new();
+        data.read_to_end(&mut text).await?;
+        drop(data);
+
+        self.expect_reply(226).await?;
+
+        Ok(Some(String::from_utf8_lossy(&text).into_owned()))
+    }
+
+    /// Starts retrieving a file, returning the data connection
+    async fn retrieve(&mut self, path: &str) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+        let data = self.open_data().await?;
+
+        let reply = self.command(&format!("RETR {path}")).await?;
+
+        match reply.0 {
+            125 | 150 => Ok(data),
+            _ => Err(format!(
+                "Status {} {} fetching {}",
+                reply.0,
+                reply.1.trim(),
+                self.url
+            ))?,
+        }
+    }
+
+    /// Waits for the server to confirm a file transfer is complete
+    async fn finish_transfer(&mut self, path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.expect_reply(226)
+            .await
+            .map_err(|e| format!("Transfer of {path} failed: {e}").into())
+    }
+
+    /// Opens a passive data connection, trying EPSV before PASV
+    async fn open_data(&mut self) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+        let port = match self.command("EPSV").await? {
+            (229, text) => parse_epsv(&text),
+            _ => match self.command("PASV").await? {
+                (227, text) => parse_pasv(&text),
+                reply => {
+                    self.check(reply, 227)?;
+                    None
+                }
+            },
+        }
+        .ok_or_else(|| format!("Unable to open a data connection for {}", self.url))?;
+
+        // Always connect to the control connection address in case the server is behind NAT
+        let addr = SocketAddr::new(self.peer.ip(), port);
+
+        Ok(timeout(self.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format!("Timed out opening data connection to {addr}"))?
+            .map_err(|e| format!("Unable to open data connection to {addr}: {e}"))?)
+    }
+
+    /// Sends a command and returns the reply
+    async fn command(
+        &mut self,
+        command: &str,
+    ) -> Result<(u16, String), Box<dyn Error + Send + Sync>> {
+        self.control
+            .get_mut()
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await?;
+
+        self.reply().await
+    }
+
+    /// Reads a reply, joining the lines of multi-line replies
+    async fn reply(&mut self) -> Result<(u16, String), Box<dyn Error + Send + Sync>> {
+        let mut text = String::new();
+        let mut code = None;
+
+        loop {
+            let mut line = String::new();
+
+            if self.control.read_line(&mut line).await? == 0 {
+                Err(format!("Connection closed by server for {}", self.url))?
+            }
+
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            let line_code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            let last = line_code.is_some() && line.as_bytes().get(3) != Some(&b'-');
+
+            if code.is_none() {
+                code = line_code;
+            }
+
+            match line.get(4..) {
+                Some(rest) if line_code.is_some() => text.push_str(rest),
+                _ => text.push_str(line),
+            }
+
+            if last && line_code == code {
+                break;
+            }
+
+            text.push('\n');
+        }
+
+        Ok((code.unwrap_or_default(), text))
+    }
+
+    /// Reads a reply and checks it has the expected code
+    async fn expect_reply(&mut self, expected: u16) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let reply = self.reply().await?;
+        self.check(reply, expected)
+    }
+
+    /// Checks a reply has the expected code
+    fn check(
+        &self,
+        reply: (u16, String),
+        expected: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if reply.0 != expected {
+            Err(format!(
+                "Unexpected FTP reply {} {} for {}",
+                reply.0,
+                reply.1.trim(),
+                self.url
+            ))?
+        }
+
+        Ok(())
+    }
+
+    /// Logs out, ignoring any errors
+    async fn quit(&mut self) {
+        let _ = self.command("QUIT").await;
+    }
+}
+
+/// Parses the port from an EPSV reply, eg. Entering Extended Passive Mode (|||6446|)
+fn parse_epsv(text: &str) -> Option<u16> {
+    let (_, rest) = text.split_once('(')?;
+    let (inner, _) = rest.split_once(')')?;
+
+    inner
+        .trim_matches(|c: char| !c.is_ascii_digit())
+        .parse()
+        .ok()
+}
+
+/// Parses the port from a PASV reply, eg. Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+fn parse_pasv(text: &str) -> Option<u16> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+
+    let numbers: Vec<u16> = text[start..]
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|n| !n.is_empty())
+        .take(6)
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+
+    match numbers[..] {
+        [_, _, _, _, p1, p2] if p1 < 256 && p2 < 256 => Some(p1 * 256 + p2),
+        _ => None,
+    }
+}
+
+/// Parses an MDTM time (YYYYMMDDHHMMSS in UTC)
+fn parse_mdtm(mdtm: &str) -> Option<SystemTime> {
+    let digits = mdtm.get(..14)?;
+
+    let field = |range: std::ops::Range<usize>| digits.get(range)?.parse::<u64>().ok();
+
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, min, sec) = (field(8..10)?, field(10..12)?, field(12..14)?);
+
+    if !(1970..10000).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch (civil from days algorithm)
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hour * 3600 + min * 60 + sec;
+
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parses an MLSD listing in to entry names and whether they are directories
+fn parse_mlsd(listing: &str) -> Vec<(String, bool)> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let (facts, name) = line.split_once(' ')?;
+
+            let kind = facts
+                .split(';')
+                .filter_map(|fact| fact.split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case("type"))
+                .map(|(_, value)| value.to_ascii_lowercase())?;
+
+            match kind.as_str() {
+                "dir" => Some((name.to_string(), true)),
+                "file" => Some((name.to_string(), false)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parses a LIST listing in Unix (ls -l) or DOS format in to entry names and whether they are
+/// directories. Symbolic links are not followed
+fn parse_list(listing: &str) -> Vec<(String, bool)> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim_end();
+
+            if line.starts_with(|c: char| c.is_ascii_digit()) {
+                // DOS format - date time <DIR>|size name
+                let kind = line.split_whitespace().nth(2)?;
+                let name = skip_fields(line, 3)?;
+
+                Some((name.to_string(), kind.eq_ignore_ascii_case("<DIR>")))
+            } else {
+                // Unix format - perms links owner group size month day time|year name
+                let kind = line.chars().next()?;
+                let name = skip_fields(line, 8)?;
+
+                match kind {
+                    'd' => Some((name.to_string(), true)),
+                    '-' => Some((name.to_string(), false)),
+                    _ => None,
+                }
+            }
+        })
+        .filter(|(name, _)| name != "." && name != "..")
+        .collect()
+}
+
+/// Returns the rest of a line after a number of whitespace separated fields
+fn skip_fields(line: &str, count: usize) -> Option<&str> {
+    let mut rest = line;
+
+    for _ in 0..count {
+        rest = rest.trim_start().split_once(char::is_whitespace)?.1;
+    }
+
+    Some(rest.trim_start())
+}
+
+/// FTP data connection body
+struct FtpBody {
+    /// Data connection
+    data: TcpStream,
+    /// File length if known
+    len: Option<u64>,
+}
+
+impl Body for FtpBody {
+    fn content_length(&self) -> Option<u64> {
+        self.len
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+        let mut buf = vec![0; CHUNK_SIZE];
+
+        let len = self.data.read(&mut buf).await?;
+
+        if len == 0 {
+            Ok(None)
+        } else {
+            buf.truncate(len);
+            Ok(Some(Bytes::from(buf)))
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use std::error::Error;
+use std::io::Read;
+use std::path::Path;
+
+use clap::ValueEnum;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::task::spawn_blocking;
+
+use crate::compress::StoreCompression;
+use crate::url::Url;
+
+/// Hash algorithms used to check downloaded files
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashType {
+    Md5,
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashType {
+    /// Looks up a hash algorithm by name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::from_str(name, true).ok()
+    }
+
+    /// Returns the lower case name of the algorithm
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha224 => "sha224",
+            Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// Returns the length of the hex digest
+    pub fn hex_len(&self) -> usize {
+        match self {
+            Self::Md5 => 32,
+            Self::Sha1 => 40,
+            Self::Sha224 => 56,
+            Self::Sha256 => 64,
+            Self::Sha384 => 96,
+            Self::Sha512 => 128,
+        }
+    }
+
+    /// Returns true if text is a hex digest for this algorithm
+    pub fn is_hex_digest(&self, text: &str) -> bool {
+        text.len() == self.hex_len() && text.chars().all(|c| c.is_ascii_hexdigit())
+    }
+}
+
+impl std::fmt::Display for HashType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Digest a downloaded file is expected to have
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectedHash {
+    /// Hash algorithm
+    pub hash_type: HashType,
+    /// Lower case hex digest
+    pub hex: String,
+}
+
+impl ExpectedHash {
+    /// Creates an expected digest from a hex string
+    pub fn new(hash_type: HashType, hex: &str) -> Option<Self> {
+        if !hash_type.is_hex_digest(hex) {
+            return None;
+        }
+
+        Some(Self {
+            hash_type,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    /// Parses a hash fragment (eg. #sha256=...) from a URL
+    pub fn from_fragment(url: &Url) -> Option<Self> {
+        let (name, hex) = url.fragment()?.split_once('=')?;
+
+        Self::new(HashType::from_name(name)?, hex)
+    }
+
+    /// Checks a file matches the expected digest. The file is only read if its digest with
+    /// the algorithm isn't already known. Files stored compressed are checked against their
+    /// original contents. Errors refer to the file by its target path
+    pub async fn verify(
+        &self,
+        path: &Path,
+        target: &Path,
+        digests: &Digests,
+        compression: Option<StoreCompression>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let actual = match (digests.get(self.hash_type), compression) {
+            (Some(hex), _) => hex.to_string(),
+            (None, None) => file_digest(path, self.hash_type).await?,
+            (None, Some(compression)) => {
+                decoded_file_digest(path, self.hash_type, compression).await?
+            }
+        };
+
+        if actual != self.hex {
+            Err(format!(
+                "{} mismatch for {}: expected {}, got {actual}",
+                self.hash_type,
+                target.display(),
+                self.hex
+            ))?
+        }
+
+        Ok(())
+    }
+}
+
+/// Digests of the contents of a downloaded file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digests {
+    /// SHA-256 of the contents
+    sha256: String,
+    /// Digest with another algorithm if one was asked for
+    other: Option<(HashType, String)>,
+}
+
+impl Digests {
+    /// Returns the hex SHA-256
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// Returns the hex digest for an algorithm if it was worked out
+    pub fn get(&self, hash_type: HashType) -> Option<&str> {
+        if hash_type == HashType::Sha256 {
+            return Some(&self.sha256);
+        }
+
+        self.other
+            .as_ref()
+            .filter(|(other_type, _)| *other_type == hash_type)
+            .map(|(_, hex)| hex.as_str())
+    }
+}
+
+/// Works out the SHA-256 of data, and optionally its digest with another algorithm, in one pass
+pub struct DigestHasher {
+    sha256: Sha256,
+    other: Option<(HashType, Hasher)>,
+}
+
+impl DigestHasher {
+    /// Creates a hasher, also working out the digest with another algorithm if given
+    pub fn new(other: Option<HashType>) -> Self {
+        Self {
+            sha256: Sha256::new(),
+            other: other
+                .filter(|hash_type| *hash_type != HashType::Sha256)
+                .map(|hash_type| (hash_type, Hasher::new(hash_type))),
+        }
+    }
+
+    /// Adds data to the digests
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+
+        if let Some((_, hasher)) = &mut self.other {
+            hasher.update(data);
+        }
+    }
+
+    /// Returns the digests of the data
+    pub fn finalize(self) -> Digests {
+        Digests {
+            sha256: to_hex(&self.sha256.finalize()),
+            other: self
+                .other
+                .map(|(hash_type, hasher)| (hash_type, to_hex(&hasher.finalize()))),
+        }
+    }
+}
+
+/// Calculates the hex digest of a file
+pub async fn file_digest(
+    path: &Path,
+    hash_type: HashType,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut hasher = Hasher::new(hash_type);
+
+    read_file(path, |data| hasher.update(data)).await?;
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Calculates the SHA-256 of a file, and its digest with another algorithm if given, reading
+/// the file once
+pub async fn file_digests(
+    path: &Path,
+    other: Option<HashType>,
+) -> Result<Digests, Box<dyn Error + Send + Sync>> {
+    let mut hasher = DigestHasher::new(other);
+
+    read_file(path, |data| hasher.update(data)).await?;
+
+    Ok(hasher.finalize())
+}
+
+/// Reads a file passing each block read to a function
+async fn read_file<F>(path: &Path, mut f: F) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnMut(&[u8]),
+{
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let len = file.read(&mut buf).await?;
+
+        if len == 0 {
+            break;
+        }
+
+        f(&buf[..len]);
+    }
+
+    Ok(())
+}
+
+/// Calculates the hex digest of the original contents of a file stored compressed
+async fn decoded_file_digest(
+    path: &Path,
+    hash_type: HashType,
+    compression: StoreCompression,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let path = path.to_path_buf();
+
+    spawn_blocking(move || -> Result<String, Box<dyn Error + Send + Sync>> {
+        let file = std::fs::File::open(&path)
+            .map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+
+        let mut reader = compression
+            .decoder(file)
+            .map_err(|e| format!("Unable to decompress {}: {e}", path.display()))?;
+
+        let mut hasher = Hasher::new(hash_type);
+        let mut buf = vec![0u8; 64 * 1024];
+
+        loop {
+            let len = reader
+                .read(&mut buf)
+                .map_err(|e| format!("Unable to decompress {}: {e}", path.display()))?;
+
+            if len == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..len]);
+        }
+
+        Ok(to_hex(&hasher.finalize()))
+    })
+    .await?
+}
+
+/// Converts bytes to a lower case hex string
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Incremental hasher for any supported algorithm
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha224(Sha224),
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(hash_type: HashType) -> Self {
+        match hash_type {
+            HashType::Md5 => Self::Md5(Md5::new()),
+            HashType::Sha1 => Self::Sha1(Sha1::new()),
+            HashType::Sha224 => Self::Sha224(Sha224::new()),
+            HashType::Sha256 => Self::Sha256(Sha256::new()),
+            HashType::Sha384 => Self::Sha384(Sha384::new()),
+            HashType::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(h) => h.update(data),
+            Self::Sha1(h) => h.update(data),
+            Self::Sha224(h) => h.update(data),
+            Self::Sha256(h) => h.update(data),
+            Self::Sha384(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md5(h) => h.finalize().to_vec(),
+            Self::Sha1(h) => h.finalize().to_vec(),
+            Self::Sha224(h) => h.finalize().to_vec(),
+            Self::Sha256(h) => h.finalize().to_vec(),
+            Self::Sha384(h) => h.finalize().to_vec(),
+            Self::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::savefile::save_file;
+
+/// Capabilities learnt about a host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HostCaps {
+    /// Host handles HEAD requests
+    pub head: bool,
+}
+
+impl Default for HostCaps {
+    fn default() -> Self {
+        Self { head: true }
+    }
+}
+
+/// Map of host names to learnt capabilities
+#[derive(Default)]
+pub struct HostCapabilities {
+    hosts: HashMap<String, HostCaps>,
+    changed: bool,
+}
+
+impl HostCapabilities {
+    /// Load capabilities from a JSON file. If the file does not exist, create an empty map
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let caps = match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                let hosts = serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load host capabilities file {file}: {e}"))?;
+
+                Self {
+                    hosts,
+                    changed: false,
+                }
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => HostCapabilities::default(),
+                _ => Err(format!("Failed to open host capabilities file {file}: {e}"))?,
+            },
+        };
+
+        Ok(caps)
+    }
+
+    /// Save capabilities to a JSON file if they have changed
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = PathBuf::from(file);
+
+        let write = self.changed
+            && if let Some(parent) = path.parent() {
+                parent.is_dir()
+            } else {
+                true
+            };
+
+        if write {
+            save_file(file, |writer| {
+                Ok(serde_json::to_writer_pretty(writer, &self.hosts)?)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the capabilities for a host
+    pub fn get(&self, host: &str) -> HostCaps {
+        self.hosts.get(host).cloned().unwrap_or_default()
+    }
+
+    /// Records that a host does not handle HEAD requests
+    pub fn set_no_head(&mut self, host: &str) {
+        let caps = self.hosts.entry(host.to_string()).or_default();
+
+        if caps.head {
+            caps.head = false;
+            self.changed = true;
+        }
+    }
+}
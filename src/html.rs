@@ -1,103 +1,191 @@
 use std::error::Error;
 
+use bytes::Bytes;
 use once_cell::sync::Lazy;
 use scraper::{Html, Selector};
 use tokio::task::JoinHandle;
 
-use crate::output::{debug, output};
+use crate::download::{save_body, BytesBody, Saved};
+use crate::listing::{add_listing_details, is_autoindex, is_sort_link, parse_autoindex};
+use crate::output::{debug, error};
 use crate::skipreason::{SkipReason, SkipReasonErr};
 use crate::state::ArcState;
-use crate::url::{Url, UrlExt};
-use crate::walk::walk_recurse;
+use crate::stats::Stats;
+use crate::url::Url;
+use crate::walk::follow_links;
+
+/// Directives from a robots meta tag
+#[derive(Debug, Default, Clone, Copy)]
+struct RobotsMeta {
+    /// Links on the page must not be followed
+    nofollow: bool,
+    /// The page must not be saved
+    noindex: bool,
+}
 
-/// Process all of the links in an HTML document returning a list of join handles for spawned download tasks
-pub async fn process_html(state: &ArcState, url: &Url, html: String) -> Vec<JoinHandle<()>> {
-    // Process all of the links
-    let mut join_handles = Vec::new();
-
-    // Get hrefs out of the document
-    let hrefs = parse_html(html);
-
-    // Process each href
-    for href in hrefs {
-        match process_href(state, url, &href).await {
-            // TODO just stats.add_errored(e) to consolidate?
-            Err(e) if e.is::<SkipReasonErr>() => {
-                state.update_stats(|mut stats| stats.add_skipped()).await;
-                output!("{e}")
-            }
-            Err(e) => {
-                state.update_stats(|mut stats| stats.add_errored()).await;
-                output!("{e}")
+/// Process all of the links in an HTML document returning a list of join handles for spawned download tasks.
+/// The document is also saved if required
+pub async fn process_html(
+    state: &ArcState,
+    url: &Url,
+    html: String,
+    stats: &mut Stats,
+) -> Vec<JoinHandle<()>> {
+    let args = state.args();
+
+    // Get hrefs and robots directives out of the document
+    let (mut hrefs, meta) = parse_html(&html, args.page_requisites);
+
+    // Drop the column sort links from directory listings
+    if is_autoindex(&html) {
+        hrefs.retain(|href| !is_sort_link(href));
+
+        // Remember the file sizes and dates listed
+        if args.check_listed_sizes || args.listing_dates {
+            add_listing_details(state, url, &parse_autoindex(&html)).await;
+        }
+    }
+
+    let meta = if args.respect_robots_meta {
+        meta
+    } else {
+        RobotsMeta::default()
+    };
+
+    // Save the document
+    if args.save_html {
+        if meta.noindex {
+            skip_page(state, url, SkipReason::NoIndex, stats);
+        } else {
+            match save_html(state, url, html, stats).await {
+                Ok(Ok(saved)) => {
+                    if let Some(audit) = state.audit() {
+                        audit.add_document(url, saved.path).await;
+                    }
+                }
+                Ok(Err(skip)) => {
+                    state.events().on_skip(&skip);
+                    stats.add_skipped();
+                }
+                Err(e) => {
+                    error!("{e}");
+                    stats.add_errored();
+                }
             }
-            Ok(join) => join_handles.push(join),
         }
     }
 
-    join_handles
+    // Follow links?
+    if meta.nofollow {
+        skip_page(state, url, SkipReason::NoFollow, stats);
+        return Vec::new();
+    }
+
+    // Join each href to the document URL
+    let links = hrefs
+        .iter()
+        .map(|href| join_href(state, url, href))
+        .collect();
+
+    // Process all of the links
+    follow_links(state, links, stats).await
+}
+
+/// Reports a skip reason for a page and updates stats
+fn skip_page(state: &ArcState, url: &Url, reason: SkipReason, stats: &mut Stats) {
+    state
+        .events()
+        .on_skip(&SkipReasonErr::new(url.to_string(), reason));
+    stats.add_skipped();
+}
+
+/// Saves an HTML document to the mirror. The inner error gives the reason if the document is
+/// skipped
+async fn save_html(
+    state: &ArcState,
+    url: &Url,
+    html: String,
+    stats: &mut Stats,
+) -> Result<Result<Saved, SkipReasonErr>, Box<dyn Error + Send + Sync>> {
+    let mut body = BytesBody::new(Bytes::from(html));
+
+    save_body(state, url, &mut body, stats).await
 }
 
 /// Anchor selector
 static ANCHOR_SEL: Lazy<Selector> = Lazy::new(|| Selector::parse("a[href]").unwrap());
 
-/// Parse an HTML document and return a list of href links to process
-fn parse_html(html: String) -> Vec<String> {
+/// Anchor and page requisite selector
+static REQUISITE_SEL: Lazy<Selector> = Lazy::new(|| {
+    Selector::parse(
+        "a[href], link[href], img[src], script[src], source[src], video[src], audio[src]",
+    )
+    .unwrap()
+});
+
+/// Meta tag selector
+static META_SEL: Lazy<Selector> = Lazy::new(|| Selector::parse("meta[name][content]").unwrap());
+
+/// Parse an HTML document and return a list of href links, optionally including links to page
+/// requisites
+pub fn parse_links(html: &str, page_requisites: bool) -> Vec<String> {
+    parse_html(html, page_requisites).0
+}
+
+/// Parse an HTML document and return a list of href links to process and any robots directives.
+/// Optionally include links to page requisites (images, stylesheets, scripts and media)
+fn parse_html(html: &str, requisites: bool) -> (Vec<String>, RobotsMeta) {
     // Parse the document
-    let document = Html::parse_document(&html);
+    let document = Html::parse_document(html);
 
-    // Select all anchors
-    let anchors = document.select(&ANCHOR_SEL);
+    // Select all anchors (and requisites)
+    let elements = if requisites {
+        document.select(&REQUISITE_SEL)
+    } else {
+        document.select(&ANCHOR_SEL)
+    };
 
     // Get all hrefs
-    anchors
+    let hrefs = elements
         .into_iter()
-        .filter_map(|a| a.value().attr("href"))
+        .filter_map(|e| e.value().attr("href").or_else(|| e.value().attr("src")))
         .map(|a| a.to_string())
-        .collect()
+        .collect();
+
+    // Get robots directives
+    let mut meta = RobotsMeta::default();
+
+    for element in document.select(&META_SEL) {
+        let element = element.value();
+
+        if matches!(element.attr("name"), Some(name) if name.eq_ignore_ascii_case("robots")) {
+            for directive in element.attr("content").unwrap_or_default().split(',') {
+                match directive.trim().to_ascii_lowercase().as_str() {
+                    "nofollow" => meta.nofollow = true,
+                    "noindex" => meta.noindex = true,
+                    "none" => {
+                        meta.nofollow = true;
+                        meta.noindex = true;
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    (hrefs, meta)
 }
 
-/// Process a href on a base URL
-async fn process_href<'a>(
-    state: &'a ArcState,
-    base_url: &'a Url,
-    href: &'a str,
-) -> Result<JoinHandle<()>, Box<dyn Error + Send + Sync>> {
-    // Join href to the base URL if necessary
-    let join = match base_url.join(href) {
+/// Join a href to a base URL if necessary
+fn join_href(state: &ArcState, base_url: &Url, href: &str) -> Result<Url, SkipReasonErr> {
+    match base_url.join(href) {
         Ok(href_url) => {
             debug!(state, 2, "href {href} of {base_url} -> {href_url}");
-
-            href_url.is_handled()?;
-
-            // Check it's not a fragment
-            if href_url.fragment().is_some() {
-                Err(SkipReasonErr::new(
-                    href_url.to_string(),
-                    SkipReason::Fragment,
-                ))?;
-            }
-
-            // Check is doesn't have a query string
-            if href_url.query().is_some() {
-                Err(SkipReasonErr::new(href_url.to_string(), SkipReason::Query))?;
-            }
-
-            // Check the URL is relative to the base URL
-            if !href_url.is_relative_to(state.url()) {
-                Err(SkipReasonErr::new(
-                    href_url.to_string(),
-                    SkipReason::NotRelative,
-                ))?;
-            }
-
-            // Recurse in to this URL
-            walk_recurse(state, href_url).await?
+            Ok(href_url)
         }
         Err(e) => Err(SkipReasonErr::new(
             href.to_string(),
             SkipReason::NotValid(e),
-        ))?,
-    };
-
-    Ok(join)
+        )),
+    }
 }
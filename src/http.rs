@@ -0,0 +1,703 @@
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::{BoxFuture, FutureExt};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, ETAG, LAST_MODIFIED, RETRY_AFTER};
+use reqwest::StatusCode;
+use tokio::time::sleep;
+
+use crate::download::{download, download_body, BytesBody, PrefixedBody};
+use crate::etags::{etag_to_header, etag_to_string, weak_match, SyntheticETag};
+use crate::fallback::fetch_other;
+use crate::feed::{is_feed_path, parse_feed, process_feed};
+use crate::html::process_html;
+use crate::interstitial::{check_interstitial, expects_file};
+use crate::limiter::Slot;
+use crate::listing::{
+    add_listing_details,
+    parse_json_listing,
+    parse_text_listing,
+    process_listing,
+};
+use crate::outcome::{check, ErrorKind, Outcome};
+use crate::output::{debug, error, output, progress};
+use crate::response::{Response, ResponseExt};
+use crate::s3::{bucket_root, parse_bucket_listing, process_bucket_listing};
+use crate::sitemap::{is_sitemap_path, parse_sitemap, process_sitemap, root_element};
+use crate::skipreason::SkipReasonErr;
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::status::StatusErr;
+use crate::transport::Transport;
+use crate::url::Url;
+use crate::walk::join_tasks;
+
+/// Number of times a request is retried when the server is throttling requests
+const MAX_THROTTLE_RETRIES: usize = 5;
+
+/// Number of bytes read from the start of an XML response to find its root element
+const XML_SNIFF_LEN: usize = 4096;
+
+/// Root elements of sitemaps, feeds and bucket listings
+const DOCUMENT_ROOTS: [&str; 6] = [
+    "urlset",
+    "sitemapindex",
+    "rss",
+    "RDF",
+    "feed",
+    "ListBucketResult",
+];
+
+/// HTTP and HTTPS transport
+pub struct HttpTransport;
+
+impl Transport for HttpTransport {
+    fn walk<'a>(
+        &'a self,
+        state: &'a ArcState,
+        url: &'a Url,
+        sem: Slot,
+        stats: &'a mut Stats,
+    ) -> BoxFuture<'a, Result<Outcome, Box<dyn Error + Send + Sync>>> {
+        walk_http(state, url, sem, stats).boxed()
+    }
+}
+
+/// Loads data from a URL. If the data is HTML, parse the document and follow links.
+/// Otherwise download the file.
+/// Use loaded etags to determine if the resource has already been downloaded and skip if so.
+async fn walk_http(
+    state: &ArcState,
+    url: &Url,
+    sem: Slot,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    // Has the file's date in the directory listing changed since it was downloaded?
+    if state.is_listed_unchanged(url).await {
+        progress!("{url} is not modified");
+        return Ok(Outcome::NotModified);
+    }
+
+    // Keep any existing file without asking the server with --no-clobber
+    if state.args().no_clobber {
+        check!(state.check_up_to_date(url, None, None).await);
+    }
+
+    // Create additional HTTP headers
+    let mut headers = HeaderMap::new();
+
+    // Is there an etag for this URL?
+    let mut old_etag = state.find_etag(url);
+
+    // Synthesized etags can't be sent to the server. Check the local file instead
+    let synth_etag = match old_etag.and_then(|etag| SyntheticETag::parse(etag)) {
+        Some(synth_etag) => {
+            debug!(state, 2, "Previous synthesized etag value: {synth_etag}");
+            old_etag = None;
+
+            if synth_etag
+                .matches_file(&check!(state.path_for_url(url).await))
+                .await
+            {
+                Some(synth_etag)
+            } else {
+                debug!(state, 1, "Local file for {url} has changed");
+                None
+            }
+        }
+        None => None,
+    };
+
+    if let Some(old_etag) = old_etag {
+        debug!(state, 2, "Previous etag value: {old_etag}");
+
+        // Set the If-None-Match request header to the old etag
+        if let Some(value) = etag_to_header(old_etag) {
+            headers.insert("If-None-Match", value);
+        } else {
+            error!("Previous etag value {old_etag} is not valid");
+        }
+    }
+
+    // Fetch the URL
+    state.events().on_fetch_start(url);
+
+    let args = state.args();
+
+    let response = if args.head_first || args.skip_existing || synth_etag.is_some() {
+        match probe(state, url, headers.clone(), stats).await? {
+            Probe::Get(response) => response,
+            Probe::Skipped(skip) => return Ok(Outcome::Skipped(skip)),
+            Probe::Head(head) => {
+                let not_modified = match &synth_etag {
+                    Some(synth_etag) => {
+                        head.status().is_success()
+                            && header_length(head.headers()) == Some(synth_etag.size)
+                    }
+                    None => {
+                        (head.status() == StatusCode::NOT_MODIFIED && old_etag.is_some())
+                            || (head.status().is_success()
+                                && etag_unchanged(head.headers(), old_etag))
+                    }
+                };
+
+                if not_modified {
+                    progress!("{url} is not modified");
+
+                    return Ok(Outcome::NotModified);
+                }
+
+                // Apply the download filters to files before fetching the body
+                if head.status().is_success()
+                    && !head.is_html(state)
+                    && !head.is_xml(state)
+                    && !is_sitemap_path(&state.response_url(head.url()))
+                    && !is_feed_path(&state.response_url(head.url()))
+                {
+                    let length = header_length(head.headers());
+                    let modified = header_modified(head.headers());
+
+                    check!(state.check_shard(url));
+                    check!(state.check_size(url, length));
+                    check!(state.check_age(url, modified));
+                    check!(state.check_mime_type(url, head.mime_type(state)));
+                    check!(state.check_up_to_date(url, length, modified).await);
+
+                    // Without an etag compare the length with the local copy
+                    if args.head_first
+                        && old_etag.is_none()
+                        && state.local_length_matches(url, length, modified).await
+                    {
+                        progress!("{url} is not modified");
+
+                        return Ok(Outcome::NotModified);
+                    }
+                }
+
+                check!(get(state, url, headers, stats).await?)
+            }
+        }
+    } else {
+        check!(get(state, url, headers, stats).await?)
+    };
+
+    // Get final URL after any redirects
+    let final_url = state.response_url(response.url());
+
+    // Only fetch a resource once when links to different URLs redirect to it
+    if final_url != *url && !state.add_processed_url(&final_url).await {
+        debug!(
+            state,
+            1, "{url} redirects to {final_url} which has already been processed"
+        );
+
+        // Nothing is left to do for the URL when resuming
+        state.checkpoint_complete(url).await;
+
+        return Ok(Outcome::Ignored);
+    }
+
+    // Get status code
+    let status = response.status();
+
+    // Check status code
+    if !status.is_success() {
+        // Not OK - check status
+        match status.as_u16() {
+            304 if old_etag.is_some() => {
+                progress!("{url} is not modified");
+                return Ok(Outcome::NotModified);
+            }
+            404 if !state.args().gzip_fallback.is_empty() => {
+                // Try the compressed or plain copy of the file instead
+                match fetch_other(state, url, stats).await? {
+                    Some(outcome) => return Ok(outcome),
+                    None => Err(StatusErr::new(status, &final_url))?,
+                }
+            }
+            _ => Err(StatusErr::new(status, &final_url))?,
+        }
+    } else {
+        debug!(state, 2, "Status {status}");
+
+        // Servers which ignore If-None-Match send the file again with the same etag
+        if etag_unchanged(response.headers(), old_etag) {
+            progress!("{url} is not modified");
+            return Ok(Outcome::NotModified);
+        }
+    }
+
+    // Has a file download returned an HTML page (captive portal, login page etc.)?
+    let mut response = if response.is_html(state) && expects_file(url) {
+        match check_interstitial(state, url, response).await? {
+            Ok(response) => response,
+            Err(e) => return Ok(Outcome::Errored(ErrorKind::Interstitial(Box::new(e)))),
+        }
+    } else {
+        response
+    };
+
+    // Is the document HTML?
+    let outcome = if response.is_html(state) {
+        // Get HTML body
+        let html = response.text().await?;
+
+        // Release the download slot
+        drop(sem);
+
+        let html_bytes = html.len();
+
+        // Process HTML
+        let join_handles = process_html(state, &final_url, html, stats).await;
+        let links = join_handles.len();
+
+        // Join the threads
+        join_tasks(join_handles).await;
+
+        Outcome::Parsed {
+            bytes: html_bytes,
+            links,
+        }
+    } else if response.is_xml(state) || is_sitemap_path(&final_url) || is_feed_path(&final_url) {
+        // Possible sitemap or feed - only buffer the whole XML body if it might be one
+        let headers = response.headers().clone();
+        let mime_type = response.mime_type(state);
+        let len = response.content_length();
+
+        let xml = match read_xml(&mut response).await? {
+            XmlBody::Document(xml) => xml,
+            XmlBody::Other(prefix) => {
+                // Not a sitemap, feed or bucket listing - check the file is in our shard, size and
+                // age limits
+                let modified = header_modified(&headers);
+
+                check!(state.check_shard(url));
+                check!(state.check_size(url, len));
+                check!(state.check_age(url, modified));
+                check!(state.check_mime_type(url, mime_type));
+                check!(state.check_up_to_date(url, len, modified).await);
+
+                // Download the resource
+                let mut body = PrefixedBody::new(prefix, response, len);
+                let outcome =
+                    download_body(state, url, &final_url, &headers, &mut body, stats).await?;
+
+                // Release the download slot
+                drop(sem);
+
+                return Ok(outcome);
+            }
+        };
+
+        let text = String::from_utf8_lossy(&xml);
+
+        // Only look for a feed if the document is not a sitemap
+        let sitemap = parse_sitemap(&text);
+        let feed = match sitemap {
+            Some(_) => None,
+            None => parse_feed(&text),
+        };
+
+        match (sitemap, feed) {
+            (Some(locs), _) => {
+                // Release the download slot
+                drop(sem);
+
+                let xml_bytes = xml.len();
+
+                // Process sitemap
+                let join_handles = process_sitemap(state, &final_url, locs, stats).await;
+                let links = join_handles.len();
+
+                // Join the threads
+                join_tasks(join_handles).await;
+
+                Outcome::Parsed {
+                    bytes: xml_bytes,
+                    links,
+                }
+            }
+            (None, Some(entries)) => {
+                // Release the download slot
+                drop(sem);
+
+                let xml_bytes = xml.len();
+
+                // Process the entries published since the last run
+                let links = process_feed(state, &final_url, entries, stats).await;
+
+                Outcome::Parsed {
+                    bytes: xml_bytes,
+                    links,
+                }
+            }
+            (None, None) if parse_bucket_listing(&text).is_some() => {
+                // Release the download slot
+                drop(sem);
+
+                // Process the bucket listing and any further pages
+                let listing = parse_bucket_listing(&text);
+
+                process_bucket_listing(state, &final_url, &bucket_root(&final_url), listing, stats)
+                    .await?
+            }
+            (None, None) => {
+                // Not a sitemap - check the file is in our shard, size and age limits
+                check!(state.check_shard(url));
+                check!(state.check_size(url, Some(xml.len() as u64)));
+                check!(state.check_age(url, header_modified(&headers)));
+                check!(state.check_mime_type(url, mime_type));
+                check!(
+                    state
+                        .check_up_to_date(url, Some(xml.len() as u64), header_modified(&headers))
+                        .await
+                );
+
+                // Download the resource
+                let mut body = BytesBody::new(xml);
+                let outcome =
+                    download_body(state, url, &final_url, &headers, &mut body, stats).await?;
+
+                // Release the download slot
+                drop(sem);
+
+                outcome
+            }
+        }
+    } else if response.is_text(state) && final_url.path().ends_with('/') {
+        // Possible plain text directory listing - get the text body
+        let headers = response.headers().clone();
+        let mime_type = response.mime_type(state);
+        let text = response.bytes().await?;
+
+        match parse_text_listing(&String::from_utf8_lossy(&text)) {
+            Some(entries) => {
+                // Release the download slot
+                drop(sem);
+
+                let text_bytes = text.len();
+
+                // Process listing
+                let join_handles = process_listing(state, &final_url, entries, stats).await;
+                let links = join_handles.len();
+
+                // Join the threads
+                join_tasks(join_handles).await;
+
+                Outcome::Parsed {
+                    bytes: text_bytes,
+                    links,
+                }
+            }
+            None => {
+                // Not a listing - check the file is in our shard, size and age limits
+                check!(state.check_shard(url));
+                check!(state.check_size(url, Some(text.len() as u64)));
+                check!(state.check_age(url, header_modified(&headers)));
+                check!(state.check_mime_type(url, mime_type));
+                check!(
+                    state
+                        .check_up_to_date(url, Some(text.len() as u64), header_modified(&headers))
+                        .await
+                );
+
+                // Download the resource
+                let mut body = BytesBody::new(text);
+                let outcome =
+                    download_body(state, url, &final_url, &headers, &mut body, stats).await?;
+
+                // Release the download slot
+                drop(sem);
+
+                outcome
+            }
+        }
+    } else if response.is_json(state) && final_url.path().ends_with('/') {
+        // Possible JSON directory listing - get the JSON body
+        let headers = response.headers().clone();
+        let mime_type = response.mime_type(state);
+        let json = response.bytes().await?;
+
+        match parse_json_listing(&String::from_utf8_lossy(&json)) {
+            Some(entries) => {
+                // Release the download slot
+                drop(sem);
+
+                let json_bytes = json.len();
+
+                // Remember the file sizes and dates listed
+                add_listing_details(state, &final_url, &entries).await;
+
+                // Process listing
+                let hrefs = entries.into_iter().map(|entry| entry.href).collect();
+                let join_handles = process_listing(state, &final_url, hrefs, stats).await;
+                let links = join_handles.len();
+
+                // Join the threads
+                join_tasks(join_handles).await;
+
+                Outcome::Parsed {
+                    bytes: json_bytes,
+                    links,
+                }
+            }
+            None => {
+                // Not a listing - check the file is in our shard, size and age limits
+                check!(state.check_shard(url));
+                check!(state.check_size(url, Some(json.len() as u64)));
+                check!(state.check_age(url, header_modified(&headers)));
+                check!(state.check_mime_type(url, mime_type));
+                check!(
+                    state
+                        .check_up_to_date(url, Some(json.len() as u64), header_modified(&headers))
+                        .await
+                );
+
+                // Download the resource
+                let mut body = BytesBody::new(json);
+                let outcome =
+                    download_body(state, url, &final_url, &headers, &mut body, stats).await?;
+
+                // Release the download slot
+                drop(sem);
+
+                outcome
+            }
+        }
+    } else {
+        // Check the file is in our shard, size and age limits
+        let modified = header_modified(response.headers());
+
+        check!(state.check_shard(url));
+        check!(state.check_size(url, response.content_length()));
+        check!(state.check_age(url, modified));
+        check!(state.check_mime_type(url, response.mime_type(state)));
+
+        // Check the local copy, which wasn't checked before the request if the host doesn't
+        // handle HEAD requests
+        check!(
+            state
+                .check_up_to_date(url, response.content_length(), modified)
+                .await
+        );
+
+        // Download the resource
+        let outcome = download(state, url, &final_url, response, stats).await?;
+
+        // Release the download slot
+        drop(sem);
+
+        outcome
+    };
+
+    Ok(outcome)
+}
+
+/// Start of an XML response body
+enum XmlBody {
+    /// Whole body of a document which may be a sitemap, feed or bucket listing
+    Document(Bytes),
+    /// Start of the body of any other XML file
+    Other(Bytes),
+}
+
+/// Reads enough of an XML response to find its root element, reading the whole body if the
+/// document may be a sitemap, feed or bucket listing
+async fn read_xml(response: &mut Response) -> Result<XmlBody, Box<dyn Error + Send + Sync>> {
+    let mut xml = BytesMut::new();
+
+    while xml.len() < XML_SNIFF_LEN {
+        match response.chunk().await? {
+            Some(chunk) => xml.extend_from_slice(&chunk),
+            None => return Ok(XmlBody::Document(xml.freeze())),
+        }
+    }
+
+    // Buffer the rest of the body if the root element wasn't found in the start of it
+    match root_element(&String::from_utf8_lossy(&xml)) {
+        Some(root) if !DOCUMENT_ROOTS.contains(&root) => return Ok(XmlBody::Other(xml.freeze())),
+        _ => (),
+    }
+
+    while let Some(chunk) = response.chunk().await? {
+        xml.extend_from_slice(&chunk);
+    }
+
+    Ok(XmlBody::Document(xml.freeze()))
+}
+
+/// Issues a GET request for a URL. The inner error gives the reason if the redirect policy
+/// refused to follow a redirect
+pub async fn get(
+    state: &ArcState,
+    url: &Url,
+    headers: HeaderMap,
+    stats: &mut Stats,
+) -> Result<Result<Response, SkipReasonErr>, Box<dyn Error + Send + Sync>> {
+    let mut retries = 0;
+
+    loop {
+        let started = Instant::now();
+
+        let result = state
+            .client()
+            .get(state.request_url(url))
+            .headers(headers.clone())
+            .send()
+            .await;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => match redirect_skip(&e) {
+                Some(skip) => return Ok(Err(skip)),
+                None => Err(e)?,
+            },
+        };
+
+        if state.args().timings {
+            stats.add_latency(started.elapsed());
+        }
+
+        // Wait and try again if the server is throttling requests
+        if !matches!(
+            response.status(),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) || retries >= MAX_THROTTLE_RETRIES
+        {
+            return Ok(Ok(response));
+        }
+
+        let Some(wait) = retry_after(response.headers()) else {
+            return Ok(Ok(response));
+        };
+
+        if wait > Duration::from_secs(state.args().max_retry_after) {
+            debug!(
+                state,
+                1,
+                "Not waiting {}s to retry {url} (limit {}s)",
+                wait.as_secs(),
+                state.args().max_retry_after
+            );
+            return Ok(Ok(response));
+        }
+
+        output!(
+            "Status {} fetching {url}, retrying in {}s",
+            response.status(),
+            wait.as_secs()
+        );
+
+        stats.add_throttled();
+        retries += 1;
+
+        sleep(wait).await;
+    }
+}
+
+/// Returns the reason the redirect policy refused to follow a redirect for a failed request
+fn redirect_skip(e: &reqwest::Error) -> Option<SkipReasonErr> {
+    e.source()?.downcast_ref::<SkipReasonErr>().cloned()
+}
+
+/// Returns the time to wait from a Retry-After header, given in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let at = httpdate::parse_http_date(value).ok()?;
+
+            Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+/// Returns true if a response has an etag matching the previous etag by weak comparison
+fn etag_unchanged(headers: &HeaderMap, old_etag: Option<&String>) -> bool {
+    match (headers.get(ETAG), old_etag) {
+        (Some(etag), Some(old_etag)) => weak_match(&etag_to_string(etag), old_etag),
+        _ => false,
+    }
+}
+
+/// Returns the content length header value
+fn header_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Returns the last modified header value
+pub fn header_modified(headers: &HeaderMap) -> Option<SystemTime> {
+    headers
+        .get(LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// Result of probing a URL
+enum Probe {
+    /// Response to a HEAD request
+    Head(Response),
+    /// Full response to a GET request for hosts which don't handle HEAD
+    Get(Response),
+    /// The redirect policy refused to follow a redirect
+    Skipped(SkipReasonErr),
+}
+
+/// Issues a HEAD request for a URL. If the host doesn't handle HEAD requests this is remembered
+/// and a GET request is issued instead, now and for the rest of the run
+async fn probe(
+    state: &ArcState,
+    url: &Url,
+    headers: HeaderMap,
+    stats: &mut Stats,
+) -> Result<Probe, Box<dyn Error + Send + Sync>> {
+    if state.host_caps(url).await.head {
+        let result = state
+            .client()
+            .head(state.request_url(url))
+            .headers(headers.clone())
+            .send()
+            .await;
+
+        match result {
+            Ok(response)
+                if !matches!(
+                    response.status(),
+                    StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+                ) =>
+            {
+                return Ok(Probe::Head(response));
+            }
+            Ok(response) => {
+                debug!(state, 1, "Status {} for HEAD {url}", response.status());
+            }
+            Err(e) if e.is_redirect() || e.is_connect() || e.is_timeout() => {
+                match redirect_skip(&e) {
+                    Some(skip) => return Ok(Probe::Skipped(skip)),
+                    None => Err(e)?,
+                }
+            }
+            Err(e) => {
+                debug!(state, 1, "HEAD {url} failed: {e}");
+            }
+        }
+
+        output!(
+            "Host {} does not handle HEAD requests, using GET",
+            url.host_str().unwrap_or_default()
+        );
+
+        state.set_host_no_head(url).await;
+    }
+
+    Ok(match get(state, url, headers, stats).await? {
+        Ok(response) => Probe::Get(response),
+        Err(skip) => Probe::Skipped(skip),
+    })
+}
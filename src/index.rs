@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::path::Path;
+
+use tokio::fs::{read_dir, read_to_string, write};
+
+/// Marker identifying a generated index page
+const INDEX_MARKER: &str = "<!-- Generated by mirrorurl -->";
+
+/// Directory entry listed on an index page
+struct IndexEntry {
+    name: String,
+    dir: bool,
+    size: u64,
+    mtime: Option<String>,
+}
+
+/// Writes an index page listing to every directory under the target directory.
+/// Existing index pages not generated by mirrorurl are left alone. Returns the number written
+pub async fn generate_indexes(
+    target: &Path,
+    index_name: &str,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut written = 0;
+
+    let mut dirs = VecDeque::new();
+    dirs.push_back(target.to_path_buf());
+
+    while let Some(dir) = dirs.pop_front() {
+        // Read the directory entries
+        let entries = read_index_entries(&dir, index_name).await?;
+
+        // Queue sub directories
+        for entry in entries.iter().filter(|entry| entry.dir) {
+            dirs.push_back(dir.join(&entry.name));
+        }
+
+        // Don't overwrite a mirrored index page
+        let index_path = dir.join(index_name);
+
+        if index_path.is_file() {
+            match read_to_string(&index_path).await {
+                Ok(content) if content.contains(INDEX_MARKER) => (),
+                _ => continue,
+            }
+        }
+
+        // Write the index page
+        let rel_dir = dir.strip_prefix(target).unwrap_or(&dir);
+
+        write(&index_path, build_index_html(rel_dir, &entries))
+            .await
+            .map_err(|e| format!("Error writing {}: {e}", index_path.display()))?;
+
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+/// Reads the entries in a directory to list, sorted with directories first
+async fn read_index_entries(
+    dir: &Path,
+    index_name: &str,
+) -> Result<Vec<IndexEntry>, Box<dyn Error + Send + Sync>> {
+    let mut entries = Vec::new();
+
+    let mut paths = read_dir(dir)
+        .await
+        .map_err(|e| format!("Unable to read directory {}: {e}", dir.display()))?;
+
+    while let Some(dirent) = paths
+        .next_entry()
+        .await
+        .map_err(|e| format!("Unable to read directory {}: {e}", dir.display()))?
+    {
+        let name = dirent.file_name().to_string_lossy().into_owned();
+
+        // Skip hidden, temporary and index files
+        if name.starts_with('.') || name.ends_with(".mirrorurl") || name == index_name {
+            continue;
+        }
+
+        let meta = dirent.metadata().await?;
+
+        entries.push(IndexEntry {
+            name,
+            dir: meta.is_dir(),
+            size: meta.len(),
+            mtime: meta.modified().ok().map(httpdate::fmt_http_date),
+        });
+    }
+
+    entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(entries)
+}
+
+/// Builds the HTML for an index page
+fn build_index_html(rel_dir: &Path, entries: &[IndexEntry]) -> String {
+    let title = html_escape(&format!("Index of /{}", rel_dir.to_string_lossy()));
+
+    let mut html = format!(
+        "<!DOCTYPE html>
+{INDEX_MARKER}
+<html>
+    <head>
+        <meta charset=\"utf-8\">
+        <title>{title}</title>
+    </head>
+    <body>
+        <h1>{title}</h1>
+        <table>
+            <tr><th>Name</th><th>Size</th><th>Modified</th></tr>
+"
+    );
+
+    if !rel_dir.as_os_str().is_empty() {
+        html.push_str("            <tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let (href, name, size) = if entry.dir {
+            (
+                format!("{}/", href_escape(&entry.name)),
+                format!("{}/", html_escape(&entry.name)),
+                String::from("-"),
+            )
+        } else {
+            (
+                href_escape(&entry.name),
+                html_escape(&entry.name),
+                entry.size.to_string(),
+            )
+        };
+
+        html.push_str(&format!(
+            "            <tr><td><a href=\"{href}\">{name}</a></td><td>{size}</td><td>{}</td></tr>\n",
+            entry.mtime.as_deref().unwrap_or_default()
+        ));
+    }
+
+    html.push_str(
+        "        </table>
+    </body>
+</html>
+",
+    );
+
+    html
+}
+
+/// Escapes text for inclusion in HTML
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent encodes a file name for use as a relative link
+fn href_escape(name: &str) -> String {
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+
+use crate::output::output;
+use crate::response::{Response, ResponseExt};
+use crate::state::ArcState;
+use crate::url::Url;
+
+/// File extensions which are never expected to be HTML pages
+const FILE_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "bin", "bz2", "cab", "deb", "dmg", "exe", "gz", "img", "iso", "jar", "lz", "lzma",
+    "msi", "pdf", "pkg", "rar", "rpm", "sig", "tar", "tbz", "tgz", "txz", "whl", "xz", "zip",
+    "zst",
+];
+
+/// Returns true if a URL is expected to be a file rather than an HTML page
+pub fn expects_file(url: &Url) -> bool {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| FILE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Error for a file download which returned an HTML page (captive portal, login page etc.)
+#[derive(Debug)]
+pub struct InterstitialErr {
+    /// The requested URL
+    url: String,
+    /// The URL the page was returned from
+    final_url: String,
+}
+
+impl Display for InterstitialErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expected a file from {} but received an HTML page from {} (login or interstitial page?)",
+            self.url, self.final_url
+        )
+    }
+}
+
+impl Error for InterstitialErr {}
+
+/// Serializes re-authentication prompts from concurrent downloads
+#[derive(Default)]
+pub struct Reauth {
+    /// Held whilst prompting
+    prompt: Mutex<()>,
+    /// Number of prompts answered
+    answered: AtomicU64,
+}
+
+/// Checks an HTML response to a file URL. If pausing is enabled the user is asked to
+/// re-authenticate and the file is fetched again, otherwise the download is abandoned with the
+/// inner error
+pub async fn check_interstitial(
+    state: &ArcState,
+    url: &Url,
+    response: Response,
+) -> Result<Result<Response, InterstitialErr>, Box<dyn Error + Send + Sync>> {
+    let final_url = state.response_url(response.url());
+
+    if state.args().pause_on_interstitial {
+        drop(response);
+
+        // Wait for the user to re-authenticate
+        pause(state.reauth(), url, &final_url).await?;
+
+        // Try again
+        let response = state.client().get(state.request_url(url)).send().await?;
+
+        if !response.status().is_success() || !response.is_html(state) {
+            return Ok(Ok(response));
+        }
+    }
+
+    Ok(Err(InterstitialErr {
+        url: url.to_string(),
+        final_url: final_url.to_string(),
+    }))
+}
+
+/// Prompts the user to re-authenticate and waits for enter to be pressed. Downloads hitting
+/// the page whilst a prompt is showing don't prompt again
+async fn pause(
+    reauth: &Reauth,
+    url: &Url,
+    final_url: &Url,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let seen = reauth.answered.load(Ordering::Acquire);
+
+    let _lock = reauth.prompt.lock().await;
+
+    if reauth.answered.load(Ordering::Acquire) == seen {
+        output!("{url} returned a login or interstitial page from {final_url}");
+        output!("Re-authenticate then press enter to continue");
+
+        spawn_blocking(|| std::io::stdin().read_line(&mut String::new())).await??;
+
+        reauth.answered.fetch_add(1, Ordering::AcqRel);
+    }
+
+    Ok(())
+}
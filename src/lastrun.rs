@@ -0,0 +1,142 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::output::{error, output};
+use crate::savefile::save_file;
+use crate::state::ArcState;
+use crate::stats::Stats;
+
+/// Least rise in errors counted as a spike
+const ERROR_SPIKE: u64 = 5;
+
+/// Totals from a run kept to compare the next run against
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RunSummary {
+    /// Files downloaded
+    pub downloads: u64,
+    /// Files not modified
+    pub not_modified: u64,
+    /// Documents parsed
+    pub documents: u64,
+    /// URLs skipped
+    pub skipped: u64,
+    /// URLs errored
+    pub errored: u64,
+}
+
+impl RunSummary {
+    /// Load a run summary from a JSON file. Returns None if the file does not exist
+    pub fn new_from_file(file: &str) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                let summary = serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load last run file {file}: {e}"))?;
+
+                Ok(Some(summary))
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(None),
+                _ => Err(format!("Failed to open last run file {file}: {e}"))?,
+            },
+        }
+    }
+
+    /// Save the run summary to a JSON file
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        save_file(file, |writer| {
+            Ok(serde_json::to_writer_pretty(writer, self)?)
+        })
+    }
+
+    /// Returns the number of files found, whether downloaded or not
+    fn files(&self) -> u64 {
+        self.downloads + self.not_modified
+    }
+
+    /// Describes the changes from a previous run
+    pub fn deltas(&self, last: &RunSummary) -> String {
+        format!(
+            "Since the last run: downloads {}, not modified {}, documents {}, skipped {}, errors {}",
+            Self::percent_delta(self.downloads, last.downloads),
+            Self::percent_delta(self.not_modified, last.not_modified),
+            Self::percent_delta(self.documents, last.documents),
+            Self::delta(self.skipped, last.skipped),
+            Self::delta(self.errored, last.errored),
+        )
+    }
+
+    /// Describes the changes from a previous run which suggest a problem upstream or with the
+    /// configuration
+    pub fn anomalies(&self, last: &RunSummary) -> Vec<String> {
+        let mut anomalies = Vec::new();
+
+        if self.errored >= last.errored + ERROR_SPIKE && self.errored >= last.errored * 2 {
+            anomalies.push(format!(
+                "Errors rose from {} to {} since the last run",
+                last.errored, self.errored
+            ));
+        }
+
+        if self.files() == 0 && last.files() > 0 {
+            anomalies.push(format!(
+                "No files were found but the last run found {}",
+                last.files()
+            ));
+        }
+
+        anomalies
+    }
+
+    /// Formats the change in a count as a percentage of the previous count, or as a number if
+    /// the previous count was zero
+    fn percent_delta(count: u64, last: u64) -> String {
+        if last == 0 {
+            Self::delta(count, last)
+        } else {
+            let percent = (count as f64 - last as f64) * 100.0 / last as f64;
+            format!("{:+}%", percent.round() as i64)
+        }
+    }
+
+    /// Formats the change in a count
+    fn delta(count: u64, last: u64) -> String {
+        format!("{:+}", count as i64 - last as i64)
+    }
+}
+
+/// Prints the changes since the last run and any anomalies, then saves this run's totals for
+/// the next run to compare against. Interrupted runs aren't saved
+pub fn compare_last_run(
+    state: &ArcState,
+    stats: &Stats,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let target = Path::new(&state.args().target);
+    let file = target.join(".last-run.json").to_string_lossy().into_owned();
+
+    let summary = stats.summary();
+
+    // A bad last run file is replaced with this run's summary
+    match RunSummary::new_from_file(&file) {
+        Ok(Some(last)) => {
+            output!("{}", summary.deltas(&last));
+
+            for anomaly in summary.anomalies(&last) {
+                output!("Warning: {anomaly}");
+            }
+        }
+        Ok(None) => (),
+        Err(e) => error!("{e}"),
+    }
+
+    if !state.is_interrupted() && target.is_dir() {
+        summary.save_to_file(&file)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,5 @@
+//! mirrorurl is used as a binary. The library only holds the test kit for writing end to end
+//! tests of mirrors outside this crate, enabled with the testkit feature
+
+#[cfg(feature = "testkit")]
+pub mod testkit;
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::url::Url;
+
+/// Number of times a fetch gives its slot up to high priority fetches before keeping one, so a
+/// steady stream of high priority fetches can't hold the others back forever
+pub const MAX_GIVE_WAY: usize = 4;
+
+/// Download slot held whilst fetching a URL. The slot is released when dropped
+pub struct Slot {
+    _host: Option<OwnedSemaphorePermit>,
+    _global: OwnedSemaphorePermit,
+}
+
+/// Snapshot of download slot usage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlotUsage {
+    /// Slots in use
+    pub busy: usize,
+    /// Total number of slots
+    pub total: usize,
+    /// Fetches waiting for a slot
+    pub waiting: usize,
+}
+
+impl Display for SlotUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} slots busy, {} waiting",
+            self.busy, self.total, self.waiting
+        )
+    }
+}
+
+/// Limits the number of concurrent requests overall and to each host
+pub struct Limiter {
+    /// Overall limit
+    limit: usize,
+    /// Overall semaphore
+    global: Arc<Semaphore>,
+    /// Number of fetches waiting for a slot
+    waiting: AtomicUsize,
+    /// Number of high priority fetches waiting for a slot
+    hot_waiting: AtomicUsize,
+    /// Notified when a high priority fetch gets a slot
+    hot_done: Notify,
+    /// Limit for each host if any
+    per_host: Option<usize>,
+    /// Semaphores for each host and port
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl Limiter {
+    /// Creates a limiter with an overall limit and an optional limit for each host
+    pub fn new(global: usize, per_host: Option<usize>) -> Self {
+        Self {
+            limit: global,
+            global: Arc::new(Semaphore::new(global)),
+            waiting: AtomicUsize::new(0),
+            hot_waiting: AtomicUsize::new(0),
+            hot_done: Notify::new(),
+            per_host: per_host.map(|limit| limit.max(1)),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for a slot to fetch a URL. High priority fetches are given slots before others, up
+    /// to MAX_GIVE_WAY times for each other fetch
+    pub async fn acquire(
+        &self,
+        url: &Url,
+        hot: bool,
+    ) -> Result<Slot, Box<dyn Error + Send + Sync>> {
+        let _waiting = Waiting::new(&self.waiting);
+
+        // Wait for the host first so a busy host doesn't tie up overall slots
+        let host = match self.host_semaphore(url) {
+            Some(sem) => Some(sem.acquire_owned().await?),
+            None => None,
+        };
+
+        // High priority fetches are counted so others give way to them
+        let hot_waiting = hot.then(|| Waiting::new(&self.hot_waiting));
+
+        let mut gave_way = 0;
+
+        let global = loop {
+            let notified = self.hot_done.notified();
+
+            let global = self.global.clone().acquire_owned().await?;
+
+            if hot || gave_way >= MAX_GIVE_WAY || self.hot_waiting.load(Ordering::Relaxed) == 0 {
+                break global;
+            }
+
+            // Give the slot up to a waiting high priority fetch and queue again once it has one
+            drop(global);
+            gave_way += 1;
+            notified.await;
+        };
+
+        if hot {
+            drop(hot_waiting);
+            self.hot_done.notify_waiters();
+        }
+
+        Ok(Slot {
+            _host: host,
+            _global: global,
+        })
+    }
+
+    /// Takes a slot to fetch a URL if one is free without waiting. Returns None if there isn't
+    /// one, or if high priority fetches are waiting for slots
+    pub fn try_acquire(&self, url: &Url) -> Option<Slot> {
+        if self.hot_waiting.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+
+        let host = match self.host_semaphore(url) {
+            Some(sem) => Some(sem.try_acquire_owned().ok()?),
+            None => None,
+        };
+
+        let global = self.global.clone().try_acquire_owned().ok()?;
+
+        Some(Slot {
+            _host: host,
+            _global: global,
+        })
+    }
+
+    /// Returns the semaphore for a URL's host and port if there is a limit for each host
+    fn host_semaphore(&self, url: &Url) -> Option<Arc<Semaphore>> {
+        let limit = self.per_host?;
+
+        let key = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+
+        let sem = self
+            .hosts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+
+        Some(sem)
+    }
+
+    /// Returns the current slot usage
+    pub fn usage(&self) -> SlotUsage {
+        SlotUsage {
+            busy: self.limit - self.global.available_permits(),
+            total: self.limit,
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts a fetch as waiting for a slot until dropped
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use crate::savefile::save_file;
+
+/// Dates given by directory listings for files when they were downloaded, keyed by URL
+#[derive(Default)]
+pub struct ListingDates {
+    dates: BTreeMap<String, String>,
+    changed: bool,
+}
+
+impl ListingDates {
+    /// Load the listing dates from a JSON file. If the file does not exist, create an empty map
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let dates = match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                let dates = serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load listing dates file {file}: {e}"))?;
+
+                Self {
+                    dates,
+                    changed: false,
+                }
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => ListingDates::default(),
+                _ => Err(format!("Failed to open listing dates file {file}: {e}"))?,
+            },
+        };
+
+        Ok(dates)
+    }
+
+    /// Save the listing dates to a JSON file if they have changed
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = PathBuf::from(file);
+
+        let write = self.changed
+            && if let Some(parent) = path.parent() {
+                parent.is_dir()
+            } else {
+                true
+            };
+
+        if write {
+            save_file(file, |writer| {
+                Ok(serde_json::to_writer_pretty(writer, &self.dates)?)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the listing date recorded for a URL
+    pub fn get(&self, url: &str) -> Option<&str> {
+        self.dates.get(url).map(String::as_str)
+    }
+
+    /// Records the listing date of a downloaded URL
+    pub fn add(&mut self, url: &str, date: &str) {
+        if self.get(url) != Some(date) {
+            self.dates.insert(url.to_string(), date.to_string());
+            self.changed = true;
+        }
+    }
+}
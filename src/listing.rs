@@ -0,0 +1,364 @@
+use std::fmt::Display;
+
+use once_cell::sync::Lazy;
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use crate::output::debug;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::url::Url;
+use crate::walk::follow_links;
+
+/// Apache mod_autoindex query parameters used by column sort and display links
+const AUTOINDEX_PARAMS: &[&str] = &["C", "O", "F", "V", "P"];
+
+/// Maximum length of a line in a plain text listing
+const MAX_LINE_LEN: usize = 2048;
+
+/// Table row selector
+static ROW_SEL: Lazy<Selector> = Lazy::new(|| Selector::parse("tr").unwrap());
+
+/// Table cell selector
+static CELL_SEL: Lazy<Selector> = Lazy::new(|| Selector::parse("td").unwrap());
+
+/// Anchor selector
+static ANCHOR_SEL: Lazy<Selector> = Lazy::new(|| Selector::parse("a[href]").unwrap());
+
+/// Preformatted text selector
+static PRE_SEL: Lazy<Selector> = Lazy::new(|| Selector::parse("pre").unwrap());
+
+/// File size given by a directory listing, which may be rounded (eg. 1.2K)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListedSize {
+    /// Size in bytes
+    bytes: u64,
+    /// Amount the actual size may differ by due to rounding
+    tolerance: u64,
+}
+
+impl ListedSize {
+    /// Creates an exact listed size
+    pub fn exact(bytes: u64) -> Self {
+        Self {
+            bytes,
+            tolerance: 0,
+        }
+    }
+
+    /// Parses a size column value, eg. 123, 1.2K or 15M. Returns None for anything else
+    /// (directories are listed with a size of -)
+    pub fn parse(text: &str) -> Option<Self> {
+        let (num, unit) = match text.char_indices().last()? {
+            (pos, 'k' | 'K') => (&text[..pos], 1u64 << 10),
+            (pos, 'm' | 'M') => (&text[..pos], 1 << 20),
+            (pos, 'g' | 'G') => (&text[..pos], 1 << 30),
+            (pos, 't' | 'T') => (&text[..pos], 1 << 40),
+            _ => return text.parse().ok().map(Self::exact),
+        };
+
+        if num.is_empty() || !num.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return None;
+        }
+
+        let value: f64 = num.parse().ok()?;
+
+        // Rounded to the last digit shown
+        let tolerance = match num.split_once('.') {
+            Some((_, decimals)) => unit / 10u64.pow(decimals.len() as u32),
+            None => unit,
+        };
+
+        Some(Self {
+            bytes: (value * unit as f64).round() as u64,
+            tolerance,
+        })
+    }
+
+    /// Returns true if a size matches the listed size
+    pub fn matches(&self, bytes: u64) -> bool {
+        bytes.abs_diff(self.bytes) <= self.tolerance
+    }
+}
+
+impl Display for ListedSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.tolerance == 0 {
+            write!(f, "{} bytes", self.bytes)
+        } else {
+            write!(f, "about {} bytes", self.bytes)
+        }
+    }
+}
+
+/// Entry in a directory listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingEntry {
+    /// Link to the entry
+    pub href: String,
+    /// Size of the entry if listed
+    pub size: Option<ListedSize>,
+    /// Modification date of the entry as listed
+    pub modified: Option<String>,
+}
+
+/// Returns true if an HTML document looks like an Apache mod_autoindex directory listing
+pub fn is_autoindex(html: &str) -> bool {
+    let lower = html.to_ascii_lowercase();
+
+    lower.contains("<title>index of ") || lower.contains("<h1>index of ")
+}
+
+/// Returns true if a link is an Apache mod_autoindex column sort or display option link,
+/// eg. ?C=N;O=D
+pub fn is_sort_link(href: &str) -> bool {
+    let Some(query) = href.strip_prefix('?') else {
+        return false;
+    };
+
+    !query.is_empty()
+        && query
+            .split([';', '&'])
+            .all(|param| match param.split_once('=') {
+                Some((name, _)) => AUTOINDEX_PARAMS.contains(&name),
+                None => false,
+            })
+}
+
+/// Parses the rows of an Apache or nginx style directory listing, either a table or
+/// preformatted text with a line per entry, returning the link and size column of each
+pub fn parse_autoindex(html: &str) -> Vec<ListingEntry> {
+    let document = Html::parse_document(html);
+
+    let mut rows = Vec::new();
+
+    // Table rows - the columns follow the cell containing the link
+    for row in document.select(&ROW_SEL) {
+        let mut href = None;
+        let mut columns = String::new();
+
+        for cell in row.select(&CELL_SEL) {
+            match href {
+                None => href = cell.select(&ANCHOR_SEL).next().and_then(anchor_href),
+                Some(_) => {
+                    columns.extend(cell.text());
+                    columns.push(' ');
+                }
+            }
+        }
+
+        if let Some(href) = href {
+            rows.push((href, columns));
+        }
+    }
+
+    // Preformatted lines - the columns follow the link up to the end of the line
+    for pre in document.select(&PRE_SEL) {
+        let mut line: Option<(String, String)> = None;
+
+        for node in pre.children() {
+            match node.value() {
+                Node::Element(_) => {
+                    if let Some(href) = ElementRef::wrap(node).and_then(anchor_href) {
+                        rows.extend(line.take());
+                        line = Some((href, String::new()));
+                    }
+                }
+                Node::Text(text) => {
+                    if let Some((_, columns)) = &mut line {
+                        match text.split_once('\n') {
+                            Some((rest, _)) => {
+                                columns.push_str(rest);
+                                rows.extend(line.take());
+                            }
+                            None => columns.push_str(text),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        rows.extend(line);
+    }
+
+    rows.into_iter()
+        .map(|(href, columns)| {
+            let columns: Vec<&str> = columns.split_whitespace().collect();
+
+            // The modification date and time are followed by the size column
+            let time_pos = columns.iter().position(|column| is_time(column));
+
+            let modified = time_pos
+                .filter(|pos| *pos > 0)
+                .map(|pos| format!("{} {}", columns[pos - 1], columns[pos]));
+
+            let size = time_pos
+                .and_then(|pos| columns.get(pos + 1))
+                .and_then(|size| ListedSize::parse(size));
+
+            ListingEntry {
+                href,
+                size,
+                modified,
+            }
+        })
+        .collect()
+}
+
+/// Returns the href of an anchor element
+fn anchor_href(anchor: ElementRef) -> Option<String> {
+    let element = anchor.value();
+
+    if element.name() != "a" {
+        return None;
+    }
+
+    element
+        .attr("href")
+        .map(String::from)
+        .filter(|href| !is_sort_link(href))
+}
+
+/// Returns true if a column is a time of day, eg. 12:00 or 12:00:00
+fn is_time(column: &str) -> bool {
+    let parts: Vec<&str> = column.split(':').collect();
+
+    (2..=3).contains(&parts.len())
+        && parts
+            .iter()
+            .all(|part| (1..=2).contains(&part.len()) && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Entry in an nginx JSON directory listing
+#[derive(Deserialize)]
+struct JsonEntry {
+    name: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+    mtime: Option<String>,
+    size: Option<u64>,
+}
+
+/// Parses an nginx JSON directory listing (autoindex_format json). Returns None if the text
+/// doesn't look like a listing
+pub fn parse_json_listing(text: &str) -> Option<Vec<ListingEntry>> {
+    let entries: Vec<JsonEntry> = serde_json::from_str(text).ok()?;
+
+    let entries = entries
+        .into_iter()
+        .map(|entry| {
+            // Escape characters which would be taken as part of the URL rather than the name
+            let mut href = format!(
+                "./{}",
+                entry
+                    .name
+                    .replace('%', "%25")
+                    .replace('#', "%23")
+                    .replace('?', "%3F")
+            );
+
+            if entry.entry_type == "directory" {
+                href.push('/');
+            }
+
+            ListingEntry {
+                href,
+                size: entry.size.map(ListedSize::exact),
+                modified: entry.mtime,
+            }
+        })
+        .collect();
+
+    Some(entries)
+}
+
+/// Records the sizes and dates given by a directory listing so downloads can be checked against
+/// the sizes and files whose dates haven't changed needn't be fetched
+pub async fn add_listing_details(state: &ArcState, url: &Url, entries: &[ListingEntry]) {
+    let args = state.args();
+
+    for entry in entries {
+        let size = entry.size.filter(|_| args.check_listed_sizes);
+        let modified = entry.modified.as_deref().filter(|_| args.listing_dates);
+
+        if size.is_none() && modified.is_none() {
+            continue;
+        }
+
+        let Ok(mut entry_url) = url.join(&entry.href) else {
+            continue;
+        };
+
+        entry_url.set_fragment(None);
+
+        let Ok(entry_url) = state.check_link(entry_url) else {
+            continue;
+        };
+
+        if let Some(size) = size {
+            debug!(state, 2, "Listing gives size of {entry_url} as {size}");
+            state.add_listed_size(&entry_url, size).await;
+        }
+
+        if let Some(modified) = modified {
+            debug!(state, 2, "Listing gives date of {entry_url} as {modified}");
+            state.add_listed_date(&entry_url, modified).await;
+        }
+    }
+}
+
+/// Parses a plain text directory listing with one URL or name per line. Blank lines and lines
+/// starting with # are ignored. Returns None if the text doesn't look like a listing
+pub fn parse_text_listing(text: &str) -> Option<Vec<String>> {
+    let mut entries = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.len() > MAX_LINE_LEN
+            || line
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+        {
+            return None;
+        }
+
+        entries.push(line.to_string());
+    }
+
+    if entries.is_empty() {
+        None
+    } else {
+        Some(entries)
+    }
+}
+
+/// Process all of the entries in a plain text or JSON listing returning a list of join handles for
+/// spawned download tasks
+pub async fn process_listing(
+    state: &ArcState,
+    url: &Url,
+    entries: Vec<String>,
+    stats: &mut Stats,
+) -> Vec<JoinHandle<()>> {
+    let links = entries
+        .iter()
+        .map(|entry| match url.join(entry) {
+            Ok(entry_url) => {
+                debug!(state, 2, "Listing entry {entry} of {url} -> {entry_url}");
+                Ok(entry_url)
+            }
+            Err(e) => Err(SkipReasonErr::new(entry.clone(), SkipReason::NotValid(e))),
+        })
+        .collect();
+
+    // Process all of the links
+    follow_links(state, links, stats).await
+}
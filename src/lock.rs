@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+use crate::output::{error, output};
+
+/// Name of the lock file in the target directory
+pub const LOCK_FILE: &str = ".mirrorurl.lock";
+
+/// Interval between updates of the heartbeat time in the lock file
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time without a heartbeat after which a lock is taken to be abandoned
+const LOCK_EXPIRY: Duration = Duration::from_secs(120);
+
+/// Contents of the lock file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LockRecord {
+    /// Unique identifier of the run holding the lock
+    owner: String,
+    /// Host name of the machine holding the lock
+    host: String,
+    /// Process ID of the run holding the lock
+    pid: u32,
+    /// Time of the last heartbeat in seconds since the epoch
+    heartbeat: u64,
+}
+
+impl LockRecord {
+    /// Creates a lock record for this run
+    fn new() -> Self {
+        let host = host_name();
+        let pid = std::process::id();
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        Self {
+            owner: format!("{host}-{pid}-{nanos}"),
+            host,
+            pid,
+            heartbeat: now(),
+        }
+    }
+}
+
+/// Advisory lock on a target directory stopping runs on other machines sharing it from mirroring
+/// in to it at the same time. The run holding the lock updates a heartbeat time in the lock file,
+/// and a lock with no recent heartbeat may be stolen. The lock is released when dropped
+pub struct TargetLock {
+    /// Path to the lock file
+    path: PathBuf,
+    /// Identifier of this run
+    owner: String,
+    /// Task updating the heartbeat
+    heartbeat: JoinHandle<()>,
+}
+
+impl TargetLock {
+    /// Takes the lock on a target directory, stealing an expired lock if asked to
+    pub async fn acquire(target: &str, steal: bool) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let dir = Path::new(target);
+
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Unable to create target directory {target}: {e}"))?;
+
+        let path = dir.join(LOCK_FILE);
+        let record = LockRecord::new();
+
+        // Create the lock file only if there isn't one already
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(fh) => write_record(fh, &record, &path)?,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let held = read_record(&path)?;
+                if now().saturating_sub(held.heartbeat) < LOCK_EXPIRY.as_secs() {
+                    Err(format!(
+                        "{target} is locked by process {} on {}",
+                        held.pid, held.host
+                    ))?
+                }
+
+                if !steal {
+                    Err(format!(
+                        "{target} is locked by process {} on {} which has stopped updating the lock (use --steal-lock to take it over)",
+                        held.pid, held.host
+                    ))?
+                }
+
+                output!(
+                    "Taking over the lock on {target} from process {} on {}",
+                    held.pid,
+                    held.host
+                );
+
+                replace_record(&path, &record)?;
+            }
+            Err(e) => Err(format!("Unable to create {}: {e}", path.display()))?,
+        }
+
+        // Make sure another run didn't take the lock at the same time
+        let held = read_record(&path)?;
+
+        if held.owner != record.owner {
+            Err(format!(
+                "{target} was locked by process {} on {} at the same time",
+                held.pid, held.host
+            ))?
+        }
+
+        let owner = record.owner.clone();
+        let heartbeat = spawn_heartbeat(path.clone(), record);
+
+        Ok(Self {
+            path,
+            owner,
+            heartbeat,
+        })
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+
+        // Leave the lock alone if another run has taken it over
+        if read_record(&self.path).is_ok_and(|record| record.owner == self.owner) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Unable to remove {}: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+/// Spawns a task which updates the heartbeat time in the lock file at intervals
+fn spawn_heartbeat(path: PathBuf, mut record: LockRecord) -> JoinHandle<()> {
+    spawn(async move {
+        let mut interval = interval(HEARTBEAT_INTERVAL);
+
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            match read_record(&path) {
+                Ok(held) if held.owner == record.owner => {
+                    record.heartbeat = now();
+
+                    if let Err(e) = replace_record(&path, &record) {
+                        error!("{e}");
+                    }
+                }
+                Ok(held) => {
+                    error!(
+                        "Lock {} was taken over by process {} on {}",
+                        path.display(),
+                        held.pid,
+                        held.host
+                    );
+                    break;
+                }
+                Err(e) => error!("{e}"),
+            }
+        }
+    })
+}
+
+/// Reads the lock file
+fn read_record(path: &Path) -> Result<LockRecord, Box<dyn Error + Send + Sync>> {
+    let fh = File::open(path).map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+
+    Ok(serde_json::from_reader(BufReader::new(fh))
+        .map_err(|e| format!("Failed to load lock file {}: {e}", path.display()))?)
+}
+
+/// Writes a lock record to a file
+fn write_record(
+    fh: File,
+    record: &LockRecord,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    serde_json::to_writer_pretty(BufWriter::new(fh), record)
+        .map_err(|e| format!("Error writing {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+/// Replaces the lock file, writing a temporary file first so it is never seen half written
+fn replace_record(path: &Path, record: &LockRecord) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}", record.owner));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let fh = File::create(&tmp_path)
+        .map_err(|e| format!("Error creating {}: {e}", tmp_path.display()))?;
+
+    write_record(fh, record, &tmp_path)?;
+
+    fs::rename(&tmp_path, path)
+        .map_err(|e| format!("Error renaming {}: {e}", tmp_path.display()))?;
+
+    Ok(())
+}
+
+/// Returns the current time in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the name of this machine. /etc/hostname is only present on Linux so the hostname
+/// command is run elsewhere
+fn host_name() -> String {
+    let non_empty = |name: String| {
+        let name = name.trim();
+        (!name.is_empty()).then(|| name.to_string())
+    };
+
+    fs::read_to_string("/etc/hostname")
+        .ok()
+        .and_then(non_empty)
+        .or_else(|| {
+            Command::new("hostname")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .and_then(non_empty)
+        })
+        .or_else(|| std::env::var("HOSTNAME").ok().and_then(non_empty))
+        .or_else(|| std::env::var("COMPUTERNAME").ok().and_then(non_empty))
+        .unwrap_or_else(|| String::from("unknown"))
+}
@@ -1,44 +1,122 @@
+use std::collections::hash_map::RandomState;
 use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
 use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
 
-use args::Args;
+use args::{Args, Command};
+use audit::audit;
+use bundle::{export_state, import_state};
+use check::check;
+use events::EventSink;
+use index::generate_indexes;
+use lastrun::compare_last_run;
+use limiter::SlotUsage;
+use lock::TargetLock;
 use log::LevelFilter;
+use metrics::{serve_metrics, spawn_metrics_writer, write_metrics_file};
+use notify::Notifier;
 use once_cell::sync::Lazy;
 use output::{error, output, Logger};
+use publish::publish_deferred;
+use rules::test_rules;
 use simple_process_stats::ProcessStats;
-use state::{ArcState, State};
-use stats::Stats;
-use tokio::time::Instant;
-use walk::walk;
+use sitemap::emit_sitemap;
+use stage::Stage;
+use state::{ArcState, State, WarmState};
+use stats::{human_size, Stats};
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, Instant};
+#[cfg(feature = "self-update")]
+use update::self_update;
+use walk::{join_tasks, walk_recurse};
 
 mod args;
+mod audit;
+mod blocklist;
+mod bundle;
+mod check;
+mod checkpoint;
+mod checksum;
+mod compress;
+mod disposition;
 mod download;
 mod etags;
+mod events;
+mod exclude;
+mod extract;
+mod fallback;
+mod feed;
+mod file;
+mod filename;
+mod fsname;
+mod ftp;
+mod hash;
+mod hosts;
 mod html;
+mod http;
+mod index;
+mod interstitial;
+mod lastrun;
+mod limiter;
+mod listdates;
+mod listing;
+mod lock;
+mod manifest;
+mod metrics;
 mod mime;
+mod namemap;
+mod notify;
+mod outcome;
 mod output;
+mod policy;
+mod publish;
+mod redirects;
+mod resolve;
 mod response;
+mod robots;
+mod rules;
+mod s3;
+mod savefile;
+mod segment;
+mod shard;
+mod sitemap;
 mod skip;
 mod skipreason;
+mod stage;
 mod state;
 mod stats;
+mod status;
+mod store;
+mod transport;
+#[cfg(feature = "self-update")]
+mod update;
 mod url;
 mod walk;
+mod wayback;
 
+#[cfg(test)]
+mod testkit;
 #[cfg(test)]
 mod tests;
 
-static LOGGER: Lazy<Logger> = Lazy::new(Logger::new);
+static LOGGER: Lazy<Arc<Logger>> = Lazy::new(|| Arc::new(Logger::new()));
 
 /// Program entry point
 fn main() -> ExitCode {
     // Set up logger
-    log::set_logger(&*LOGGER).expect("Failed to set logger");
+    log::set_logger(&**LOGGER).expect("Failed to set logger");
     log::set_max_level(LevelFilter::Info);
 
     match start_async() {
         Ok(_) => ExitCode::SUCCESS,
+        Err(e) if e.is::<InterruptedErr>() => {
+            error!("{e}");
+            ExitCode::from(EXIT_INTERRUPTED)
+        }
         Err(e) => {
             error!("{e}");
             ExitCode::FAILURE
@@ -46,20 +124,52 @@ fn main() -> ExitCode {
     }
 }
 
+/// Interval between crawl state saves
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exit code when the run is interrupted (128 + SIGINT)
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Error returned when the run was interrupted before all URLs were processed
+#[derive(Debug)]
+struct InterruptedErr;
+
+impl std::fmt::Display for InterruptedErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted before all URLs were processed")
+    }
+}
+
+impl Error for InterruptedErr {}
+
+/// Background tasks for a run which are aborted when dropped, so they stop however the run ends
+#[derive(Default)]
+struct BackgroundTasks(Vec<JoinHandle<()>>);
+
+impl BackgroundTasks {
+    /// Adds a task if one was started
+    fn add(&mut self, task: Option<JoinHandle<()>>) {
+        self.0.extend(task);
+    }
+}
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
 /// Parse command line args, start tokio and run
 fn start_async() -> Result<(), Box<dyn Error + Send + Sync>> {
     // Parse command line arguments
     let args = Args::parse()?;
 
-    if args.debug > 0 {
-        // Set max log level to Debug if debugging required
-        log::set_max_level(LevelFilter::Debug);
-
-        if args.debug > 2 {
-            // Log debug messages from all modules
-            LOGGER.set_all_targets(true);
-        }
-    }
+    // Set initial log level, debug targets and verbosity
+    LOGGER.set_debug_targets(&args.debug_target);
+    LOGGER.set_debug_level(args.debug);
+    LOGGER.set_verbosity(args.verbosity());
 
     // Create tokio runtime
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -67,6 +177,19 @@ fn start_async() -> Result<(), Box<dyn Error + Send + Sync>> {
         .worker_threads(args.threads)
         .build()?;
 
+    // Run a command instead of mirroring?
+    match args.command.clone() {
+        Some(Command::Check) => return runtime.block_on(check(args)),
+        Some(Command::TestRules { items }) => {
+            return runtime.block_on(test_rules(args, &items, LOGGER.clone()))
+        }
+        Some(Command::ExportState { file }) => return runtime.block_on(export_state(&args, &file)),
+        Some(Command::ImportState { file }) => return runtime.block_on(import_state(&args, &file)),
+        #[cfg(feature = "self-update")]
+        Some(Command::SelfUpdate { check }) => return runtime.block_on(self_update(check)),
+        None => {}
+    }
+
     // Start tokio runtime and call the main function
     runtime.block_on(async {
         let start = Instant::now();
@@ -80,25 +203,488 @@ fn start_async() -> Result<(), Box<dyn Error + Send + Sync>> {
 
 /// Async entry point
 async fn async_main(args: Args) -> Result<Stats, Box<dyn Error + Send + Sync>> {
+    match args.watch {
+        Some(every) => watch(args, every).await,
+        None => run(args, &mut None).await,
+    }
+}
+
+/// Mirrors at intervals until interrupted or the maximum number of runs is reached, returning
+/// the result of the last run. The HTTP client and etags are kept between runs
+async fn watch(args: Args, every: Duration) -> Result<Stats, Box<dyn Error + Send + Sync>> {
+    let mut warm = None;
+    let mut runs = 0;
+
+    loop {
+        let result = run(args.clone(), &mut warm).await;
+        runs += 1;
+
+        if args.max_runs.is_some_and(|max| runs >= max) {
+            return result;
+        }
+
+        match &result {
+            Err(e) if e.is::<InterruptedErr>() => return result,
+            Err(e) => error!("{e}"),
+            Ok(_) => (),
+        }
+
+        let delay = every + jitter(args.watch_jitter);
+        output!("Next run in {} seconds", delay.as_secs());
+
+        tokio::select! {
+            _ = sleep(delay) => (),
+            _ = tokio::signal::ctrl_c() => {
+                output!("Interrupted - not running again");
+                return result;
+            }
+        }
+    }
+}
+
+/// Returns a random delay of up to the maximum jitter
+fn jitter(max: Option<Duration>) -> Duration {
+    match max {
+        Some(max) if !max.is_zero() => {
+            let random = RandomState::new().build_hasher().finish();
+            Duration::from_millis(random % (max.as_millis() as u64 + 1))
+        }
+        _ => Duration::ZERO,
+    }
+}
+
+/// Mirrors the URLs once, failing the run by the exit policy and telling the completion command
+/// and webhook how it went
+async fn run(
+    args: Args,
+    warm: &mut Option<WarmState>,
+) -> Result<Stats, Box<dyn Error + Send + Sync>> {
+    let strict = args.strict;
+    let min_success_rate = args.min_success_rate;
+    let notifier = Notifier::new(&args);
+
+    let result = async {
+        let stats = mirror_locked(args, LOGGER.clone(), warm).await?;
+
+        // Fail the run if too many files failed
+        check_exit_policy(&stats, strict, min_success_rate)?;
+
+        Ok(stats)
+    }
+    .await;
+
+    // Tell the completion command and webhook how the run went
+    if let Some(notifier) = notifier {
+        notifier.notify(&result).await;
+    }
+
+    result
+}
+
+/// Returns an error if the run failed --strict or --min-success-rate
+fn check_exit_policy(
+    stats: &Stats,
+    strict: bool,
+    min_success_rate: Option<f64>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let failed = stats.failed();
+
+    if strict && failed > 0 {
+        match failed {
+            1 => Err("1 file failed to download")?,
+            n => Err(format!("{n} files failed to download"))?,
+        }
+    }
+
+    if let Some(min) = min_success_rate {
+        let rate = stats.success_rate();
+
+        if rate < min {
+            Err(format!(
+                "Success rate {rate:.1}% is below the minimum of {min}%"
+            ))?
+        }
+    }
+
+    Ok(())
+}
+
+/// Async entry point reporting events to an event sink
+#[cfg(test)]
+async fn async_main_with_events(
+    args: Args,
+    events: Arc<dyn EventSink>,
+) -> Result<Stats, Box<dyn Error + Send + Sync>> {
+    mirror_locked(args, events, &mut None).await
+}
+
+/// Mirrors the URLs holding the target lock if locking, staging the mirror if staging
+async fn mirror_locked(
+    args: Args,
+    events: Arc<dyn EventSink>,
+    warm: &mut Option<WarmState>,
+) -> Result<Stats, Box<dyn Error + Send + Sync>> {
+    // Stop runs on other machines mirroring in to the target at the same time
+    let _lock = if args.lock {
+        Some(TargetLock::acquire(&args.target, args.steal_lock).await?)
+    } else {
+        None
+    };
+
+    if !args.stage {
+        return mirror(args, events, warm).await;
+    }
+
+    // Mirror in to the staging directory
+    let stage = Stage::prepare(&args.target, &args.index_name).await?;
+
+    let args = Args {
+        target: stage.dir().to_string_lossy().into_owned(),
+        ..args
+    };
+
+    let result = mirror(args, events, warm).await;
+
+    // Swap it in only if everything was fetched
+    match &result {
+        Ok(stats) if stats.failed() == 0 => stage.swap().await?,
+        _ => output!(
+            "Keeping staged mirror {} for the next run as the run did not complete",
+            stage.dir().display()
+        ),
+    }
+
+    result
+}
+
+/// Mirrors the URLs in to the target directory, reusing the HTTP client and etags from the last
+/// run and leaving them for the next in watch mode
+async fn mirror(
+    args: Args,
+    events: Arc<dyn EventSink>,
+    warm: &mut Option<WarmState>,
+) -> Result<Stats, Box<dyn Error + Send + Sync>> {
     // Create shared state
-    let state = Arc::new(State::new(args)?);
+    let mut state = State::new(args, events, warm.take()).await?;
+
+    // Load robots.txt rules
+    state.load_robots().await?;
+
+    let state = Arc::new(state);
 
-    // Acquire a download slot
-    let sem = state.acquire_slot().await?;
+    let mut background = BackgroundTasks::default();
 
-    // Process main url
-    walk(&state, state.url(), sem).await;
+    // Expose metrics while running
+    if let Some(addr) = state.args().metrics_listen {
+        background.add(Some(serve_metrics(&state, addr).await?));
+    }
+
+    background.add(
+        state
+            .args()
+            .metrics_file
+            .as_ref()
+            .map(|file| spawn_metrics_writer(&state, file)),
+    );
+
+    // Watch for debug level change and status requests
+    background.add(spawn_signal_handler(&state));
+
+    // Stop starting new fetches on Ctrl-C
+    background.add(Some(spawn_interrupt_handler(&state)));
+
+    // Save crawl progress periodically
+    background.add(spawn_checkpoint_saver(&state));
+
+    // Save etags periodically
+    background.add(spawn_etags_saver(&state));
+
+    // Print the status periodically
+    background.add(spawn_status_reporter(&state));
+
+    // Process the start URLs and any left over by the run being resumed
+    let mut urls = state.start_urls().to_vec();
+    urls.extend(state.resume_urls().await);
+
+    let mut join_handles = Vec::new();
+
+    for url in urls {
+        join_handles.push(walk_recurse(&state, url).await?);
+    }
+
+    // Wait for them to finish
+    join_tasks(join_handles).await;
+
+    // Stop watching for signals and the periodic saves
+    drop(background);
+
+    // Log out of the idle FTP connections
+    state.ftp_pool().close().await;
+
+    // Move metadata files held back until the data files arrived in to place
+    publish_deferred(&state).await?;
 
     // Get and print stats
-    let stats = state.get_stats().await;
-    stats.print();
+    let stats = state.get_stats();
+    stats.print(state.args().bytes);
+
+    // Show the changes since the last run
+    if state.args().compare_last_run {
+        if let Err(e) = compare_last_run(&state, &stats) {
+            error!("{e}");
+        }
+    }
+
+    // Keep the client and etags for the next run
+    if state.args().watch.is_some() {
+        *warm = Some(state.warm());
+    }
 
     // Save the new etags list
     state.save_etags().await?;
 
+    // Save learnt host capabilities
+    state.save_host_caps().await?;
+
+    // Save the shortened local path map
+    state.save_name_map().await?;
+
+    // Save the listing dates of downloaded files
+    state.save_listing_dates().await?;
+
+    // Save the feed cursors
+    state.save_feed_cursors().await?;
+
+    // Save the manifest
+    state.save_manifest().await?;
+
+    // Save or remove the crawl state
+    state.save_checkpoint().await?;
+
+    // Write the final metrics
+    if let Some(file) = &state.args().metrics_file {
+        if let Err(e) = write_metrics_file(&state, file).await {
+            error!("{e}");
+        }
+    }
+
+    // Check the saved documents against the mirror
+    if let Err(e) = audit(&state).await {
+        error!("{e}");
+    }
+
+    // Write directory index pages
+    if state.args().generate_index {
+        let target = Path::new(&state.args().target);
+
+        if target.is_dir() {
+            let pages = generate_indexes(target, &state.args().index_name).await?;
+            output!("Generated {pages} index pages");
+        }
+    }
+
+    // Write the mirror sitemap
+    if let (Some(file), Some(publish_base)) =
+        (&state.args().emit_sitemap, &state.args().publish_base)
+    {
+        let target = Path::new(&state.args().target);
+
+        if target.is_dir() {
+            let urls = emit_sitemap(
+                target,
+                Path::new(file),
+                publish_base,
+                &state.args().index_name,
+            )
+            .await?;
+            output!("Wrote {urls} URLs to sitemap {file}");
+        }
+    }
+
+    // Report the failure which aborted the run or the interruption now progress has been saved
+    if let Some(reason) = state.aborted() {
+        Err(reason)?
+    }
+
+    if state.is_interrupted() {
+        Err(InterruptedErr)?
+    }
+
     Ok(stats)
 }
 
+/// Spawns a task which saves the crawl state at intervals when resuming is enabled
+fn spawn_checkpoint_saver(state: &ArcState) -> Option<JoinHandle<()>> {
+    if !state.args().resume {
+        return None;
+    }
+
+    let state = state.clone();
+
+    Some(spawn(async move {
+        let mut interval = interval(CHECKPOINT_INTERVAL);
+
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = state.save_checkpoint().await {
+                error!("{e}");
+            }
+        }
+    }))
+}
+
+/// Spawns a task which saves the etags collected so far at intervals, so they aren't lost if
+/// the run is cut short
+fn spawn_etags_saver(state: &ArcState) -> Option<JoinHandle<()>> {
+    let secs = state.args().etags_save_interval;
+
+    if state.args().no_etags || secs == 0 {
+        return None;
+    }
+
+    let state = state.clone();
+
+    Some(spawn(async move {
+        let mut interval = interval(Duration::from_secs(secs));
+
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = state.flush_etags().await {
+                error!("{e}");
+            }
+        }
+    }))
+}
+
+/// Spawns a task which prints a status line at intervals
+fn spawn_status_reporter(state: &ArcState) -> Option<JoinHandle<()>> {
+    let secs = state.args().status_interval;
+
+    if secs == 0 {
+        return None;
+    }
+
+    let state = state.clone();
+
+    Some(spawn(async move {
+        let mut interval = interval(Duration::from_secs(secs));
+
+        // The first tick completes immediately
+        interval.tick().await;
+
+        let mut last_bytes = 0;
+        let mut last_time = Instant::now();
+
+        loop {
+            interval.tick().await;
+
+            let stats = state.get_stats();
+            let now = Instant::now();
+
+            let bytes = stats.bytes();
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+            let rate = (bytes.saturating_sub(last_bytes) as f64 / elapsed) as u64;
+
+            output!(
+                "{}",
+                status_line(&state.slot_usage(), &stats, rate, state.args().bytes)
+            );
+
+            last_bytes = bytes;
+            last_time = now;
+        }
+    }))
+}
+
+/// Formats a status line from the slot usage, stats and transfer rate in bytes per second
+fn status_line(usage: &SlotUsage, stats: &Stats, rate: u64, raw_bytes: bool) -> String {
+    let rate = match human_size(rate) {
+        Some(size) if !raw_bytes => format!("{size}/s"),
+        _ => format!("{rate} bytes/s"),
+    };
+
+    format!(
+        "Status: {} fetching, {} waiting, {} processed, {} errored, {rate}",
+        usage.busy,
+        usage.waiting,
+        stats.processed(),
+        stats.failed()
+    )
+}
+
+/// Spawns a task which stops new fetches being started when Ctrl-C is pressed. Fetches in
+/// progress are allowed to finish so progress can be saved
+fn spawn_interrupt_handler(state: &ArcState) -> JoinHandle<()> {
+    let state = state.clone();
+
+    spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                output!("Interrupted - waiting for fetches in progress to finish");
+                state.interrupt();
+            }
+            Err(e) => error!("Unable to install Ctrl-C handler: {e}"),
+        }
+    })
+}
+
+/// Spawns a task which cycles the debug level each time SIGHUP is received and prints the
+/// download slot usage and stats each time SIGUSR1 is received
+#[cfg(unix)]
+fn spawn_signal_handler(state: &ArcState) -> Option<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Unable to install SIGHUP handler: {e}");
+            return None;
+        }
+    };
+
+    let mut status = match signal(SignalKind::user_defined1()) {
+        Ok(status) => status,
+        Err(e) => {
+            error!("Unable to install SIGUSR1 handler: {e}");
+            return None;
+        }
+    };
+
+    let state = state.clone();
+
+    Some(spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = hangup.recv() => {
+                    let level = state.cycle_debug_level();
+                    LOGGER.set_debug_level(level);
+                    output!("Debug level set to {level}");
+                }
+                Some(()) = status.recv() => {
+                    // Print progress so far
+                    output!("{}", state.slot_usage());
+                    state.get_stats().print(state.args().bytes);
+                }
+                else => break,
+            }
+        }
+    }))
+}
+
+/// Signal handling is not supported on this platform
+#[cfg(not(unix))]
+fn spawn_signal_handler(_state: &ArcState) -> Option<JoinHandle<()>> {
+    None
+}
+
 async fn print_process_stats(start: Instant) {
     let end = Instant::now();
 
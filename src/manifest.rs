@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::redirects::RedirectHop;
+use crate::savefile::save_file;
+
+/// Record of a file saved to the mirror
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// URL the file was downloaded from
+    pub url: String,
+    /// Path of the file relative to the target directory
+    pub path: String,
+    /// Path of the file before the compression extension was added if it is stored compressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_path: Option<String>,
+    /// File size in bytes (uncompressed)
+    pub size: u64,
+    /// SHA-256 of the file contents
+    pub sha256: String,
+    /// Time the file was saved in seconds since the epoch
+    pub timestamp: u64,
+    /// Members unpacked from the file if it is an archive
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+    /// Redirects followed to reach the URL
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<RedirectHop>,
+    /// A download to replace the file failed so the previous copy was kept
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
+}
+
+impl ManifestEntry {
+    /// Creates a manifest entry for a file saved now
+    pub fn new(url: String, path: String, size: u64, sha256: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            url,
+            path,
+            original_path: None,
+            size,
+            sha256,
+            timestamp,
+            members: Vec::new(),
+            redirects: Vec::new(),
+            stale: false,
+        }
+    }
+}
+
+/// Manifest of the files in the mirror keyed by URL
+#[derive(Default)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+    changed: bool,
+}
+
+impl Manifest {
+    /// Load the manifest from a JSON file. If the file does not exist, create an empty manifest
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let manifest = match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                let entries: Vec<ManifestEntry> = serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load manifest file {file}: {e}"))?;
+
+                Self {
+                    entries: entries
+                        .into_iter()
+                        .map(|entry| (entry.url.clone(), entry))
+                        .collect(),
+                    changed: false,
+                }
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Manifest::default(),
+                _ => Err(format!("Failed to open manifest file {file}: {e}"))?,
+            },
+        };
+
+        Ok(manifest)
+    }
+
+    /// Save the manifest to a JSON file if it has changed
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = PathBuf::from(file);
+
+        let write = self.changed
+            && if let Some(parent) = path.parent() {
+                parent.is_dir()
+            } else {
+                true
+            };
+
+        if write {
+            let entries: Vec<&ManifestEntry> = self.entries.values().collect();
+
+            save_file(file, |writer| {
+                Ok(serde_json::to_writer_pretty(writer, &entries)?)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds or replaces the entry for a URL
+    pub fn add(&mut self, entry: ManifestEntry) {
+        self.entries.insert(entry.url.clone(), entry);
+        self.changed = true;
+    }
+
+    /// Returns an iterator over the manifest entries
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.values()
+    }
+
+    /// Finds the paths of files with the given size and SHA-256
+    pub fn find_by_hash(&self, size: u64, sha256: &str) -> Vec<&str> {
+        self.entries
+            .values()
+            .filter(|entry| entry.size == size && entry.sha256 == sha256)
+            .map(|entry| entry.path.as_str())
+            .collect()
+    }
+
+    /// Records the members unpacked from the file for a URL
+    pub fn set_members(&mut self, url: &str, members: Vec<String>) {
+        if let Some(entry) = self.entries.get_mut(url) {
+            entry.members = members;
+            self.changed = true;
+        }
+    }
+
+    /// Records the redirects followed to reach the file for a URL
+    pub fn set_redirects(&mut self, url: &str, redirects: Vec<RedirectHop>) {
+        if let Some(entry) = self.entries.get_mut(url) {
+            entry.redirects = redirects;
+            self.changed = true;
+        }
+    }
+
+    /// Marks the file for a URL as a kept previous copy after a failed download
+    pub fn set_stale(&mut self, url: &str) {
+        if let Some(entry) = self.entries.get_mut(url) {
+            entry.stale = true;
+            self.changed = true;
+        }
+    }
+}
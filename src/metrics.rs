@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::fs::{rename, write};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+use crate::limiter::SlotUsage;
+use crate::output::{debug, error};
+use crate::state::ArcState;
+use crate::stats::Stats;
+
+/// Interval between metrics file updates
+const METRICS_FILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Formats the stats and slot usage in the Prometheus text exposition format
+pub fn render(stats: &Stats, usage: &SlotUsage) -> String {
+    let mut text = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(text, "# HELP mirrorurl_{name} {help}");
+        let _ = writeln!(text, "# TYPE mirrorurl_{name} {kind}");
+        let _ = writeln!(text, "mirrorurl_{name} {value}");
+    };
+
+    for (name, help, value) in stats.counters() {
+        metric(name, "counter", help, value);
+    }
+
+    metric(
+        "fetches_in_flight",
+        "gauge",
+        "Fetches in progress",
+        usage.busy as u64,
+    );
+    metric(
+        "fetches_waiting",
+        "gauge",
+        "Fetches waiting for a slot",
+        usage.waiting as u64,
+    );
+
+    text
+}
+
+/// Starts serving the metrics over HTTP on an address until the task is aborted
+pub async fn serve_metrics(
+    state: &ArcState,
+    addr: SocketAddr,
+) -> Result<JoinHandle<()>, Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Unable to listen for metrics requests on {addr}: {e}"))?;
+
+    let state = state.clone();
+
+    Ok(spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    debug!(state, 2, "Metrics request from {peer}");
+
+                    let state = state.clone();
+
+                    spawn(async move {
+                        if let Err(e) = respond(&state, stream).await {
+                            debug!(state, 1, "Metrics request failed: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Unable to accept metrics connection: {e}"),
+            }
+        }
+    }))
+}
+
+/// Reads an HTTP request and responds with the metrics
+async fn respond(state: &ArcState, stream: TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = BufReader::new(stream);
+
+    // Read the request line and skip the headers
+    let mut request = String::new();
+    stream.read_line(&mut request).await?;
+
+    loop {
+        let mut line = String::new();
+
+        if stream.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let response = if request.starts_with("GET ") && (path == "/" || path == "/metrics") {
+        let body = render(&state.get_stats(), &state.slot_usage());
+
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    };
+
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Spawns a task which writes the metrics to a file at intervals for a textfile collector
+pub fn spawn_metrics_writer(state: &ArcState, file: &str) -> JoinHandle<()> {
+    let state = state.clone();
+    let file = file.to_string();
+
+    spawn(async move {
+        let mut interval = interval(METRICS_FILE_INTERVAL);
+
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = write_metrics_file(&state, &file).await {
+                error!("{e}");
+            }
+        }
+    })
+}
+
+/// Writes the metrics to a file, replacing it in one step so collectors never see it half
+/// written
+pub async fn write_metrics_file(
+    state: &ArcState,
+    file: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let text = render(&state.get_stats(), &state.slot_usage());
+
+    let tmp_file = format!("{file}.tmp");
+
+    write(&tmp_file, text)
+        .await
+        .map_err(|e| format!("Error writing {tmp_file}: {e}"))?;
+
+    rename(&tmp_file, Path::new(file))
+        .await
+        .map_err(|e| format!("Error renaming {tmp_file} to {file}: {e}"))?;
+
+    Ok(())
+}
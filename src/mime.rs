@@ -4,6 +4,9 @@ pub use mime::Mime;
 pub trait MimeExt {
     /// Returns true if MIME types are equal
     fn equal(&self, other: &Mime) -> bool;
+
+    /// Returns true if the MIME type matches a type/subtype pattern (subtype may be *)
+    fn matches(&self, pattern: &str) -> bool;
 }
 
 impl MimeExt for Mime {
@@ -11,4 +14,15 @@ impl MimeExt for Mime {
     fn equal(&self, other: &Mime) -> bool {
         self.type_() == other.type_() && self.subtype() == other.subtype()
     }
+
+    /// Tests if this MIME type matches a type/subtype pattern, ignoring case
+    fn matches(&self, pattern: &str) -> bool {
+        match pattern.trim().split_once('/') {
+            Some((type_, subtype)) => {
+                self.type_().as_str().eq_ignore_ascii_case(type_)
+                    && (subtype == "*" || self.subtype().as_str().eq_ignore_ascii_case(subtype))
+            }
+            None => false,
+        }
+    }
 }
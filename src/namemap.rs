@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use crate::savefile::save_file;
+
+/// Map of shortened local paths to the URLs they were downloaded from
+#[derive(Default)]
+pub struct NameMap {
+    names: BTreeMap<String, String>,
+    changed: bool,
+}
+
+impl NameMap {
+    /// Load the name map from a JSON file. If the file does not exist, create an empty map
+    pub fn new_from_file(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let map = match File::open(file) {
+            Ok(fh) => {
+                let reader = BufReader::new(fh);
+
+                let names = serde_json::from_reader(reader)
+                    .map_err(|e| format!("Failed to load name map file {file}: {e}"))?;
+
+                Self {
+                    names,
+                    changed: false,
+                }
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => NameMap::default(),
+                _ => Err(format!("Failed to open name map file {file}: {e}"))?,
+            },
+        };
+
+        Ok(map)
+    }
+
+    /// Save the name map to a JSON file if it has changed
+    pub fn save_to_file(&self, file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = PathBuf::from(file);
+
+        let write = self.changed
+            && if let Some(parent) = path.parent() {
+                parent.is_dir()
+            } else {
+                true
+            };
+
+        if write {
+            save_file(file, |writer| {
+                Ok(serde_json::to_writer_pretty(writer, &self.names)?)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the URL a local path was mapped from
+    pub fn url(&self, path: &str) -> Option<&str> {
+        self.names.get(path).map(String::as_str)
+    }
+
+    /// Returns the local path a URL was mapped to
+    pub fn path(&self, url: &str) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(_, mapped)| *mapped == url)
+            .map(|(path, _)| path.as_str())
+    }
+
+    /// Records the URL a shortened or numbered local path was mapped from
+    pub fn add(&mut self, path: &str, url: &str) {
+        if self.names.get(path).map(String::as_str) != Some(url) {
+            self.names.insert(path.to_string(), url.to_string());
+            self.changed = true;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::process::Stdio;
+
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::args::Args;
+use crate::output::error;
+use crate::stats::Stats;
+use crate::url::Url;
+
+/// Summary of a finished run delivered to the completion command and webhook
+#[derive(Serialize, Debug)]
+pub struct Completion<'a> {
+    /// True if the run succeeded
+    pub success: bool,
+    /// Error the run failed with
+    pub error: Option<String>,
+    /// Target directory
+    pub target: &'a str,
+    /// URLs mirrored
+    pub urls: &'a [String],
+    /// Totals if the run finished
+    pub stats: Option<BTreeMap<&'static str, u64>>,
+}
+
+impl<'a> Completion<'a> {
+    /// Builds the summary of a run
+    pub fn new(
+        target: &'a str,
+        urls: &'a [String],
+        result: &Result<Stats, Box<dyn Error + Send + Sync>>,
+    ) -> Self {
+        match result {
+            Ok(stats) => Self {
+                success: true,
+                error: None,
+                target,
+                urls,
+                stats: Some(
+                    stats
+                        .counters()
+                        .into_iter()
+                        .map(|(name, _, value)| (name.trim_end_matches("_total"), value))
+                        .collect(),
+                ),
+            },
+            Err(e) => Self {
+                success: false,
+                error: Some(e.to_string()),
+                target,
+                urls,
+                stats: None,
+            },
+        }
+    }
+}
+
+/// Runs the completion command and calls the webhook when the run finishes
+pub struct Notifier {
+    /// Shell command to run
+    cmd: Option<String>,
+    /// URL to post to
+    webhook: Option<Url>,
+    /// Target directory
+    target: String,
+    /// URLs mirrored
+    urls: Vec<String>,
+}
+
+impl Notifier {
+    /// Creates a notifier from the command line arguments, or returns None if no notifications
+    /// are wanted
+    pub fn new(args: &Args) -> Option<Self> {
+        if args.on_complete_cmd.is_none() && args.webhook_url.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            cmd: args.on_complete_cmd.clone(),
+            webhook: args.webhook_url.clone(),
+            target: args.target.clone(),
+            urls: args.urls.clone(),
+        })
+    }
+
+    /// Delivers the summary of the run. Failures are reported but don't change the result of
+    /// the run
+    pub async fn notify(&self, result: &Result<Stats, Box<dyn Error + Send + Sync>>) {
+        let completion = Completion::new(&self.target, &self.urls, result);
+
+        let json = match serde_json::to_string(&completion) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Unable to build the completion summary: {e}");
+                return;
+            }
+        };
+
+        if let Some(cmd) = &self.cmd {
+            if let Err(e) = run_cmd(cmd, &json).await {
+                error!("Completion command '{cmd}' failed: {e}");
+            }
+        }
+
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = call_webhook(webhook, &json).await {
+                error!("Completion webhook {webhook} failed: {e}");
+            }
+        }
+    }
+}
+
+/// Runs a shell command passing the summary on its standard input
+async fn run_cmd(cmd: &str, json: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    };
+
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may not read its input
+        let _ = stdin.write_all(json.as_bytes()).await;
+    }
+
+    let status = child.wait().await?;
+
+    if !status.success() {
+        Err(format!("exited with {status}"))?
+    }
+
+    Ok(())
+}
+
+/// Posts the summary to a webhook
+async fn call_webhook(url: &Url, json: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let response = reqwest::Client::new()
+        .post(url.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .body(json.to_string())
+        .send()
+        .await?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        Err(format!("Status {status}"))?
+    }
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::interstitial::InterstitialErr;
+use crate::skipreason::SkipReasonErr;
+
+/// Result of processing a single URL
+#[derive(Debug)]
+pub enum Outcome {
+    /// The URL was not processed (already processed or the run was interrupted)
+    Ignored,
+    /// A file was downloaded
+    Downloaded { bytes: usize },
+    /// The resource has not changed since the last download
+    NotModified,
+    /// A document was parsed and its links followed
+    Parsed { bytes: usize, links: usize },
+    /// The URL was skipped
+    Skipped(SkipReasonErr),
+    /// Processing the URL failed
+    Errored(ErrorKind),
+}
+
+/// Kinds of failure processing a URL
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// A file download returned a login or interstitial page
+    Interstitial(Box<InterstitialErr>),
+    /// Any other error
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Interstitial(e) => write!(f, "{e}"),
+            ErrorKind::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<SkipReasonErr> for Outcome {
+    fn from(skip: SkipReasonErr) -> Self {
+        Outcome::Skipped(skip)
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for Outcome {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        Outcome::Errored(ErrorKind::Other(e))
+    }
+}
+
+/// Unwraps the result of a check, returning Outcome::Skipped from the calling walk function if
+/// the URL is to be skipped
+macro_rules! check {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(skip) => return Ok($crate::outcome::Outcome::Skipped(skip)),
+        }
+    };
+}
+
+pub(crate) use check;
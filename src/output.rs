@@ -1,8 +1,9 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 #[cfg(test)]
 use std::sync::{Mutex, MutexGuard};
+use std::sync::{RwLock, RwLockReadGuard};
 
-use log::{Level, Metadata, Record};
+use log::{Level, LevelFilter, Metadata, Record};
 #[cfg(test)]
 use thread_local::ThreadLocal;
 
@@ -12,6 +13,18 @@ macro_rules! output {
     }};
 }
 
+macro_rules! progress {
+    ($($arg:tt)*) => {{
+        log::info!(target: $crate::output::PROGRESS_TARGET, $($arg)*)
+    }};
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {{
+        log::info!(target: $crate::output::VERBOSE_TARGET, $($arg)*)
+    }};
+}
+
 macro_rules! error {
     ($($arg:tt)*) => {{
         log::error!($($arg)*)
@@ -28,11 +41,44 @@ macro_rules! debug {
     }
 }
 
-pub(crate) use {debug, error, output};
+pub(crate) use {debug, error, output, progress, verbose};
+
+/// Log target for per-URL progress messages
+pub const PROGRESS_TARGET: &str = "mirrorurl::progress";
+
+/// Log target for messages only shown when verbose
+pub const VERBOSE_TARGET: &str = "mirrorurl::verbose";
+
+/// How much is output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Errors only
+    Quiet,
+    /// Errors, warnings and summaries without per-URL progress
+    NoProgress,
+    /// Per-URL progress as well
+    #[default]
+    Normal,
+    /// Extra detail such as redirects and links followed
+    Verbose,
+}
+
+/// Filter for debug/trace message targets
+#[derive(Debug, Clone, PartialEq)]
+enum DebugTargets {
+    /// Only log messages from this crate
+    Crate,
+    /// Log messages from all targets
+    All,
+    /// Only log messages from the listed modules or crates
+    List(Vec<String>),
+}
 
 /// Global logger structure
 pub struct Logger {
-    all_targets: AtomicBool,
+    targets: RwLock<DebugTargets>,
+    verbosity: RwLock<Verbosity>,
+    debugging: AtomicBool,
     #[cfg(test)]
     messages: ThreadLocal<Mutex<Vec<String>>>,
 }
@@ -41,20 +87,87 @@ impl Logger {
     /// Creates a new logger
     pub fn new() -> Self {
         Self {
-            all_targets: AtomicBool::new(false),
+            targets: RwLock::new(DebugTargets::Crate),
+            verbosity: RwLock::new(Verbosity::default()),
+            debugging: AtomicBool::new(false),
             #[cfg(test)]
             messages: ThreadLocal::new(),
         }
     }
 
-    /// Sets the flag to log debug/trace from all targets
-    pub fn set_all_targets(&self, all_targets: bool) {
-        self.all_targets.store(all_targets, Ordering::Relaxed);
+    /// Sets the maximum log level and target filtering from a debug level
+    pub fn set_debug_level(&self, debug: u8) {
+        // Verbose messages are shown when debugging
+        self.debugging.store(debug > 0, Ordering::Relaxed);
+
+        if debug > 0 {
+            // Set max log level to Debug if debugging required
+            log::set_max_level(LevelFilter::Debug);
+        } else {
+            log::set_max_level(LevelFilter::Info);
+        }
+
+        let mut targets = self.targets.write().expect("Failed to lock targets");
+
+        // Log debug messages from all modules at the highest level unless an explicit list is set
+        if !matches!(*targets, DebugTargets::List(_)) {
+            *targets = if debug > 2 {
+                DebugTargets::All
+            } else {
+                DebugTargets::Crate
+            };
+        }
+    }
+
+    /// Restricts debug/trace messages to a list of modules (eg. walk) or crates (eg. reqwest)
+    pub fn set_debug_targets(&self, list: &[String]) {
+        if !list.is_empty() {
+            *self.targets.write().expect("Failed to lock targets") =
+                DebugTargets::List(list.to_vec());
+        }
+    }
+
+    /// Sets how much non-debug output is shown
+    pub fn set_verbosity(&self, verbosity: Verbosity) {
+        *self.verbosity.write().expect("Failed to lock verbosity") = verbosity;
+    }
+
+    /// Returns true if an error, warning or info message should be output at the verbosity set
+    fn verbosity_enabled(&self, metadata: &Metadata) -> bool {
+        let verbosity = *self.verbosity.read().expect("Failed to lock verbosity");
+
+        if metadata.level() == Level::Error {
+            return true;
+        }
+
+        match metadata.target() {
+            _ if verbosity == Verbosity::Quiet => false,
+            PROGRESS_TARGET => verbosity >= Verbosity::Normal,
+            VERBOSE_TARGET => {
+                verbosity >= Verbosity::Verbose || self.debugging.load(Ordering::Relaxed)
+            }
+            _ => true,
+        }
+    }
+
+    /// Locks the target filter for reading
+    fn read_targets(&self) -> RwLockReadGuard<'_, DebugTargets> {
+        self.targets.read().expect("Failed to lock targets")
+    }
+
+    /// Returns true if a target matches a module or crate name
+    fn target_matches(target: &str, name: &str) -> bool {
+        let is_match = |target: &str| match target.strip_prefix(name) {
+            Some(rest) => rest.is_empty() || rest.starts_with("::"),
+            None => false,
+        };
+
+        is_match(target) || matches!(target.strip_prefix("mirrorurl::"), Some(m) if is_match(m))
     }
 
     #[cfg(test)]
     /// Locks the messages vector and returns the mutex guard
-    fn lock_messages(&self) -> MutexGuard<Vec<String>> {
+    fn lock_messages(&self) -> MutexGuard<'_, Vec<String>> {
         self.messages
             .get_or(|| Mutex::new(Vec::new()))
             .lock()
@@ -77,7 +190,7 @@ impl log::Log for Logger {
             let level = metadata.level();
 
             match level {
-                Level::Debug | Level::Trace if self.all_targets.load(Ordering::Relaxed) => {
+                Level::Debug | Level::Trace if *self.read_targets() != DebugTargets::Crate => {
                     eprintln!("{} {}: {}", level, metadata.target(), record.args())
                 }
                 Level::Error | Level::Warn | Level::Debug | Level::Trace => {
@@ -103,15 +216,19 @@ impl log::Log for Logger {
     /// Returns true if the message should be output
     fn enabled(&self, metadata: &Metadata) -> bool {
         if metadata.level() > Level::Info {
-            // Debug / Trace - all targets enabled?
-            if self.all_targets.load(Ordering::Relaxed) {
-                true
-            } else {
-                metadata.target().starts_with("mirrorurl")
+            // Debug / Trace - check target filter
+            let target = metadata.target();
+
+            match &*self.read_targets() {
+                DebugTargets::Crate => target.starts_with("mirrorurl"),
+                DebugTargets::All => true,
+                DebugTargets::List(list) => {
+                    list.iter().any(|name| Self::target_matches(target, name))
+                }
             }
         } else {
             // Error / Warning / Info
-            true
+            self.verbosity_enabled(metadata)
         }
     }
 }
@@ -0,0 +1,226 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::args::Args;
+use crate::exclude::ExcludeList;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::url::{HostScope, Url, UrlExt};
+
+/// Action to take for a link with a particular property
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkAction {
+    /// Skip the link
+    #[default]
+    Skip,
+    /// Remove the offending part of the link and follow it
+    Strip,
+    /// Follow the link as is (fragments are never sent so are stripped)
+    Follow,
+}
+
+/// Crawl policy as loaded from a policy file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    fragment: Option<LinkAction>,
+    query: Option<LinkAction>,
+    span_hosts: bool,
+    allow_hosts: Vec<String>,
+}
+
+/// Decides which discovered links are followed
+#[derive(Debug, Clone)]
+pub struct CrawlPolicy {
+    /// Action for URLs with a fragment
+    fragment: LinkAction,
+    /// Action for URLs with a query string
+    query: LinkAction,
+    /// URLs which may be crawled
+    scope: HostScope,
+    /// Sub-trees of the base URL to restrict the crawl to
+    only: Vec<String>,
+    /// File extensions to download (all if empty)
+    accept: Vec<String>,
+    /// File extensions not to download
+    reject: Vec<String>,
+    /// wget and rsync style exclude patterns
+    exclude: ExcludeList,
+}
+
+/// Extensions of pages which are always crawled when an accept list is given
+const PAGE_EXTENSIONS: &[&str] = &[
+    "asp", "aspx", "cgi", "htm", "html", "jsp", "php", "shtml", "xhtml",
+];
+
+impl CrawlPolicy {
+    /// Creates the crawl policy from the policy file (if any) overridden by command line arguments
+    pub fn new(args: &Args, base_url: &Url) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let file = match &args.policy_file {
+            Some(file) => Self::load_file(file)?,
+            None => PolicyFile::default(),
+        };
+
+        let mut allow_hosts = file.allow_hosts;
+        allow_hosts.extend(args.allow_host.iter().cloned());
+
+        Ok(Self {
+            fragment: args.fragments.or(file.fragment).unwrap_or_default(),
+            query: if args.allow_query {
+                LinkAction::Follow
+            } else {
+                args.queries.or(file.query).unwrap_or_default()
+            },
+            scope: HostScope::new(
+                base_url.clone(),
+                args.span_hosts || file.span_hosts,
+                allow_hosts,
+            ),
+            only: args
+                .only
+                .iter()
+                .map(|only| only.trim_start_matches('/').to_string())
+                .collect(),
+            accept: Self::normalise_extensions(&args.accept),
+            reject: Self::normalise_extensions(&args.reject),
+            exclude: match &args.exclude_from {
+                Some(file) => ExcludeList::new_from_file(file)?,
+                None => ExcludeList::default(),
+            },
+        })
+    }
+
+    /// Converts extensions to lower case without a leading dot
+    fn normalise_extensions(extensions: &[String]) -> Vec<String> {
+        extensions
+            .iter()
+            .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect()
+    }
+
+    /// Checks a policy file can be loaded
+    pub fn check_file(file: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Self::load_file(file).map(|_| ())
+    }
+
+    /// Loads a policy file
+    fn load_file(file: &str) -> Result<PolicyFile, Box<dyn Error + Send + Sync>> {
+        let fh = File::open(file).map_err(|e| format!("Failed to open policy file {file}: {e}"))?;
+
+        let reader = BufReader::new(fh);
+
+        Ok(serde_json::from_reader(reader)
+            .map_err(|e| format!("Failed to load policy file {file}: {e}"))?)
+    }
+
+    /// Adds a sub-tree of the base URL to restrict the crawl to
+    pub fn add_only(&mut self, rel: &str) {
+        self.only.push(rel.trim_start_matches('/').to_string());
+    }
+
+    /// Returns the crawl scope
+    pub fn scope(&self) -> &HostScope {
+        &self.scope
+    }
+
+    /// Checks a link against the policy, returning the URL to follow or the reason to skip it
+    pub fn check(&self, mut url: Url) -> Result<Url, SkipReasonErr> {
+        // Local files may only be followed from local directories
+        if (url.scheme() == "file") != (self.scope.base_url().scheme() == "file") {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::Transport))?;
+        }
+
+        // Check it's not a fragment
+        if url.fragment().is_some() {
+            match self.fragment {
+                LinkAction::Skip => Err(SkipReasonErr::new(url.to_string(), SkipReason::Fragment))?,
+                LinkAction::Strip | LinkAction::Follow => url.set_fragment(None),
+            }
+        }
+
+        // Check is doesn't have a query string
+        if url.query().is_some() {
+            match self.query {
+                LinkAction::Skip => Err(SkipReasonErr::new(url.to_string(), SkipReason::Query))?,
+                LinkAction::Strip => url.set_query(None),
+                LinkAction::Follow => (),
+            }
+        }
+
+        // Check the URL is relative to the base URL or on an allowed host
+        if !self.scope.contains(&url) {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::NotRelative))?;
+        }
+
+        // Check the URL is in one of the sub-trees being crawled
+        if !self.in_only(&url) {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::NotOnly))?;
+        }
+
+        // Check the path doesn't match an exclude pattern
+        if self.exclude.is_excluded(&url, self.scope.base_url()) {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::Excluded))?;
+        }
+
+        // Check the file extension is accepted
+        if !self.extension_accepted(&url) {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::Extension))?;
+        }
+
+        Ok(url)
+    }
+
+    /// Returns true if the file name of a URL passes the accept and reject extension lists.
+    /// Directories, names without an extension and pages are only subject to the reject list
+    fn extension_accepted(&self, url: &Url) -> bool {
+        let name = match url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+        {
+            Some(name) if !name.is_empty() => name.to_ascii_lowercase(),
+            _ => return true,
+        };
+
+        let matches = |ext: &String| {
+            name.strip_suffix(ext.as_str())
+                .is_some_and(|stem| stem.ends_with('.'))
+        };
+
+        if self.reject.iter().any(matches) {
+            return false;
+        }
+
+        if self.accept.is_empty() {
+            return true;
+        }
+
+        match name.rsplit_once('.') {
+            Some((_, ext)) if !PAGE_EXTENSIONS.contains(&ext) => self.accept.iter().any(matches),
+            _ => true,
+        }
+    }
+
+    /// Returns true if a URL relative to the base is within, or a directory leading to, one of
+    /// the --only sub-trees
+    fn in_only(&self, url: &Url) -> bool {
+        if self.only.is_empty() {
+            return true;
+        }
+
+        match url.relative_path(self.scope.base_url()) {
+            Some(rel) => {
+                rel.is_empty()
+                    || self.only.iter().any(|only| {
+                        rel.starts_with(only.as_str())
+                            || (rel.ends_with('/') && only.starts_with(rel))
+                    })
+            }
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use tokio::fs::{remove_file, rename};
+
+use crate::output::{debug, output};
+use crate::state::State;
+use crate::url::Url;
+
+/// Metadata file patterns used when none are given (apt and yum repository indexes)
+pub const DEFAULT_METADATA_GLOBS: &[&str] = &["dists/**", "repodata/**"];
+
+/// Order in which downloaded files replace the files in the mirror
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum PublishOrder {
+    /// Replace each file as soon as it has been downloaded
+    #[default]
+    AsFetched,
+    /// Replace index and metadata files only once every other file has been downloaded
+    DataFirst,
+}
+
+/// A downloaded metadata file waiting to replace the file in the mirror
+pub struct Deferred {
+    /// URLs the file was downloaded from
+    pub urls: Vec<Url>,
+    /// Final URL the file was downloaded from
+    pub final_url: Url,
+    /// Downloaded temporary file
+    pub tmp_path: PathBuf,
+    /// Path of the file in the mirror
+    pub path: PathBuf,
+    /// Number of bytes in the file
+    pub size: u64,
+    /// SHA-256 of the file contents
+    pub sha256: String,
+}
+
+/// Moves deferred metadata files in to place. If the run was interrupted or any file failed to
+/// download the new metadata could reference files which are missing, so the previous copies
+/// are kept and the new etags are forgotten so the files are fetched again next time
+pub async fn publish_deferred(state: &State) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let deferred = state.take_deferred().await;
+
+    if deferred.is_empty() {
+        return Ok(());
+    }
+
+    if state.is_interrupted() || state.get_stats().failed() > 0 {
+        output!(
+            "Not publishing {} metadata files as not all data files were downloaded",
+            deferred.len()
+        );
+
+        for file in deferred {
+            let _ = remove_file(&file.tmp_path).await;
+            state.remove_etags(&file.urls);
+        }
+
+        return Ok(());
+    }
+
+    let count = deferred.len();
+
+    for file in deferred {
+        rename(&file.tmp_path, &file.path)
+            .await
+            .map_err(|e| format!("Unable to publish {}: {e}", file.path.display()))?;
+
+        debug!(state, 1, "Published {}", file.path.display());
+
+        state
+            .add_manifest_entry(&file.final_url, &file.path, file.size, &file.sha256)
+            .await;
+    }
+
+    output!("Published {count} metadata files");
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{create_dir_all, remove_file, symlink_metadata, write};
+
+use crate::output::{debug, progress};
+use crate::state::ArcState;
+use crate::url::Url;
+
+/// How redirects inside the mirror are recorded at the original URL's path
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RecordRedirects {
+    /// Symbolic link to the file saved for the final URL
+    Symlink,
+    /// HTML page redirecting to the file saved for the final URL
+    Stub,
+}
+
+/// A redirect followed whilst fetching a URL
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedirectHop {
+    /// URL which responded with the redirect
+    pub url: String,
+    /// Status code of the redirect
+    pub status: u16,
+    /// URL redirected to
+    pub location: String,
+}
+
+impl Display for RedirectHop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} -> {}", self.status, self.url, self.location)
+    }
+}
+
+/// Redirect chains followed by the HTTP client, keyed by the URL first requested. Shared with
+/// the client's redirect policy
+#[derive(Default, Clone)]
+pub struct RedirectLog {
+    chains: Arc<Mutex<HashMap<String, Vec<RedirectHop>>>>,
+}
+
+impl RedirectLog {
+    /// Records a redirect for a request. The first redirect of a request starts a new chain
+    pub fn add(&self, initial: &Url, hop: RedirectHop, first: bool) {
+        let mut chains = self.chains.lock().expect("Failed to lock redirect log");
+        let chain = chains.entry(initial.to_string()).or_default();
+
+        if first {
+            chain.clear();
+        }
+
+        chain.push(hop);
+    }
+
+    /// Removes and returns the redirect chain followed for a URL
+    pub fn take(&self, url: &Url) -> Vec<RedirectHop> {
+        self.chains
+            .lock()
+            .expect("Failed to lock redirect log")
+            .remove(url.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Records a redirect inside the mirror at the path of the URL first requested, pointing at
+/// the file saved for the final URL. URLs outside the mirror are ignored
+pub async fn record_redirect(
+    state: &ArcState,
+    how: RecordRedirects,
+    url: &Url,
+    final_url: &Url,
+    path: &Path,
+) {
+    let link_path = match state.path_for_url(url).await {
+        Ok(link_path) if link_path != path => link_path,
+        Ok(_) => return,
+        Err(e) => {
+            debug!(state, 1, "Not recording redirect from {url}: {e}");
+            return;
+        }
+    };
+
+    match write_redirect(how, &link_path, path).await {
+        Ok(()) => progress!(
+            "Recorded redirect from {url} to {final_url} at {}",
+            link_path.display()
+        ),
+        Err(e) => debug!(
+            state,
+            1,
+            "Unable to record redirect from {url} at {}: {e}",
+            link_path.display()
+        ),
+    }
+}
+
+/// Replaces any file at a path with a link or stub page pointing at another file
+async fn write_redirect(
+    how: RecordRedirects,
+    link_path: &Path,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Create directories if necessary
+    if let Some(parent) = link_path.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    // Remove the file or link left by a previous run
+    if let Ok(meta) = symlink_metadata(link_path).await {
+        if meta.is_dir() {
+            Err("A directory is in the way")?
+        }
+
+        remove_file(link_path).await?;
+    }
+
+    let target = relative_to(path, link_path.parent().unwrap_or(Path::new("")));
+
+    match how {
+        RecordRedirects::Symlink => symlink_file(&target, link_path).await?,
+        RecordRedirects::Stub => {
+            // Percent signs in file names have to be escaped to be used in a URL
+            let href = target
+                .to_string_lossy()
+                .replace('\\', "/")
+                .replace('%', "%25")
+                .replace('&', "&amp;")
+                .replace('"', "&quot;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+
+            write(
+                link_path,
+                format!(
+                    "<!DOCTYPE html>\n<html><head><meta http-equiv=\"refresh\" content=\"0; url={href}\"></head><body><a href=\"{href}\">{href}</a></body></html>\n"
+                ),
+            )
+            .await?
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the path to a file relative to a directory
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    let path: Vec<Component> = path.components().collect();
+    let dir: Vec<Component> = dir.components().collect();
+
+    let common = path.iter().zip(&dir).take_while(|(a, b)| a == b).count();
+
+    (common..dir.len())
+        .map(|_| Component::ParentDir)
+        .chain(path[common..].iter().copied())
+        .collect()
+}
+
+/// Creates a symbolic link to a file
+#[cfg(unix)]
+async fn symlink_file(original: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink(original, link).await
+}
+
+/// Creates a symbolic link to a file
+#[cfg(windows)]
+async fn symlink_file(original: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink_file(original, link).await
+}
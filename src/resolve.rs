@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+use url::Host;
+
+use crate::url::Url;
+
+/// Fixed address to connect to for a host name instead of looking it up in DNS
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolve {
+    /// Host name
+    pub host: String,
+    /// Address to connect to
+    pub addr: IpAddr,
+}
+
+impl Resolve {
+    /// Returns the socket address to pass to the HTTP client. The port is taken from the URL
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr, 0)
+    }
+
+    /// Rewrites the host of a URL to a virtual host name, returning the resolve entry which
+    /// still sends requests to the original host's address
+    pub fn for_host_header(
+        url: &mut Url,
+        name: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let addr = match url.host() {
+            Some(Host::Ipv4(addr)) => IpAddr::V4(addr),
+            Some(Host::Ipv6(addr)) => IpAddr::V6(addr),
+            Some(Host::Domain(domain)) => {
+                let port = url.port_or_known_default().unwrap_or(0);
+
+                (domain, port)
+                    .to_socket_addrs()
+                    .map_err(|e| format!("Unable to resolve {domain}: {e}"))?
+                    .next()
+                    .ok_or_else(|| format!("No addresses found for {domain}"))?
+                    .ip()
+            }
+            None => Err(format!("URL {url} has no host to connect to"))?,
+        };
+
+        url.set_host(Some(name))
+            .map_err(|e| format!("Invalid host header name {name}: {e}"))?;
+
+        Ok(Self {
+            host: name.to_string(),
+            addr,
+        })
+    }
+}
+
+impl FromStr for Resolve {
+    type Err = String;
+
+    /// Parses a resolve entry in host:addr form
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, addr) = s
+            .split_once(':')
+            .ok_or_else(|| format!("'{s}' is not in the form host:addr"))?;
+
+        if host.is_empty() {
+            Err(format!("'{s}' has no host name"))?
+        }
+
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("'{addr}' is not an IP address"))?;
+
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            addr,
+        })
+    }
+}
@@ -8,7 +8,20 @@ use crate::state::ArcState;
 
 /// Extension trait for a reqwest Response
 pub trait ResponseExt {
+    /// Returns true if the response can be parsed as HTML
     fn is_html(&self, state: &ArcState) -> bool;
+
+    /// Returns true if the response is an XML document
+    fn is_xml(&self, state: &ArcState) -> bool;
+
+    /// Returns true if the response is plain text
+    fn is_text(&self, state: &ArcState) -> bool;
+
+    /// Returns true if the response is a JSON document
+    fn is_json(&self, state: &ArcState) -> bool;
+
+    /// Returns the MIME type of the response if known
+    fn mime_type(&self, state: &ArcState) -> Option<Mime>;
 }
 
 /// HMTL MIME type
@@ -17,9 +30,61 @@ static MIME_HTML: Lazy<Mime> = Lazy::new(|| "text/html".parse::<Mime>().unwrap()
 /// XHTML MIME type
 static MIME_XHTML: Lazy<Mime> = Lazy::new(|| "application/xhtml+xml".parse::<Mime>().unwrap());
 
+/// XML MIME type
+static MIME_XML: Lazy<Mime> = Lazy::new(|| "application/xml".parse::<Mime>().unwrap());
+
+/// Text XML MIME type
+static MIME_TEXT_XML: Lazy<Mime> = Lazy::new(|| "text/xml".parse::<Mime>().unwrap());
+
+/// RSS MIME type
+static MIME_RSS: Lazy<Mime> = Lazy::new(|| "application/rss+xml".parse::<Mime>().unwrap());
+
+/// Atom MIME type
+static MIME_ATOM: Lazy<Mime> = Lazy::new(|| "application/atom+xml".parse::<Mime>().unwrap());
+
+/// Plain text MIME type
+static MIME_TEXT: Lazy<Mime> = Lazy::new(|| "text/plain".parse::<Mime>().unwrap());
+
+/// JSON MIME type
+static MIME_JSON: Lazy<Mime> = Lazy::new(|| "application/json".parse::<Mime>().unwrap());
+
 impl ResponseExt for Response {
     /// Returns true if the response can be parsed as HTML
     fn is_html(&self, state: &ArcState) -> bool {
+        // Is it html or xhtml?
+        self.mime_type(state)
+            .map(|mime_type| mime_type.equal(&MIME_HTML) || mime_type.equal(&MIME_XHTML))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the response is an XML document
+    fn is_xml(&self, state: &ArcState) -> bool {
+        self.mime_type(state)
+            .map(|mime_type| {
+                mime_type.equal(&MIME_XML)
+                    || mime_type.equal(&MIME_TEXT_XML)
+                    || mime_type.equal(&MIME_RSS)
+                    || mime_type.equal(&MIME_ATOM)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the response is plain text
+    fn is_text(&self, state: &ArcState) -> bool {
+        self.mime_type(state)
+            .map(|mime_type| mime_type.equal(&MIME_TEXT))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the response is a JSON document
+    fn is_json(&self, state: &ArcState) -> bool {
+        self.mime_type(state)
+            .map(|mime_type| mime_type.equal(&MIME_JSON))
+            .unwrap_or(false)
+    }
+
+    /// Returns the MIME type from the content type header
+    fn mime_type(&self, state: &ArcState) -> Option<Mime> {
         // Get content MIME type
         if let Some(mime_type) = self
             .headers()
@@ -29,8 +94,7 @@ impl ResponseExt for Response {
         {
             debug!(state, 2, "MIME type of {} is {mime_type}", self.url());
 
-            // Is it html or xhtml?
-            mime_type.equal(&MIME_HTML) || mime_type.equal(&MIME_XHTML)
+            Some(mime_type)
         } else {
             debug!(
                 state,
@@ -39,7 +103,7 @@ impl ResponseExt for Response {
                 self.url()
             );
 
-            false
+            None
         }
     }
 }
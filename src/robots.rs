@@ -0,0 +1,170 @@
+use std::error::Error;
+
+use reqwest::Client;
+
+use crate::url::{Url, UrlExt};
+
+/// User agent token matched against robots.txt groups
+const USER_AGENT: &str = "mirrorurl";
+
+/// Allow or disallow rule from a robots.txt file
+#[derive(Debug, Clone)]
+struct Rule {
+    /// True if the rule allows access
+    allow: bool,
+    /// Path pattern
+    pattern: String,
+}
+
+/// Parsed robots.txt rules for the base URL host
+#[derive(Debug, Clone, Default)]
+pub struct Robots {
+    rules: Vec<Rule>,
+}
+
+impl Robots {
+    /// Fetches and parses robots.txt for the host of a URL. If the file can't be fetched all paths are allowed
+    pub async fn fetch(client: &Client, url: &Url) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let robots_url = url.join("/robots.txt")?;
+
+        let response = client.get(robots_url.clone()).send().await;
+
+        let robots = match response {
+            Ok(response) if response.status().is_success() => {
+                let text = response.text().await?;
+                Self::parse(&text)
+            }
+            Ok(response) => {
+                log::debug!("Status {} fetching {robots_url}", response.status());
+                Self::default()
+            }
+            Err(e) => {
+                log::debug!("Failed to fetch {robots_url}: {e}");
+                Self::default()
+            }
+        };
+
+        Ok(robots)
+    }
+
+    /// Parses a robots.txt file, keeping the rules for this user agent or the wildcard user agent
+    pub fn parse(text: &str) -> Self {
+        let mut ours = Vec::new();
+        let mut wildcard = Vec::new();
+        let mut found_ours = false;
+
+        // Current group state
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        let mut rules: Vec<Rule> = Vec::new();
+
+        let mut end_group = |agents: &mut Vec<String>, rules: &mut Vec<Rule>| {
+            if agents.iter().any(|a| a == USER_AGENT) {
+                found_ours = true;
+                ours.append(rules);
+            } else if agents.iter().any(|a| a == "*") {
+                wildcard.append(rules);
+            }
+
+            agents.clear();
+            rules.clear();
+        };
+
+        for line in text.lines() {
+            // Remove comments
+            let line = match line.split_once('#') {
+                Some((line, _)) => line,
+                None => line,
+            };
+
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim();
+
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        // Start of a new group
+                        end_group(&mut agents, &mut rules);
+                        in_rules = false;
+                    }
+
+                    agents.push(value.to_ascii_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+
+                    // An empty disallow allows everything
+                    if !value.is_empty() {
+                        rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.to_string(),
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        end_group(&mut agents, &mut rules);
+
+        Self {
+            rules: if found_ours { ours } else { wildcard },
+        }
+    }
+
+    /// Returns true if the path of the URL may be fetched. The longest matching rule wins, allow winning ties
+    pub fn allowed(&self, url: &Url) -> bool {
+        let path = url.full_path();
+
+        let mut best: Option<&Rule> = None;
+
+        for rule in &self.rules {
+            if Self::matches(&rule.pattern, path) {
+                best = match best {
+                    Some(b) if b.pattern.len() > rule.pattern.len() => Some(b),
+                    Some(b) if b.pattern.len() == rule.pattern.len() && b.allow => Some(b),
+                    _ => Some(rule),
+                };
+            }
+        }
+
+        best.map(|rule| rule.allow).unwrap_or(true)
+    }
+
+    /// Matches a path against a rule pattern supporting '*' wildcards and '$' end anchors
+    fn matches(pattern: &str, path: &str) -> bool {
+        let (pattern, anchored) = match pattern.strip_suffix('$') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+
+        let mut parts = pattern.split('*');
+
+        // First part must be a prefix
+        let first = parts.next().unwrap_or_default();
+
+        let Some(mut rest) = path.strip_prefix(first) else {
+            return false;
+        };
+
+        let parts: Vec<&str> = parts.collect();
+
+        for (i, part) in parts.iter().enumerate() {
+            if anchored && i == parts.len() - 1 {
+                // Last part must be a suffix
+                return rest.ends_with(part);
+            }
+
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false,
+            }
+        }
+
+        !anchored || rest.is_empty()
+    }
+}
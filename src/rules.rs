@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::args::Args;
+use crate::events::EventSink;
+use crate::output::output;
+use crate::state::State;
+use crate::url::Url;
+
+/// Prints the rule which applies to each URL or path relative to the first URL to mirror, and
+/// the local path for URLs which would be downloaded
+pub async fn test_rules(
+    args: Args,
+    items: &[String],
+    events: Arc<dyn EventSink>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let state = State::new(args, events, None).await?;
+
+    let base = state
+        .start_urls()
+        .first()
+        .ok_or("No URL to test rules against")?
+        .clone();
+
+    for item in items {
+        // Anything which isn't a URL is taken as a path relative to the first URL
+        let url = match Url::parse(item) {
+            Ok(url) => url,
+            Err(_) => base
+                .join(item)
+                .map_err(|e| format!("Invalid path {item}: {e}"))?,
+        };
+
+        match test_url(&state, url).await {
+            Ok((url, path)) => output!("{url}: saved to {}", path.display()),
+            Err(e) => output!("{e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the crawl policy, shard and skip list to a URL. Returns the URL as it would be
+/// fetched and its local path
+async fn test_url(state: &State, url: Url) -> Result<(Url, PathBuf), Box<dyn Error + Send + Sync>> {
+    let url = state.check_link(url)?;
+
+    state.check_shard(&url)?;
+
+    let path = state.path_for_url(&url).await?;
+
+    Ok((url, path))
+}
@@ -0,0 +1,325 @@
+use std::error::Error;
+
+use futures::future::{BoxFuture, FutureExt};
+use percent_encoding::percent_decode_str;
+use reqwest::header::IF_NONE_MATCH;
+use reqwest::StatusCode;
+
+use crate::download::download;
+use crate::etags::etag_to_header;
+use crate::http::header_modified;
+use crate::limiter::Slot;
+use crate::outcome::{check, Outcome};
+use crate::output::{debug, error, progress};
+use crate::sitemap::{root_element, unescape};
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::status::StatusErr;
+use crate::transport::Transport;
+use crate::url::Url;
+use crate::walk::{follow_links, join_tasks};
+
+/// Amazon S3 bucket host name suffix used when no endpoint is given
+const S3_HOST_SUFFIX: &str = "s3.amazonaws.com";
+
+/// S3 transport for s3://bucket/prefix/ URLs. Directory URLs are listed with ListObjectsV2 and
+/// objects are fetched over HTTPS
+pub struct S3Transport;
+
+impl Transport for S3Transport {
+    fn walk<'a>(
+        &'a self,
+        state: &'a ArcState,
+        url: &'a Url,
+        sem: Slot,
+        stats: &'a mut Stats,
+    ) -> BoxFuture<'a, Result<Outcome, Box<dyn Error + Send + Sync>>> {
+        walk_s3(state, url, sem, stats).boxed()
+    }
+}
+
+/// Processes an s3:// URL. URLs with an empty path or ending in a slash list the objects under
+/// the prefix, anything else is an object to download
+async fn walk_s3(
+    state: &ArcState,
+    url: &Url,
+    sem: Slot,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    let bucket_url = bucket_url(state, url)?;
+    let key = percent_decode_str(url.path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+
+    state.events().on_fetch_start(url);
+
+    if key.is_empty() || key.ends_with('/') {
+        // List the objects under the prefix
+        let mut listing_url = bucket_url;
+        listing_url
+            .query_pairs_mut()
+            .append_pair("list-type", "2")
+            .append_pair("prefix", &key);
+
+        // Release the download slot
+        drop(sem);
+
+        let mut root = url.clone();
+        root.set_path("/");
+
+        return process_bucket_listing(state, &listing_url, &root, None, stats).await;
+    }
+
+    // Fetch the object, sending the etag from the last run
+    let mut request = state.client().get(bucket_url.join(&escape_key(&key))?);
+
+    if let Some(old_etag) = state.find_etag(url) {
+        debug!(state, 2, "Previous etag value: {old_etag}");
+
+        match etag_to_header(old_etag) {
+            Some(value) => request = request.header(IF_NONE_MATCH, value),
+            None => error!("Previous etag value {old_etag} is not valid"),
+        }
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+
+    if status == StatusCode::NOT_MODIFIED && state.find_etag(url).is_some() {
+        progress!("{url} is not modified");
+        return Ok(Outcome::NotModified);
+    } else if !status.is_success() {
+        Err(StatusErr::new(status, response.url()))?
+    }
+
+    // Check the file is in our shard, size and age limits
+    check!(state.check_shard(url));
+    check!(state.check_size(url, response.content_length()));
+    check!(state.check_age(url, header_modified(response.headers())));
+
+    // Download the object
+    let outcome = download(state, url, url, response, stats).await?;
+
+    // Release the download slot
+    drop(sem);
+
+    Ok(outcome)
+}
+
+/// Returns the HTTPS URL of the bucket for an s3:// URL. Buckets are addressed by virtual host
+/// on Amazon S3, or by path under the endpoint given with --s3-endpoint
+fn bucket_url(state: &ArcState, url: &Url) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    let bucket = match url.host_str() {
+        Some(bucket) if !bucket.is_empty() => bucket,
+        _ => Err(format!("URL {url} has no bucket name"))?,
+    };
+
+    let bucket_url = match &state.args().s3_endpoint {
+        Some(endpoint) => {
+            let mut bucket_url = endpoint.clone();
+
+            if !bucket_url.path().ends_with('/') {
+                bucket_url.set_path(&format!("{}/", bucket_url.path()));
+            }
+
+            bucket_url.join(&format!("{bucket}/"))?
+        }
+        None => Url::parse(&format!("https://{bucket}.{S3_HOST_SUFFIX}/"))?,
+    };
+
+    Ok(bucket_url)
+}
+
+/// Returns true if a URL is an S3 ListObjectsV2 bucket listing request
+pub fn is_bucket_listing(url: &Url) -> bool {
+    url.query_pairs()
+        .any(|(name, value)| name == "list-type" && value == "2")
+}
+
+/// Returns the URL objects in a bucket listing are relative to - the listing URL without the
+/// query string
+pub fn bucket_root(listing_url: &Url) -> Url {
+    let mut root = listing_url.clone();
+
+    root.set_query(None);
+    root.set_fragment(None);
+
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", listing_url.path()));
+    }
+
+    root
+}
+
+/// A page of a bucket listing
+#[derive(Debug, Default, PartialEq)]
+pub struct BucketListing {
+    /// Object keys
+    keys: Vec<String>,
+    /// Set if there are more pages
+    truncated: bool,
+    /// ListObjectsV2 token for the next page
+    next_token: Option<String>,
+    /// ListObjects (version 1) marker for the next page
+    next_marker: Option<String>,
+    /// Size of the XML document
+    bytes: usize,
+}
+
+/// Parses an S3 ListBucketResult document. Returns None if the document is not a bucket listing
+pub fn parse_bucket_listing(xml: &str) -> Option<BucketListing> {
+    if root_element(xml) != Some("ListBucketResult") {
+        return None;
+    }
+
+    let keys = element_values(xml, "Key")
+        .into_iter()
+        .filter(|key| !key.ends_with('/'))
+        .collect();
+
+    Some(BucketListing {
+        keys,
+        truncated: element_values(xml, "IsTruncated")
+            .first()
+            .map(String::as_str)
+            == Some("true"),
+        next_token: element_values(xml, "NextContinuationToken").pop(),
+        next_marker: element_values(xml, "NextMarker").pop(),
+        bytes: xml.len(),
+    })
+}
+
+impl BucketListing {
+    /// Returns the URL of the next page of the listing, if any
+    fn next_page_url(&self, listing_url: &Url) -> Option<Url> {
+        if !self.truncated {
+            return None;
+        }
+
+        let (name, value) = match (&self.next_token, &self.next_marker) {
+            (Some(token), _) => ("continuation-token", token),
+            (None, Some(marker)) => ("marker", marker),
+            (None, None) => ("marker", self.keys.last()?),
+        };
+
+        let pairs: Vec<(String, String)> = listing_url
+            .query_pairs()
+            .filter(|(pair_name, _)| pair_name != name)
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+
+        let mut next_url = listing_url.clone();
+
+        next_url
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair(name, value);
+
+        Some(next_url)
+    }
+}
+
+/// Fetches and parses a page of a bucket listing
+async fn fetch_listing(
+    state: &ArcState,
+    listing_url: &Url,
+) -> Result<BucketListing, Box<dyn Error + Send + Sync>> {
+    debug!(state, 1, "Fetching bucket listing {listing_url}");
+
+    let response = state.client().get(listing_url.clone()).send().await?;
+    let status = response.status();
+
+    if !status.is_success() {
+        Err(format!("Status {status} fetching {listing_url}"))?
+    }
+
+    let xml = response.text().await?;
+
+    match parse_bucket_listing(&xml) {
+        Some(listing) => Ok(listing),
+        None => Err(format!("{listing_url} is not a bucket listing"))?,
+    }
+}
+
+/// Fetches the remaining pages of a bucket listing and follows the object keys, which are
+/// relative to the root URL. The first page is fetched too if not given
+pub async fn process_bucket_listing(
+    state: &ArcState,
+    listing_url: &Url,
+    root: &Url,
+    first: Option<BucketListing>,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    let mut page = match first {
+        Some(page) => page,
+        None => fetch_listing(state, listing_url).await?,
+    };
+
+    let mut keys = Vec::new();
+    let mut bytes = 0;
+
+    loop {
+        let next_url = page.next_page_url(listing_url);
+
+        bytes += page.bytes;
+        keys.append(&mut page.keys);
+
+        match next_url {
+            Some(next_url) => page = fetch_listing(state, &next_url).await?,
+            None => break,
+        }
+    }
+
+    // Build the object URLs
+    let links = keys
+        .into_iter()
+        .map(|key| {
+            root.join(&escape_key(&key))
+                .map_err(|e| SkipReasonErr::new(key, SkipReason::NotValid(e)))
+        })
+        .collect();
+
+    // Process the objects
+    let join_handles = follow_links(state, links, stats).await;
+    let links = join_handles.len();
+
+    // Join the threads
+    join_tasks(join_handles).await;
+
+    Ok(Outcome::Parsed { bytes, links })
+}
+
+/// Escapes an object key to join to a bucket URL
+fn escape_key(key: &str) -> String {
+    let key = key
+        .replace('%', "%25")
+        .replace('#', "%23")
+        .replace('?', "%3F");
+
+    format!("./{key}")
+}
+
+/// Returns the text of each element with a given name in an XML document
+fn element_values(xml: &str, name: &str) -> Vec<String> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+
+    let mut values = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+
+        values.push(unescape(rest[..end].trim()));
+
+        rest = &rest[end + close.len()..];
+    }
+
+    values
+}
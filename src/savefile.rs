@@ -0,0 +1,28 @@
+use std::error::Error;
+use std::fs::{rename, File};
+use std::io::BufWriter;
+
+/// Saves a file by writing a temporary file next to it, syncing that to disk and renaming it
+/// over the file. A crash part way through leaves the previous copy of the file in place
+pub fn save_file<F>(file: &str, write: F) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let tmp_file = format!("{file}.tmp");
+
+    let fh = File::create(&tmp_file).map_err(|e| format!("Error creating {tmp_file}: {e}"))?;
+
+    let mut writer = BufWriter::new(fh);
+
+    write(&mut writer).map_err(|e| format!("Error writing {tmp_file}: {e}"))?;
+
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|fh| fh.sync_all())
+        .map_err(|e| format!("Error writing {tmp_file}: {e}"))?;
+
+    rename(&tmp_file, file).map_err(|e| format!("Error renaming {tmp_file}: {e}"))?;
+
+    Ok(())
+}
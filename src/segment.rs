@@ -0,0 +1,284 @@
+use std::error::Error;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::Path;
+
+use futures::future::try_join_all;
+use reqwest::header::{
+    HeaderMap,
+    HeaderValue,
+    ACCEPT_ENCODING,
+    ACCEPT_RANGES,
+    CONTENT_ENCODING,
+    ETAG,
+    IF_RANGE,
+    LAST_MODIFIED,
+    RANGE,
+};
+use reqwest::StatusCode;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::download::{preallocate, Body};
+use crate::http::get;
+use crate::limiter::Slot;
+use crate::output::debug;
+use crate::response::Response;
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::url::Url;
+
+/// Smallest segment worth fetching over another connection
+const MIN_SEGMENT_SIZE: u64 = 1 << 20;
+
+/// Plan for downloading a file in segments over several connections. The first segment is read
+/// from the original response and the rest are fetched with range requests
+pub struct Segments {
+    /// URL to request the segments from
+    url: Url,
+    /// Total length of the file
+    length: u64,
+    /// Validator making sure the segments come from the same version of the file
+    if_range: HeaderValue,
+    /// Byte ranges of the segments
+    ranges: Vec<Range<u64>>,
+    /// Download slots held for the range requests
+    slots: Vec<Slot>,
+}
+
+impl Segments {
+    /// Works out the segments to download a response in. Returns None if segmented downloads
+    /// are disabled, the server doesn't accept ranges, the file can't be validated, the file is
+    /// too small or no download slots are free for the range requests
+    pub fn plan(state: &ArcState, response: &Response) -> Option<Self> {
+        let args = state.args();
+
+        // Compressed files have to be written in order
+        if args.segments < 2 || args.store_compressed.is_some() {
+            return None;
+        }
+
+        let headers = response.headers();
+
+        // The server must accept byte ranges of the file as sent
+        if headers.get(ACCEPT_RANGES)?.as_bytes() != b"bytes"
+            || headers.contains_key(CONTENT_ENCODING)
+        {
+            return None;
+        }
+
+        // Without a validator the segments could come from different versions of the file.
+        // Weak etags can't be used to validate a range
+        let if_range = headers
+            .get(ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .or_else(|| headers.get(LAST_MODIFIED))?
+            .clone();
+
+        let length = response.content_length()?;
+
+        let wanted = (length / MIN_SEGMENT_SIZE).min(args.segments as u64);
+
+        if wanted < 2 {
+            return None;
+        }
+
+        // Each range request needs a download slot of its own so the overall and per host
+        // limits hold. Only free slots are taken as waiting whilst holding the slot for the
+        // original response could deadlock
+        let url = state.response_url(response.url());
+
+        let slots: Vec<Slot> = (1..wanted)
+            .map_while(|_| state.try_acquire_slot(&url))
+            .collect();
+
+        let count = slots.len() as u64 + 1;
+
+        if count < 2 {
+            return None;
+        }
+
+        let segment_len = length.div_ceil(count);
+
+        let ranges = (0..count)
+            .map(|i| i * segment_len..((i + 1) * segment_len).min(length))
+            .collect();
+
+        Some(Self {
+            url,
+            length,
+            if_range,
+            ranges,
+            slots,
+        })
+    }
+
+    /// Downloads the segments in to a file, reading the first from the body. Returns the number
+    /// of bytes written
+    pub async fn download<B>(
+        mut self,
+        state: &ArcState,
+        body: &mut B,
+        mut file: File,
+        path: &Path,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>>
+    where
+        B: Body,
+    {
+        debug!(
+            state,
+            1,
+            "Downloading {} in {} segments",
+            self.url,
+            self.ranges.len()
+        );
+
+        // Preallocate the file so each segment can be written at its offset
+        preallocate(&file, self.length, path).await?;
+
+        let first = async {
+            let mut bytes = 0;
+            let first_len = self.ranges[0].end;
+
+            while bytes < first_len {
+                let Some(chunk) = body
+                    .next_chunk()
+                    .await
+                    .map_err(|e| format!("Error downloading chunk: {e}"))?
+                else {
+                    break;
+                };
+
+                // Stop at the end of the segment
+                let take = chunk.len().min((first_len - bytes) as usize);
+
+                file.write_all(&chunk[..take])
+                    .await
+                    .map_err(|e| format!("Error writing to {}: {e}", path.display()))?;
+
+                bytes += take as u64;
+
+                // Debug delay
+                state.debug_delay().await;
+            }
+
+            check_segment_len(&self.ranges[0], bytes, path)?;
+
+            file.flush()
+                .await
+                .map_err(|e| format!("Error writing to {}: {e}", path.display()))?;
+
+            Ok::<_, Box<dyn Error + Send + Sync>>(bytes)
+        };
+
+        let slots = std::mem::take(&mut self.slots);
+
+        let rest = try_join_all(
+            self.ranges[1..]
+                .iter()
+                .zip(slots)
+                .map(|(range, slot)| self.download_range(state, range, path, slot)),
+        );
+
+        let (first, rest) = futures::try_join!(first, rest)?;
+
+        Ok((first + rest.iter().sum::<u64>()) as usize)
+    }
+
+    /// Fetches a range of the file with a range request and writes it at its offset in the file.
+    /// The download slot is released once the range has been written
+    async fn download_range(
+        &self,
+        state: &ArcState,
+        range: &Range<u64>,
+        path: &Path,
+        _slot: Slot,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&format!("bytes={}-{}", range.start, range.end - 1))?,
+        );
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        headers.insert(IF_RANGE, self.if_range.clone());
+
+        // Throttled range requests are retried in the same way as other requests
+        let mut stats = Stats::default();
+        let result = get(state, &self.url, headers, &mut stats).await;
+        state.add_stats(&stats);
+
+        let mut response = result??;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            Err(format!(
+                "Status {} fetching bytes {}-{} of {}",
+                response.status(),
+                range.start,
+                range.end - 1,
+                self.url
+            ))?
+        }
+
+        let mut file = File::options()
+            .write(true)
+            .open(path)
+            .await
+            .map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(|e| format!("Error seeking in {}: {e}", path.display()))?;
+
+        let mut bytes = 0;
+
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| format!("Error downloading chunk: {e}"))?
+        {
+            bytes += chunk.len() as u64;
+
+            if bytes > range.end - range.start {
+                break;
+            }
+
+            debug!(state, 2, "Read {} bytes at {}", chunk.len(), range.start);
+
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| format!("Error writing to {}: {e}", path.display()))?;
+
+            // Debug delay
+            state.debug_delay().await;
+        }
+
+        check_segment_len(range, bytes, path)?;
+
+        file.flush()
+            .await
+            .map_err(|e| format!("Error writing to {}: {e}", path.display()))?;
+
+        Ok(bytes)
+    }
+}
+
+/// Checks the whole of a segment was received
+fn check_segment_len(
+    range: &Range<u64>,
+    bytes: u64,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let expected = range.end - range.start;
+
+    if bytes != expected {
+        Err(format!(
+            "Length mismatch for bytes {}-{} of {}: expected {expected} bytes, got {bytes}",
+            range.start,
+            range.end - 1,
+            path.display()
+        ))?
+    }
+
+    Ok(())
+}
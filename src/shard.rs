@@ -0,0 +1,55 @@
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+/// Selects one of a number of shards of the files in a mirror
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    /// Shard number (1 based)
+    index: u64,
+    /// Number of shards
+    count: u64,
+}
+
+impl Shard {
+    /// Returns true if a file path belongs to this shard. The assignment only depends on the path
+    /// so every process agrees without coordination
+    pub fn contains(&self, path: &str) -> bool {
+        let digest = Sha256::digest(path.as_bytes());
+
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+
+        u64::from_be_bytes(bytes) % self.count == self.index - 1
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    /// Parses a shard in i/n form
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("'{s}' is not in the form i/n"))?;
+
+        let index: u64 = index
+            .parse()
+            .map_err(|_| format!("'{index}' is not a number"))?;
+        let count: u64 = count
+            .parse()
+            .map_err(|_| format!("'{count}' is not a number"))?;
+
+        if count == 0 || index == 0 || index > count {
+            Err(format!("Shard {index} of {count} is out of range"))?
+        }
+
+        Ok(Self { index, count })
+    }
+}
+
+impl std::fmt::Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
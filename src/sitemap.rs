@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs::{read_dir, write};
+use tokio::task::JoinHandle;
+
+use crate::output::debug;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::url::Url;
+use crate::walk::follow_links;
+
+/// Returns true if the URL path looks like a sitemap
+pub fn is_sitemap_path(url: &Url) -> bool {
+    url.path().ends_with("sitemap.xml")
+}
+
+/// Parses a sitemap or sitemap index document returning the location URLs.
+/// Returns None if the document is not a sitemap
+pub fn parse_sitemap(xml: &str) -> Option<Vec<String>> {
+    // Check the root element
+    match root_element(xml) {
+        Some("urlset") | Some("sitemapindex") => (),
+        _ => return None,
+    }
+
+    let mut locs = Vec::new();
+    let mut rest = xml;
+
+    // Find each loc element
+    while let Some(start) = rest.find("<loc>") {
+        rest = &rest[start + 5..];
+
+        let Some(end) = rest.find("</loc>") else {
+            break;
+        };
+
+        let loc = rest[..end].trim();
+        let loc = loc
+            .strip_prefix("<![CDATA[")
+            .and_then(|loc| loc.strip_suffix("]]>"))
+            .unwrap_or(loc);
+
+        locs.push(unescape(loc.trim()));
+
+        rest = &rest[end + 6..];
+    }
+
+    Some(locs)
+}
+
+/// Process all of the locations in a sitemap returning a list of join handles for spawned download tasks
+pub async fn process_sitemap(
+    state: &ArcState,
+    url: &Url,
+    locs: Vec<String>,
+    stats: &mut Stats,
+) -> Vec<JoinHandle<()>> {
+    let links = locs
+        .iter()
+        .map(|loc| match url.join(loc) {
+            Ok(loc_url) => {
+                debug!(state, 2, "Sitemap location {loc} of {url} -> {loc_url}");
+                Ok(loc_url)
+            }
+            Err(e) => Err(SkipReasonErr::new(loc.clone(), SkipReason::NotValid(e))),
+        })
+        .collect();
+
+    // Process all of the links
+    follow_links(state, links, stats).await
+}
+
+/// Writes a sitemap describing the files in the target directory as published under a base URL.
+/// Returns the number of URLs written
+pub async fn emit_sitemap(
+    target: &Path,
+    file: &Path,
+    publish_base: &Url,
+    index_name: &str,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut entries = Vec::new();
+
+    let mut dirs = VecDeque::new();
+    dirs.push_back((target.to_path_buf(), Vec::new()));
+
+    while let Some((dir, segments)) = dirs.pop_front() {
+        let mut paths = read_dir(&dir)
+            .await
+            .map_err(|e| format!("Unable to read directory {}: {e}", dir.display()))?;
+
+        while let Some(dirent) = paths
+            .next_entry()
+            .await
+            .map_err(|e| format!("Unable to read directory {}: {e}", dir.display()))?
+        {
+            let name = dirent.file_name().to_string_lossy().into_owned();
+
+            // Skip hidden and temporary files and the sitemap itself
+            if name.starts_with('.') || name.ends_with(".mirrorurl") || dirent.path() == file {
+                continue;
+            }
+
+            let meta = dirent.metadata().await?;
+
+            let mut segments = segments.clone();
+
+            if meta.is_dir() {
+                segments.push(name);
+                dirs.push_back((dirent.path(), segments));
+            } else {
+                let url = published_url(publish_base, &segments, &name, index_name)?;
+                entries.push((url, meta.modified().ok()));
+            }
+        }
+    }
+
+    entries.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+    // Build the document
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">
+",
+    );
+
+    for (url, mtime) in &entries {
+        xml.push_str(&format!(
+            "  <url>\n    <loc>{}</loc>\n",
+            escape(url.as_str())
+        ));
+
+        if let Some(mtime) = mtime {
+            xml.push_str(&format!(
+                "    <lastmod>{}</lastmod>\n",
+                w3c_datetime(*mtime)
+            ));
+        }
+
+        xml.push_str("  </url>\n");
+    }
+
+    xml.push_str("</urlset>\n");
+
+    write(file, xml)
+        .await
+        .map_err(|e| format!("Error writing {}: {e}", file.display()))?;
+
+    Ok(entries.len())
+}
+
+/// Builds the published URL for a file in the mirror
+fn published_url(
+    publish_base: &Url,
+    segments: &[String],
+    name: &str,
+    index_name: &str,
+) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    let mut url = publish_base.clone();
+
+    // Split out any query string encoded in to the file name
+    let (name, query) = match name.split_once("%3F") {
+        Some((name, query)) => (name, Some(query.replace("%2F", "/"))),
+        None => (name, None),
+    };
+
+    {
+        let mut path = url
+            .path_segments_mut()
+            .map_err(|_| format!("Publish base {publish_base} can't be a base URL"))?;
+
+        // Remove the trailing empty segment from the base
+        path.pop_if_empty();
+        path.extend(segments);
+
+        // Index files are published as the directory
+        if name == index_name {
+            path.push("");
+        } else {
+            path.push(name);
+        }
+    }
+
+    url.set_query(query.as_deref());
+
+    Ok(url)
+}
+
+/// Formats a time as a W3C datetime in UTC
+fn w3c_datetime(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Convert days since the epoch to a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Returns the local name of the root element of an XML document
+pub fn root_element(xml: &str) -> Option<&str> {
+    let mut rest = xml;
+
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+
+        if rest.starts_with('?') || rest.starts_with('!') {
+            // Skip declarations, processing instructions and comments
+            let end = if rest.starts_with("!--") {
+                rest.find("-->")? + 3
+            } else {
+                rest.find('>')? + 1
+            };
+
+            rest = &rest[end..];
+        } else {
+            let end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/')?;
+            let name = &rest[..end];
+
+            // Strip any namespace prefix
+            return Some(match name.split_once(':') {
+                Some((_, local)) => local,
+                None => name,
+            });
+        }
+    }
+}
+
+/// Escapes XML special characters
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Replaces XML character entities
+pub fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
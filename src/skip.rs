@@ -2,6 +2,38 @@ use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
 
+/// Files mirrorurl keeps its state in in the target directory
+const STATE_FILES: [&str; 9] = [
+    ".etags.json",
+    ".manifest.json",
+    ".mirrorurl-state.json",
+    ".hosts.json",
+    ".names.json",
+    ".listing-dates.json",
+    ".last-run.json",
+    ".feed-cursors.json",
+    ".mirrorurl.lock",
+];
+
+/// Returns true if a relative file path is one of mirrorurl's own state or temporary files, as
+/// found in the listings of another mirror which is served over HTTP
+pub fn is_state_file(rel_path: &str) -> bool {
+    let path = rel_path.split_once('?').map_or(rel_path, |(path, _)| path);
+
+    let mut components = path.split('/');
+    let name = components.next_back().unwrap_or_default();
+
+    // Quarantine and staging directories
+    if components.any(|dir| dir == ".mirrorurl") {
+        return true;
+    }
+
+    // State files are saved via temporary files
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+
+    STATE_FILES.contains(&name) || name.ends_with(".mirrorurl")
+}
+
 /// Holds a list for partial file paths to skip downloading
 #[derive(Default)]
 pub struct SkipList {
@@ -4,7 +4,7 @@ use std::fmt::Display;
 use url::ParseError;
 
 /// Reason for skipping a file
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SkipReason {
     Transport,
     SkipList,
@@ -14,6 +14,28 @@ pub enum SkipReason {
     NotValid(ParseError),
     RedirectNotRel(String),
     TooManyRedirects,
+    Robots,
+    NoFollow,
+    NoIndex,
+    NotOnly,
+    Extension,
+    Shard,
+    Size(u64),
+    /// MIME type is not accepted
+    MimeType(String),
+    Empty,
+    UpToDate,
+    TooNew,
+    Collision(String),
+    Excluded,
+    /// Content matches a hash in the blocklist
+    Blocked(String),
+    /// Response status is in --ignore-status
+    Status(u16),
+    /// Path is a state file of another mirror
+    StateFile,
+    /// Feed entry was published before the last run
+    FeedSeen,
 }
 
 impl Display for SkipReason {
@@ -28,12 +50,29 @@ impl Display for SkipReason {
             NotValid(e) => write!(f, "URL is not valid: {e}"),
             RedirectNotRel(to) => write!(f, "Redirect to {to} is not relative to the base URL"),
             TooManyRedirects => f.write_str("Too many redirects"),
+            Robots => f.write_str("Path is disallowed by robots.txt"),
+            NoFollow => f.write_str("Page links are marked nofollow"),
+            NoIndex => f.write_str("Page is marked noindex"),
+            NotOnly => f.write_str("Path is outside the --only sub-trees"),
+            Extension => f.write_str("File extension is not accepted"),
+            Shard => f.write_str("File is in another shard"),
+            Size(size) => write!(f, "File size {size} is outside the size limits"),
+            MimeType(mime_type) => write!(f, "MIME type {mime_type} is not accepted"),
+            Empty => f.write_str("Response body is empty"),
+            UpToDate => f.write_str("Local file is up to date"),
+            TooNew => f.write_str("File was modified more recently than --min-age"),
+            Collision(url) => write!(f, "Local file name is already used by {url}"),
+            Excluded => f.write_str("Path matches an exclude pattern"),
+            Blocked(hash) => write!(f, "Content matches blocked hash {hash}"),
+            Status(status) => write!(f, "Status {status} is ignored"),
+            StateFile => f.write_str("Path is a mirrorurl state file"),
+            FeedSeen => f.write_str("Feed entry was published before the last run"),
         }
     }
 }
 
 /// Error encapsulation a skipped file reason
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SkipReasonErr {
     /// The skipped URL
     url: String,
@@ -46,6 +85,16 @@ impl SkipReasonErr {
     pub fn new(url: String, reason: SkipReason) -> Self {
         Self { url, reason }
     }
+
+    /// Returns the skipped URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the reason for skipping
+    pub fn reason(&self) -> &SkipReason {
+        &self.reason
+    }
 }
 
 impl Display for SkipReasonErr {
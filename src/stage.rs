@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{
+    copy,
+    create_dir_all,
+    hard_link,
+    read_dir,
+    read_link,
+    remove_dir_all,
+    remove_file,
+    rename,
+};
+
+use crate::lock::LOCK_FILE;
+use crate::output::{error, output};
+
+/// Staging directory a run mirrors in to before it replaces the live mirror. The live target
+/// becomes a symbolic link to a generation directory ({target}.1, {target}.2 ...) and the link
+/// is replaced atomically once the run completes
+pub struct Stage {
+    /// Live target path
+    target: PathBuf,
+    /// Staging directory
+    dir: PathBuf,
+}
+
+impl Stage {
+    /// Prepares the staging directory ({target}.stage). A staging directory left by a run which
+    /// did not complete is carried on with, otherwise it is populated with hard links to the
+    /// files in the live mirror. Files which are rewritten in place (hidden state files and
+    /// index pages) are copied instead so the live copies are left alone
+    pub async fn prepare(
+        target: &str,
+        index_name: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let target = PathBuf::from(target);
+        let dir = sibling(&target, ".stage")?;
+
+        if dir.is_dir() {
+            output!("Continuing staged mirror in {}", dir.display());
+        } else if target.is_dir() {
+            // Populate a temporary directory first so an incomplete copy is never carried on with
+            let tmp_dir = sibling(&target, ".stage.mirrorurl")?;
+
+            if tmp_dir.is_dir() {
+                remove_dir_all(&tmp_dir).await.map_err(|e| {
+                    format!("Unable to remove directory {}: {e}", tmp_dir.display())
+                })?;
+            }
+
+            link_tree(&target, &tmp_dir, index_name).await?;
+
+            rename(&tmp_dir, &dir)
+                .await
+                .map_err(|e| format!("Unable to rename {}: {e}", tmp_dir.display()))?;
+        }
+
+        Ok(Self { target, dir })
+    }
+
+    /// Returns the staging directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Makes the staging directory the next generation of the live mirror and points the live
+    /// target at it, removing the previous generation. A live target which is a directory
+    /// rather than a link is moved aside first, so is missing briefly the first time only
+    pub async fn swap(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Find the current generation
+        let old_link = read_link(&self.target).await.ok();
+        let old_generation = old_link
+            .as_deref()
+            .and_then(|link| generation(&self.target, link));
+
+        // Move the staging directory to the next generation's directory
+        let gen_dir = sibling(
+            &self.target,
+            &format!(".{}", old_generation.unwrap_or(0) + 1),
+        )?;
+
+        if gen_dir.is_dir() {
+            remove_dir_all(&gen_dir)
+                .await
+                .map_err(|e| format!("Unable to remove directory {}: {e}", gen_dir.display()))?;
+        }
+
+        if !self.dir.is_dir() {
+            create_dir_all(&self.dir)
+                .await
+                .map_err(|e| format!("Unable to create directory {}: {e}", self.dir.display()))?;
+        }
+
+        rename(&self.dir, &gen_dir)
+            .await
+            .map_err(|e| format!("Unable to rename {}: {e}", self.dir.display()))?;
+
+        // Work out what to remove afterwards
+        let old_dir = match (&old_link, old_generation) {
+            (Some(_), Some(old_generation)) => {
+                Some(sibling(&self.target, &format!(".{old_generation}"))?)
+            }
+            (Some(_), None) => None,
+            (None, _) if self.target.is_dir() => {
+                let aside = sibling(&self.target, ".old")?;
+
+                rename(&self.target, &aside)
+                    .await
+                    .map_err(|e| format!("Unable to rename {}: {e}", self.target.display()))?;
+
+                Some(aside)
+            }
+            (None, _) => None,
+        };
+
+        // Point a new link at the generation and rename it over the live target
+        let link = sibling(&self.target, ".link")?;
+        let _ = remove_file(&link).await;
+
+        let gen_name = gen_dir.file_name().map(PathBuf::from).unwrap_or_default();
+
+        symlink_dir(&gen_name, &link)
+            .await
+            .map_err(|e| format!("Unable to create link {}: {e}", link.display()))?;
+
+        rename(&link, &self.target)
+            .await
+            .map_err(|e| format!("Unable to replace {}: {e}", self.target.display()))?;
+
+        output!(
+            "Swapped in staged mirror {} as {}",
+            gen_dir.display(),
+            self.target.display()
+        );
+
+        // Remove the previous generation
+        if let Some(old_dir) = old_dir {
+            if let Err(e) = remove_dir_all(&old_dir).await {
+                error!("Unable to remove directory {}: {e}", old_dir.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns a path next to a path with a suffix added to its file name
+fn sibling(path: &Path, suffix: &str) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let mut name = match path.file_name() {
+        Some(name) => OsString::from(name),
+        None => Err(format!(
+            "Unable to stage {} as it has no name",
+            path.display()
+        ))?,
+    };
+
+    name.push(suffix);
+
+    Ok(path.with_file_name(name))
+}
+
+/// Returns the generation number of the directory a live target link points to, or None if it
+/// doesn't point to a generation directory next to the target
+fn generation(target: &Path, link: &Path) -> Option<u64> {
+    if link
+        .parent()
+        .is_some_and(|parent| !parent.as_os_str().is_empty())
+    {
+        return None;
+    }
+
+    let target_name = target.file_name()?.to_str()?;
+
+    link.to_str()?
+        .strip_prefix(target_name)?
+        .strip_prefix('.')?
+        .parse()
+        .ok()
+}
+
+/// Recreates a directory tree using hard links to the files in it. Hidden files and index pages
+/// are copied, and temporary files and the lock file are left out
+async fn link_tree(
+    src: &Path,
+    dst: &Path,
+    index_name: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut dirs = VecDeque::new();
+    dirs.push_back((src.to_path_buf(), dst.to_path_buf()));
+
+    while let Some((src_dir, dst_dir)) = dirs.pop_front() {
+        create_dir_all(&dst_dir)
+            .await
+            .map_err(|e| format!("Unable to create directory {}: {e}", dst_dir.display()))?;
+
+        let mut paths = read_dir(&src_dir)
+            .await
+            .map_err(|e| format!("Unable to read directory {}: {e}", src_dir.display()))?;
+
+        while let Some(dirent) = paths
+            .next_entry()
+            .await
+            .map_err(|e| format!("Unable to read directory {}: {e}", src_dir.display()))?
+        {
+            let name = dirent.file_name().to_string_lossy().into_owned();
+
+            // Leave out temporary files and the target lock
+            if name.ends_with(".mirrorurl") || name.starts_with(LOCK_FILE) {
+                continue;
+            }
+
+            let src_path = dirent.path();
+            let dst_path = dst_dir.join(dirent.file_name());
+            let file_type = dirent.file_type().await?;
+
+            if file_type.is_dir() {
+                dirs.push_back((src_path, dst_path));
+            } else if file_type.is_symlink() {
+                let link = read_link(&src_path).await?;
+
+                symlink_dir(&link, &dst_path)
+                    .await
+                    .map_err(|e| format!("Unable to create link {}: {e}", dst_path.display()))?;
+            } else if name.starts_with('.')
+                || name == index_name
+                || hard_link(&src_path, &dst_path).await.is_err()
+            {
+                copy(&src_path, &dst_path)
+                    .await
+                    .map_err(|e| format!("Unable to copy {}: {e}", src_path.display()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a symbolic link to a directory
+#[cfg(unix)]
+async fn symlink_dir(original: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink(original, link).await
+}
+
+/// Creates a symbolic link to a directory
+#[cfg(windows)]
+async fn symlink_dir(original: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink_dir(original, link).await
+}
@@ -1,56 +1,239 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use reqwest::redirect::Policy;
-use reqwest::Client;
-use tokio::sync::{Mutex, MutexGuard, OwnedSemaphorePermit, Semaphore};
+use reqwest::{Certificate, Client, Identity};
+use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
 use crate::args::Args;
-use crate::etags::ETags;
-use crate::output::debug;
-use crate::skip::SkipList;
+use crate::audit::Audit;
+use crate::blocklist::HashBlocklist;
+use crate::checkpoint::Checkpoint;
+use crate::checksum::Checksums;
+use crate::etags::{ETags, SharedETags};
+use crate::events::EventSink;
+use crate::exclude::matches_pattern;
+use crate::feed::{is_feed_path, FeedCursor, FeedCursors};
+use crate::filename::decode_path;
+use crate::fsname::{legal_path, shorten_path};
+use crate::ftp::FtpPool;
+use crate::hash::ExpectedHash;
+use crate::hosts::{HostCapabilities, HostCaps};
+use crate::interstitial::Reauth;
+use crate::limiter::{Limiter, Slot, SlotUsage};
+use crate::listdates::ListingDates;
+use crate::listing::ListedSize;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::mime::{Mime, MimeExt};
+use crate::namemap::NameMap;
+use crate::output::{debug, output};
+use crate::policy::CrawlPolicy;
+use crate::publish::{Deferred, PublishOrder, DEFAULT_METADATA_GLOBS};
+use crate::redirects::{RedirectHop, RedirectLog};
+use crate::resolve::Resolve;
+use crate::robots::Robots;
+use crate::s3::{bucket_root, is_bucket_listing};
+use crate::sitemap::is_sitemap_path;
+use crate::skip::{is_state_file, SkipList};
 use crate::skipreason::{SkipReason, SkipReasonErr};
-use crate::stats::Stats;
-use crate::url::{Url, UrlExt};
+use crate::stats::{AtomicStats, Stats};
+use crate::store::MetadataStore;
+use crate::transport::Transports;
+use crate::url::{HostScope, Url, UrlExt};
+use crate::wayback::Wayback;
 
 /// Program state shared between all threads
 pub struct State {
     /// Base URL
     url: Url,
-    /// Set of processed URLs
-    processed_urls: Mutex<HashSet<Url>>,
+    /// Starting URLs
+    start_urls: Vec<Url>,
+    /// Link crawl policy
+    policy: CrawlPolicy,
+    /// Set of processed URLs in normal form
+    processed_urls: Mutex<HashSet<String>>,
+    /// Local files claimed by URLs in normal form when decoding or flattening file names
+    local_paths: Mutex<HashMap<PathBuf, String>>,
+    /// Digests expected for URLs from link hash fragments
+    expected_hashes: Mutex<HashMap<Url, ExpectedHash>>,
+    /// File sizes given by directory listings
+    listed_sizes: Mutex<HashMap<Url, ListedSize>>,
+    /// File dates given by directory listings
+    listed_dates: Mutex<HashMap<Url, String>>,
+    /// Redirect chains followed by the HTTP client
+    redirects: RedirectLog,
     /// Etags file path as a string
     etags_file: String,
+    /// Shared metadata store
+    store: Option<MetadataStore>,
     /// Old etags collection (loaded at startup)
     old_etags: ETags,
     /// New etags collection (added to whilst running)
-    new_etags: Mutex<ETags>,
+    new_etags: SharedETags,
+    /// Crawl state file path as a string
+    checkpoint_file: String,
+    /// Crawl progress
+    checkpoint: Mutex<Checkpoint>,
+    /// Manifest file path as a string
+    manifest_file: String,
+    /// Manifest of saved files
+    manifest: Mutex<Manifest>,
+    /// Details recorded for the post-run audit
+    audit: Option<Audit>,
+    /// Downloaded metadata files waiting to be published
+    deferred: Mutex<Vec<Deferred>>,
+    /// Published checksums to verify downloads against
+    checksums: Option<Checksums>,
+    /// Re-authentication prompt state
+    reauth: Reauth,
+    /// Host capabilities file path as a string
+    hosts_file: String,
+    /// Name map file path
+    names_file: String,
+    /// Shortened local paths
+    name_map: Mutex<NameMap>,
+    /// Listing dates file path as a string
+    listing_dates_file: String,
+    /// Listing dates of downloaded files
+    listing_dates: Mutex<ListingDates>,
+    /// Feed cursors file path as a string
+    feed_cursors_file: String,
+    /// Publication time of the newest entry seen in each feed
+    feed_cursors: Mutex<FeedCursors>,
+    /// Learnt host capabilities
+    host_caps: Mutex<HostCapabilities>,
+    /// Idle FTP connections
+    ftp_pool: FtpPool,
     /// File skip list
     skip_list: SkipList,
-    /// Concurrect fetch semaphore
-    conc_sem: Arc<Semaphore>,
+    /// Digests of content which must not be mirrored
+    blocklist: Option<HashBlocklist>,
+    /// robots.txt rules for the base URL host
+    robots: Robots,
+    /// Concurrent fetch limiter
+    limiter: Limiter,
     /// HTTP client
     client: Client,
+    /// Wayback Machine URL rewriter
+    wayback: Option<Wayback>,
+    /// Transports by URL scheme
+    transports: Transports,
     /// Command line arguments
     args: Args,
     /// Statistics
-    stats: Mutex<Stats>,
+    stats: AtomicStats,
+    /// Event notifications
+    events: Arc<dyn EventSink>,
+    /// Current debug level
+    debug_level: AtomicU8,
+    /// Set when the run has been interrupted
+    interrupted: AtomicBool,
+    /// Reason the run was aborted if it was
+    aborted: std::sync::Mutex<Option<String>>,
 }
 
+/// Parts of the state kept between runs in watch mode
+pub struct WarmState {
+    /// HTTP client with its open connections
+    client: Client,
+    /// Redirect log shared with the client's redirect policy
+    redirects: RedirectLog,
+    /// Etags collected so far
+    etags: ETags,
+}
+
+/// Maximum debug level
+const MAX_DEBUG_LEVEL: u8 = 3;
+
 impl State {
-    /// Creates the state
-    pub fn new(args: Args) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        // Make sure the URL parses first
-        let url = Url::parse(&args.url)?;
+    /// Creates the state, reusing the HTTP client and etags from the last run if given
+    pub async fn new(
+        args: Args,
+        events: Arc<dyn EventSink>,
+        warm: Option<WarmState>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        // Make sure the URLs parse first
+        let mut start_urls = root_urls(&args)?
+            .iter()
+            .map(|url| parse_root_url(url))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Build DNS overrides
+        let mut resolves = args.resolve.clone();
+
+        if let Some(name) = &args.host_header {
+            // Connect to the URLs' address whilst presenting the virtual host name
+            for start_url in &mut start_urls {
+                let resolve = Resolve::for_host_header(start_url, name)?;
+
+                if !resolves.contains(&resolve) {
+                    resolves.push(resolve);
+                }
+            }
+        }
+
+        // --feed URLs are feeds whatever their path
+        let feeds = args
+            .feed
+            .iter()
+            .filter_map(|feed| Url::parse(feed).ok())
+            .collect::<Vec<_>>();
+
+        // A sitemap or feed URL crawls the directory containing it, and a bucket listing the
+        // bucket
+        let roots = start_urls
+            .iter()
+            .map(|start_url| {
+                if is_sitemap_path(start_url)
+                    || is_feed_path(start_url)
+                    || feeds.contains(start_url)
+                {
+                    start_url.join("./")
+                } else if is_bucket_listing(start_url) {
+                    Ok(bucket_root(start_url))
+                } else {
+                    Ok(start_url.clone())
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Multiple URLs are mirrored from their common parent directory
+        let url = common_base(&roots)?;
+
+        // Create transport registry
+        let transports = Transports::new();
 
         // Check the URL is processable
-        url.is_handled()?;
+        transports.is_handled(&url)?;
 
-        // Create HTTP client
-        let client = Self::create_http_client(&args, url.clone())?;
+        // Build the crawl policy
+        let mut policy = CrawlPolicy::new(&args, &url)?;
+
+        // Restrict the crawl to the sub-tree of each URL
+        if roots.len() > 1 {
+            for root in &roots {
+                if let Some(rel) = root.relative_path(&url) {
+                    policy.add_only(rel);
+                }
+            }
+        }
+
+        // Create HTTP client, logging the redirects it follows
+        let (client, redirects, warm_etags) = match warm {
+            Some(warm) => (warm.client, warm.redirects, Some(warm.etags)),
+            None => {
+                let redirects = RedirectLog::default();
+                let client =
+                    Self::create_http_client(&args, policy.scope().clone(), &resolves, &redirects)?;
+
+                (client, redirects, None)
+            }
+        };
 
         // Build etags file path
         let mut etags_file = PathBuf::from(&args.target);
@@ -59,13 +242,115 @@ impl State {
             .to_str()
             .ok_or("Unable to build path to .etags")?;
 
+        // Pruning would drop etags which other processes sharing the store still rely on
+        if args.prune_etags && args.metadata_store.is_some() {
+            Err("--prune-etags can't be used with --metadata-store")?
+        }
+
+        // Open the shared metadata store
+        let store = match &args.metadata_store {
+            Some(file) => Some(MetadataStore::open(file)?),
+            None => None,
+        };
+
         let etags = if args.no_etags {
             ETags::default()
+        } else if let Some(etags) = warm_etags {
+            // Carry on with the etags from the last run
+            etags
+        } else if let Some(store) = &store {
+            // Load etags from the metadata store
+            store.load_etags().await?
         } else {
             // Load etags if present
-            ETags::new_from_file(etags_file)?
+            let etags = ETags::new_from_file(etags_file)?;
+
+            // Etags from another mirror would give the wrong answers
+            if let Some(base) = etags.base().filter(|base| *base != url.as_str()) {
+                if args.force {
+                    output!("Warning: {etags_file} was saved mirroring {base}, not {url}");
+                } else {
+                    Err(format!(
+                        "{etags_file} was saved mirroring {base}, not {url} (use --force to use it anyway)"
+                    ))?
+                }
+            }
+
+            etags
+        };
+
+        // Build crawl state file path
+        let mut checkpoint_file = PathBuf::from(&args.target);
+        checkpoint_file.push(".mirrorurl-state.json");
+        let checkpoint_file = checkpoint_file
+            .to_str()
+            .ok_or("Unable to build path to .mirrorurl-state")?;
+
+        let checkpoint = if args.resume {
+            // Load crawl state if present
+            Checkpoint::new_from_file(checkpoint_file)?
+        } else {
+            Checkpoint::default()
+        };
+
+        // Build manifest file path
+        let mut manifest_file = PathBuf::from(&args.target);
+        manifest_file.push(".manifest.json");
+        let manifest_file = manifest_file
+            .to_str()
+            .ok_or("Unable to build path to .manifest")?;
+
+        let manifest = if args.manifest {
+            // Load manifest if present
+            Manifest::new_from_file(manifest_file)?
+        } else {
+            Manifest::default()
         };
 
+        // Build host capabilities file path
+        let mut hosts_file = PathBuf::from(&args.target);
+        hosts_file.push(".hosts.json");
+        let hosts_file = hosts_file
+            .to_str()
+            .ok_or("Unable to build path to .hosts")?;
+
+        // Load host capabilities if present
+        let host_caps = HostCapabilities::new_from_file(hosts_file)?;
+
+        // Build name map file path
+        let mut names_file = PathBuf::from(&args.target);
+        names_file.push(".names.json");
+        let names_file = names_file
+            .to_str()
+            .ok_or("Unable to build path to .names")?;
+
+        // Load name map if present
+        let name_map = NameMap::new_from_file(names_file)?;
+
+        // Build listing dates file path
+        let mut listing_dates_file = PathBuf::from(&args.target);
+        listing_dates_file.push(".listing-dates.json");
+        let listing_dates_file = listing_dates_file
+            .to_str()
+            .ok_or("Unable to build path to .listing-dates")?;
+
+        let listing_dates = if args.listing_dates {
+            // Load listing dates if present
+            ListingDates::new_from_file(listing_dates_file)?
+        } else {
+            ListingDates::default()
+        };
+
+        // Build feed cursors file path
+        let mut feed_cursors_file = PathBuf::from(&args.target);
+        feed_cursors_file.push(".feed-cursors.json");
+        let feed_cursors_file = feed_cursors_file
+            .to_str()
+            .ok_or("Unable to build path to .feed-cursors")?;
+
+        // Load feed cursors if present
+        let feed_cursors = FeedCursors::new_from_file(feed_cursors_file)?;
+
         // Load skip list
         let skip_list = if let Some(skip_file) = &args.skip_file {
             SkipList::new_from_file(skip_file)?
@@ -73,23 +358,97 @@ impl State {
             SkipList::new()
         };
 
+        // Load hash blocklist
+        let blocklist = args
+            .blocklist_hashes
+            .as_deref()
+            .map(HashBlocklist::new_from_file)
+            .transpose()?;
+
         Ok(Self {
             url,
+            start_urls,
+            policy,
             processed_urls: Mutex::new(HashSet::new()),
+            local_paths: Mutex::new(HashMap::new()),
+            expected_hashes: Mutex::new(HashMap::new()),
+            listed_sizes: Mutex::new(HashMap::new()),
+            listed_dates: Mutex::new(HashMap::new()),
+            redirects,
             etags_file: etags_file.to_string(),
+            store,
             old_etags: etags,
-            new_etags: Mutex::new(ETags::default()),
+            new_etags: SharedETags::default(),
+            checkpoint_file: checkpoint_file.to_string(),
+            checkpoint: Mutex::new(checkpoint),
+            manifest_file: manifest_file.to_string(),
+            manifest: Mutex::new(manifest),
+            audit: args.audit.then(Audit::default),
+            deferred: Mutex::new(Vec::new()),
+            checksums: args.verify.map(Checksums::new),
+            reauth: Reauth::default(),
+            hosts_file: hosts_file.to_string(),
+            host_caps: Mutex::new(host_caps),
+            ftp_pool: FtpPool::default(),
+            names_file: names_file.to_string(),
+            name_map: Mutex::new(name_map),
+            listing_dates_file: listing_dates_file.to_string(),
+            listing_dates: Mutex::new(listing_dates),
+            feed_cursors_file: feed_cursors_file.to_string(),
+            feed_cursors: Mutex::new(feed_cursors),
             skip_list,
-            conc_sem: Arc::new(Semaphore::new(args.concurrent_fetch)),
+            blocklist,
+            robots: Robots::default(),
+            limiter: Limiter::new(args.concurrent_fetch, args.concurrent_per_host),
             client,
+            wayback: Wayback::from_args(&args)?,
+            transports,
+            debug_level: AtomicU8::new(args.debug),
+            interrupted: AtomicBool::new(false),
+            aborted: std::sync::Mutex::new(None),
             args,
-            stats: Mutex::new(Stats::default()),
+            stats: AtomicStats::default(),
+            events,
         })
     }
 
-    /// Returns a reference to the starting URL
-    pub fn url(&self) -> &Url {
-        &self.url
+    /// Fetches the robots.txt rules for the base URL host unless disabled
+    pub async fn load_robots(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.args.ignore_robots && matches!(self.url.scheme(), "http" | "https") {
+            self.robots = Robots::fetch(&self.client, &self.url).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the URL is not disallowed by the base URL host's robots.txt. The rules only apply
+    /// to the same host and port
+    pub fn check_robots(&self, url: &Url) -> Result<(), SkipReasonErr> {
+        if url.host_str() == self.url.host_str()
+            && url.port_or_known_default() == self.url.port_or_known_default()
+            && !self.robots.allowed(url)
+        {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::Robots))?
+        }
+
+        Ok(())
+    }
+
+    /// Returns the starting URLs
+    pub fn start_urls(&self) -> &[Url] {
+        &self.start_urls
+    }
+
+    /// Returns a reference to the transport registry
+    pub fn transports(&self) -> &Transports {
+        &self.transports
+    }
+
+    /// Checks a discovered link has a supported transport and is allowed by the crawl policy
+    pub fn check_link(&self, url: Url) -> Result<Url, SkipReasonErr> {
+        self.transports.is_handled(&url)?;
+
+        self.policy.check(url)
     }
 
     /// Returns a reference to the HTTP client
@@ -97,58 +456,580 @@ impl State {
         &self.client
     }
 
+    /// Returns the URL to request for a URL - its snapshot URL if mirroring via the Wayback Machine
+    pub fn request_url(&self, url: &Url) -> Url {
+        match &self.wayback {
+            Some(wayback) => wayback.snapshot_url(url),
+            None => url.clone(),
+        }
+    }
+
+    /// Returns the URL a response is for, mapping snapshot URLs back to the original URL
+    pub fn response_url(&self, url: &Url) -> Url {
+        match self.wayback.as_ref().and_then(|w| w.original_url(url)) {
+            Some(original_url) => original_url,
+            None => url.clone(),
+        }
+    }
+
     /// Adds a URL to the processed list. Returns false if URL alredy seen
-    pub async fn add_processed_url(&self, url: Url) -> bool {
-        self.processed_urls.lock().await.insert(url)
+    pub async fn add_processed_url(&self, url: &Url) -> bool {
+        self.processed_urls.lock().await.insert(url.normalised())
+    }
+
+    /// Records the digest expected for a URL. The first digest seen for a URL is kept
+    pub async fn add_expected_hash(&self, url: &Url, hash: ExpectedHash) {
+        self.expected_hashes
+            .lock()
+            .await
+            .entry(url.clone())
+            .or_insert(hash);
+    }
+
+    /// Looks up the digest expected for a URL
+    pub async fn expected_hash(&self, url: &Url) -> Option<ExpectedHash> {
+        self.expected_hashes.lock().await.get(url).cloned()
+    }
+
+    /// Records the size given for a URL by a directory listing
+    pub async fn add_listed_size(&self, url: &Url, size: ListedSize) {
+        self.listed_sizes.lock().await.insert(url.clone(), size);
+    }
+
+    /// Looks up the size given for a URL by a directory listing
+    pub async fn listed_size(&self, url: &Url) -> Option<ListedSize> {
+        self.listed_sizes.lock().await.get(url).copied()
+    }
+
+    /// Records the date given for a URL by a directory listing
+    pub async fn add_listed_date(&self, url: &Url, date: &str) {
+        self.listed_dates
+            .lock()
+            .await
+            .insert(url.clone(), date.to_string());
+    }
+
+    /// Returns true if the date given for a URL by a directory listing is the same as when it
+    /// was downloaded and the local file is still present
+    pub async fn is_listed_unchanged(&self, url: &Url) -> bool {
+        let Some(date) = self.listed_dates.lock().await.get(url).cloned() else {
+            return false;
+        };
+
+        if self.listing_dates.lock().await.get(url.as_str()) != Some(date.as_str()) {
+            return false;
+        }
+
+        self.path_for_url(url)
+            .await
+            .is_ok_and(|path| path.is_file())
+    }
+
+    /// Records the date given by a directory listing for a downloaded URL
+    pub async fn record_listed_date(&self, url: &Url) {
+        if let Some(date) = self.listed_dates.lock().await.get(url) {
+            self.listing_dates.lock().await.add(url.as_str(), date);
+        }
+    }
+
+    /// Returns true if content with a SHA-256 digest is on the hash blocklist
+    pub fn is_blocked(&self, sha256: &str) -> bool {
+        self.blocklist
+            .as_ref()
+            .is_some_and(|blocklist| blocklist.contains(sha256))
+    }
+
+    /// Returns the published checksums if downloads are being verified
+    pub fn checksums(&self) -> Option<&Checksums> {
+        self.checksums.as_ref()
+    }
+
+    /// Returns the re-authentication prompt state
+    pub fn reauth(&self) -> &Reauth {
+        &self.reauth
+    }
+
+    /// Returns the idle FTP connections
+    pub fn ftp_pool(&self) -> &FtpPool {
+        &self.ftp_pool
     }
 
     /// Acquire a download slot
-    pub async fn acquire_slot(&self) -> Result<OwnedSemaphorePermit, Box<dyn Error + Send + Sync>> {
-        Ok(self.conc_sem.clone().acquire_owned().await?)
+    pub async fn acquire_slot(&self, url: &Url) -> Result<Slot, Box<dyn Error + Send + Sync>> {
+        self.limiter.acquire(url, self.is_hot(url)).await
+    }
+
+    /// Takes a download slot if one is free without waiting
+    pub fn try_acquire_slot(&self, url: &Url) -> Option<Slot> {
+        self.limiter.try_acquire(url)
+    }
+
+    /// Returns true if a URL matches one of the --hot patterns
+    pub fn is_hot(&self, url: &Url) -> bool {
+        self.args
+            .hot
+            .iter()
+            .any(|pattern| matches_pattern(pattern, url, &self.url))
+    }
+
+    /// Returns true if a downloaded URL must only replace the file in the mirror once all other
+    /// files have been downloaded
+    pub fn defers_publish(&self, url: &Url) -> bool {
+        if self.args.publish_order != PublishOrder::DataFirst {
+            return false;
+        }
+
+        if self.args.metadata_glob.is_empty() {
+            DEFAULT_METADATA_GLOBS
+                .iter()
+                .any(|pattern| matches_pattern(pattern, url, &self.url))
+        } else {
+            self.args
+                .metadata_glob
+                .iter()
+                .any(|pattern| matches_pattern(pattern, url, &self.url))
+        }
+    }
+
+    /// Returns the post-run audit details if auditing
+    pub fn audit(&self) -> Option<&Audit> {
+        self.audit.as_ref()
+    }
+
+    /// Adds a downloaded file to the list waiting to be published
+    pub async fn defer_publish(&self, deferred: Deferred) {
+        self.deferred.lock().await.push(deferred);
+    }
+
+    /// Removes and returns the downloaded files waiting to be published
+    pub async fn take_deferred(&self) -> Vec<Deferred> {
+        std::mem::take(&mut *self.deferred.lock().await)
+    }
+
+    /// Returns the current download slot usage
+    pub fn slot_usage(&self) -> SlotUsage {
+        self.limiter.usage()
     }
 
     /// Build file relative path for a given URL
-    pub async fn path_for_url(&self, url: &Url) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    pub async fn path_for_url(&self, url: &Url) -> Result<PathBuf, SkipReasonErr> {
+        let (mut path, short) = self.plain_path_for_url(url)?;
+
+        // Record the URL shortened names came from
+        if let Some(short) = short {
+            self.name_map.lock().await.add(&short, url.as_str());
+        }
+
+        // Flattened file names collide with each other, so later URLs get numbered names
+        if self.args.flatten {
+            path = self.claim_flat_path(path, url).await;
+        }
+
+        // Decoded file names can collide with each other
+        if self.args.decode_filenames {
+            let mut local_paths = self.local_paths.lock().await;
+            let claimant = local_paths
+                .entry(path.clone())
+                .or_insert_with(|| url.normalised());
+
+            if *claimant != url.normalised() {
+                Err(SkipReasonErr::new(
+                    url.to_string(),
+                    SkipReason::Collision(claimant.clone()),
+                ))?
+            }
+        }
+
+        debug!(self, 2, "URL {url} maps to file {}", path.display());
+
+        Ok(path)
+    }
+
+    /// Returns the local path a URL was saved to without claiming a path for it, or None if the
+    /// path belongs to another URL
+    pub async fn saved_path_for_url(&self, url: &Url) -> Result<Option<PathBuf>, SkipReasonErr> {
+        let (path, _) = self.plain_path_for_url(url)?;
+        let normalised = url.normalised();
+
+        // Colliding flattened names are in the name map
+        if self.args.flatten {
+            if let Some(name) = self.name_map.lock().await.path(&normalised) {
+                let candidate = Path::new(&self.args.target).join(name);
+
+                if candidate.parent() == path.parent() {
+                    return Ok(Some(candidate));
+                }
+            }
+        }
+
+        if self.args.flatten || self.args.decode_filenames {
+            let local_paths = self.local_paths.lock().await;
+
+            if local_paths
+                .get(&path)
+                .is_some_and(|claimant| *claimant != normalised)
+            {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(path))
+    }
+
+    /// Builds the local path for a URL before any collisions are resolved, returning the path
+    /// relative to the target directory as well if it had to be shortened
+    fn plain_path_for_url(&self, url: &Url) -> Result<(PathBuf, Option<String>), SkipReasonErr> {
         // Start with download directory
         let mut path = PathBuf::from(&self.args.target);
 
         // Get relative path of the URL from the base
-        let rel = match url.relative_path(&self.url) {
-            Some(rel) => rel,
+        let (rel, other_host) = match url.relative_path(&self.url) {
+            Some(rel) => (rel, false),
+            None if self.policy.scope().is_allowed_host(url) => {
+                (url.full_path().trim_start_matches('/'), true)
+            }
             None => Err(SkipReasonErr::new(url.to_string(), SkipReason::NotRelative))?,
         };
 
-        if rel.is_empty() {
-            // Not relative - use the unnamed file name
-            path.push(&self.args.unnamed);
+        let local = if rel.is_empty() {
+            if url.path().ends_with('/') {
+                // Directory style URL - use the index file name
+                self.args.index_name.clone()
+            } else {
+                // Not relative - use the unnamed file name
+                self.args.unnamed.clone()
+            }
         } else {
             // Is it in the skip list?
             if self.skip_list.find(rel) {
                 Err(SkipReasonErr::new(url.to_string(), SkipReason::SkipList))?
             }
 
-            // Use relative path
-            path.push(rel);
+            // Is it another mirror's state file?
+            if is_state_file(rel) {
+                Err(SkipReasonErr::new(url.to_string(), SkipReason::StateFile))?
+            }
+
+            // Decode percent escapes in the path
+            let decoded;
+
+            let rel = if self.args.decode_filenames {
+                decoded = match rel.split_once('?') {
+                    Some((rel_path, query)) => format!("{}?{query}", decode_path(rel_path)),
+                    None => decode_path(rel),
+                };
+                &decoded
+            } else {
+                rel
+            };
+
+            match rel.split_once('?') {
+                Some((rel_path, query)) => {
+                    // Use relative path with the query string encoded in to the file name
+                    let (dir, name) = match rel_path.rsplit_once('/') {
+                        Some((dir, name)) => (format!("{dir}/"), name),
+                        None => (String::new(), rel_path),
+                    };
+
+                    let name = if name.is_empty() {
+                        &self.args.index_name
+                    } else {
+                        name
+                    };
+
+                    format!("{dir}{name}%3F{}", query.replace('/', "%2F"))
+                }
+                None => {
+                    if rel.ends_with('/') {
+                        // Directory style URL - use the index file name
+                        format!("{rel}{}", self.args.index_name)
+                    } else {
+                        // Use relative path
+                        rel.to_string()
+                    }
+                }
+            }
+        };
+
+        // Drop leading directories
+        let local = if self.args.cut_dirs > 0 {
+            cut_dirs(&local, self.args.cut_dirs)
+        } else {
+            local
+        };
+
+        // Flatten deep directory trees, or drop the directories altogether
+        let local = match self.args.flatten_depth {
+            Some(depth) => flatten_path(&local, depth),
+            None if self.args.flatten => match local.rsplit_once('/') {
+                Some((_, name)) => name.to_string(),
+                None => local,
+            },
+            None => local,
+        };
+
+        // Keep other hosts' files in a directory named after the host so they can't collide with
+        // the base URL's files, unless host directories are turned off
+        let host_directory =
+            self.args.host_directories || (other_host && !self.args.no_host_directories);
+
+        let local = match host_dir(url) {
+            Some(host) if host_directory => format!("{host}/{local}"),
+            _ => local,
+        };
+
+        // Make the file names legal on Windows
+        let local = if cfg!(windows) || self.args.windows_names {
+            legal_path(&local)
+        } else {
+            local
+        };
+
+        // Files are stored with the extension of the compression applied
+        let local = match self.args.store_compressed {
+            Some(compression) => format!("{local}{}", compression.extension()),
+            None => local,
+        };
+
+        // Shorten names and paths which are too long, recording the URL they came from
+        let base_len = path.as_os_str().len();
+
+        match shorten_path(
+            &local,
+            base_len,
+            self.args.max_name_length,
+            self.args.max_path_length,
+        ) {
+            Some(short) => {
+                debug!(self, 1, "Local path {local} for {url} shortened to {short}");
+
+                path.push(&short);
+                Ok((path, Some(short)))
+            }
+            None => {
+                path.push(local);
+                Ok((path, None))
+            }
+        }
+    }
+
+    /// Claims a local path in the flattened tree for a URL, numbering the file name until one
+    /// is found which no other URL has claimed. Colliding names are recorded in the name map so
+    /// each URL keeps its name in later runs whatever order the URLs are found in
+    async fn claim_flat_path(&self, path: PathBuf, url: &Url) -> PathBuf {
+        let normalised = url.normalised();
+        let mut local_paths = self.local_paths.lock().await;
+        let mut name_map = self.name_map.lock().await;
+
+        let target = Path::new(&self.args.target);
+        let rel = |path: &Path| {
+            path.strip_prefix(target)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        // Reuse the name given to the URL by an earlier run
+        if let Some(name) = name_map.path(&normalised) {
+            let candidate = target.join(name);
+
+            if candidate.parent() == path.parent() {
+                local_paths.insert(candidate.clone(), normalised);
+                return candidate;
+            }
         }
 
-        debug!(self, 2, "URL {url} maps to file {}", path.display());
+        let mut candidate = path.clone();
+        let mut n = 0;
 
-        Ok(path)
+        loop {
+            // Names given to other URLs by earlier runs are kept for them
+            let reserved = name_map
+                .url(&rel(&candidate))
+                .is_some_and(|owner| owner != normalised);
+
+            if !reserved {
+                let claimant = local_paths
+                    .entry(candidate.clone())
+                    .or_insert_with(|| normalised.clone());
+
+                if *claimant == normalised {
+                    if n > 0 {
+                        name_map.add(&rel(&candidate), &normalised);
+                    }
+
+                    return candidate;
+                }
+
+                if n == 0 {
+                    // Record the URL which was given the plain name as well
+                    let claimant = claimant.clone();
+                    name_map.add(&rel(&candidate), &claimant);
+                }
+            }
+
+            n += 1;
+            candidate = numbered_path(&path, n);
+        }
+    }
+
+    /// Checks a file URL belongs to the shard being downloaded
+    pub fn check_shard(&self, url: &Url) -> Result<(), SkipReasonErr> {
+        if let Some(shard) = &self.args.shard {
+            let path = url
+                .relative_path(&self.url)
+                .unwrap_or_else(|| url.full_path());
+
+            if !shard.contains(path) {
+                Err(SkipReasonErr::new(url.to_string(), SkipReason::Shard))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a file size is within the --min-size and --max-size limits. Files of unknown
+    /// size are always allowed
+    pub fn check_size(&self, url: &Url, size: Option<u64>) -> Result<(), SkipReasonErr> {
+        if let Some(size) = size {
+            let too_small = self.args.min_size.is_some_and(|min| size < min);
+            let too_large = self.args.max_size.is_some_and(|max| size > max);
+
+            if too_small || too_large {
+                Err(SkipReasonErr::new(url.to_string(), SkipReason::Size(size)))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a file MIME type is allowed by --accept-type and --reject-type. Files of unknown
+    /// type are always allowed
+    pub fn check_mime_type(&self, url: &Url, mime_type: Option<Mime>) -> Result<(), SkipReasonErr> {
+        if let Some(mime_type) = mime_type {
+            let accepted = self.args.accept_type.is_empty()
+                || self
+                    .args
+                    .accept_type
+                    .iter()
+                    .any(|pattern| mime_type.matches(pattern));
+            let rejected = self
+                .args
+                .reject_type
+                .iter()
+                .any(|pattern| mime_type.matches(pattern));
+
+            if !accepted || rejected {
+                Err(SkipReasonErr::new(
+                    url.to_string(),
+                    SkipReason::MimeType(mime_type.essence_str().to_string()),
+                ))?
+            }
+        }
+
+        Ok(())
     }
 
-    /// Update stats
-    pub async fn update_stats<'a, F>(&'a self, update_fn: F)
-    where
-        F: FnOnce(MutexGuard<'a, Stats>),
-    {
-        let stats_lock = self.stats.lock().await;
+    /// Returns true if the local copy of a file has the length reported by the server and was
+    /// not modified before the server's copy
+    pub async fn local_length_matches(
+        &self,
+        url: &Url,
+        size: Option<u64>,
+        modified: Option<SystemTime>,
+    ) -> bool {
+        let Some(size) = size else {
+            return false;
+        };
+
+        let Ok(path) = self.path_for_url(url).await else {
+            return false;
+        };
 
-        update_fn(stats_lock);
+        let meta = match tokio::fs::metadata(&path).await {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return false,
+        };
+
+        meta.len() == size
+            && modified.is_none_or(|modified| {
+                meta.modified()
+                    .is_ok_and(|local| unix_secs(local) >= unix_secs(modified))
+            })
+    }
+
+    /// Checks a file was last modified at least --min-age ago. Files with an unknown
+    /// modification time are always allowed
+    pub fn check_age(&self, url: &Url, modified: Option<SystemTime>) -> Result<(), SkipReasonErr> {
+        if let (Some(min_age), Some(modified)) = (self.args.min_age, modified) {
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+
+            if age < min_age {
+                Err(SkipReasonErr::new(url.to_string(), SkipReason::TooNew))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether the local copy of a file needs replacing. With --no-clobber any existing
+    /// file is kept, with --skip-existing the file is kept if the size and modification time
+    /// reported by the server match
+    pub async fn check_up_to_date(
+        &self,
+        url: &Url,
+        size: Option<u64>,
+        modified: Option<SystemTime>,
+    ) -> Result<(), SkipReasonErr> {
+        if !self.args.skip_existing && !self.args.no_clobber {
+            return Ok(());
+        }
+
+        let path = self.path_for_url(url).await?;
+
+        let meta = match tokio::fs::metadata(&path).await {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return Ok(()),
+        };
+
+        let up_to_date = if self.args.no_clobber {
+            true
+        } else {
+            let size_matches = size.map(|size| size == meta.len());
+
+            let modified_matches = modified.map(|modified| {
+                meta.modified()
+                    .is_ok_and(|local| unix_secs(local) == unix_secs(modified))
+            });
+
+            match (size_matches, modified_matches) {
+                (None, None) => false,
+                (size, modified) => size.unwrap_or(true) && modified.unwrap_or(true),
+            }
+        };
+
+        if up_to_date {
+            Err(SkipReasonErr::new(url.to_string(), SkipReason::UpToDate))?
+        }
+
+        Ok(())
+    }
+
+    /// Adds the stats for a processed URL
+    pub fn add_stats(&self, stats: &Stats) {
+        self.stats.add(stats);
+    }
+
+    /// Returns the event sink
+    pub fn events(&self) -> &dyn EventSink {
+        self.events.as_ref()
     }
 
     /// Gets a copy of the stats
-    pub async fn get_stats(&self) -> Stats {
-        self.stats.lock().await.clone()
+    pub fn get_stats(&self) -> Stats {
+        self.stats.snapshot()
     }
 
     /// Looks for an etag in the etag list for a given URL
@@ -157,37 +1038,366 @@ impl State {
     }
 
     /// Add an etag for a list of URLs to the new etags collection
-    pub async fn add_etags(&self, urls: Vec<&Url>, etag: &str) {
-        let mut new_etags = self.new_etags.lock().await;
-
+    pub fn add_etags(&self, urls: Vec<&Url>, etag: &str) {
         for url in urls {
-            new_etags.add(url.to_string(), etag.to_string());
+            self.new_etags.add(url.to_string(), etag.to_string());
             debug!(self, 2, "Set etag for {url} to {etag}")
         }
+    }
 
-        drop(new_etags);
+    /// Forgets the new etags for a list of URLs
+    pub fn remove_etags(&self, urls: &[Url]) {
+        for url in urls {
+            self.new_etags.remove(url.as_ref());
+            debug!(self, 2, "Removed etag for {url}")
+        }
     }
 
-    /// Save the etags file
+    /// Returns the HTTP client and the etags known so far for the next run in watch mode
+    pub fn warm(&self) -> WarmState {
+        let mut etags = ETags::default();
+        etags.extend(&self.old_etags);
+        etags.extend(&self.new_etags.snapshot());
+
+        WarmState {
+            client: self.client.clone(),
+            redirects: self.redirects.clone(),
+            etags,
+        }
+    }
+
+    /// Save the etags file, dropping the etags for URLs not seen if pruning
     pub async fn save_etags(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         if !self.args.no_etags {
-            let new_etags = &mut self.new_etags.lock().await;
+            let new_etags = self.new_etags.take();
+
+            match self.pruned_etags().await {
+                Some(old_etags) => self.save_etags_file(new_etags, &old_etags)?,
+                None => self.write_etags(new_etags).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the old etags for the URLs seen in this run if pruning removed any. Nothing is
+    /// pruned if the run didn't complete as URLs may have been missed
+    async fn pruned_etags(&self) -> Option<ETags> {
+        if !self.args.prune_etags {
+            return None;
+        }
+
+        if self.is_interrupted() || self.get_stats().failed() > 0 {
+            output!("Not pruning etags as the run did not complete");
+            return None;
+        }
+
+        let processed = self.processed_urls.lock().await;
+
+        let mut old_etags = ETags::default();
+        old_etags.extend(&self.old_etags);
+
+        let pruned = old_etags.retain(|url| {
+            let seen = Url::parse(url).is_ok_and(|url| processed.contains(&url.normalised()));
+
+            if !seen {
+                debug!(self, 1, "Pruning etag for {url}");
+            }
+
+            seen
+        });
+
+        match pruned {
+            0 => None,
+            1 => {
+                output!("Pruned 1 stale etag");
+                Some(old_etags)
+            }
+            n => {
+                output!("Pruned {n} stale etags");
+                Some(old_etags)
+            }
+        }
+    }
+
+    /// Saves the etags collected so far, keeping them for the final save. Does nothing if none
+    /// have changed since the last time
+    pub async fn flush_etags(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.args.no_etags && self.new_etags.take_changed() {
+            debug!(self, 1, "Saving etags collected so far");
+            self.write_etags(self.new_etags.snapshot()).await?;
+        }
+
+        Ok(())
+    }
 
-            if !new_etags.is_empty() {
-                // Merge old etags in to new etags and save to file
-                new_etags
-                    .extend(&self.old_etags)
-                    .save_to_file(&self.etags_file)?
+    /// Writes new etags to the metadata store or the etags file
+    async fn write_etags(&self, new_etags: ETags) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !new_etags.is_empty() {
+            if let Some(store) = &self.store {
+                // Save new etags to the metadata store
+                store.save_etags(new_etags).await?
+            } else {
+                self.save_etags_file(new_etags, &self.old_etags)?
             }
         }
 
         Ok(())
     }
 
+    /// Merges old etags in to new etags and saves them to the etags file with the base URL
+    fn save_etags_file(
+        &self,
+        mut new_etags: ETags,
+        old_etags: &ETags,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        new_etags.set_base(self.url.to_string());
+        new_etags.extend(old_etags).save_to_file(&self.etags_file)
+    }
+
+    /// Records a saved file in the manifest
+    pub async fn add_manifest_entry(&self, url: &Url, path: &Path, size: u64, sha256: &str) {
+        if self.args.manifest {
+            let rel_path = path.strip_prefix(&self.args.target).unwrap_or(path);
+
+            let mut entry = ManifestEntry::new(
+                url.to_string(),
+                rel_path.to_string_lossy().into_owned(),
+                size,
+                sha256.to_string(),
+            );
+
+            // Record the name the file would have uncompressed
+            if let Some(compression) = self.args.store_compressed {
+                entry.original_path = entry
+                    .path
+                    .strip_suffix(compression.extension())
+                    .map(String::from);
+            }
+
+            self.manifest.lock().await.add(entry);
+        }
+    }
+
+    /// Looks in the manifest for another file in the mirror with the same contents
+    pub async fn find_duplicate(&self, path: &Path, size: u64, sha256: &str) -> Option<PathBuf> {
+        let manifest = self.manifest.lock().await;
+
+        manifest
+            .find_by_hash(size, sha256)
+            .into_iter()
+            .map(|rel_path| Path::new(&self.args.target).join(rel_path))
+            .find(|existing| {
+                existing != path
+                    && std::fs::metadata(existing)
+                        .map(|meta| meta.is_file() && meta.len() == size)
+                        .unwrap_or(false)
+            })
+    }
+
+    /// Records in the manifest that the previous copy of a file was kept after a failed download
+    pub async fn set_manifest_stale(&self, url: &Url) {
+        if self.args.manifest {
+            self.manifest.lock().await.set_stale(url.as_str());
+        }
+    }
+
+    /// Removes and returns the redirect chain followed fetching a URL
+    pub fn take_redirects(&self, url: &Url) -> Vec<RedirectHop> {
+        self.redirects.take(url)
+    }
+
+    /// Records the redirect chain followed to reach a file in the manifest
+    pub async fn set_manifest_redirects(&self, final_url: &str, redirects: Vec<RedirectHop>) {
+        if self.args.manifest {
+            self.manifest
+                .lock()
+                .await
+                .set_redirects(final_url, redirects);
+        }
+    }
+
+    /// Records the members unpacked from an archive in the manifest
+    pub async fn set_manifest_members(&self, url: &Url, members: &[PathBuf]) {
+        if self.args.manifest {
+            self.manifest.lock().await.set_members(
+                url.as_str(),
+                members
+                    .iter()
+                    .map(|member| member.to_string_lossy().into_owned())
+                    .collect(),
+            );
+        }
+    }
+
+    /// Save the manifest file
+    pub async fn save_manifest(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.manifest.lock().await.save_to_file(&self.manifest_file)
+    }
+
+    /// Records a URL which is about to be processed in the crawl state
+    pub async fn checkpoint_queue(&self, url: &Url) {
+        if self.args.resume {
+            self.checkpoint.lock().await.queue(url.as_str());
+        }
+    }
+
+    /// Records a URL which has been fully processed in the crawl state
+    pub async fn checkpoint_complete(&self, url: &Url) {
+        if self.args.resume {
+            self.checkpoint.lock().await.complete(url.as_str());
+        }
+    }
+
+    /// Returns true if a URL was fully processed by a previous run being resumed
+    pub async fn is_completed(&self, url: &Url) -> bool {
+        self.args.resume && self.checkpoint.lock().await.is_completed(url.as_str())
+    }
+
+    /// Returns the URLs left to process by a previous run being resumed
+    pub async fn resume_urls(&self) -> Vec<Url> {
+        self.checkpoint
+            .lock()
+            .await
+            .frontier()
+            .filter_map(|url| Url::parse(url).ok())
+            .collect()
+    }
+
+    /// Saves the crawl state. The file is removed once there is nothing left to process
+    pub async fn save_checkpoint(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.args.resume {
+            return Ok(());
+        }
+
+        let checkpoint = self.checkpoint.lock().await.clone();
+
+        if checkpoint.is_finished() && !self.is_interrupted() {
+            match std::fs::remove_file(&self.checkpoint_file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => Err(format!("Error removing {}: {e}", self.checkpoint_file))?,
+            }
+
+            Ok(())
+        } else {
+            checkpoint.save_to_file(&self.checkpoint_file)
+        }
+    }
+
+    /// Returns the learnt capabilities for the host of a URL
+    pub async fn host_caps(&self, url: &Url) -> HostCaps {
+        self.host_caps
+            .lock()
+            .await
+            .get(url.host_str().unwrap_or_default())
+    }
+
+    /// Records that the host of a URL does not handle HEAD requests
+    pub async fn set_host_no_head(&self, url: &Url) {
+        let host = url.host_str().unwrap_or_default();
+
+        self.host_caps.lock().await.set_no_head(host);
+    }
+
+    /// Save the host capabilities file
+    pub async fn save_host_caps(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.host_caps.lock().await.save_to_file(&self.hosts_file)
+    }
+
+    /// Save the name map file
+    pub async fn save_name_map(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.name_map.lock().await.save_to_file(&self.names_file)
+    }
+
+    /// Save the listing dates file
+    pub async fn save_listing_dates(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.listing_dates
+            .lock()
+            .await
+            .save_to_file(&self.listing_dates_file)
+    }
+
+    /// Returns the newest entries seen in a feed by the last run
+    pub async fn feed_cursor(&self, url: &Url) -> Option<FeedCursor> {
+        self.feed_cursors.lock().await.get(url.as_str())
+    }
+
+    /// Records the newest entries seen in a feed
+    pub async fn set_feed_cursor(&self, url: &Url, cursor: FeedCursor) {
+        let published = cursor.published;
+        self.feed_cursors.lock().await.set(url.as_str(), cursor);
+        debug!(self, 2, "Set feed cursor for {url} to {published}")
+    }
+
+    /// Save the feed cursors file. The cursors aren't moved on if the run didn't complete as
+    /// entries may have been missed
+    pub async fn save_feed_cursors(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let feed_cursors = self.feed_cursors.lock().await;
+
+        if !feed_cursors.is_changed() {
+            return Ok(());
+        }
+
+        if self.is_interrupted() || self.get_stats().failed() > 0 {
+            output!("Not moving feed cursors on as the run did not complete");
+            return Ok(());
+        }
+
+        feed_cursors.save_to_file(&self.feed_cursors_file)
+    }
+
+    /// Returns a reference to the command line arguments
+    pub fn args(&self) -> &Args {
+        &self.args
+    }
+
     /// Returns the debug level
     #[inline]
     pub fn debug_level(&self) -> u8 {
-        self.args.debug
+        self.debug_level.load(Ordering::Relaxed)
+    }
+
+    /// Moves to the next debug level, wrapping back to zero after the maximum, and returns it
+    pub fn cycle_debug_level(&self) -> u8 {
+        let level = (self.debug_level() + 1) % (MAX_DEBUG_LEVEL + 1);
+
+        self.debug_level.store(level, Ordering::Relaxed);
+
+        level
+    }
+
+    /// Stops any new fetches being started
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if the run has been interrupted
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Stops any new fetches being started and fails the run. Only the first reason is kept
+    pub fn abort(&self, reason: String) {
+        let mut aborted = self
+            .aborted
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        if aborted.is_none() {
+            output!("Aborting - waiting for fetches in progress to finish");
+            *aborted = Some(reason);
+        }
+
+        self.interrupt();
+    }
+
+    /// Returns the reason the run was aborted if it was
+    pub fn aborted(&self) -> Option<String> {
+        self.aborted
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
     }
 
     /// Performs a debug delay
@@ -200,26 +1410,50 @@ impl State {
     }
 
     /// Creates the HTTP client
-    fn create_http_client(args: &Args, url: Url) -> Result<Client, Box<dyn Error + Send + Sync>> {
+    pub fn create_http_client(
+        args: &Args,
+        scope: HostScope,
+        resolves: &[Resolve],
+        redirects: &RedirectLog,
+    ) -> Result<Client, Box<dyn Error + Send + Sync>> {
         // Create redirect policy
         let max_redirects = args.max_redirects;
 
+        // Redirects between snapshots are checked against the original URLs
+        let wayback = Wayback::from_args(args)?;
+        let original = move |url: &Url| match wayback.as_ref().and_then(|w| w.original_url(url)) {
+            Some(original_url) => original_url,
+            None => url.clone(),
+        };
+
+        let redirects = redirects.clone();
+
         let redirect_policy = Policy::custom(move |attempt| {
-            // Check no more that 10 redirects and that path is relative to the base URL
-            if attempt.previous().len() > max_redirects {
-                let initial = attempt.previous()[0].clone();
+            let initial = original(&attempt.previous()[0]);
+
+            // Record the redirect in the chain for the URL first requested
+            if let Some(from) = attempt.previous().last() {
+                redirects.add(
+                    &initial,
+                    RedirectHop {
+                        url: original(from).to_string(),
+                        status: attempt.status().as_u16(),
+                        location: original(attempt.url()).to_string(),
+                    },
+                    attempt.previous().len() == 1,
+                );
+            }
 
+            // Check no more that 10 redirects and that path is in the crawl scope
+            if attempt.previous().len() > max_redirects {
                 attempt.error(SkipReasonErr::new(
                     initial.to_string(),
                     SkipReason::TooManyRedirects,
                 ))
             } else {
-                let attempt_url = attempt.url();
-
-                if !attempt_url.is_relative_to(&url) {
-                    let initial = attempt.previous()[0].clone();
-                    let attempt_url = attempt.url().clone();
+                let attempt_url = original(attempt.url());
 
+                if !scope.contains(&attempt_url) {
                     attempt.error(SkipReasonErr::new(
                         initial.to_string(),
                         SkipReason::RedirectNotRel(attempt_url.to_string()),
@@ -230,13 +1464,224 @@ impl State {
             }
         });
 
-        // Create HTTP client
-        Ok(Client::builder()
+        // Create HTTP client builder
+        let mut builder = Client::builder()
             .redirect(redirect_policy)
             .connect_timeout(Duration::from_secs(args.connect_timeout))
             .timeout(Duration::from_secs(args.fetch_timeout))
-            .build()?)
+            .gzip(!args.no_compression)
+            .brotli(!args.no_compression)
+            .deflate(!args.no_compression);
+
+        // Choose the HTTP version
+        if args.http1_only {
+            builder = builder.http1_only();
+        } else if args.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+
+        // Tune the connection pool
+        if let Some(max) = args.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+
+        if let Some(secs) = args.tcp_keepalive {
+            builder = builder.tcp_keepalive(Duration::from_secs(secs));
+        }
+
+        // Add extra root certificate
+        if let Some(cacert) = &args.cacert {
+            builder = builder.add_root_certificate(load_cacert(cacert)?);
+        }
+
+        // Add client certificate
+        if let (Some(client_cert), Some(client_key)) = (&args.client_cert, &args.client_key) {
+            builder = builder.identity(load_identity(client_cert, client_key)?);
+        }
+
+        // Disable certificate verification
+        if args.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        // Override DNS resolution
+        for resolve in resolves {
+            builder = builder.resolve(&resolve.host, resolve.socket_addr());
+        }
+
+        // Create HTTP client
+        Ok(builder.build()?)
     }
 }
 
 pub type ArcState = Arc<State>;
+
+/// Loads a CA certificate file
+pub fn load_cacert(cacert: &str) -> Result<Certificate, Box<dyn Error + Send + Sync>> {
+    let pem = std::fs::read(cacert)
+        .map_err(|e| format!("Unable to read CA certificate file {cacert}: {e}"))?;
+
+    Ok(Certificate::from_pem(&pem)
+        .map_err(|e| format!("Unable to load CA certificate file {cacert}: {e}"))?)
+}
+
+/// Loads a client certificate and private key
+pub fn load_identity(
+    client_cert: &str,
+    client_key: &str,
+) -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let cert_pem = std::fs::read(client_cert)
+        .map_err(|e| format!("Unable to read client certificate file {client_cert}: {e}"))?;
+
+    let key_pem = std::fs::read(client_key)
+        .map_err(|e| format!("Unable to read client key file {client_key}: {e}"))?;
+
+    Ok(Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+        .map_err(|e| format!("Unable to load client certificate {client_cert}: {e}"))?)
+}
+
+/// Collects the URLs to mirror from the command line and the URL file
+pub fn root_urls(args: &Args) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let mut urls = args.urls.clone();
+
+    if let Some(url_file) = &args.url_file {
+        let content = std::fs::read_to_string(url_file)
+            .map_err(|e| format!("Unable to read URL file {url_file}: {e}"))?;
+
+        urls.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
+    }
+
+    urls.extend(args.feed.iter().cloned());
+
+    if urls.is_empty() {
+        Err("No URLs to mirror")?
+    }
+
+    Ok(urls)
+}
+
+/// Parses a URL to mirror. Local paths to existing files and directories are converted to
+/// file:// URLs
+pub fn parse_root_url(url: &str) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    match Url::parse(url) {
+        Ok(url) => Ok(url),
+        Err(url::ParseError::RelativeUrlWithoutBase) if Path::new(url).exists() => {
+            let path = Path::new(url)
+                .canonicalize()
+                .map_err(|e| format!("Unable to resolve path {url}: {e}"))?;
+
+            let file_url = if path.is_dir() {
+                Url::from_directory_path(&path)
+            } else {
+                Url::from_file_path(&path)
+            };
+
+            Ok(file_url.map_err(|_| format!("Unable to convert path {url} to a URL"))?)
+        }
+        Err(e) => Err(format!("Invalid URL {url}: {e}"))?,
+    }
+}
+
+/// Returns the base URL for a set of root URLs. A single URL is its own base, otherwise the
+/// deepest directory containing all of the URLs is used
+fn common_base(roots: &[Url]) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    let first = &roots[0];
+
+    if roots.len() == 1 {
+        return Ok(first.clone());
+    }
+
+    let mut base = first.join("./")?;
+
+    for root in &roots[1..] {
+        if root.scheme() != first.scheme()
+            || root.host_str() != first.host_str()
+            || root.port_or_known_default() != first.port_or_known_default()
+        {
+            Err(format!("URL {root} is not on the same host as {first}"))?
+        }
+
+        while !root.path().starts_with(base.path()) {
+            base = base.join("../")?;
+        }
+    }
+
+    base.set_query(None);
+
+    Ok(base)
+}
+
+/// Returns a time in whole seconds since the unix epoch
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the directory name for a URL's host, with the port if it isn't the scheme's default
+fn host_dir(url: &Url) -> Option<String> {
+    let host = url.host_str().filter(|host| !host.is_empty())?;
+
+    Some(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+/// Drops up to a number of leading directories from a local path, always keeping the file name
+fn cut_dirs(local: &str, n: usize) -> String {
+    let mut rest = local;
+
+    for _ in 0..n {
+        match rest.split_once('/') {
+            Some((_, tail)) => rest = tail,
+            None => break,
+        }
+    }
+
+    rest.to_string()
+}
+
+/// Adds a number to a file name before its extensions (eg. report.pdf -> report-1.pdf)
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    // A leading dot doesn't start an extension
+    let first_len = name.chars().next().map_or(0, char::len_utf8);
+
+    let numbered = match name[first_len..].find('.') {
+        Some(pos) => {
+            let (stem, extensions) = name.split_at(first_len + pos);
+            format!("{stem}-{n}{extensions}")
+        }
+        None => format!("{name}-{n}"),
+    };
+
+    path.with_file_name(numbered)
+}
+
+/// Joins the path components below a directory depth in to a single file name, encoding
+/// the separators
+fn flatten_path(local: &str, depth: usize) -> String {
+    let components: Vec<&str> = local.split('/').collect();
+
+    if components.len() <= depth + 1 {
+        return local.to_string();
+    }
+
+    let (dirs, rest) = components.split_at(depth);
+
+    dirs.iter()
+        .map(|dir| format!("{dir}/"))
+        .chain([rest.join("%2F")])
+        .collect()
+}
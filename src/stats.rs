@@ -1,5 +1,14 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
 use num::PrimInt;
 
+use crate::lastrun::RunSummary;
+use crate::outcome::{ErrorKind, Outcome};
 use crate::output::output;
 
 #[derive(Default, Debug, Clone, PartialEq)]
@@ -11,9 +20,191 @@ pub struct Stats {
     not_modified: u64,
     skipped: u64,
     errored: u64,
+    interstitial: u64,
+    kept_stale: u64,
+    throttled: u64,
+    latency: Histogram,
+    transfer: Histogram,
+    detail: Breakdown,
 }
 
 impl Stats {
+    /// Prints the stats, with sizes as exact byte counts if raw_bytes is set
+    pub fn print(&self, raw_bytes: bool) {
+        let size = |bytes: usize| match human_size(bytes as u64) {
+            Some(size) if !raw_bytes => size,
+            _ => Self::format_qty(bytes, "byte", "bytes"),
+        };
+
+        output!(
+            "{} parsed ({})",
+            Self::format_qty(self.html_docs, "document", "documents"),
+            size(self.html_bytes),
+        );
+        output!(
+            "{} downloaded ({}), {} not modified, {} skipped, {} errored",
+            Self::format_qty(self.downloads, "file", "files"),
+            size(self.download_bytes),
+            self.not_modified,
+            self.skipped,
+            self.errored
+        );
+
+        if self.interstitial > 0 {
+            output!(
+                "{} returned a login or interstitial page",
+                Self::format_qty(self.interstitial, "file", "files")
+            );
+        }
+
+        if self.kept_stale > 0 {
+            output!(
+                "{} kept from a previous download after a failed replacement",
+                Self::format_qty(self.kept_stale, "file", "files")
+            );
+        }
+
+        if self.throttled > 0 {
+            output!(
+                "{} throttled by the server and retried",
+                Self::format_qty(self.throttled, "request", "requests")
+            );
+        }
+
+        if self.latency.count() > 0 {
+            output!(
+                "Response time {} ({})",
+                self.latency,
+                Self::format_qty(self.latency.count(), "request", "requests")
+            );
+        }
+
+        if self.transfer.count() > 0 {
+            output!(
+                "Transfer time {} ({})",
+                self.transfer,
+                Self::format_qty(self.transfer.count(), "file", "files")
+            );
+        }
+
+        if !self.detail.is_empty() {
+            output!("Downloads by directory:");
+
+            for (dir, totals) in Breakdown::by_size(&self.detail.dirs) {
+                output!(
+                    "  {dir}: {} ({})",
+                    Self::format_qty(totals.files, "file", "files"),
+                    size(totals.bytes)
+                );
+            }
+
+            output!("Downloads by extension:");
+
+            for (ext, totals) in Breakdown::by_size(&self.detail.exts) {
+                output!(
+                    "  {ext}: {} ({})",
+                    Self::format_qty(totals.files, "file", "files"),
+                    size(totals.bytes)
+                );
+            }
+        }
+    }
+
+    /// Returns the totals kept to compare the next run against
+    pub fn summary(&self) -> RunSummary {
+        RunSummary {
+            downloads: self.downloads,
+            not_modified: self.not_modified,
+            documents: self.html_docs,
+            skipped: self.skipped,
+            errored: self.errored,
+        }
+    }
+
+    /// Returns the number of URLs processed
+    pub fn processed(&self) -> u64 {
+        self.downloads + self.html_docs + self.not_modified + self.skipped + self.errored
+    }
+
+    /// Returns the number of bytes downloaded and parsed
+    pub fn bytes(&self) -> usize {
+        self.download_bytes + self.html_bytes
+    }
+
+    /// Returns the name, description and value of each counter for exporting as metrics
+    pub fn counters(&self) -> [(&'static str, &'static str, u64); 8] {
+        [
+            ("downloads_total", "Files downloaded", self.downloads),
+            (
+                "download_bytes_total",
+                "Bytes downloaded",
+                self.download_bytes as u64,
+            ),
+            ("documents_total", "Documents parsed", self.html_docs),
+            (
+                "document_bytes_total",
+                "Bytes of documents parsed",
+                self.html_bytes as u64,
+            ),
+            (
+                "not_modified_total",
+                "Files not modified",
+                self.not_modified,
+            ),
+            ("skipped_total", "URLs skipped", self.skipped),
+            ("errors_total", "URLs which failed", self.failed()),
+            (
+                "throttled_total",
+                "Requests throttled by the server and retried",
+                self.throttled,
+            ),
+        ]
+    }
+
+    /// Returns the number of files which failed to download
+    pub fn failed(&self) -> u64 {
+        self.errored + self.interstitial
+    }
+
+    /// Returns the percentage of the files fetched which didn't fail
+    pub fn success_rate(&self) -> f64 {
+        let succeeded = self.downloads + self.html_docs + self.not_modified;
+        let attempted = succeeded + self.failed();
+
+        if attempted == 0 {
+            100.0
+        } else {
+            succeeded as f64 * 100.0 / attempted as f64
+        }
+    }
+
+    /// Formats a quantity + unit
+    fn format_qty<T>(qty: T, single: &str, plural: &str) -> String
+    where
+        T: PrimInt + std::fmt::Display,
+    {
+        if qty.is_one() {
+            format!("{} {}", qty, single)
+        } else {
+            format!("{} {}", qty, plural)
+        }
+    }
+}
+
+impl Stats {
+    /// Add the outcome of processing a URL to the stats
+    pub fn add_outcome(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Ignored => {}
+            Outcome::Downloaded { bytes } => self.add_download(*bytes),
+            Outcome::NotModified => self.add_not_modified(),
+            Outcome::Parsed { bytes, .. } => self.add_html(*bytes),
+            Outcome::Skipped(_) => self.add_skipped(),
+            Outcome::Errored(ErrorKind::Interstitial(_)) => self.add_interstitial(),
+            Outcome::Errored(ErrorKind::Other(_)) => self.add_errored(),
+        }
+    }
+
     /// Add a download to the stats
     pub fn add_download(&mut self, bytes: usize) {
         self.downloads += 1;
@@ -41,32 +232,294 @@ impl Stats {
         self.errored += 1;
     }
 
-    /// Prints the stats
-    pub fn print(&self) {
-        output!(
-            "{} parsed ({})",
-            Self::format_qty(self.html_docs, "document", "documents"),
-            Self::format_qty(self.html_bytes, "byte", "bytes"),
-        );
-        output!(
-            "{} downloaded ({}), {} not modified, {} skipped, {} errored",
-            Self::format_qty(self.downloads, "file", "files"),
-            Self::format_qty(self.download_bytes, "byte", "bytes"),
-            self.not_modified,
-            self.skipped,
-            self.errored
-        );
+    /// Add a file download which returned a login or interstitial page to the stats
+    pub fn add_interstitial(&mut self) {
+        self.interstitial += 1;
     }
 
-    /// Formats a quantity + unit
-    fn format_qty<T>(qty: T, single: &str, plural: &str) -> String
-    where
-        T: PrimInt + std::fmt::Display,
-    {
-        if qty.is_one() {
-            format!("{} {}", qty, single)
-        } else {
-            format!("{} {}", qty, plural)
+    /// Add a file whose previous copy was kept after a failed download to the stats
+    pub fn add_kept_stale(&mut self) {
+        self.kept_stale += 1;
+    }
+
+    /// Add a request throttled by the server to the stats
+    pub fn add_throttled(&mut self) {
+        self.throttled += 1;
+    }
+
+    /// Add the time taken for a server to respond to a request to the stats
+    pub fn add_latency(&mut self, time: Duration) {
+        self.latency.add(time);
+    }
+
+    /// Add the time taken to transfer a file to the stats
+    pub fn add_transfer_time(&mut self, time: Duration) {
+        self.transfer.add(time);
+    }
+
+    /// Add a downloaded file to the breakdown by directory and extension. The path is relative
+    /// to the target directory
+    pub fn add_detail(&mut self, rel_path: &Path, bytes: usize) {
+        self.detail.add(rel_path, bytes);
+    }
+}
+
+/// Downloads broken down by top level directory and by file extension
+#[derive(Default, Debug, Clone, PartialEq)]
+struct Breakdown {
+    dirs: BTreeMap<String, Totals>,
+    exts: BTreeMap<String, Totals>,
+}
+
+/// Number of files and bytes in a breakdown entry
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct Totals {
+    files: u64,
+    bytes: usize,
+}
+
+impl Totals {
+    /// Adds other totals to these
+    fn add(&mut self, other: &Totals) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Breakdown {
+    /// Adds a file to the breakdown
+    fn add(&mut self, rel_path: &Path, bytes: usize) {
+        let mut components = rel_path.components();
+        components.next_back();
+
+        let dir = match components.next() {
+            Some(Component::Normal(dir)) => dir.to_string_lossy().into_owned(),
+            _ => String::from("(top level)"),
+        };
+
+        let ext = match rel_path.extension() {
+            Some(ext) => ext.to_string_lossy().to_lowercase(),
+            None => String::from("(none)"),
+        };
+
+        let totals = Totals { files: 1, bytes };
+
+        self.dirs.entry(dir).or_default().add(&totals);
+        self.exts.entry(ext).or_default().add(&totals);
+    }
+
+    /// Adds another breakdown to this one
+    fn merge(&mut self, other: &Breakdown) {
+        for (dir, totals) in &other.dirs {
+            self.dirs.entry(dir.clone()).or_default().add(totals);
+        }
+
+        for (ext, totals) in &other.exts {
+            self.exts.entry(ext.clone()).or_default().add(totals);
+        }
+    }
+
+    /// Returns true if nothing has been added
+    fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    /// Returns the entries of a breakdown map, largest first
+    fn by_size(map: &BTreeMap<String, Totals>) -> Vec<(&String, &Totals)> {
+        let mut entries = map.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, s)| Reverse(s.bytes));
+        entries
+    }
+}
+
+/// Size units above bytes
+const SIZE_UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+/// Formats a size in bytes in KiB, MiB, GiB etc. to one decimal place, or returns None if it is
+/// under 1 KiB
+pub fn human_size(bytes: u64) -> Option<String> {
+    let mut size = bytes as f64 / 1024.0;
+
+    if size < 1.0 {
+        return None;
+    }
+
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < SIZE_UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    Some(format!("{size:.1} {}", SIZE_UNITS[unit]))
+}
+
+/// Statistics which can be updated concurrently without locking
+#[derive(Default)]
+pub struct AtomicStats {
+    downloads: AtomicU64,
+    download_bytes: AtomicUsize,
+    html_docs: AtomicU64,
+    html_bytes: AtomicUsize,
+    not_modified: AtomicU64,
+    skipped: AtomicU64,
+    errored: AtomicU64,
+    interstitial: AtomicU64,
+    kept_stale: AtomicU64,
+    throttled: AtomicU64,
+    latency: AtomicHistogram,
+    transfer: AtomicHistogram,
+    detail: Mutex<Breakdown>,
+}
+
+impl AtomicStats {
+    /// Adds the stats for a processed URL in one update
+    pub fn add(&self, stats: &Stats) {
+        self.downloads.fetch_add(stats.downloads, Ordering::Relaxed);
+        self.download_bytes
+            .fetch_add(stats.download_bytes, Ordering::Relaxed);
+        self.html_docs.fetch_add(stats.html_docs, Ordering::Relaxed);
+        self.html_bytes
+            .fetch_add(stats.html_bytes, Ordering::Relaxed);
+        self.not_modified
+            .fetch_add(stats.not_modified, Ordering::Relaxed);
+        self.skipped.fetch_add(stats.skipped, Ordering::Relaxed);
+        self.errored.fetch_add(stats.errored, Ordering::Relaxed);
+        self.interstitial
+            .fetch_add(stats.interstitial, Ordering::Relaxed);
+        self.kept_stale
+            .fetch_add(stats.kept_stale, Ordering::Relaxed);
+        self.throttled.fetch_add(stats.throttled, Ordering::Relaxed);
+        self.latency.add(&stats.latency);
+        self.transfer.add(&stats.transfer);
+
+        if !stats.detail.is_empty() {
+            self.lock_detail().merge(&stats.detail);
+        }
+    }
+
+    /// Takes a copy of the current stats
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            downloads: self.downloads.load(Ordering::Relaxed),
+            download_bytes: self.download_bytes.load(Ordering::Relaxed),
+            html_docs: self.html_docs.load(Ordering::Relaxed),
+            html_bytes: self.html_bytes.load(Ordering::Relaxed),
+            not_modified: self.not_modified.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            errored: self.errored.load(Ordering::Relaxed),
+            interstitial: self.interstitial.load(Ordering::Relaxed),
+            kept_stale: self.kept_stale.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            transfer: self.transfer.snapshot(),
+            detail: self.lock_detail().clone(),
+        }
+    }
+
+    /// Locks the breakdown by directory and extension
+    fn lock_detail(&self) -> std::sync::MutexGuard<'_, Breakdown> {
+        self.detail
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Upper bounds of the histogram buckets in milliseconds. The last bucket holds everything longer
+const BUCKET_BOUNDS: [u64; 18] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000,
+    200_000, 500_000,
+];
+
+/// Number of histogram buckets
+const BUCKETS: usize = BUCKET_BOUNDS.len() + 1;
+
+/// Histogram of durations with buckets on a 1-2-5 scale
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+}
+
+impl Histogram {
+    /// Adds a duration
+    pub fn add(&mut self, time: Duration) {
+        self.counts[Self::bucket(time)] += 1;
+    }
+
+    /// Returns the number of durations added
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the upper bound in milliseconds of the bucket holding a percentile, or None if it
+    /// is in the last bucket
+    pub fn percentile(&self, percent: u64) -> Option<u64> {
+        let target = (self.count() * percent).div_ceil(100).max(1);
+        let mut total = 0;
+
+        for (bucket, count) in self.counts.iter().enumerate() {
+            total += count;
+
+            if total >= target {
+                return BUCKET_BOUNDS.get(bucket).copied();
+            }
+        }
+
+        None
+    }
+
+    /// Returns the bucket for a duration
+    fn bucket(time: Duration) -> usize {
+        let ms = time.as_millis();
+
+        BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| ms <= bound as u128)
+            .unwrap_or(BUCKET_BOUNDS.len())
+    }
+
+    /// Formats a percentile
+    fn format_percentile(&self, percent: u64) -> String {
+        match self.percentile(percent) {
+            Some(ms) if ms < 1_000 => format!("<={ms}ms"),
+            Some(ms) => format!("<={}s", ms / 1_000),
+            None => format!(">{}s", BUCKET_BOUNDS[BUCKET_BOUNDS.len() - 1] / 1_000),
+        }
+    }
+}
+
+impl std::fmt::Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {}, p90 {}, p99 {}",
+            self.format_percentile(50),
+            self.format_percentile(90),
+            self.format_percentile(99)
+        )
+    }
+}
+
+/// Histogram of durations which can be added to concurrently without locking
+#[derive(Default)]
+struct AtomicHistogram {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl AtomicHistogram {
+    /// Adds the counts of another histogram
+    fn add(&self, histogram: &Histogram) {
+        for (count, add) in self.counts.iter().zip(histogram.counts) {
+            if add > 0 {
+                count.fetch_add(add, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Takes a copy of the current counts
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            counts: std::array::from_fn(|i| self.counts[i].load(Ordering::Relaxed)),
         }
     }
 }
@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use reqwest::StatusCode;
+
+/// Error for a request which returned an unsuccessful status
+#[derive(Debug)]
+pub struct StatusErr {
+    /// Status returned
+    status: StatusCode,
+    /// URL fetched
+    url: String,
+}
+
+impl StatusErr {
+    /// Creates a status error
+    pub fn new(status: StatusCode, url: impl Display) -> Self {
+        Self {
+            status,
+            url: url.to_string(),
+        }
+    }
+
+    /// Returns the status returned
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl Display for StatusErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Status {} fetching {}", self.status, self.url)
+    }
+}
+
+impl Error for StatusErr {}
+
+/// Set of HTTP statuses given as codes (eg. 404) or classes (eg. 5xx)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatusSet {
+    /// Ranges of status codes
+    ranges: Vec<(u16, u16)>,
+}
+
+impl StatusSet {
+    /// Parses a comma separated list of status codes and classes
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let ranges = s
+            .split(',')
+            .map(|item| {
+                let item = item.trim();
+
+                let range = match item.to_ascii_lowercase().strip_suffix("xx") {
+                    Some(class) => class.parse::<u16>().ok().map(|c| (c * 100, c * 100 + 99)),
+                    None => item.parse::<u16>().ok().map(|code| (code, code)),
+                };
+
+                range
+                    .filter(|(from, to)| *from >= 100 && *to <= 599)
+                    .ok_or_else(|| {
+                        format!("'{item}' is not a status code or class (eg. 404 or 4xx)")
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { ranges })
+    }
+
+    /// Returns true if a status is in the set
+    pub fn contains(&self, status: StatusCode) -> bool {
+        let code = status.as_u16();
+
+        self.ranges
+            .iter()
+            .any(|(from, to)| (*from..=*to).contains(&code))
+    }
+}
@@ -0,0 +1,135 @@
+use std::error::Error;
+#[cfg(feature = "metadata-store")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "metadata-store")]
+use std::time::Duration;
+
+#[cfg(feature = "metadata-store")]
+use rusqlite::{Connection, TransactionBehavior};
+#[cfg(feature = "metadata-store")]
+use tokio::task::spawn_blocking;
+
+use crate::etags::ETags;
+
+/// Time to wait for another process to release the database
+#[cfg(feature = "metadata-store")]
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// SQLite metadata store which can be shared between several processes mirroring the same tree.
+/// One connection is held open for the life of the store. Queries block, so they are run on the
+/// blocking thread pool
+#[cfg(feature = "metadata-store")]
+pub struct MetadataStore {
+    file: String,
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// Placeholder for the metadata store when built without SQLite support. It can't be opened
+#[cfg(not(feature = "metadata-store"))]
+pub struct MetadataStore {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(feature = "metadata-store"))]
+impl MetadataStore {
+    /// Fails as there is no SQLite support
+    pub fn open(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Err(format!(
+            "Unable to open metadata store {file}: built without the metadata-store feature"
+        ))?
+    }
+
+    /// Loads all of the etags in the store
+    pub async fn load_etags(&self) -> Result<ETags, Box<dyn Error + Send + Sync>> {
+        match self.never {}
+    }
+
+    /// Saves etags to the store
+    pub async fn save_etags(&self, _etags: ETags) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.never {}
+    }
+}
+
+#[cfg(feature = "metadata-store")]
+impl MetadataStore {
+    /// Opens the store, creating the database file and tables if necessary
+    pub fn open(file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let conn = Connection::open(file)
+            .and_then(|conn| {
+                conn.busy_timeout(BUSY_TIMEOUT)?;
+                Ok(conn)
+            })
+            .map_err(|e| format!("Failed to open metadata store {file}: {e}"))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS etags (url TEXT PRIMARY KEY, etag TEXT NOT NULL)",
+        )
+        .map_err(|e| format!("Failed to initialise metadata store {file}: {e}"))?;
+
+        Ok(Self {
+            file: file.to_string(),
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Loads all of the etags in the store
+    pub async fn load_etags(&self) -> Result<ETags, Box<dyn Error + Send + Sync>> {
+        let conn = self.conn.clone();
+        let file = self.file.clone();
+
+        spawn_blocking(move || {
+            let conn = conn.lock().expect("Failed to lock metadata store");
+            let mut etags = ETags::default();
+
+            let mut load = || -> rusqlite::Result<()> {
+                let mut stmt = conn.prepare("SELECT url, etag FROM etags")?;
+                let mut rows = stmt.query([])?;
+
+                while let Some(row) = rows.next()? {
+                    etags.add(row.get(0)?, row.get(1)?);
+                }
+
+                Ok(())
+            };
+
+            load().map_err(|e| format!("Failed to load etags from metadata store {file}: {e}"))?;
+
+            Ok(etags)
+        })
+        .await?
+    }
+
+    /// Saves etags to the store in a single transaction, leaving etags for other URLs alone
+    pub async fn save_etags(&self, etags: ETags) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let conn = self.conn.clone();
+        let file = self.file.clone();
+
+        spawn_blocking(move || {
+            let mut conn = conn.lock().expect("Failed to lock metadata store");
+
+            let mut save = || -> rusqlite::Result<()> {
+                // Take the write lock up front so waiting for other processes happens here
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT INTO etags (url, etag) VALUES (?1, ?2)
+                        ON CONFLICT (url) DO UPDATE SET etag = excluded.etag",
+                    )?;
+
+                    for (url, etag) in etags.iter() {
+                        stmt.execute((url, etag))?;
+                    }
+                }
+
+                // The transaction is rolled back if it is dropped before the commit
+                tx.commit()
+            };
+
+            save().map_err(|e| format!("Failed to save etags to metadata store {file}: {e}"))?;
+
+            Ok(())
+        })
+        .await?
+    }
+}
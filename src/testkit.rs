@@ -0,0 +1,517 @@
+//! Helpers for writing end to end tests of mirrors: a builder for the responses of a test HTTP
+//! server, a minimal FTP server, archive and document builders, and a checker for the tree
+//! written to the target directory
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use httptest::matchers::request;
+use httptest::responders::status_code;
+use httptest::{Expectation, Server};
+use tokio::fs::{create_dir_all, read, read_dir, read_link, File};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Builds the responses a test HTTP server expects to give
+pub struct Scenario<'a> {
+    server: &'a Server,
+}
+
+impl<'a> Scenario<'a> {
+    /// Starts a scenario for a server
+    pub fn new(server: &'a Server) -> Self {
+        Self { server }
+    }
+
+    /// Expects a single GET request for a path and responds with an HTML document
+    pub fn html(self, path: &str, doc: &str) -> Self {
+        self.server.expect(
+            Expectation::matching(request::method_path("GET", path.to_string())).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "text/html")
+                    .body(doc.to_string()),
+            ),
+        );
+
+        self
+    }
+
+    /// Expects a single GET request for a path and responds with the content
+    pub fn file(self, path: &str, content: &str) -> Self {
+        self.server.expect(
+            Expectation::matching(request::method_path("GET", path.to_string()))
+                .respond_with(status_code(200).body(content.to_string())),
+        );
+
+        self
+    }
+
+    /// Expects a single GET request for a path and responds with a status code
+    pub fn status(self, path: &str, status: u16) -> Self {
+        self.server.expect(
+            Expectation::matching(request::method_path("GET", path.to_string()))
+                .respond_with(status_code(status)),
+        );
+
+        self
+    }
+}
+
+/// Builds an HTML document containing an anchor for each link
+pub fn build_html_anchors_doc<A>(anchors: &[A]) -> String
+where
+    A: Display,
+{
+    let mut doc = String::new();
+
+    doc.push_str(
+        r#"<DOCTYPE html>
+<html>
+    <head>
+    </head>
+    <body>"#,
+    );
+
+    for a in anchors {
+        doc.push_str(&format!("        <a href=\"{a}\">Anchor: {a}</a>\n"));
+    }
+
+    doc.push_str(
+        "\
+    </body>
+</html>",
+    );
+
+    doc
+}
+
+/// Compresses content with gzip
+pub fn build_gz(content: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+    encoder
+        .write_all(content.as_bytes())
+        .expect("Error compressing content");
+
+    encoder.finish().expect("Error compressing content")
+}
+
+/// Builds a gzipped tar archive from a list of member names and contents. Names longer than
+/// the header field are written with GNU long name entries
+pub fn build_tar_gz(members: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    for (name, content) in members {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+
+        builder
+            .append_data(&mut header, name, content.as_bytes())
+            .expect("Failed to write tar member");
+    }
+
+    builder
+        .into_inner()
+        .expect("Failed to write tar trailer")
+        .finish()
+        .expect("Failed to finish gzip stream")
+}
+
+/// Builds a deflated zip archive from a list of member names and contents
+pub fn build_zip(members: &[(&str, &str)]) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+
+    for (name, content) in members {
+        writer
+            .start_file(*name, zip::write::FileOptions::default())
+            .expect("Failed to write zip header");
+        writer
+            .write_all(content.as_bytes())
+            .expect("Failed to write zip data");
+    }
+
+    writer
+        .finish()
+        .expect("Failed to write zip central directory")
+        .into_inner()
+}
+
+/// Creates a file with its parent directories
+pub async fn create_tmp_file(path: &Path, content: &str) {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .await
+            .expect("Error creating directory");
+    }
+
+    let mut fh = File::create(path).await.expect("Error creating file");
+    fh.write_all(content.as_bytes())
+        .await
+        .expect("Error writing file");
+}
+
+/// Checks a directory tree holds exactly the expected directories, files and links. Paths are
+/// relative to the directory
+pub async fn check_tree<S1, S2>(dir: &Path, expected: &[TmpFile<S1, S2>])
+where
+    S1: Deref<Target = str> + Display,
+    S2: Deref<Target = str> + Display,
+{
+    let contents = get_tmp_contents(dir).await;
+
+    dump_tmp_contents(&contents);
+
+    // Check the correct files exist
+    compare_tmp_contents(&contents, expected, "Download directory", false);
+    compare_tmp_contents(expected, &contents, "Expected", true);
+}
+
+/// Entry expected in a directory tree
+pub enum TmpFile<S1, S2> {
+    /// Directory path
+    Dir(S1),
+    /// File path and contents
+    File(S1, S2),
+    /// Symbolic link path and target
+    Link(S1, S2),
+}
+
+/// Reads the entries of a directory tree
+async fn get_tmp_contents(dir: &Path) -> Vec<TmpFile<String, String>> {
+    let mut contents = Vec::new();
+
+    let mut process_paths = VecDeque::new();
+    process_paths.push_back(dir.to_path_buf());
+
+    while let Some(d) = process_paths.pop_front() {
+        let mut paths = read_dir(&d)
+            .await
+            .unwrap_or_else(|_| panic!("Failed to read directory {}", d.display()));
+
+        loop {
+            let dirent = paths
+                .next_entry()
+                .await
+                .unwrap_or_else(|_| panic!("Failed to read next directory entry {}", d.display()));
+            match dirent {
+                None => break,
+                Some(dirent) => {
+                    let full_path = dirent.path();
+
+                    let rel_path = full_path
+                        .strip_prefix(dir)
+                        .expect("Failed to remove directory prefix");
+
+                    let file_type = dirent.file_type().await.unwrap_or_else(|_| {
+                        panic!("Error getting file type for {}", rel_path.display())
+                    });
+
+                    let rel_name = rel_path
+                        .to_str()
+                        .expect("File name could not be converted to string")
+                        .to_string();
+
+                    if file_type.is_symlink() {
+                        let target = read_link(&full_path).await.unwrap_or_else(|_| {
+                            panic!("Failed to read link {}", full_path.display())
+                        });
+
+                        contents.push(TmpFile::Link(
+                            rel_name,
+                            target.to_string_lossy().into_owned(),
+                        ));
+                    } else if file_type.is_dir() {
+                        process_paths.push_back(full_path);
+
+                        contents.push(TmpFile::Dir(rel_name));
+                    } else {
+                        let content = read(&full_path).await.unwrap_or_else(|_| {
+                            panic!("Failed to read file {}", full_path.display())
+                        });
+
+                        contents.push(TmpFile::File(
+                            rel_name,
+                            String::from_utf8_lossy(&content).into_owned(),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    contents
+}
+
+/// Prints the entries of a directory tree
+fn dump_tmp_contents(contents: &[TmpFile<String, String>]) {
+    println!("Temp dir contents:");
+
+    for f in contents {
+        match f {
+            TmpFile::Dir(d) => println!("  {d}/"),
+            TmpFile::File(f, c) => println!("  {f} ({} bytes)", c.len()),
+            TmpFile::Link(l, t) => println!("  {l} -> {t}"),
+        }
+    }
+}
+
+/// Checks every entry in c1 is in c2, comparing the contents if compare is set
+fn compare_tmp_contents<S1, S2, S3, S4>(
+    c1: &[TmpFile<S1, S2>],
+    c2: &[TmpFile<S3, S4>],
+    desc: &str,
+    compare: bool,
+) where
+    S1: Deref<Target = str> + Display,
+    S2: Deref<Target = str> + Display,
+    S3: Deref<Target = str> + Display,
+    S4: Deref<Target = str> + Display,
+{
+    // Check the correct files exist
+    for f1 in c1 {
+        match f1 {
+            TmpFile::Dir(d1) => {
+                assert!(
+                    c2.iter()
+                        .filter_map(|d2| match d2 {
+                            TmpFile::Dir(d) => Some(d),
+                            _ => None,
+                        })
+                        .any(|d2| { d1.deref() == d2.deref() }),
+                    "{desc} contains file {d1}, which does not match"
+                );
+            }
+            TmpFile::File(f1, cnt1) => {
+                match c2
+                    .iter()
+                    .find(|f| matches!(f, TmpFile::File(f2, _) if f1.deref() == f2.deref()))
+                {
+                    Some(TmpFile::File(f2, cnt2)) => {
+                        if compare {
+                            assert_eq!(
+                                cnt1.deref(),
+                                cnt2.deref(),
+                                "Contents of file {f2} incorrect"
+                            );
+                        }
+                    }
+                    _ => {
+                        panic!("{desc} contains file {f1}, which does not match");
+                    }
+                }
+            }
+            TmpFile::Link(l1, t1) => {
+                match c2
+                    .iter()
+                    .find(|l| matches!(l, TmpFile::Link(l2, _) if l1.deref() == l2.deref()))
+                {
+                    Some(TmpFile::Link(l2, t2)) => {
+                        if compare {
+                            assert_eq!(t1.deref(), t2.deref(), "Target of link {l2} incorrect");
+                        }
+                    }
+                    _ => {
+                        panic!("{desc} contains link {l1}, which does not match");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Minimal FTP server serving files from memory
+pub struct FtpServer {
+    addr: SocketAddr,
+    sessions: Arc<AtomicUsize>,
+}
+
+impl FtpServer {
+    /// Starts the server with a list of file paths and contents. Directory listings are sent
+    /// in response to MLSD if mlsd is set, otherwise only LIST is supported
+    pub async fn run(files: &[(&str, &str)], mlsd: bool) -> Self {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to start FTP server");
+
+        let addr = listener.local_addr().unwrap();
+
+        let files: Arc<BTreeMap<String, String>> = Arc::new(
+            files
+                .iter()
+                .map(|(path, content)| (path.to_string(), content.to_string()))
+                .collect(),
+        );
+
+        let sessions = Arc::new(AtomicUsize::new(0));
+        let accepted = sessions.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(ftp_session(stream, files.clone(), mlsd));
+            }
+        });
+
+        Self { addr, sessions }
+    }
+
+    /// Returns the number of control connections accepted
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Returns the URL for a path on the server
+    pub fn url(&self, path: &str) -> String {
+        format!("ftp://{}{path}", self.addr)
+    }
+}
+
+/// Handles an FTP control connection
+async fn ftp_session(stream: TcpStream, files: Arc<BTreeMap<String, String>>, mlsd: bool) {
+    let mut control = BufReader::new(stream);
+    let mut passive: Option<TcpListener> = None;
+
+    macro_rules! reply {
+        ($($arg:tt)*) => {
+            if control
+                .get_mut()
+                .write_all(format!("{}\r\n", format!($($arg)*)).as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        };
+    }
+
+    reply!("220 Test FTP server");
+
+    loop {
+        let mut line = String::new();
+
+        if control.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+
+        let line = line.trim_end();
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+
+        // Directory entries as (name, size or None for directories)
+        let entries = |dir: &str| {
+            let dir = format!("{}/", dir.trim_end_matches('/'));
+            let mut entries = BTreeMap::new();
+
+            for (path, content) in files.iter() {
+                if let Some(rest) = path.strip_prefix(&dir) {
+                    match rest.split_once('/') {
+                        Some((sub, _)) => entries.insert(sub.to_string(), None),
+                        None => entries.insert(rest.to_string(), Some(content.len())),
+                    };
+                }
+            }
+
+            entries
+        };
+
+        match command {
+            "USER" => reply!("331 Password required"),
+            "PASS" => reply!("230 Logged in"),
+            "TYPE" => reply!("200 Type set"),
+            "NOOP" => reply!("200 OK"),
+            "EPSV" | "PASV" => {
+                let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                    .await
+                    .unwrap();
+                let port = listener.local_addr().unwrap().port();
+
+                passive = Some(listener);
+
+                if command == "EPSV" {
+                    reply!("229 Entering Extended Passive Mode (|||{port}|)");
+                } else {
+                    reply!(
+                        "227 Entering Passive Mode (127,0,0,1,{},{})",
+                        port / 256,
+                        port % 256
+                    );
+                }
+            }
+            "SIZE" => match files.get(arg) {
+                Some(content) => reply!("213 {}", content.len()),
+                None => reply!("550 Not a plain file"),
+            },
+            "MDTM" => match files.get(arg) {
+                Some(_) => reply!("213 20240102030405"),
+                None => reply!("550 Not a plain file"),
+            },
+            "CWD" => {
+                if entries(arg).is_empty() {
+                    reply!("550 No such directory");
+                } else {
+                    reply!("250 Directory changed");
+                }
+            }
+            "MLSD" | "LIST" | "RETR" => {
+                let data = match command {
+                    "MLSD" if !mlsd => {
+                        reply!("500 Unknown command");
+                        continue;
+                    }
+                    "MLSD" => entries(arg)
+                        .iter()
+                        .map(|(name, size)| match size {
+                            Some(size) => format!("type=file;size={size}; {name}\r\n"),
+                            None => format!("type=dir; {name}\r\n"),
+                        })
+                        .collect::<String>(),
+                    "LIST" => entries(arg)
+                        .iter()
+                        .map(|(name, size)| match size {
+                            Some(size) => {
+                                format!("-rw-r--r--   1 ftp ftp {size:8} Jan 02 03:04 {name}\r\n")
+                            }
+                            None => {
+                                format!("drwxr-xr-x   2 ftp ftp        0 Jan 02 03:04 {name}\r\n")
+                            }
+                        })
+                        .collect(),
+                    _ => match files.get(arg) {
+                        Some(content) => content.clone(),
+                        None => {
+                            reply!("550 No such file");
+                            continue;
+                        }
+                    },
+                };
+
+                let Some(listener) = passive.take() else {
+                    reply!("425 Use PASV first");
+                    continue;
+                };
+
+                reply!("150 Opening data connection");
+
+                if let Ok((mut stream, _)) = listener.accept().await {
+                    let _ = stream.write_all(data.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+
+                reply!("226 Transfer complete");
+            }
+            "QUIT" => {
+                reply!("221 Goodbye");
+                return;
+            }
+            _ => reply!("502 Command not implemented"),
+        }
+    }
+}
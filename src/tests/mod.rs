@@ -1,12 +1,33 @@
 use httptest::matchers::*;
 use httptest::responders::*;
-use httptest::Expectation;
+use httptest::{Expectation, Server};
 
 mod helpers;
 use helpers::*;
 
-use super::async_main;
-use crate::stats::Stats;
+use super::{async_main, async_main_with_events, status_line};
+use crate::bundle::{export_state, import_state};
+use crate::check::check;
+use crate::compress::StoreCompression;
+use crate::download::{preallocate, EmptyFiles};
+use crate::events::EventSink;
+use crate::extract::ArchiveType;
+use crate::feed::{parse_date, parse_feed, FeedCursor, FeedEntry};
+use crate::hash::{file_digests, DigestHasher, HashType};
+use crate::lastrun::RunSummary;
+use crate::limiter::{Limiter, SlotUsage, MAX_GIVE_WAY};
+use crate::metrics::render as render_metrics;
+use crate::policy::LinkAction;
+use crate::publish::PublishOrder;
+use crate::redirects::RecordRedirects;
+use crate::rules::test_rules;
+use crate::savefile::save_file;
+use crate::shard::Shard;
+use crate::skipreason::SkipReasonErr;
+use crate::stats::{human_size, Histogram, Stats};
+use crate::status::StatusSet;
+use crate::url::Url;
+use crate::LOGGER;
 
 #[tokio::test]
 async fn test_404() {
@@ -45,35 +66,69 @@ async fn test_404() {
 }
 
 #[tokio::test]
-async fn test_single_file() {
-    let (args, mut server, tmpdir) = test_setup("/file");
+async fn test_ignore_status() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
 
-    let file_content = "Hello, world!";
+    args.ignore_status = Some(StatusSet::parse("403,4xx").unwrap());
 
-    // Configure the server to expect a single GET /file request and respond with the file content.
+    // Configure the server to expect a single GET / request and respond with a 404 status code.
     server.expect(
-        Expectation::matching(request::method_path("GET", "/file"))
-            .respond_with(status_code(200).body(file_content)),
+        Expectation::matching(request::method_path("GET", "/")).respond_with(status_code(404)),
     );
 
     // Build expected stats
     let mut expected_stats = Stats::default();
-    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
 
     // Build expected messages
     let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/file")),
-        format!(
-            "INFO: Downloading {} to {}/download/__file.dat (size {})",
-            server.url("/file"),
-            tmpdir.path().display(),
-            file_content.len()
-        ),
+        format!("INFO: Fetching {}", server.url("/")),
+        format!("INFO: Skipping {}: Status 404 is ignored", server.url("/")),
         "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 1 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_fail_on() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.fail_on = Some(StatusSet::parse("5xx").unwrap());
+
+    // Configure the server to expect a single GET / request and respond with a 500 status code.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(status_code(500)),
+    );
+
+    // Build expected messages
+    let error = format!(
+        "Run aborted: Status 500 Internal Server Error fetching {}",
+        server.url("/")
+    );
+
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/")),
         format!(
-            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
-            file_content.len()
+            "ERROR: Status 500 Internal Server Error fetching {}",
+            server.url("/")
         ),
+        "INFO: Aborting - waiting for fetches in progress to finish".to_string(),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
     ];
 
     // Process
@@ -82,107 +137,171 @@ async fn test_single_file() {
     // Check results
     check_results(
         result,
-        Ok(expected_stats),
+        Err(error.into()),
         &expected_messages,
         &mut server,
         &tmpdir,
-        &[
-            TmpFile::Dir("download"),
-            TmpFile::File("download/__file.dat", file_content),
-        ],
+        &[] as &[TmpFile<&str, &str>; 0],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_single_file_etag() {
-    let (args, mut server, tmpdir) = test_setup("/file");
+async fn test_strict() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
 
-    let file_content = "Hello, world!";
+    args.strict = true;
+    args.min_success_rate = Some(50.0);
 
-    let etag_value = "etagvalue";
+    // Configure the server to expect a single GET / request and respond with a 404 status code.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(status_code(404)),
+    );
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/")),
+        format!("ERROR: Status 404 Not Found fetching {}", server.url("/")),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
+    ];
 
-    let etags_content = generate_etags_json(vec![(
-        server.url("/file").to_string(),
-        etag_value.to_string(),
-    )]);
+    // Process
+    let result = async_main(args).await;
 
-    // **** First process ****
+    // Check results
+    check_results(
+        result,
+        Err("1 file failed to download".into()),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
 
-    // Configure the server to expect a single GET /file request and respond with the file content and etag
+#[tokio::test]
+async fn test_min_success_rate() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.min_success_rate = Some(75.0);
+
+    // Build document with two anchors
+    let html_doc = build_html_anchors_doc(&["file1", "file2"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET / request and respond with the html document.
     server.expect(
-        Expectation::matching(all_of!(
-            request::method_path("GET", "/file"),
-            request::headers(not(contains(key("if-none-match")))),
-        ))
-        .respond_with(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(
             status_code(200)
-                .append_header("ETag", "etagvalue")
-                .body(file_content),
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
         ),
     );
 
-    // Build expected stats
-    let mut expected_stats = Stats::default();
-    expected_stats.add_download(file_content.len());
+    // Configure the server to expect a single GET /file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Configure the server to expect a single GET /file2 request and respond with 404.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file2")).respond_with(status_code(404)),
+    );
 
     // Build expected messages
     let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/file")),
+        format!("INFO: Fetching {}", server.url("/")),
+        format!("INFO: Fetching {}", server.url("/file1")),
+        format!("INFO: Fetching {}", server.url("/file2")),
         format!(
-            "INFO: Downloading {} to {}/download/__file.dat (size {})",
-            server.url("/file"),
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/file1"),
             tmpdir.path().display(),
             file_content.len()
         ),
-        "INFO: 0 documents parsed (0 bytes)".to_string(),
         format!(
-            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            "ERROR: Status 404 Not Found fetching {}",
+            server.url("/file2")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
             file_content.len()
         ),
     ];
 
     // Process
-    let result = async_main(args.clone()).await;
+    let result = async_main(args).await;
 
     // Check results
     check_results(
         result,
-        Ok(expected_stats),
+        Err("Success rate 66.7% is below the minimum of 75%".into()),
         &expected_messages,
         &mut server,
         &tmpdir,
         &[
             TmpFile::Dir("download"),
-            TmpFile::File("download/.etags.json", etags_content.as_str()),
-            TmpFile::File("download/__file.dat", file_content),
+            TmpFile::File("download/file1", file_content),
         ],
     )
     .await;
+}
 
-    // **** Second process ****
+#[tokio::test]
+async fn test_compare_last_run() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
 
-    // Configure the server to expect a single GET /file request with a valid If-None-Matches header and respond with 304 not modified
+    args.compare_last_run = true;
+
+    // Create the summary of a run which downloaded the file
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+
+    let last_run = RunSummary {
+        downloads: 1,
+        ..Default::default()
+    };
+    std::fs::write(
+        path.join(".last-run.json"),
+        serde_json::to_string_pretty(&last_run).unwrap(),
+    )
+    .unwrap();
+
+    // Configure the server to expect a single GET /file request and respond with a 404 status code.
     server.expect(
-        Expectation::matching(all_of!(
-            request::method_path("GET", "/file"),
-            request::headers(contains(("if-none-match", etag_value))),
-        ))
-        .respond_with(status_code(304)),
+        Expectation::matching(request::method_path("GET", "/file")).respond_with(status_code(404)),
     );
 
     // Build expected stats
     let mut expected_stats = Stats::default();
-    expected_stats.add_not_modified();
+    expected_stats.add_errored();
 
     // Build expected messages
     let expected_messages = [
         format!("INFO: Fetching {}", server.url("/file")),
-        format!("INFO: {} is not modified", server.url("/file"),),
+        format!(
+            "ERROR: Status 404 Not Found fetching {}",
+            server.url("/file")
+        ),
         "INFO: 0 documents parsed (0 bytes)".to_string(),
-        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
+        "INFO: Since the last run: downloads -100%, not modified +0, documents +0, skipped +0, errors +1".to_string(),
+        "INFO: Warning: No files were found but the last run found 1".to_string(),
     ];
 
+    // This run's totals are saved for the next run
+    let this_run = serde_json::to_string_pretty(&RunSummary {
+        errored: 1,
+        ..Default::default()
+    })
+    .unwrap();
+
     // Process
     let result = async_main(args).await;
 
@@ -195,25 +314,39 @@ async fn test_single_file_etag() {
         &tmpdir,
         &[
             TmpFile::Dir("download"),
-            TmpFile::File("download/.etags.json", etags_content.as_str()),
-            TmpFile::File("download/__file.dat", file_content),
+            TmpFile::File("download/.last-run.json", this_run.as_str()),
         ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_single_file_no_etag() {
+async fn test_compare_last_run_bad_file() {
     let (mut args, mut server, tmpdir) = test_setup("/file");
 
-    args.no_etags = true;
+    args.compare_last_run = true;
+
+    // Create a last run file which can't be loaded
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+    path.push(".last-run.json");
+    std::fs::write(&path, "garbage").unwrap();
 
     let file_content = "Hello, world!";
 
-    // Configure the server to expect a single GET /file request and respond with the file content.
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), "etagvalue".to_string())],
+    );
+
+    // Configure the server to expect a single GET /file request and respond with the file content and etag
     server.expect(
-        Expectation::matching(request::method_path("GET", "/file"))
-            .respond_with(status_code(200).body(file_content)),
+        Expectation::matching(request::method_path("GET", "/file")).respond_with(
+            status_code(200)
+                .append_header("ETag", "etagvalue")
+                .body(file_content),
+        ),
     );
 
     // Build expected stats
@@ -234,12 +367,23 @@ async fn test_single_file_no_etag() {
             "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
             file_content.len()
         ),
+        format!(
+            "ERROR: Failed to load last run file {}: expected value at line 1 column 1",
+            path.display()
+        ),
     ];
 
+    // The bad file is replaced with this run's totals
+    let this_run = serde_json::to_string_pretty(&RunSummary {
+        downloads: 1,
+        ..Default::default()
+    })
+    .unwrap();
+
     // Process
     let result = async_main(args).await;
 
-    // Check results
+    // Check results - the etags are still saved
     check_results(
         result,
         Ok(expected_stats),
@@ -248,18 +392,63 @@ async fn test_single_file_no_etag() {
         &tmpdir,
         &[
             TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/.last-run.json", this_run.as_str()),
             TmpFile::File("download/__file.dat", file_content),
         ],
     )
     .await;
 }
 
+#[test]
+fn test_run_anomalies() {
+    let last = RunSummary {
+        downloads: 10,
+        not_modified: 90,
+        errored: 2,
+        ..Default::default()
+    };
+
+    // A normal run
+    let summary = RunSummary {
+        downloads: 12,
+        not_modified: 88,
+        errored: 3,
+        ..Default::default()
+    };
+
+    assert!(summary.anomalies(&last).is_empty());
+    assert_eq!(
+        summary.deltas(&last),
+        "Since the last run: downloads +20%, not modified -2%, documents +0, skipped +0, errors +1"
+    );
+
+    // An error spike
+    let summary = RunSummary {
+        not_modified: 100,
+        errored: 7,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        summary.anomalies(&last),
+        ["Errors rose from 2 to 7 since the last run"]
+    );
+}
+
 #[tokio::test]
-async fn test_single_html_empty() {
+async fn test_skip_state_files() {
     let (args, mut server, tmpdir) = test_setup("/");
 
-    // Build document with no anchors
-    let html_doc = build_html_anchors_doc(&[] as &[&str; 0]);
+    // Build a listing of another mirror including its state files
+    let html_doc = build_html_anchors_doc(&[
+        "file1",
+        ".etags.json",
+        ".mirrorurl/quarantine/file2",
+        "file3.mirrorurl",
+    ]);
+
+    let file_content = "Hello, world!";
 
     // Configure the server to expect a single GET / request and respond with the html document.
     server.expect(
@@ -270,17 +459,49 @@ async fn test_single_html_empty() {
         ),
     );
 
+    // Configure the server to expect a single GET /file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
     // Build expected stats
     let mut expected_stats = Stats::default();
     expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    for _ in 0..3 {
+        expected_stats.add_skipped();
+    }
 
     // Build expected messages
-    let expected_messages = [
+    let mut expected_messages = vec![
         format!("INFO: Fetching {}", server.url("/")),
+        format!("INFO: Fetching {}", server.url("/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
         format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
-        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 0 errored".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 3 skipped, 0 errored",
+            file_content.len()
+        ),
     ];
 
+    for path in [
+        "/.etags.json",
+        "/.mirrorurl/quarantine/file2",
+        "/file3.mirrorurl",
+    ] {
+        expected_messages.push(format!(
+            "INFO: Skipping {}: Path is a mirrorurl state file",
+            server.url(path)
+        ));
+    }
+
     // Process
     let result = async_main(args).await;
 
@@ -291,17 +512,24 @@ async fn test_single_html_empty() {
         &expected_messages,
         &mut server,
         &tmpdir,
-        &[] as &[TmpFile<&str, &str>; 0],
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_single_html_404() {
-    let (args, mut server, tmpdir) = test_setup("/");
+async fn test_stats_detail() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
 
-    // Build document single anchor
-    let html_doc = build_html_anchors_doc(&["file"]);
+    args.stats_detail = true;
+
+    // Build document with anchors to files in a directory and at the top level
+    let html_doc = build_html_anchors_doc(&["dir/file1.txt", "dir/file2.TXT", "file3"]);
+
+    let file_content = "Hello, world!";
 
     // Configure the server to expect a single GET / request and respond with the html document.
     server.expect(
@@ -312,27 +540,52 @@ async fn test_single_html_404() {
         ),
     );
 
-    // Configure the server to expect a single GET /file request and respond with 404.
-    server.expect(
-        Expectation::matching(request::method_path("GET", "/file")).respond_with(status_code(404)),
-    );
+    // Configure the server to expect a GET request for each file and respond with the file content.
+    for file in ["/dir/file1.txt", "/dir/file2.TXT", "/file3"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", file))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
 
     // Build expected stats
     let mut expected_stats = Stats::default();
     expected_stats.add_html(html_doc.len());
-    expected_stats.add_errored();
+
+    for file in ["dir/file1.txt", "dir/file2.TXT", "file3"] {
+        expected_stats.add_download(file_content.len());
+        expected_stats.add_detail(std::path::Path::new(file), file_content.len());
+    }
 
     // Build expected messages
-    let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/")),
-        format!("INFO: Fetching {}", server.url("/file")),
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/"))];
+
+    for file in ["dir/file1.txt", "dir/file2.TXT", "file3"] {
+        expected_messages.push(format!(
+            "INFO: Fetching {}",
+            server.url(&format!("/{file}"))
+        ));
+        expected_messages.push(format!(
+            "INFO: Downloading {} to {}/download/{file} (size {})",
+            server.url(&format!("/{file}")),
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.extend([
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
         format!(
-            "ERROR: Status 404 Not Found fetching {}",
-            server.url("/file")
+            "INFO: 3 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 3
         ),
-        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
-        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
-    ];
+        "INFO: Downloads by directory:".to_string(),
+        format!("INFO:   dir: 2 files ({} bytes)", file_content.len() * 2),
+        format!("INFO:   (top level): 1 file ({} bytes)", file_content.len()),
+        "INFO: Downloads by extension:".to_string(),
+        format!("INFO:   txt: 2 files ({} bytes)", file_content.len() * 2),
+        format!("INFO:   (none): 1 file ({} bytes)", file_content.len()),
+    ]);
 
     // Process
     let result = async_main(args).await;
@@ -344,29 +597,201 @@ async fn test_single_html_404() {
         &expected_messages,
         &mut server,
         &tmpdir,
-        &[] as &[TmpFile<&str, &str>; 0],
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/dir"),
+            TmpFile::File("download/dir/file1.txt", file_content),
+            TmpFile::File("download/dir/file2.TXT", file_content),
+            TmpFile::File("download/file3", file_content),
+        ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_single_html() {
-    let (args, mut server, tmpdir) = test_setup("/root");
+async fn test_scenario() {
+    let (args, mut server, tmpdir) = test_setup("/");
 
-    // Build document with some anchors
-    let html_doc = build_html_anchors_doc(&[
-        "../notrelative",
-        "file://some_file",
-        "http://example.com",
-        "#",
-        "#hash",
-        "?",
-        "?query",
-        "?query#hash",
-        &server.url("/notrelative").to_string(),
-        &server.url("/root/file1").to_string(), // Valid full URL
-        "root/file2",                           // Valid relative URL
-    ]);
+    let html_doc = build_html_anchors_doc(&["file1", "dir/file2", "missing"]);
+
+    // Configure the server with the testkit scenario builder
+    Scenario::new(&server)
+        .html("/", &html_doc)
+        .file("/file1", "File 1")
+        .file("/dir/file2", "File 2")
+        .status("/missing", 404);
+
+    // Process
+    let result = async_main(args).await.expect("Run failed");
+
+    // Check the server and the tree written
+    server.verify_and_clear();
+    LOGGER.get_messages();
+
+    check_tree(
+        tmpdir.path(),
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/dir"),
+            TmpFile::File("download/dir/file2", "File 2"),
+            TmpFile::File("download/file1", "File 1"),
+        ],
+    )
+    .await;
+
+    assert_eq!(result.failed(), 1);
+}
+
+#[tokio::test]
+async fn test_metrics_file() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    let metrics_file = tmpdir.path().join("metrics.prom");
+    args.metrics_file = Some(metrics_file.to_string_lossy().into_owned());
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /file request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // The final metrics are written when the run finishes
+    let idle = SlotUsage {
+        busy: 0,
+        total: 0,
+        waiting: 0,
+    };
+
+    let metrics = render_metrics(&expected_stats, &idle);
+
+    assert!(
+        metrics.contains("# TYPE mirrorurl_downloads_total counter\nmirrorurl_downloads_total 1\n")
+    );
+    assert!(metrics.contains("mirrorurl_download_bytes_total 13\n"));
+    assert!(metrics
+        .contains("# TYPE mirrorurl_fetches_in_flight gauge\nmirrorurl_fetches_in_flight 0\n"));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+            TmpFile::File("metrics.prom", metrics.as_str()),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_metrics_file_error() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    // Write the metrics in to a directory which doesn't exist
+    let metrics_file = tmpdir.path().join("missing/metrics.prom");
+    args.metrics_file = Some(metrics_file.to_string_lossy().into_owned());
+
+    let file_content = "Hello, world!";
+
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), "etagvalue".to_string())],
+    );
+
+    // Configure the server to expect a single GET /file request and respond with the file content and etag
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file")).respond_with(
+            status_code(200)
+                .append_header("ETag", "etagvalue")
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+        format!(
+            "ERROR: Error writing {}.tmp: No such file or directory (os error 2)",
+            metrics_file.display()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results - failing to write the metrics doesn't lose the etags
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_metrics_listen() {
+    let (mut args, mut server, tmpdir) = test_setup("/root");
+
+    // Find a free port to serve the metrics on
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+
+    args.metrics_listen = Some(addr);
+
+    // Build document linking to a quick file and a slow file
+    let html_doc = build_html_anchors_doc(&["root/file1", "root/file2"]);
 
     let file_content = "Hello, world!";
 
@@ -385,10 +810,14 @@ async fn test_single_html() {
             .respond_with(status_code(200).body(file_content)),
     );
 
-    // Configure the server to expect a single GET /root/file2 request and respond with the file content.
+    // Configure the server to expect a single GET /root/file2 request and respond after a delay
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root/file2"))
-            .respond_with(status_code(200).body(file_content)),
+        Expectation::matching(request::method_path("GET", "/root/file2")).respond_with(
+            delay_and_then(
+                std::time::Duration::from_secs(2),
+                status_code(200).body(file_content),
+            ),
+        ),
     );
 
     // Build expected stats
@@ -399,35 +828,11 @@ async fn test_single_html() {
         expected_stats.add_download(file_content.len());
     }
 
-    for _ in 0..9 {
-        expected_stats.add_skipped();
-    }
-
     // Build expected messages
     let expected_messages = [
         format!("INFO: Fetching {}", server.url("/root")),
         format!("INFO: Fetching {}", server.url("/root/file1")),
         format!("INFO: Fetching {}", server.url("/root/file2")),
-        format!(
-            "INFO: Skipping {}: URL is not relative to the base URL",
-            server.url("/notrelative")
-        ),
-        "INFO: Skipping file://some_file/: The transport is not supported".to_string(),
-        "INFO: Skipping http://example.com/: URL is not relative to the base URL".to_string(),
-        format!("INFO: Skipping {}#: URL is a fragment", server.url("/root")),
-        format!(
-            "INFO: Skipping {}#hash: URL is a fragment",
-            server.url("/root")
-        ),
-        format!("INFO: Skipping {}: URL has a query", server.url("/root?")),
-        format!(
-            "INFO: Skipping {}: URL has a query",
-            server.url("/root?query")
-        ),
-        format!(
-            "INFO: Skipping {}#hash: URL is a fragment",
-            server.url("/root?query")
-        ),
         format!(
             "INFO: Downloading {} to {}/download/file1 (size {})",
             server.url("/root/file1"),
@@ -442,13 +847,31 @@ async fn test_single_html() {
         ),
         format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
         format!(
-            "INFO: 2 files downloaded ({} bytes), 0 not modified, 9 skipped, 0 errored",
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
             file_content.len() * 2
         ),
     ];
 
+    // Scrapes the metrics while the slow file is still downloading. The quick file has been
+    // counted in full, and the document isn't counted until all of its links are processed
+    let scrape = async {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        let metrics = reqwest::get(format!("http://{addr}/metrics"))
+            .await
+            .expect("Metrics request failed")
+            .text()
+            .await
+            .expect("Failed to read metrics");
+
+        assert!(metrics.contains("\nmirrorurl_downloads_total 1\n"));
+        assert!(metrics.contains("\nmirrorurl_download_bytes_total 13\n"));
+        assert!(metrics.contains("\nmirrorurl_documents_total 0\n"));
+        assert!(metrics.contains("\nmirrorurl_document_bytes_total 0\n"));
+    };
+
     // Process
-    let result = async_main(args).await;
+    let (result, ()) = tokio::join!(async_main(args), scrape);
 
     // Check results
     check_results(
@@ -467,45 +890,60 @@ async fn test_single_html() {
 }
 
 #[tokio::test]
-async fn test_single_xhtml() {
-    let (args, mut server, tmpdir) = test_setup("/root");
+async fn test_webhook() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
 
-    // Build document with some anchors
-    let html_doc = build_html_anchors_doc(&[&server.url("/root/file1").to_string()]);
+    args.webhook_url = Some(Url::parse(&server.url("/hook").to_string()).unwrap());
 
     let file_content = "Hello, world!";
 
-    // Configure the server to expect a single GET /root request and respond with the html document
+    // Configure the server to expect a single GET /file request and respond with the file content.
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root")).respond_with(
-            status_code(200)
-                .append_header("Content-Type", "application/xhtml+xml")
-                .body(html_doc.clone()),
-        ),
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
     );
 
-    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    // Configure the server to expect the summary to be posted to the webhook
+    let summary = serde_json::json!({
+        "success": true,
+        "error": null,
+        "target": args.target,
+        "urls": [server.url("/file").to_string()],
+        "stats": {
+            "document_bytes": 0,
+            "documents": 0,
+            "download_bytes": file_content.len(),
+            "downloads": 1,
+            "errors": 0,
+            "not_modified": 0,
+            "skipped": 0,
+            "throttled": 0,
+        },
+    });
+
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root/file1"))
-            .respond_with(status_code(200).body(file_content)),
+        Expectation::matching(all_of!(
+            request::method_path("POST", "/hook"),
+            request::headers(contains(("content-type", "application/json"))),
+            request::body(json_decoded(eq(summary))),
+        ))
+        .respond_with(status_code(200)),
     );
 
     // Build expected stats
     let mut expected_stats = Stats::default();
-    expected_stats.add_html(html_doc.len());
     expected_stats.add_download(file_content.len());
 
     // Build expected messages
     let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/root")),
-        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/file")),
         format!(
-            "INFO: Downloading {} to {}/download/file1 (size {})",
-            server.url("/root/file1"),
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
             tmpdir.path().display(),
             file_content.len()
         ),
-        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
         format!(
             "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
             file_content.len()
@@ -524,53 +962,38 @@ async fn test_single_xhtml() {
         &tmpdir,
         &[
             TmpFile::Dir("download"),
-            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/__file.dat", file_content),
         ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_single_html_duplicate() {
-    let (args, mut server, tmpdir) = test_setup("/root");
-
-    // Build document with some anchors
-    let html_doc =
-        build_html_anchors_doc(&["root/file1", server.url("/root/file1").to_string().as_str()]);
+async fn test_single_file() {
+    let (args, mut server, tmpdir) = test_setup("/file");
 
     let file_content = "Hello, world!";
 
-    // Configure the server to expect a single GET /root request and respond with the html document
-    server.expect(
-        Expectation::matching(request::method_path("GET", "/root")).respond_with(
-            status_code(200)
-                .append_header("Content-Type", "text/html")
-                .body(html_doc.clone()),
-        ),
-    );
-
-    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    // Configure the server to expect a single GET /file request and respond with the file content.
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root/file1"))
+        Expectation::matching(request::method_path("GET", "/file"))
             .respond_with(status_code(200).body(file_content)),
     );
 
     // Build expected stats
     let mut expected_stats = Stats::default();
-    expected_stats.add_html(html_doc.len());
     expected_stats.add_download(file_content.len());
 
     // Build expected messages
     let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/root")),
-        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/file")),
         format!(
-            "INFO: Downloading {} to {}/download/file1 (size {})",
-            server.url("/root/file1"),
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
             tmpdir.path().display(),
             file_content.len()
         ),
-        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
         format!(
             "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
             file_content.len()
@@ -589,98 +1012,10450 @@ async fn test_single_html_duplicate() {
         &tmpdir,
         &[
             TmpFile::Dir("download"),
-            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/__file.dat", file_content),
         ],
     )
     .await;
 }
 
-#[tokio::test]
-async fn test_multi_html() {
-    let (mut args, mut server, tmpdir) = test_setup("/root/");
-    args.debug = 0;
+#[test]
+fn test_save_file() {
+    use std::io::Write;
 
-    const SUB_PAGES: usize = 16;
+    let (_args, _server, tmpdir) = test_setup("/");
 
-    // Start expected stats
-    let mut expected_stats = Stats::default();
+    let file = tmpdir.path().join("state.json");
+    let file = file.to_str().unwrap();
 
-    // Start expected messages
-    let mut expected_messages = Vec::new();
+    // Save the file
+    save_file(file, |writer| Ok(writer.write_all(b"first")?)).unwrap();
 
-    // Start expected contents
-    let mut expected_contents = vec![TmpFile::Dir("download".to_string())];
+    assert_eq!(std::fs::read_to_string(file).unwrap(), "first");
 
-    // File content
-    let file_content = "Hello, world!";
+    // A failed save leaves the previous copy in place
+    let result = save_file(file, |writer| {
+        writer.write_all(b"sec")?;
+        Err("Failed part way through")?
+    });
 
-    // Build main document with some anchors
-    let main_anchors = (0..SUB_PAGES)
-        .map(|s| format!("{}/", s))
-        .collect::<Vec<_>>();
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        format!("Error writing {file}.tmp: Failed part way through")
+    );
+    assert_eq!(std::fs::read_to_string(file).unwrap(), "first");
 
-    let main_html_doc = build_html_anchors_doc(&main_anchors);
+    // The temporary file is renamed over the file
+    save_file(file, |writer| Ok(writer.write_all(b"second")?)).unwrap();
 
-    // Configure the server to expect a single GET /root request and respond with the main html document
-    server.expect(
-        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
-            status_code(200)
+    assert_eq!(std::fs::read_to_string(file).unwrap(), "second");
+    assert!(!tmpdir.path().join("state.json.tmp").exists());
+}
+
+#[tokio::test]
+async fn test_large_file() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.bytes = true;
+
+    // Numbered lines so any misplaced chunk shows up, spanning several write buffers
+    let file_content = (0..70000).map(|i| format!("{i:08}\n")).collect::<String>();
+
+    // Configure the server to expect a single GET /file request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content.clone())),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content.as_str()),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_client_tuning() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+    args.http1_only = true;
+    args.pool_max_idle_per_host = Some(2);
+    args.tcp_keepalive = Some(30);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /file request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_compressed_response() {
+    let (args, mut server, tmpdir) = test_setup("/file");
+
+    let file_content = "Hello, world! Hello, world! Hello, world!";
+    let file_gz = build_gz(file_content);
+
+    // Configure the server to expect a GET /file request accepting gzip and respond with the
+    // compressed content
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/file"),
+            request::headers(contains(("accept-encoding", matches("gzip")))),
+        ])
+        .respond_with(
+            status_code(200)
+                .append_header("Content-Encoding", "gzip")
+                .body(file_gz),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size unknown)",
+            server.url("/file"),
+            tmpdir.path().display(),
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_no_compression() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+    args.no_compression = true;
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a GET /file request not accepting compression
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/file"),
+            request::headers(not(contains(key("accept-encoding")))),
+        ])
+        .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_file_etag() {
+    let (args, mut server, tmpdir) = test_setup("/file");
+
+    let file_content = "Hello, world!";
+
+    let etag_value = "etagvalue";
+
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), etag_value.to_string())],
+    );
+
+    // **** First process ****
+
+    // Configure the server to expect a single GET /file request and respond with the file content and etag
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(not(contains(key("if-none-match")))),
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("ETag", "etagvalue")
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // Configure the server to expect a single GET /file request with a valid If-None-Matches header and respond with 304 not modified
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains(("if-none-match", etag_value))),
+        ))
+        .respond_with(status_code(304)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!("INFO: {} is not modified", server.url("/file"),),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_watch() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.watch = Some(std::time::Duration::ZERO);
+    args.max_runs = Some(2);
+
+    let file_content = "Hello, world!";
+
+    let etag_value = "etagvalue";
+
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), etag_value.to_string())],
+    );
+
+    // Configure the server to expect a GET /file request without an etag and respond with the
+    // file content and etag
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(not(contains(key("if-none-match")))),
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("ETag", etag_value)
+                .body(file_content),
+        ),
+    );
+
+    // Configure the server to expect the second run's GET /file request with the etag and
+    // respond with 304 not modified
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains(("if-none-match", etag_value))),
+        ))
+        .respond_with(status_code(304)),
+    );
+
+    // Build expected stats for the last run
+    let mut expected_stats = Stats::default();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+        "INFO: Next run in 0 seconds".to_string(),
+        format!("INFO: {} is not modified", server.url("/file")),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_etags_other_base() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    let file_content = "Hello, world!";
+
+    // Create an etags file saved mirroring a different URL
+    let mut etags_path = tmpdir.path().to_path_buf();
+    etags_path.push("download");
+    std::fs::create_dir_all(&etags_path).unwrap();
+    etags_path.push(".etags.json");
+
+    let other_etags = generate_etags_json(Some("http://other.example/file"), vec![]);
+    std::fs::write(&etags_path, &other_etags).unwrap();
+
+    // **** First process ****
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Err(format!(
+            "{} was saved mirroring http://other.example/file, not {} (use --force to use it anyway)",
+            etags_path.display(),
+            server.url("/file")
+        )
+        .into()),
+        &[] as &[&str; 0],
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", other_etags.as_str()),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    args.force = true;
+
+    // Configure the server to expect a single GET /file request and respond with the file content and etag
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file")).respond_with(
+            status_code(200)
+                .append_header("ETag", "etagvalue")
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!(
+            "INFO: Warning: {} was saved mirroring http://other.example/file, not {}",
+            etags_path.display(),
+            server.url("/file")
+        ),
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // The etags file now belongs to this URL
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), "etagvalue".to_string())],
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_prune_etags() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.prune_etags = true;
+
+    let file_content = "Hello, world!";
+    let etag_value = "etagvalue";
+
+    // Create an etags file with an entry for a URL which is no longer linked
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+
+    let old_etags = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![
+            (server.url("/file").to_string(), etag_value.to_string()),
+            (server.url("/gone").to_string(), "goneetag".to_string()),
+        ],
+    );
+    std::fs::write(path.join(".etags.json"), &old_etags).unwrap();
+    std::fs::write(path.join("__file.dat"), file_content).unwrap();
+
+    // Configure the server to expect a single GET /file request with the etag and respond with 304 not modified
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains(("if-none-match", etag_value))),
+        ))
+        .respond_with(status_code(304)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!("INFO: {} is not modified", server.url("/file")),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+        "INFO: Pruned 1 stale etag".to_string(),
+    ];
+
+    // Only the etag for the URL seen is kept
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), etag_value.to_string())],
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_prune_etags_metadata_store() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.prune_etags = true;
+    args.metadata_store = Some(
+        tmpdir
+            .path()
+            .join("metadata.db")
+            .to_string_lossy()
+            .to_string(),
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Err("--prune-etags can't be used with --metadata-store".into()),
+        &[] as &[&str; 0],
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_etags_flush() {
+    let (mut args, mut server, tmpdir) = test_setup("/root");
+
+    args.etags_save_interval = 1;
+
+    // Build document linking to a quick file and a slow file
+    let html_doc = build_html_anchors_doc(&["root/file1", "root/file2"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file1 request and respond with the file content and etag
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1")).respond_with(
+            status_code(200)
+                .append_header("ETag", "etag1")
+                .body(file_content),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file2 request and respond after a delay
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2")).respond_with(
+            delay_and_then(
+                std::time::Duration::from_secs(3),
+                status_code(200)
+                    .append_header("ETag", "etag2")
+                    .body(file_content),
+            ),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    for _ in 0..2 {
+        expected_stats.add_download(file_content.len());
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/file2 (size {})",
+            server.url("/root/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    let base = server.url("/root").to_string();
+
+    // Only the etag for the quick file has been collected while the slow file is in flight
+    let flushed_etags = generate_etags_json(
+        Some(&base),
+        vec![(server.url("/root/file1").to_string(), "etag1".to_string())],
+    );
+
+    let etags_content = generate_etags_json(
+        Some(&base),
+        vec![
+            (server.url("/root/file1").to_string(), "etag1".to_string()),
+            (server.url("/root/file2").to_string(), "etag2".to_string()),
+        ],
+    );
+
+    // Looks at the etags file while the slow file is still downloading
+    let download = tmpdir.path().join("download");
+
+    let peek = async {
+        tokio::time::sleep(std::time::Duration::from_millis(1800)).await;
+
+        let flushed = std::fs::read_to_string(download.join(".etags.json"))
+            .expect("Etags file not saved during the run");
+
+        assert_eq!(flushed, flushed_etags);
+        assert!(!download.join(".etags.json.tmp").exists());
+    };
+
+    // Process
+    let (result, ()) = tokio::join!(async_main(args), peek);
+
+    let etags = std::fs::read_to_string(download.join(".etags.json")).unwrap();
+    assert_etags_json_eq(&etags, &etags_content);
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_many_etags() {
+    let (mut args, mut server, tmpdir) = test_setup("/root");
+
+    args.bytes = true;
+    args.concurrent_fetch = 50;
+
+    let names = (0..50).map(|i| format!("file{i}")).collect::<Vec<_>>();
+
+    // Build document linking to all of the files
+    let links = names
+        .iter()
+        .map(|name| format!("root/{name}"))
+        .collect::<Vec<_>>();
+
+    let html_doc = build_html_anchors_doc(&links.iter().map(String::as_str).collect::<Vec<_>>());
+
+    // Configure the server to expect a single GET /root request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET request for each file and respond with the
+    // file name as content and etag
+    for name in &names {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{name}")))
+                .respond_with(
+                    status_code(200)
+                        .append_header("ETag", format!("etag-{name}"))
+                        .body(name.clone()),
+                ),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    for name in &names {
+        expected_stats.add_download(name.len());
+    }
+
+    let total_bytes = names.iter().map(String::len).sum::<usize>();
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 50 files downloaded ({total_bytes} bytes), 0 not modified, 0 skipped, 0 errored"
+        ),
+    ];
+
+    for name in &names {
+        let url = server.url(&format!("/root/{name}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{name} (size {})",
+            tmpdir.path().display(),
+            name.len()
+        ));
+    }
+
+    // Every etag is kept
+    let etags_content = generate_etags_json(
+        Some(&server.url("/root").to_string()),
+        names
+            .iter()
+            .map(|name| {
+                (
+                    server.url(&format!("/root/{name}")).to_string(),
+                    format!("etag-{name}"),
+                )
+            })
+            .collect(),
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    let etags = std::fs::read_to_string(tmpdir.path().join("download/.etags.json")).unwrap();
+    assert_etags_json_eq(&etags, &etags_content);
+
+    let paths = names
+        .iter()
+        .map(|name| format!("download/{name}"))
+        .collect::<Vec<_>>();
+
+    let mut expected_tmp = vec![
+        TmpFile::Dir("download"),
+        TmpFile::File("download/.etags.json", etags.as_str()),
+    ];
+
+    for (path, name) in paths.iter().zip(&names) {
+        expected_tmp.push(TmpFile::File(path.as_str(), name.as_str()));
+    }
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_tmp,
+    )
+    .await;
+}
+
+#[test]
+fn test_shared_etags_threads() {
+    use crate::etags::SharedETags;
+    use crate::stats::AtomicStats;
+
+    let etags = SharedETags::default();
+    let stats = AtomicStats::default();
+
+    // Add etags and stats from several threads at once
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let etags = &etags;
+            let stats = &stats;
+
+            scope.spawn(move || {
+                for i in 0..1000 {
+                    etags.add(format!("http://host/{thread}/{i}"), format!("{thread}-{i}"));
+
+                    let mut url_stats = Stats::default();
+                    url_stats.add_download(10);
+                    url_stats.add_skipped();
+
+                    stats.add(&url_stats);
+                }
+            });
+        }
+    });
+
+    assert!(etags.take_changed());
+    assert!(!etags.take_changed());
+
+    let snapshot = etags.snapshot();
+    assert_eq!(snapshot.iter().count(), 8000);
+    assert!(snapshot
+        .iter()
+        .any(|(url, etag)| url == "http://host/7/999" && etag == "7-999"));
+
+    let mut expected_stats = Stats::default();
+
+    for _ in 0..8000 {
+        expected_stats.add_download(10);
+        expected_stats.add_skipped();
+    }
+
+    assert_eq!(stats.snapshot(), expected_stats);
+
+    // Taking the etags empties the map
+    assert_eq!(etags.take().iter().count(), 8000);
+    assert!(etags.snapshot().is_empty());
+}
+
+#[tokio::test]
+async fn test_weak_etag() {
+    let (args, mut server, tmpdir) = test_setup("/file");
+
+    let file_content = "Hello, world!";
+
+    // Create an existing download with a weak etag
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(server.url("/file").to_string(), "W/\"v1\"".to_string())],
+    );
+    std::fs::write(path.join(".etags.json"), &etags_content).unwrap();
+    std::fs::write(path.join("__file.dat"), file_content).unwrap();
+
+    // Configure the server to ignore the If-None-Match header and respond with the file and the strong etag
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains(("if-none-match", "W/\"v1\""))),
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("ETag", "\"v1\"")
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!("INFO: {} is not modified", server.url("/file")),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_retry_after() {
+    let (args, mut server, tmpdir) = test_setup("/file");
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to throttle the first GET /file request and then respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .times(2)
+            .respond_with(cycle![
+                status_code(429).append_header("Retry-After", "0"),
+                status_code(200).body(file_content),
+            ]),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_throttled();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Status 429 Too Many Requests fetching {}, retrying in 0s",
+            server.url("/file")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+        "INFO: 1 request throttled by the server and retried".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_file_no_etag() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.no_etags = true;
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /file request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_html_empty() {
+    let (args, mut server, tmpdir) = test_setup("/");
+
+    // Build document with no anchors
+    let html_doc = build_html_anchors_doc(&[] as &[&str; 0]);
+
+    // Configure the server to expect a single GET / request and respond with the html document.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/")),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_html_404() {
+    let (args, mut server, tmpdir) = test_setup("/");
+
+    // Build document single anchor
+    let html_doc = build_html_anchors_doc(&["file"]);
+
+    // Configure the server to expect a single GET / request and respond with the html document.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /file request and respond with 404.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file")).respond_with(status_code(404)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/")),
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "ERROR: Status 404 Not Found fetching {}",
+            server.url("/file")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_html() {
+    let (args, mut server, tmpdir) = test_setup("/root");
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&[
+        "../notrelative",
+        "file://some_file",
+        "http://example.com",
+        "#",
+        "#hash",
+        "?",
+        "?query",
+        "?query#hash",
+        &server.url("/notrelative").to_string(),
+        &server.url("/root/file1").to_string(), // Valid full URL
+        "root/file2",                           // Valid relative URL
+    ]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Configure the server to expect a single GET /root/file2 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    for _ in 0..2 {
+        expected_stats.add_download(file_content.len());
+    }
+
+    for _ in 0..9 {
+        expected_stats.add_skipped();
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "INFO: Skipping {}: URL is not relative to the base URL",
+            server.url("/notrelative")
+        ),
+        "INFO: Skipping file://some_file/: The transport is not supported".to_string(),
+        "INFO: Skipping http://example.com/: URL is not relative to the base URL".to_string(),
+        format!("INFO: Skipping {}#: URL is a fragment", server.url("/root")),
+        format!(
+            "INFO: Skipping {}#hash: URL is a fragment",
+            server.url("/root")
+        ),
+        format!("INFO: Skipping {}: URL has a query", server.url("/root?")),
+        format!(
+            "INFO: Skipping {}: URL has a query",
+            server.url("/root?query")
+        ),
+        format!(
+            "INFO: Skipping {}#hash: URL is a fragment",
+            server.url("/root?query")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/file2 (size {})",
+            server.url("/root/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 9 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_xhtml() {
+    let (args, mut server, tmpdir) = test_setup("/root");
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&[&server.url("/root/file1").to_string()]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/xhtml+xml")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_single_html_duplicate() {
+    let (args, mut server, tmpdir) = test_setup("/root");
+
+    // Build document with some anchors
+    let html_doc =
+        build_html_anchors_doc(&["root/file1", server.url("/root/file1").to_string().as_str()]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_multi_html() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+    args.debug = 0;
+    args.bytes = true;
+
+    const SUB_PAGES: usize = 16;
+
+    // Start expected stats
+    let mut expected_stats = Stats::default();
+
+    // Start expected messages
+    let mut expected_messages = Vec::new();
+
+    // Start expected contents
+    let mut expected_contents = vec![TmpFile::Dir("download".to_string())];
+
+    // File content
+    let file_content = "Hello, world!";
+
+    // Build main document with some anchors
+    let main_anchors = (0..SUB_PAGES)
+        .map(|s| format!("{}/", s))
+        .collect::<Vec<_>>();
+
+    let main_html_doc = build_html_anchors_doc(&main_anchors);
+
+    // Configure the server to expect a single GET /root request and respond with the main html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(main_html_doc.clone()),
+        ),
+    );
+
+    expected_stats.add_html(main_html_doc.len());
+    expected_messages.push(format!("INFO: Fetching {}", server.url("/root/")));
+
+    // Configure the server to serve sub pages
+    let html_doc = build_html_anchors_doc(&(0..SUB_PAGES).collect::<Vec<_>>());
+
+    for page in 0..SUB_PAGES {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{}/", page)))
+                .respond_with(
+                    status_code(200)
+                        .append_header("Content-Type", "text/html")
+                        .body(html_doc.clone()),
+                ),
+        );
+
+        expected_stats.add_html(html_doc.len());
+        expected_messages.push(format!("INFO: Fetching {}/{page}/", server.url("/root")));
+
+        // Serve up the file content
+        for a in 0..SUB_PAGES {
+            server.expect(
+                Expectation::matching(request::method_path("GET", format!("/root/{page}/{a}")))
+                    .respond_with(status_code(200).body(file_content)),
+            );
+
+            expected_contents.push(TmpFile::Dir(format!("download/{a}")));
+            expected_messages.push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
+
+            expected_stats.add_download(file_content.len());
+            expected_contents.push(TmpFile::File(format!("download/{page}/{a}"), file_content));
+            expected_messages.push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
+            expected_messages.push(format!(
+                "INFO: Downloading {}/{page}/{a} to {}/download/{page}/{a} (size {})",
+                server.url("/root"),
+                tmpdir.path().display(),
+                file_content.len()
+            ));
+        }
+    }
+
+    expected_messages.push(format!(
+        "INFO: {} documents parsed ({} bytes)",
+        SUB_PAGES + 1,
+        main_html_doc.len() + (SUB_PAGES * html_doc.len())
+    ));
+    expected_messages.push(format!(
+        "INFO: {} files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        SUB_PAGES * SUB_PAGES,
+        SUB_PAGES * SUB_PAGES * file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_contents,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_multi_html_skiplist() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    const SUB_PAGES: usize = 4;
+
+    // Generate skip list
+    let (skip_path, skip_content) = generate_skiplist_json(&tmpdir, vec!["1", "2/", "3/1"]).await;
+    args.skip_file = Some(skip_path.to_str().unwrap().to_string());
+
+    // Start expected stats
+    let mut expected_stats = Stats::default();
+
+    // Start expected messages
+    let mut expected_messages = Vec::new();
+
+    // Start expected contents
+    let mut expected_contents = vec![
+        TmpFile::File("skiplist.json".to_string(), skip_content.as_str()),
+        TmpFile::Dir("download".to_string()),
+    ];
+
+    // File content
+    let file_content = "Hello, world!";
+
+    // Build main document with some anchors
+    let main_anchors = (1..=SUB_PAGES)
+        .map(|s| format!("{}/", s))
+        .collect::<Vec<_>>();
+
+    let main_html_doc = build_html_anchors_doc(&main_anchors);
+
+    // Configure the server to expect a single GET /root request and respond with the main html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(main_html_doc.clone()),
+        ),
+    );
+
+    expected_stats.add_html(main_html_doc.len());
+    expected_messages.push(format!("INFO: Fetching {}", server.url("/root/")));
+
+    // Configure the server to serve sub pages
+    let html_doc = build_html_anchors_doc(&(1..=SUB_PAGES).collect::<Vec<_>>());
+
+    for page in 1..=SUB_PAGES {
+        if page > 2 {
+            server.expect(
+                Expectation::matching(request::method_path("GET", format!("/root/{}/", page)))
+                    .respond_with(
+                        status_code(200)
+                            .append_header("Content-Type", "text/html")
+                            .body(html_doc.clone()),
+                    ),
+            );
+
+            expected_stats.add_html(html_doc.len());
+            expected_messages.push(format!("INFO: Fetching {}/{page}/", server.url("/root")));
+
+            // Serve up the file content
+            for a in 1..=SUB_PAGES {
+                if page != 3 || a != 1 {
+                    server.expect(
+                        Expectation::matching(request::method_path(
+                            "GET",
+                            format!("/root/{page}/{a}"),
+                        ))
+                        .respond_with(status_code(200).body(file_content)),
+                    );
+
+                    expected_contents.push(TmpFile::Dir(format!("download/{page}")));
+                    expected_messages
+                        .push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
+
+                    expected_stats.add_download(file_content.len());
+                    expected_contents
+                        .push(TmpFile::File(format!("download/{page}/{a}"), file_content));
+                    expected_messages
+                        .push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
+                    expected_messages.push(format!(
+                        "INFO: Downloading {}/{page}/{a} to {}/download/{page}/{a} (size {})",
+                        server.url("/root"),
+                        tmpdir.path().display(),
+                        file_content.len()
+                    ));
+                } else {
+                    expected_stats.add_skipped();
+                    expected_messages.push(format!(
+                        "INFO: Skipping {}/{page}/{a}: Path is in the skip list",
+                        server.url("/root")
+                    ));
+                }
+            }
+        } else {
+            expected_stats.add_skipped();
+            expected_messages.push(format!(
+                "INFO: Skipping {}/{page}/: Path is in the skip list",
+                server.url("/root")
+            ));
+        }
+    }
+
+    expected_messages.push("INFO: 3 documents parsed (626 bytes)".to_string());
+    expected_messages.push(
+        "INFO: 7 files downloaded (91 bytes), 0 not modified, 3 skipped, 0 errored".to_string(),
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_contents,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_redirect() {
+    let (args, mut server, tmpdir) = test_setup("/root");
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&["beforefile", "extfile"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root request and respond with a redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root"))
+            .respond_with(status_code(301).append_header("Location", "/root/")),
+    );
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/beforefile request and respond with a relative redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/beforefile"))
+            .respond_with(status_code(301).append_header("Location", "/root/afterfile")),
+    );
+
+    // Configure the server to expect a single GET /root/beforefile request and respond with a relative redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/afterfile"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Configure the server to expect a single GET /root/extfile request and respond with an non-relative redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/extfile"))
+            .respond_with(status_code(301).append_header("Location", "/other/extfile")),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Fetching {}", server.url("/root/beforefile")),
+        format!("INFO: Fetching {}", server.url("/root/extfile")),
+        format!(
+            "INFO: Skipping {}: Redirect to {} is not relative to the base URL",
+            server.url("/root/extfile"),
+            server.url("/other/extfile")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/afterfile (size {})",
+            server.url("/root/afterfile"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/afterfile", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_record_redirects() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.record_redirects = Some(RecordRedirects::Symlink);
+
+    // Build document with an anchor to a moved file
+    let html_doc = build_html_anchors_doc(&["old/file"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/old/file request and respond with a redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/old/file"))
+            .respond_with(status_code(301).append_header("Location", "/root/new/file")),
+    );
+
+    // Configure the server to expect a single GET /root/new/file request and respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/new/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/old/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/new/file (size {})",
+            server.url("/root/new/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Recorded redirect from {} to {} at {}/download/old/file",
+            server.url("/root/old/file"),
+            server.url("/root/new/file"),
+            tmpdir.path().display()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/new"),
+            TmpFile::File("download/new/file", file_content),
+            TmpFile::Dir("download/old"),
+            TmpFile::Link("download/old/file", "../new/file"),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_too_many_redirects() {
+    let (args, mut server, tmpdir) = test_setup("/root");
+
+    // Configure the server to expect a single GET /root request and respond with a redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root"))
+            .respond_with(status_code(301).append_header("Location", "/root/1")),
+    );
+
+    for i in 1..=args.max_redirects {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{}", i)))
+                .respond_with(
+                    status_code(301).append_header("Location", format!("/root/{}", i + 1)),
+                ),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Skipping {}: Too many redirects", server.url("/root")),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 1 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_cacert_missing() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    // Point the CA certificate at a file which doesn't exist
+    let mut cacert = tmpdir.path().to_path_buf();
+    cacert.push("missing.pem");
+    args.cacert = Some(cacert.to_string_lossy().to_string());
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Err(format!(
+            "Unable to read CA certificate file {}: No such file or directory (os error 2)",
+            cacert.display()
+        )
+        .into()),
+        &[] as &[&str; 0],
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_client_cert() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    // Write the client certificate and key
+    let client_cert = tmpdir.path().join("client.pem");
+    let client_key = tmpdir.path().join("client.key");
+    std::fs::write(&client_cert, CLIENT_CERT_PEM).unwrap();
+    std::fs::write(&client_key, CLIENT_KEY_PEM).unwrap();
+
+    args.client_cert = Some(client_cert.to_string_lossy().to_string());
+    args.client_key = Some(client_key.to_string_lossy().to_string());
+
+    let file_content = "Hello, world!";
+
+    // **** First process ****
+
+    // Configure the server to expect a single GET /file request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("client.key", CLIENT_KEY_PEM),
+            TmpFile::File("client.pem", CLIENT_CERT_PEM),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // Point the client key at a file which doesn't exist
+    let missing_key = tmpdir.path().join("missing.key");
+    args.client_key = Some(missing_key.to_string_lossy().to_string());
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Err(format!(
+            "Unable to read client key file {}: No such file or directory (os error 2)",
+            missing_key.display()
+        )
+        .into()),
+        &[] as &[&str; 0],
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("client.key", CLIENT_KEY_PEM),
+            TmpFile::File("client.pem", CLIENT_CERT_PEM),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_allow_host() {
+    // Bind to the IPv4 loopback address so localhost can be used as a different host name
+    let (mut args, mut server, tmpdir) = test_setup_ipv4("/root");
+
+    args.allow_host = vec!["localhost".to_string()];
+
+    let port = server.addr().port();
+
+    // Build a URL for the server on a different host name
+    let other_url = format!("http://localhost:{port}/other/file");
+
+    // Build document with an anchor to the other host
+    let html_doc = build_html_anchors_doc(&[&other_url]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /other/file request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/other/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root")),
+        format!("INFO: Fetching {other_url}"),
+        format!(
+            "INFO: Downloading {other_url} to {}/download/localhost:{port}/other/file (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download".to_string()),
+            TmpFile::Dir(format!("download/localhost:{port}")),
+            TmpFile::Dir(format!("download/localhost:{port}/other")),
+            TmpFile::File(
+                format!("download/localhost:{port}/other/file"),
+                file_content.to_string(),
+            ),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_host_directories() {
+    // Bind to the IPv4 loopback address so localhost can be used as a different host name
+    let (mut args, mut server, tmpdir) = test_setup_ipv4("/root/");
+
+    args.allow_host = vec!["localhost".to_string()];
+    args.host_directories = true;
+
+    let port = server.addr().port();
+
+    // Build a URL for the server on a different host name
+    let other_url = format!("http://localhost:{port}/other/file");
+
+    // Build document with an anchor to a file on the base host and one on the other host
+    let html_doc = build_html_anchors_doc(&["file1", &other_url]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for path in ["/root/file1", "/other/file"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {other_url}"),
+        format!(
+            "INFO: Downloading {} to {}/download/127.0.0.1:{port}/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {other_url} to {}/download/localhost:{port}/other/file (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download".to_string()),
+            TmpFile::Dir(format!("download/127.0.0.1:{port}")),
+            TmpFile::File(
+                format!("download/127.0.0.1:{port}/file1"),
+                file_content.to_string(),
+            ),
+            TmpFile::Dir(format!("download/localhost:{port}")),
+            TmpFile::Dir(format!("download/localhost:{port}/other")),
+            TmpFile::File(
+                format!("download/localhost:{port}/other/file"),
+                file_content.to_string(),
+            ),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_no_host_directories() {
+    // Bind to the IPv4 loopback address so localhost can be used as a different host name
+    let (mut args, mut server, tmpdir) = test_setup_ipv4("/root/");
+
+    args.allow_host = vec!["localhost".to_string()];
+    args.no_host_directories = true;
+    args.cut_dirs = 1;
+
+    let port = server.addr().port();
+
+    // Build a URL for the server on a different host name
+    let other_url = format!("http://localhost:{port}/other/sub/file");
+
+    // Build document with an anchor to a file on the base host and one on the other host
+    let html_doc = build_html_anchors_doc(&["a/file1", &other_url]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for path in ["/root/a/file1", "/other/sub/file"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/a/file1")),
+        format!("INFO: Fetching {other_url}"),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/a/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {other_url} to {}/download/sub/file (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_fragment_strip() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.fragments = Some(LinkAction::Strip);
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&["file1#hash", "file1", "#"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_file_source() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    let file_content = "Hello, world!";
+
+    // Create a source directory tree
+    let mut source = tmpdir.path().to_path_buf();
+    source.push("source");
+    create_tmp_file(&source.join("file1"), file_content).await;
+    create_tmp_file(&source.join("sub").join("file2"), file_content).await;
+
+    let source_url = Url::from_directory_path(&source).unwrap();
+
+    args.urls = vec![source_url.to_string()];
+    args.no_etags = true;
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+
+    for _ in 0..2 {
+        expected_stats.add_html(0);
+        expected_stats.add_download(file_content.len());
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {source_url}"),
+        format!("INFO: Fetching {source_url}file1"),
+        format!("INFO: Fetching {source_url}sub/"),
+        format!("INFO: Fetching {source_url}sub/file2"),
+        format!(
+            "INFO: Downloading {source_url}file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {source_url}sub/file2 to {}/download/sub/file2 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 2 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("source"),
+            TmpFile::File("source/file1", file_content),
+            TmpFile::Dir("source/sub"),
+            TmpFile::File("source/sub/file2", file_content),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_file_verify() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.verify = Some(HashType::Md5);
+    args.no_etags = true;
+
+    let file_content = "Hello, world!";
+    let good_md5 = "6cd3556deb0da54bca060b4c39479839";
+    let bad_md5 = "00000000000000000000000000000000";
+    let sums = format!("{good_md5}  file1\n{bad_md5}  file2\n");
+
+    // Create a source directory with a checksum file
+    let source = tmpdir.path().join("source");
+    create_tmp_file(&source.join("MD5SUMS"), &sums).await;
+    create_tmp_file(&source.join("file1"), file_content).await;
+    create_tmp_file(&source.join("file2"), file_content).await;
+
+    let source_url = Url::from_directory_path(&source).unwrap();
+
+    args.urls = vec![source_url.to_string()];
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(0);
+    expected_stats.add_download(sums.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {source_url}")];
+
+    for (file, size) in [
+        ("MD5SUMS", sums.len()),
+        ("file1", file_content.len()),
+        ("file2", file_content.len()),
+    ] {
+        expected_messages.push(format!("INFO: Fetching {source_url}{file}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {source_url}{file} to {}/download/{file} (size {size})",
+            tmpdir.path().display(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "ERROR: md5 mismatch for {}/download/file2: expected {bad_md5}, got {good_md5}",
+        tmpdir.path().display()
+    ));
+    expected_messages.push("INFO: 1 document parsed (0 bytes)".to_string());
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+        sums.len() + file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("source"),
+            TmpFile::File("source/MD5SUMS", sums.as_str()),
+            TmpFile::File("source/file1", file_content),
+            TmpFile::File("source/file2", file_content),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/MD5SUMS", sums.as_str()),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_allow_query() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.allow_query = true;
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&["download.php?id=1", "sub/?a/b"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/download.php?id=1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/root/download.php"),
+            request::query(url_decoded(contains(("id", "1"))))
+        ])
+        .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Configure the server to expect a single GET /root/sub/?a/b request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/sub/"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    for _ in 0..2 {
+        expected_stats.add_download(file_content.len());
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/download.php?id=1")),
+        format!("INFO: Fetching {}", server.url("/root/sub/?a/b")),
+        format!(
+            "INFO: Downloading {} to {}/download/download.php%3Fid=1 (size {})",
+            server.url("/root/download.php?id=1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/sub/index.html%3Fa%2Fb (size {})",
+            server.url("/root/sub/?a/b"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/download.php%3Fid=1", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/index.html%3Fa%2Fb", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_robots() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.ignore_robots = false;
+
+    let robots = "\
+User-agent: otherbot
+Disallow: /
+
+User-agent: *
+Disallow: /root/private
+Allow: /root/private/public
+";
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&["private/file", "private/public", "file1"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /robots.txt request and respond with the rules
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/robots.txt"))
+            .respond_with(status_code(200).body(robots)),
+    );
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect GET requests for the allowed files
+    for path in ["/root/private/public", "/root/file1"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/private/public")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Skipping {}: Path is disallowed by robots.txt",
+            server.url("/root/private/file")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/private/public (size {})",
+            server.url("/root/private/public"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/private"),
+            TmpFile::File("download/private/public", file_content),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_robots_other_port() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.ignore_robots = false;
+
+    // Second server on the same host with a different port
+    let mut other = Server::run();
+
+    let robots = "\
+User-agent: *
+Disallow: /root/private
+";
+
+    // Build document linking to a disallowed path on both servers
+    let html_doc = build_html_anchors_doc(&[
+        "private/file",
+        &other.url("/root/private/other").to_string(),
+    ]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /robots.txt request and respond with the rules
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/robots.txt"))
+            .respond_with(status_code(200).body(robots)),
+    );
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the other server to expect a single GET /root/private/other request as the rules
+    // don't apply to it
+    other.expect(
+        Expectation::matching(request::method_path("GET", "/root/private/other"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", other.url("/root/private/other")),
+        format!(
+            "INFO: Skipping {}: Path is disallowed by robots.txt",
+            server.url("/root/private/file")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/private/other (size {})",
+            other.url("/root/private/other"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    other.verify_and_clear();
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/private"),
+            TmpFile::File("download/private/other", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_head_first_no_head() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.head_first = true;
+
+    // Build document with some anchors
+    let html_doc = build_html_anchors_doc(&["file1", "file2"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single HEAD /root/ request and respond with method not allowed
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/root/"))
+            .respond_with(status_code(405)),
+    );
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect GET requests only for the files
+    for path in ["/root/file1", "/root/file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    let host = Url::parse(&server.url_str("/"))
+        .unwrap()
+        .host_str()
+        .unwrap()
+        .to_string();
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Host {host} does not handle HEAD requests, using GET"),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/file2 (size {})",
+            server.url("/root/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    let hosts_json = format!("{{\n  \"{host}\": {{\n    \"head\": false\n  }}\n}}");
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.hosts.json", hosts_json.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_sitemap() {
+    let (args, mut server, tmpdir) = test_setup("/root/sitemap.xml");
+
+    // Build sitemap document
+    let sitemap = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+    <url><loc>{}</loc></url>
+    <url><loc>{}</loc></url>
+</urlset>"#,
+        server.url("/root/file1"),
+        server.url("/root/data.xml")
+    );
+
+    let file_content = "Hello, world!";
+    let xml_content = "<data>Hello, world!</data>";
+
+    // Configure the server to expect a single GET /root/sitemap.xml request and respond with the sitemap
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/sitemap.xml")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/xml")
+                .body(sitemap.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/file1 request and respond with the file content.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Configure the server to expect a single GET /root/data.xml request and respond with an XML document.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/data.xml")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/xml")
+                .body(xml_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(sitemap.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(xml_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/sitemap.xml")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/root/data.xml")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/data.xml (size {})",
+            server.url("/root/data.xml"),
+            tmpdir.path().display(),
+            xml_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", sitemap.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() + xml_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/data.xml", xml_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_large_xml() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/sitemap.xml");
+
+    args.bytes = true;
+
+    // Build a sitemap document which is longer than the start of the body sniffed
+    let sitemap = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+    <!-- {} -->
+    <url><loc>{}</loc></url>
+</urlset>"#,
+        "padding ".repeat(1024),
+        server.url("/root/data.xml")
+    );
+
+    // Build an XML file which is streamed to disk
+    let xml_content = format!("<data>{}</data>", "Hello, world! ".repeat(1024));
+
+    // Configure the server to expect a single GET /root/sitemap.xml request and respond with the sitemap
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/sitemap.xml")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/xml")
+                .body(sitemap.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/data.xml request and respond with the XML file
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/data.xml")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/xml")
+                .body(xml_content.clone()),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(sitemap.len());
+    expected_stats.add_download(xml_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/sitemap.xml")),
+        format!("INFO: Fetching {}", server.url("/root/data.xml")),
+        format!(
+            "INFO: Downloading {} to {}/download/data.xml (size {})",
+            server.url("/root/data.xml"),
+            tmpdir.path().display(),
+            xml_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", sitemap.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            xml_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/data.xml", xml_content.as_str()),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_feed() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/podcast.rss");
+
+    args.bytes = true;
+
+    let addr = server.addr();
+
+    // Build feed document
+    let build_feed = |episodes: &[(&str, &str)]| {
+        let items = episodes
+            .iter()
+            .map(|(path, date)| {
+                format!(
+                    r#"    <item>
+      <title>{path}</title>
+      <link>http://{addr}/root/</link>
+      <enclosure url="http://{addr}{path}" length="13" type="audio/mpeg"/>
+      <pubDate>{date}</pubDate>
+    </item>
+"#
+                )
+            })
+            .collect::<String>();
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Podcast</title>
+    <link>http://{addr}/root/</link>
+{items}  </channel>
+</rss>"#
+        )
+    };
+
+    let file_content = "Hello, world!";
+
+    let ep1 = ("/root/ep1.mp3", "Fri, 01 Mar 2024 00:00:00 GMT");
+    let ep2 = ("/root/ep2.mp3", "Fri, 08 Mar 2024 00:00:00 GMT");
+    let ep3 = ("/root/ep3.mp3", "Fri, 15 Mar 2024 00:00:00 GMT");
+
+    // Published at the same time as episode 2 but added to the feed after the first run
+    let ep2b = ("/root/ep2b.mp3", ep2.1);
+
+    let cursor_json = |published: u64, paths: &[&str]| {
+        serde_json::to_string_pretty(&std::collections::BTreeMap::from([(
+            format!("http://{addr}/root/podcast.rss"),
+            FeedCursor {
+                published,
+                links: paths
+                    .iter()
+                    .map(|path| format!("http://{addr}{path}"))
+                    .collect(),
+            },
+        )]))
+        .unwrap()
+    };
+
+    // **** First process ****
+
+    let feed = build_feed(&[ep2, ep1]);
+
+    // Configure the server to expect a single GET /root/podcast.rss request and respond with the feed
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/podcast.rss")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/rss+xml")
+                .body(feed.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET request for each episode
+    for (path, _) in [ep1, ep2] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(feed.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/podcast.rss")),
+        format!("INFO: 1 document parsed ({} bytes)", feed.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    for (path, _) in [ep1, ep2] {
+        expected_messages.push(format!("INFO: Fetching {}", server.url(path)));
+        expected_messages.push(format!(
+            "INFO: Downloading {} to {}/download/{} (size {})",
+            server.url(path),
+            tmpdir.path().display(),
+            &path[6..],
+            file_content.len()
+        ));
+    }
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    let cursor = cursor_json(1709856000, &[ep2.0]);
+
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.feed-cursors.json", cursor.as_str()),
+            TmpFile::File("download/ep1.mp3", file_content),
+            TmpFile::File("download/ep2.mp3", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    let feed = build_feed(&[ep3, ep2b, ep2, ep1]);
+
+    // Configure the server to expect a single GET /root/podcast.rss request and respond with the
+    // feed with new episodes
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/podcast.rss")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/rss+xml")
+                .body(feed.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET request for each new episode only
+    for (path, _) in [ep3, ep2b] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(feed.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/podcast.rss")),
+        format!(
+            "INFO: Skipping {}: Feed entry was published before the last run",
+            server.url(ep1.0)
+        ),
+        format!(
+            "INFO: Skipping {}: Feed entry was published before the last run",
+            server.url(ep2.0)
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", feed.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 2 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    for (path, _) in [ep3, ep2b] {
+        expected_messages.push(format!("INFO: Fetching {}", server.url(path)));
+        expected_messages.push(format!(
+            "INFO: Downloading {} to {}/download/{} (size {})",
+            server.url(path),
+            tmpdir.path().display(),
+            &path[6..],
+            file_content.len()
+        ));
+    }
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    let cursor = cursor_json(1710460800, &[ep3.0]);
+
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.feed-cursors.json", cursor.as_str()),
+            TmpFile::File("download/ep1.mp3", file_content),
+            TmpFile::File("download/ep2.mp3", file_content),
+            TmpFile::File("download/ep2b.mp3", file_content),
+            TmpFile::File("download/ep3.mp3", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_feed_retry() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/podcast.rss");
+
+    args.bytes = true;
+
+    let addr = server.addr();
+
+    let file_content = "Hello, world!";
+
+    let ep1 = ("/root/ep1.mp3", "Fri, 01 Mar 2024 00:00:00 GMT");
+    let ep2 = ("/root/ep2.mp3", "Fri, 08 Mar 2024 00:00:00 GMT");
+
+    // Build feed document
+    let items = [ep2, ep1]
+        .iter()
+        .map(|(path, date)| {
+            format!(
+                r#"    <item>
+      <title>{path}</title>
+      <enclosure url="http://{addr}{path}" length="13" type="audio/mpeg"/>
+      <pubDate>{date}</pubDate>
+    </item>
+"#
+            )
+        })
+        .collect::<String>();
+
+    let feed = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Podcast</title>
+{items}  </channel>
+</rss>"#
+    );
+
+    let cursor = serde_json::to_string_pretty(&std::collections::BTreeMap::from([(
+        format!("http://{addr}/root/podcast.rss"),
+        FeedCursor {
+            published: 1709856000,
+            links: [format!("http://{addr}{}", ep2.0)].into(),
+        },
+    )]))
+    .unwrap();
+
+    // **** First process ****
+
+    // Configure the server to expect a single GET /root/podcast.rss request and respond with the feed
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/podcast.rss")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/rss+xml")
+                .body(feed.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET request for each episode, failing the newest
+    server.expect(
+        Expectation::matching(request::method_path("GET", ep1.0))
+            .respond_with(status_code(200).body(file_content)),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", ep2.0)).respond_with(status_code(500)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(feed.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/podcast.rss")),
+        format!("INFO: Fetching {}", server.url(ep1.0)),
+        format!("INFO: Fetching {}", server.url(ep2.0)),
+        format!(
+            "INFO: Downloading {} to {}/download/ep1.mp3 (size {})",
+            server.url(ep1.0),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "ERROR: Status 500 Internal Server Error fetching {}",
+            server.url(ep2.0)
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", feed.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results - the cursor isn't moved on past the failed episode
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/ep1.mp3", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // Configure the server to expect the feed and a single GET request for each episode again
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/podcast.rss")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/rss+xml")
+                .body(feed.clone()),
+        ),
+    );
+
+    for (path, _) in [ep1, ep2] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(feed.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/podcast.rss")),
+        format!("INFO: 1 document parsed ({} bytes)", feed.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    for (path, _) in [ep1, ep2] {
+        expected_messages.push(format!("INFO: Fetching {}", server.url(path)));
+        expected_messages.push(format!(
+            "INFO: Downloading {} to {}/download/{} (size {})",
+            server.url(path),
+            tmpdir.path().display(),
+            &path[6..],
+            file_content.len()
+        ));
+    }
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results - the failed episode is retried and the cursor moves on
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.feed-cursors.json", cursor.as_str()),
+            TmpFile::File("download/ep1.mp3", file_content),
+            TmpFile::File("download/ep2.mp3", file_content),
+        ],
+    )
+    .await;
+}
+
+#[test]
+fn test_parse_feed() {
+    let atom = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Releases</title>
+  <link href="https://example.com/"/>
+  <entry>
+    <title>v1.1</title>
+    <link rel="alternate" href="https://example.com/v1.1.html"/>
+    <link rel="enclosure" href="https://example.com/v1.1.tar.gz" length="100"/>
+    <updated>2024-03-08T01:30:00+01:00</updated>
+  </entry>
+  <entry>
+    <title>v1.0</title>
+    <link href="https://example.com/v1.0.html?a=1&amp;b=2"/>
+    <published>2024-03-01T00:00:00.250Z</published>
+  </entry>
+  <entry>
+    <title>No link</title>
+  </entry>
+</feed>"#;
+
+    assert_eq!(
+        parse_feed(atom),
+        Some(vec![
+            FeedEntry {
+                link: "https://example.com/v1.1.tar.gz".to_string(),
+                published: Some(1709857800),
+            },
+            FeedEntry {
+                link: "https://example.com/v1.0.html?a=1&b=2".to_string(),
+                published: Some(1709251200),
+            },
+        ])
+    );
+
+    assert_eq!(parse_feed("<urlset></urlset>"), None);
+
+    assert_eq!(
+        parse_date("Fri, 08 Mar 2024 00:00:00 GMT"),
+        Some(1709856000)
+    );
+    assert_eq!(parse_date("8 Mar 2024 01:00 +0100"), Some(1709856000));
+    assert_eq!(
+        parse_date("Thu, 07 Mar 2024 19:00:00 EST"),
+        Some(1709856000)
+    );
+    assert_eq!(parse_date("2024-03-08"), Some(1709856000));
+    assert_eq!(parse_date("last Friday"), None);
+}
+
+#[tokio::test]
+async fn test_page_requisites() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.page_requisites = true;
+
+    // Build document with some requisites
+    let html_doc = r#"<DOCTYPE html>
+<html>
+    <head>
+        <link rel="stylesheet" href="style.css">
+        <script src="script.js"></script>
+    </head>
+    <body>
+        <img src="image.png">
+        <a href="file1">File</a>
+    </body>
+</html>"#;
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc),
+        ),
+    );
+
+    let files = ["style.css", "script.js", "image.png", "file1"];
+
+    // Configure the server to expect a GET request for each file
+    for file in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    // Build expected files
+    let mut expected_files = vec![TmpFile::Dir("download".to_string())];
+
+    for file in files {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{file}"),
+            file_content.to_string(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 4 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 4
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_robots_meta() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.save_html = true;
+    args.respect_robots_meta = true;
+
+    // Build documents
+    let html_doc = build_html_anchors_doc(&["page2"]);
+
+    let html_doc2 = r#"<DOCTYPE html>
+<html>
+    <head>
+        <meta name="robots" content="noindex, nofollow">
+    </head>
+    <body>
+        <a href="file1">File</a>
+    </body>
+</html>"#;
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/page2 request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/page2")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc2),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_html(html_doc2.len());
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!(
+            "INFO: Downloading {} to {}/download/index.html (size {})",
+            server.url("/root/"),
+            tmpdir.path().display(),
+            html_doc.len()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/page2")),
+        format!(
+            "INFO: Skipping {}: Page is marked noindex",
+            server.url("/root/page2")
+        ),
+        format!(
+            "INFO: Skipping {}: Page links are marked nofollow",
+            server.url("/root/page2")
+        ),
+        format!(
+            "INFO: 2 documents parsed ({} bytes)",
+            html_doc.len() + html_doc2.len()
+        ),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 2 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/index.html", html_doc.as_str()),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_synth_etag() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.synth_etags = true;
+
+    let file_content = "Hello, world!";
+    let file_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+
+    let mut etags_path = tmpdir.path().to_path_buf();
+    etags_path.push("download");
+    etags_path.push(".etags.json");
+
+    // **** First process ****
+
+    // Configure the server to expect a single GET /file request and respond with the file content and no validators
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check the synthesized etag
+    let etags_content = tokio::fs::read_to_string(&etags_path)
+        .await
+        .expect("Failed to read etags file");
+
+    assert!(etags_content.contains(&format!("\"mirrorurl:{}:", file_content.len())));
+    assert!(etags_content.contains(file_sha256));
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // Configure the server to expect a single HEAD /file request without an If-None-Matches header
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("HEAD", "/file"),
+            request::headers(not(contains(key("if-none-match")))),
+        ))
+        .respond_with(
+            status_code(200).append_header("Content-Length", file_content.len().to_string()),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!("INFO: {} is not modified", server.url("/file"),),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_auto_extract() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.auto_extract = vec![ArchiveType::TarGz];
+
+    // Build the archive with a member name too long for the tar header
+    let long_dir = format!("docs/{}", "chapter".repeat(16));
+    let long_name = format!("{long_dir}/notes.txt");
+
+    let archive = build_tar_gz(&[
+        ("readme.txt", "Hello, world!"),
+        ("docs/guide.txt", "Read me first"),
+        (&long_name, "Long name"),
+    ]);
+
+    // Build document linking to the archive
+    let html_doc = build_html_anchors_doc(&["bundle.tar.gz"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/bundle.tar.gz request and respond with the archive
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/bundle.tar.gz")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/gzip")
+                .body(archive.clone()),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(archive.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/bundle.tar.gz")),
+        format!(
+            "INFO: Downloading {} to {}/download/bundle.tar.gz (size {})",
+            server.url("/root/bundle.tar.gz"),
+            tmpdir.path().display(),
+            archive.len()
+        ),
+        format!(
+            "INFO: Extracted 3 members from {0}/download/bundle.tar.gz to {0}/download/bundle.tar.gz.d",
+            tmpdir.path().display(),
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            archive.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download".to_string()),
+            TmpFile::File(
+                "download/bundle.tar.gz".to_string(),
+                String::from_utf8_lossy(&archive).into_owned(),
+            ),
+            TmpFile::Dir("download/bundle.tar.gz.d".to_string()),
+            TmpFile::File(
+                "download/bundle.tar.gz.d/readme.txt".to_string(),
+                "Hello, world!".to_string(),
+            ),
+            TmpFile::Dir("download/bundle.tar.gz.d/docs".to_string()),
+            TmpFile::File(
+                "download/bundle.tar.gz.d/docs/guide.txt".to_string(),
+                "Read me first".to_string(),
+            ),
+            TmpFile::Dir(format!("download/bundle.tar.gz.d/{long_dir}")),
+            TmpFile::File(
+                format!("download/bundle.tar.gz.d/{long_name}"),
+                "Long name".to_string(),
+            ),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_auto_extract_zip() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.auto_extract = vec![ArchiveType::Zip];
+
+    // Build the archive
+    let archive = build_zip(&[
+        ("readme.txt", "Hello, world!"),
+        ("docs/guide.txt", "Read me first"),
+    ]);
+
+    // Build document linking to the archive
+    let html_doc = build_html_anchors_doc(&["bundle.zip"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/bundle.zip request and respond with the archive
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/bundle.zip")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/zip")
+                .body(archive.clone()),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(archive.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/bundle.zip")),
+        format!(
+            "INFO: Downloading {} to {}/download/bundle.zip (size {})",
+            server.url("/root/bundle.zip"),
+            tmpdir.path().display(),
+            archive.len()
+        ),
+        format!(
+            "INFO: Extracted 2 members from {0}/download/bundle.zip to {0}/download/bundle.zip.d",
+            tmpdir.path().display(),
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            archive.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download".to_string()),
+            TmpFile::File(
+                "download/bundle.zip".to_string(),
+                String::from_utf8_lossy(&archive).into_owned(),
+            ),
+            TmpFile::Dir("download/bundle.zip.d".to_string()),
+            TmpFile::File(
+                "download/bundle.zip.d/readme.txt".to_string(),
+                "Hello, world!".to_string(),
+            ),
+            TmpFile::Dir("download/bundle.zip.d/docs".to_string()),
+            TmpFile::File(
+                "download/bundle.zip.d/docs/guide.txt".to_string(),
+                "Read me first".to_string(),
+            ),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_generate_index() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.generate_index = true;
+
+    // Build document linking to a file and a file in a sub directory
+    let html_doc = build_html_anchors_doc(&["file1", "sub/file2"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for file in ["file1", "sub/file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for file in ["file1", "sub/file2"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 2
+    ));
+    expected_messages.push("INFO: Generated 2 index pages".to_string());
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check the generated index pages
+    let root_index = tokio::fs::read_to_string(tmpdir.path().join("download/index.html"))
+        .await
+        .expect("Failed to read root index page");
+
+    assert!(root_index.contains("<title>Index of /</title>"));
+    assert!(root_index.contains("<a href=\"sub/\">sub/</a></td><td>-</td>"));
+    assert!(root_index.contains(&format!(
+        "<a href=\"file1\">file1</a></td><td>{}</td>",
+        file_content.len()
+    )));
+
+    let sub_index = tokio::fs::read_to_string(tmpdir.path().join("download/sub/index.html"))
+        .await
+        .expect("Failed to read sub directory index page");
+
+    assert!(sub_index.contains("<title>Index of /sub</title>"));
+    assert!(sub_index.contains("<a href=\"../\">../</a>"));
+    assert!(sub_index.contains(&format!(
+        "<a href=\"file2\">file2</a></td><td>{}</td>",
+        file_content.len()
+    )));
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/index.html", root_index.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/index.html", sub_index.as_str()),
+            TmpFile::File("download/sub/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_index_name() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.index_name = "default.htm".to_string();
+
+    // Build document linking to a directory style URL
+    let html_doc = build_html_anchors_doc(&["sub/"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/sub/ request and respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/sub/"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/sub/")),
+        format!(
+            "INFO: Downloading {} to {}/download/sub/default.htm (size {})",
+            server.url("/root/sub/"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/default.htm", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_emit_sitemap() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    let sitemap_path = tmpdir.path().join("sitemap.xml");
+
+    args.save_html = true;
+    args.emit_sitemap = Some(sitemap_path.to_string_lossy().into_owned());
+    args.publish_base = Some(Url::parse("https://mirror.example.com/pub/").unwrap());
+
+    // Build document linking to files
+    let html_doc = build_html_anchors_doc(&["file1", "sub/file2"]);
+
+    let file_content = "Hello, world!";
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for file in ["file1", "sub/file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!(
+            "INFO: Downloading {} to {}/download/index.html (size {})",
+            server.url("/root/"),
+            tmpdir.path().display(),
+            html_doc.len()
+        ),
+    ];
+
+    for file in ["file1", "sub/file2"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 2
+    ));
+    expected_messages.push(format!(
+        "INFO: Wrote 3 URLs to sitemap {}",
+        sitemap_path.display()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check the sitemap
+    let sitemap = tokio::fs::read_to_string(&sitemap_path)
+        .await
+        .expect("Failed to read sitemap");
+
+    let locs: Vec<&str> = sitemap
+        .lines()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("<loc>")
+                .and_then(|line| line.strip_suffix("</loc>"))
+        })
+        .collect();
+
+    assert_eq!(
+        locs,
+        [
+            "https://mirror.example.com/pub/",
+            "https://mirror.example.com/pub/file1",
+            "https://mirror.example.com/pub/sub/file2",
+        ]
+    );
+
+    assert_eq!(
+        sitemap
+            .lines()
+            .filter(|line| line.contains("<lastmod>"))
+            .count(),
+        3
+    );
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("sitemap.xml", sitemap.as_str()),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/index.html", html_doc.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_digests() {
+    let (_args, _server, tmpdir) = test_setup("/");
+
+    let file_content = "Hello, world!";
+    let sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+    let md5 = "6cd3556deb0da54bca060b4c39479839";
+
+    // Digests worked out as data is written
+    let mut hasher = DigestHasher::new(Some(HashType::Md5));
+    hasher.update(&file_content.as_bytes()[..5]);
+    hasher.update(&file_content.as_bytes()[5..]);
+    let digests = hasher.finalize();
+
+    assert_eq!(digests.sha256(), sha256);
+    assert_eq!(digests.get(HashType::Sha256), Some(sha256));
+    assert_eq!(digests.get(HashType::Md5), Some(md5));
+    assert_eq!(digests.get(HashType::Sha1), None);
+
+    // Digests worked out by reading a file back
+    let path = tmpdir.path().join("file");
+    create_tmp_file(&path, file_content).await;
+
+    assert_eq!(
+        file_digests(&path, Some(HashType::Md5)).await.unwrap(),
+        digests
+    );
+
+    let digests = file_digests(&path, None).await.unwrap();
+    assert_eq!(digests.sha256(), sha256);
+    assert_eq!(digests.get(HashType::Md5), None);
+}
+
+#[tokio::test]
+async fn test_preallocate() {
+    let (_args, _server, tmpdir) = test_setup("/");
+
+    let path = tmpdir.path().join("file");
+    let file = tokio::fs::File::create(&path).await.unwrap();
+
+    preallocate(&file, 1 << 20, &path).await.unwrap();
+
+    assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), 1 << 20);
+}
+
+#[tokio::test]
+async fn test_hash_fragment() {
+    let (args, mut server, tmpdir) = test_setup("/root/");
+
+    let file_content = "Hello, world!";
+    let good_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+    let bad_sha256 = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    // Build document linking to files annotated with hash fragments
+    let html_doc = build_html_anchors_doc(&[
+        format!("file1#sha256={good_sha256}"),
+        format!("file2#sha256={bad_sha256}"),
+    ]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file without the fragment
+    for file in ["file1", "file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for file in ["file1", "file2"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "ERROR: sha256 mismatch for {}/download/file2: expected {bad_sha256}, got {good_sha256}",
+        tmpdir.path().display()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+        file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_quarantine_failed() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+    args.quarantine_failed = true;
+
+    let file_content = "Hello, world!";
+    let good_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+    let bad_sha256 = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    // Build document linking to files annotated with hash fragments, one of which is wrong
+    let html_doc = build_html_anchors_doc(&[
+        format!("file1#sha256={good_sha256}"),
+        format!("file2#sha256={bad_sha256}"),
+    ]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file without the fragment
+    for file in ["file1", "file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for file in ["file1", "file2"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    let mismatch = format!(
+        "sha256 mismatch for {}/download/file2: expected {bad_sha256}, got {good_sha256}",
+        tmpdir.path().display()
+    );
+
+    expected_messages.push(format!(
+        "INFO: Quarantined {} as {}/download/.mirrorurl/quarantine/file2",
+        server.url("/root/file2"),
+        tmpdir.path().display()
+    ));
+    expected_messages.push(format!("ERROR: {mismatch}"));
+
+    let reason = format!("{}\n{mismatch}\n", server.url("/root/file2"));
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+        file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/.mirrorurl"),
+            TmpFile::Dir("download/.mirrorurl/quarantine"),
+            TmpFile::File("download/.mirrorurl/quarantine/file2", file_content),
+            TmpFile::File("download/.mirrorurl/quarantine/file2.reason", &reason),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_gzip_fallback() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+    args.gzip_fallback = vec!["=.gz".parse().unwrap()];
+
+    let packages = "Package: test\nVersion: 1.0\n";
+    let packages_gz = build_gz(packages);
+
+    // Build document linking to a plain file only published compressed and a compressed file
+    // only published plain
+    let html_doc = build_html_anchors_doc(&["Packages", "Sources.gz"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to return 404 for the linked files and serve the other copies
+    for file in ["Packages", "Sources.gz"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(404)),
+        );
+    }
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/Packages.gz"))
+            .respond_with(status_code(200).body(packages_gz.clone())),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/Sources"))
+            .respond_with(status_code(200).body(packages)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(packages.len());
+    expected_stats.add_download(packages_gz.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/Packages")),
+        format!("INFO: Fetching {}", server.url("/root/Sources.gz")),
+        format!(
+            "INFO: Converting {} to {}",
+            server.url("/root/Packages.gz"),
+            server.url("/root/Packages")
+        ),
+        format!(
+            "INFO: Converting {} to {}",
+            server.url("/root/Sources"),
+            server.url("/root/Sources.gz")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/Packages (size {})",
+            server.url("/root/Packages"),
+            tmpdir.path().display(),
+            packages.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/Sources.gz (size {})",
+            server.url("/root/Sources.gz"),
+            tmpdir.path().display(),
+            packages_gz.len()
+        ),
+    ];
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        packages.len() + packages_gz.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/Packages", packages),
+            TmpFile::File(
+                "download/Sources.gz",
+                &String::from_utf8_lossy(&packages_gz),
+            ),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_via_wayback() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+    args.urls = vec!["http://dead.example/root/".to_string()];
+    args.via_wayback = Some("20200101".to_string());
+    args.wayback_endpoint = Some(Url::parse(&server.url("/").to_string()).unwrap());
+
+    let file_content = "Hello, world!";
+
+    // Build document with a link to a file
+    let html_doc = build_html_anchors_doc(&["file1"]);
+
+    // Configure the server to redirect the snapshot request to the nearest snapshot
+    server.expect(
+        Expectation::matching(request::method_path(
+            "GET",
+            "/web/20200101id_/http://dead.example/root/",
+        ))
+        .respond_with(status_code(302).append_header(
+            "Location",
+            "/web/20191231120000id_/http://dead.example/root/",
+        )),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path(
+            "GET",
+            "/web/20191231120000id_/http://dead.example/root/",
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to return the snapshot of the linked file
+    server.expect(
+        Expectation::matching(request::method_path(
+            "GET",
+            "/web/20200101id_/http://dead.example/root/file1",
+        ))
+        .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        "INFO: Fetching http://dead.example/root/".to_string(),
+        "INFO: Fetching http://dead.example/root/file1".to_string(),
+        format!(
+            "INFO: Downloading http://dead.example/root/file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_verify_checksums() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.verify = Some(HashType::Md5);
+
+    let file_content = "Hello, world!";
+    let good_md5 = "6cd3556deb0da54bca060b4c39479839";
+    let bad_md5 = "00000000000000000000000000000000";
+
+    // Build document linking to files
+    let html_doc = build_html_anchors_doc(&["file1", "file2"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for file in ["file1", "file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Configure the server to expect a single GET /root/MD5SUMS request and respond with a checksum for file1
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/MD5SUMS"))
+            .respond_with(status_code(200).body(format!("{good_md5}  file1\n"))),
+    );
+
+    // Configure the server to expect a single GET /root/file2.md5 request and respond with a bad checksum
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2.md5"))
+            .respond_with(status_code(200).body(format!("{bad_md5} *file2\n"))),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for file in ["file1", "file2"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "ERROR: md5 mismatch for {}/download/file2: expected {bad_md5}, got {good_md5}",
+        tmpdir.path().display()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+        file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_verify_checksums_encoded() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.verify = Some(HashType::Md5);
+
+    let file_content = "Hello, world!";
+    let good_md5 = "6cd3556deb0da54bca060b4c39479839";
+    let bad_md5 = "00000000000000000000000000000000";
+
+    // Build document linking to a file with a space in its name
+    let html_doc = build_html_anchors_doc(&["a%20b.txt"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/a%20b.txt request and respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/a%20b.txt"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Configure the server to expect a single GET /root/MD5SUMS request and respond with a bad
+    // checksum for the file under its decoded name
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/MD5SUMS"))
+            .respond_with(status_code(200).body(format!("{bad_md5}  a b.txt\n"))),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let url = server.url("/root/a%20b.txt");
+
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {url}"),
+        format!(
+            "INFO: Downloading {url} to {}/download/a%20b.txt (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "ERROR: md5 mismatch for {}/download/a%20b.txt: expected {bad_md5}, got {good_md5}",
+            tmpdir.path().display()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[TmpFile::Dir("download")] as &[TmpFile<&str, &str>; 1],
+    )
+    .await;
+}
+
+#[cfg(feature = "metadata-store")]
+#[tokio::test]
+async fn test_metadata_store() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    let store_path = tmpdir.path().join("metadata.sqlite");
+    args.metadata_store = Some(store_path.to_string_lossy().into_owned());
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to files in two sub-trees
+    let html_doc = build_html_anchors_doc(&["a/file", "b/file"]);
+
+    // **** Shard processes ****
+
+    for (shard, other) in [("a", "b"), ("b", "a")] {
+        let mut args = args.clone();
+        args.only = vec![format!("{shard}/")];
+
+        // Configure the server to expect a single GET /root/ request and respond with the html document
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "text/html")
+                    .body(html_doc.clone()),
+            ),
+        );
+
+        // Configure the server to expect a single GET request for the file in this shard
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{shard}/file")))
+                .respond_with(
+                    status_code(200)
+                        .append_header("ETag", format!("\"{shard}\""))
+                        .body(file_content),
+                ),
+        );
+
+        // Build expected stats
+        let mut expected_stats = Stats::default();
+        expected_stats.add_html(html_doc.len());
+        expected_stats.add_download(file_content.len());
+        expected_stats.add_skipped();
+
+        // Build expected messages
+        let expected_messages = [
+            format!("INFO: Fetching {}", server.url("/root/")),
+            format!(
+                "INFO: Skipping {}: Path is outside the --only sub-trees",
+                server.url(&format!("/root/{other}/file"))
+            ),
+            format!(
+                "INFO: Fetching {}",
+                server.url(&format!("/root/{shard}/file"))
+            ),
+            format!(
+                "INFO: Downloading {} to {}/download/{shard}/file (size {})",
+                server.url(&format!("/root/{shard}/file")),
+                tmpdir.path().display(),
+                file_content.len()
+            ),
+            format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+            format!(
+                "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+                file_content.len()
+            ),
+        ];
+
+        // Process
+        let result = async_main(args).await;
+
+        // Check results
+        let store_content = String::from_utf8_lossy(
+            &tokio::fs::read(&store_path)
+                .await
+                .expect("Failed to read metadata store"),
+        )
+        .into_owned();
+
+        let mut expected_files = vec![
+            TmpFile::File("metadata.sqlite".to_string(), store_content),
+            TmpFile::Dir("download".to_string()),
+        ];
+
+        for file_shard in ["a", "b"] {
+            if file_shard == shard || file_shard == "a" {
+                expected_files.push(TmpFile::Dir(format!("download/{file_shard}")));
+                expected_files.push(TmpFile::File(
+                    format!("download/{file_shard}/file"),
+                    file_content.to_string(),
+                ));
+            }
+        }
+
+        check_results(
+            result,
+            Ok(expected_stats),
+            &expected_messages,
+            &mut server,
+            &tmpdir,
+            &expected_files,
+        )
+        .await;
+    }
+
+    // **** Unsharded process ****
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file with the etag saved by each shard
+    for shard in ["a", "b"] {
+        server.expect(
+            Expectation::matching(all_of!(
+                request::method_path("GET", format!("/root/{shard}/file")),
+                request::headers(contains(("if-none-match", format!("\"{shard}\"")))),
+            ))
+            .respond_with(status_code(304)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_not_modified();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for shard in ["a", "b"] {
+        let url = server.url(&format!("/root/{shard}/file"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!("INFO: {url} is not modified"));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(
+        "INFO: 0 files downloaded (0 bytes), 2 not modified, 0 skipped, 0 errored".to_string(),
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    let store_content = String::from_utf8_lossy(
+        &tokio::fs::read(&store_path)
+            .await
+            .expect("Failed to read metadata store"),
+    )
+    .into_owned();
+
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("metadata.sqlite", store_content.as_str()),
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/a"),
+            TmpFile::File("download/a/file", file_content),
+            TmpFile::Dir("download/b"),
+            TmpFile::File("download/b/file", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_manifest() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.manifest = true;
+
+    let file_content = "Hello, world!";
+    let file_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+
+    // Build document linking to a file
+    let html_doc = build_html_anchors_doc(&["sub/file1"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/sub/file1 request and respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/sub/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/sub/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/sub/file1 (size {})",
+            server.url("/root/sub/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check the manifest
+    let manifest = tokio::fs::read_to_string(tmpdir.path().join("download/.manifest.json"))
+        .await
+        .expect("Failed to read manifest");
+
+    let entries: serde_json::Value =
+        serde_json::from_str(&manifest).expect("Failed to parse manifest");
+
+    let entries = entries.as_array().expect("Manifest is not an array");
+    assert_eq!(entries.len(), 1);
+
+    let entry = &entries[0];
+    assert_eq!(entry["url"], server.url("/root/sub/file1").to_string());
+    assert_eq!(entry["path"], "sub/file1");
+    assert_eq!(entry["size"], file_content.len());
+    assert_eq!(entry["sha256"], file_sha256);
+    assert!(entry["timestamp"].as_u64().expect("Timestamp missing") > 0);
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.manifest.json", manifest.as_str()),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_store_compressed() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.manifest = true;
+    args.store_compressed = Some(StoreCompression::Gzip);
+
+    let file_content = "Hello, world!";
+    let file_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+    let file_gz = build_gz(file_content);
+
+    // Build document linking to a file
+    let html_doc = build_html_anchors_doc(&["sub/file1"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/sub/file1 request and respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/sub/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/sub/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/sub/file1.gz (size {})",
+            server.url("/root/sub/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check the manifest records the original name and contents
+    let manifest = tokio::fs::read_to_string(tmpdir.path().join("download/.manifest.json"))
+        .await
+        .expect("Failed to read manifest");
+
+    let entries: serde_json::Value =
+        serde_json::from_str(&manifest).expect("Failed to parse manifest");
+
+    let entries = entries.as_array().expect("Manifest is not an array");
+    assert_eq!(entries.len(), 1);
+
+    let entry = &entries[0];
+    assert_eq!(entry["path"], "sub/file1.gz");
+    assert_eq!(entry["original_path"], "sub/file1");
+    assert_eq!(entry["size"], file_content.len());
+    assert_eq!(entry["sha256"], file_sha256);
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.manifest.json", manifest.as_str()),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file1.gz", &String::from_utf8_lossy(&file_gz)),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_dedupe() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.manifest = true;
+    args.dedupe = true;
+    args.concurrent_fetch = 1;
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to two files with the same content
+    let html_doc = build_html_anchors_doc(&["file1", "file2"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for file in ["file1", "file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for file in ["file1", "file2"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: Linked {0}/download/file2 to identical file {0}/download/file1",
+        tmpdir.path().display()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 2
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check the files are the same inode
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta1 = std::fs::metadata(tmpdir.path().join("download/file1")).unwrap();
+        let meta2 = std::fs::metadata(tmpdir.path().join("download/file2")).unwrap();
+
+        assert_eq!(meta1.ino(), meta2.ino());
+    }
+
+    let manifest = tokio::fs::read_to_string(tmpdir.path().join("download/.manifest.json"))
+        .await
+        .expect("Failed to read manifest");
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.manifest.json", manifest.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_shard() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    let shard: Shard = "1/2".parse().unwrap();
+    args.shard = Some(shard);
+
+    let file_content = "Hello, world!";
+    let files = [
+        "file1",
+        "file2",
+        "file3",
+        "file4",
+        "file5",
+        "file6",
+        "file1.zip",
+        "file2.zip",
+        "file3.zip",
+        "file4.zip",
+        "file5.zip",
+        "file6.zip",
+    ];
+
+    // Work out which files are in the shard
+    let (ours, theirs): (Vec<&str>, Vec<&str>) =
+        files.iter().partition(|file| shard.contains(file));
+
+    // Links to files in another shard with a known file extension are not fetched
+    let fetched = |file: &&str| !file.ends_with(".zip");
+
+    assert!(!ours.is_empty() && theirs.iter().any(fetched) && !theirs.iter().all(fetched));
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file which is fetched
+    let others = theirs.iter().filter(|file| fetched(file));
+
+    for file in ours.iter().chain(others) {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    // Build expected files
+    let mut expected_files = vec![TmpFile::Dir("download".to_string())];
+
+    for file in &ours {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{file}"),
+            file_content.to_string(),
+        ));
+    }
+
+    for file in &theirs {
+        expected_stats.add_skipped();
+
+        let url = server.url(&format!("/root/{file}"));
+
+        if fetched(file) {
+            expected_messages.push(format!("INFO: Fetching {url}"));
+        }
+        expected_messages.push(format!("INFO: Skipping {url}: File is in another shard"));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: {} files downloaded ({} bytes), 0 not modified, {} skipped, 0 errored",
+        ours.len(),
+        file_content.len() * ours.len(),
+        theirs.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_host_header() {
+    let (mut args, mut server, tmpdir) = test_setup_ipv4("/root/");
+
+    args.host_header = Some("mirror.test".to_string());
+
+    let port = server.addr().port();
+    let host = format!("mirror.test:{port}");
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to the file
+    let html_doc = build_html_anchors_doc(&["file1"]);
+
+    // Configure the server to expect requests carrying the virtual host name
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/root/"),
+            request::headers(contains(("host", host.clone()))),
+        ])
+        .respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/root/file1"),
+            request::headers(contains(("host", host.clone()))),
+        ])
+        .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching http://{host}/root/"),
+        format!("INFO: Fetching http://{host}/root/file1"),
+        format!(
+            "INFO: Downloading http://{host}/root/file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Build expected files
+    let expected_files = [
+        TmpFile::Dir("download"),
+        TmpFile::File("download/file1", file_content),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_size_limits() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.min_size = Some(5);
+    args.max_size = Some(10);
+
+    let files = [
+        ("small", "abc"),
+        ("medium", "abcdefg"),
+        ("large", "abcdefghijklmnopqrst"),
+    ];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files.map(|(file, _)| file));
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for (file, content) in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(7);
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/small")),
+        format!(
+            "INFO: Skipping {}: File size 3 is outside the size limits",
+            server.url("/root/small")
+        ),
+        format!("INFO: Fetching {}", server.url("/root/medium")),
+        format!(
+            "INFO: Downloading {} to {}/download/medium (size 7)",
+            server.url("/root/medium"),
+            tmpdir.path().display()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/large")),
+        format!(
+            "INFO: Skipping {}: File size 20 is outside the size limits",
+            server.url("/root/large")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 1 file downloaded (7 bytes), 0 not modified, 2 skipped, 0 errored".to_string(),
+    ];
+
+    // Build expected files
+    let expected_files = [
+        TmpFile::Dir("download"),
+        TmpFile::File("download/medium", "abcdefg"),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_interstitial() {
+    let (args, mut server, tmpdir) = test_setup("/root/");
+
+    let login_doc = "<html><body><form>Please log in</form></body></html>";
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["image.iso", "notes.txt"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to redirect the ISO image to a login page
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/image.iso")).respond_with(
+            status_code(302).append_header("Location", server.url("/root/login").to_string()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/login")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(login_doc),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/notes.txt"))
+            .respond_with(status_code(200).body("Notes")),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(5);
+    expected_stats.add_interstitial();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/image.iso")),
+        format!(
+            "ERROR: Expected a file from {} but received an HTML page from {} (login or interstitial page?)",
+            server.url("/root/image.iso"),
+            server.url("/root/login")
+        ),
+        format!("INFO: Fetching {}", server.url("/root/notes.txt")),
+        format!(
+            "INFO: Downloading {} to {}/download/notes.txt (size 5)",
+            server.url("/root/notes.txt"),
+            tmpdir.path().display()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 1 file downloaded (5 bytes), 0 not modified, 0 skipped, 0 errored".to_string(),
+        "INFO: 1 file returned a login or interstitial page".to_string(),
+    ];
+
+    // Build expected files
+    let expected_files = [
+        TmpFile::Dir("download"),
+        TmpFile::File("download/notes.txt", "Notes"),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_accept_reject() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.accept = vec!["txt".to_string(), ".TAR.GZ".to_string()];
+    args.reject = vec!["old.txt".to_string()];
+
+    let file_content = "Hello, world!";
+    let accepted = ["file1.txt", "file2.tar.gz"];
+    let rejected = ["file3.iso", "file4.gz", "file5.old.txt"];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&[&accepted[..], &rejected[..]].concat());
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each accepted file only
+    for file in accepted {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    // Build expected files
+    let mut expected_files = vec![TmpFile::Dir("download".to_string())];
+
+    for file in accepted {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{file}"),
+            file_content.to_string(),
+        ));
+    }
+
+    for file in rejected {
+        expected_stats.add_skipped();
+
+        expected_messages.push(format!(
+            "INFO: Skipping {}: File extension is not accepted",
+            server.url(&format!("/root/{file}"))
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 3 skipped, 0 errored",
+        file_content.len() * 2
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_flatten_depth() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.flatten_depth = Some(1);
+
+    let file_content = "Hello, world!";
+    let files = [
+        ("file1", "file1"),
+        ("a/file2", "a/file2"),
+        ("a/b/file3", "a/b%2Ffile3"),
+        ("a/b/c/file4", "a/b%2Fc%2Ffile4"),
+    ];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files.map(|(file, _)| file));
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for (file, _) in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    // Build expected files
+    let mut expected_files = vec![
+        TmpFile::Dir("download".to_string()),
+        TmpFile::Dir("download/a".to_string()),
+    ];
+
+    for (file, local) in files {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{local} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{local}"),
+            file_content.to_string(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 4 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 4
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_cut_dirs() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.cut_dirs = 1;
+
+    let file_content = "Hello, world!";
+    let files = [
+        ("file1", "file1"),
+        ("a/file2", "file2"),
+        ("a/b/file3", "b/file3"),
+        ("a/b/c/", "b/c/index.html"),
+    ];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files.map(|(file, _)| file));
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for (file, _) in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    // Build expected files
+    let mut expected_files = vec![
+        TmpFile::Dir("download".to_string()),
+        TmpFile::Dir("download/b".to_string()),
+        TmpFile::Dir("download/b/c".to_string()),
+    ];
+
+    for (file, local) in files {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{local} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{local}"),
+            file_content.to_string(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 4 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 4
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_flatten() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.flatten = true;
+
+    // Fetch one at a time so the files are named in link order
+    args.concurrent_fetch = 1;
+
+    let files = [
+        ("a/report.pdf", "report.pdf", "First report"),
+        ("b/report.pdf", "report-1.pdf", "Second report"),
+        ("b/c/notes.txt", "notes.txt", "Notes"),
+    ];
+
+    // The second run finds the links in the reverse order but keeps the names
+    for run in 0..2 {
+        let mut files = files.to_vec();
+
+        if run == 1 {
+            files.reverse();
+        }
+
+        // Build document linking to the files
+        let links: Vec<&str> = files.iter().map(|(file, _, _)| *file).collect();
+        let html_doc = build_html_anchors_doc(&links);
+
+        // Configure the server to expect a single GET /root/ request and respond with the html document
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "text/html")
+                    .body(html_doc.clone()),
+            ),
+        );
+
+        // Configure the server to expect a GET request for each file
+        for (file, _, content) in &files {
+            server.expect(
+                Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                    .respond_with(status_code(200).body(*content)),
+            );
+        }
+
+        // Build expected stats
+        let mut expected_stats = Stats::default();
+        expected_stats.add_html(html_doc.len());
+
+        // Build expected messages
+        let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+        // The colliding names are recorded in the name map
+        let names = serde_json::to_string_pretty(&std::collections::BTreeMap::from([
+            ("report-1.pdf", server.url("/root/b/report.pdf").to_string()),
+            ("report.pdf", server.url("/root/a/report.pdf").to_string()),
+        ]))
+        .unwrap();
+
+        // Build expected files
+        let mut expected_files = vec![
+            TmpFile::Dir("download".to_string()),
+            TmpFile::File("download/.names.json".to_string(), names),
+        ];
+
+        let mut total = 0;
+
+        for (file, local, content) in &files {
+            expected_stats.add_download(content.len());
+            total += content.len();
+
+            let url = server.url(&format!("/root/{file}"));
+
+            expected_messages.push(format!("INFO: Fetching {url}"));
+            expected_messages.push(format!(
+                "INFO: Downloading {url} to {}/download/{local} (size {})",
+                tmpdir.path().display(),
+                content.len()
+            ));
+
+            expected_files.push(TmpFile::File(
+                format!("download/{local}"),
+                content.to_string(),
+            ));
+        }
+
+        expected_messages.push(format!(
+            "INFO: 1 document parsed ({} bytes)",
+            html_doc.len()
+        ));
+        expected_messages.push(format!(
+            "INFO: 3 files downloaded ({total} bytes), 0 not modified, 0 skipped, 0 errored"
+        ));
+
+        // Process
+        let result = async_main(args.clone()).await;
+
+        // Check results
+        check_results(
+            result,
+            Ok(expected_stats),
+            &expected_messages,
+            &mut server,
+            &tmpdir,
+            &expected_files,
+        )
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn test_head_first_filters() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.head_first = true;
+    args.max_size = Some(10);
+
+    let small_content = "Hello";
+    let large_content = "Hello, world!";
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["small", "large"]);
+
+    // Configure the server to expect HEAD and GET /root/ requests and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .append_header("Content-Length", html_doc.len().to_string()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect HEAD requests for both files but GET only for the small one
+    for (path, content) in [
+        ("/root/small", small_content),
+        ("/root/large", large_content),
+    ] {
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", path)).respond_with(
+                status_code(200).append_header("Content-Length", content.len().to_string()),
+            ),
+        );
+    }
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/small"))
+            .respond_with(status_code(200).body(small_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(small_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/small")),
+        format!(
+            "INFO: Downloading {} to {}/download/small (size {})",
+            server.url("/root/small"),
+            tmpdir.path().display(),
+            small_content.len()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/large")),
+        format!(
+            "INFO: Skipping {}: File size {} is outside the size limits",
+            server.url("/root/large"),
+            large_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            small_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/small", small_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_head_first_types_and_lengths() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.head_first = true;
+    args.reject_type = vec!["application/zip".to_string()];
+
+    let file_content = "Hello, world!";
+
+    // Create local copies of a file with the same length and a file with a different length
+    let path = tmpdir.path().join("download");
+    std::fs::create_dir_all(&path).unwrap();
+    std::fs::write(path.join("same"), file_content).unwrap();
+    std::fs::write(path.join("changed"), "Old").unwrap();
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["same", "changed", "archive"]);
+
+    // Configure the server to expect HEAD and GET /root/ requests and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .append_header("Content-Length", html_doc.len().to_string()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect HEAD requests for all files but GET only for the changed one
+    for (path, content_type) in [
+        ("/root/same", "text/plain"),
+        ("/root/changed", "text/plain"),
+        ("/root/archive", "application/zip"),
+    ] {
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", path)).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", content_type)
+                    .append_header("Content-Length", file_content.len().to_string()),
+            ),
+        );
+    }
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/changed")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/plain")
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_not_modified();
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/same")),
+        format!("INFO: {} is not modified", server.url("/root/same")),
+        format!("INFO: Fetching {}", server.url("/root/changed")),
+        format!(
+            "INFO: Downloading {} to {}/download/changed (size {})",
+            server.url("/root/changed"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/archive")),
+        format!(
+            "INFO: Skipping {}: MIME type application/zip is not accepted",
+            server.url("/root/archive"),
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 1 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/changed", file_content),
+            TmpFile::File("download/same", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_empty_files() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.empty_files = EmptyFiles::Skip;
+
+    // Create a previously downloaded copy of the file
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+    path.push("file1");
+    std::fs::write(&path, "Good content").unwrap();
+
+    // Build document linking to the file
+    let html_doc = build_html_anchors_doc(&["file1"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to respond to the file request with an empty body
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size 0)",
+            server.url("/root/file1"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: Skipping {}: Response body is empty",
+            server.url("/root/file1")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 1 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", "Good content"),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_skip_existing() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.skip_existing = true;
+
+    let file_content = "Hello, world!";
+    let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let modified = httpdate::parse_http_date(last_modified).unwrap();
+
+    // Create local copies of file1 (up to date) and file2 (stale)
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+
+    std::fs::write(path.join("file1"), file_content).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(path.join("file1"))
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    std::fs::write(path.join("file2"), "Old").unwrap();
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["file1", "file2", "file3"]);
+
+    // Configure the server to expect HEAD and GET /root/ requests and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .append_header("Content-Length", html_doc.len().to_string()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect HEAD requests for all files but GET only for changed files
+    for file in ["file1", "file2", "file3"] {
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", format!("/root/{file}")))
+                .respond_with(
+                    status_code(200)
+                        .append_header("Content-Length", file_content.len().to_string())
+                        .append_header("Last-Modified", last_modified),
+                ),
+        );
+    }
+
+    for file in ["file2", "file3"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(
+                    status_code(200)
+                        .append_header("Last-Modified", last_modified)
+                        .body(file_content),
+                ),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_skipped();
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Skipping {}: Local file is up to date",
+            server.url("/root/file1")
+        ),
+    ];
+
+    for file in ["file2", "file3"] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+        file_content.len() * 2
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+            TmpFile::File("download/file3", file_content),
+        ],
+    )
+    .await;
+
+    // Check downloaded files were given the server's modification time
+    for file in ["file2", "file3"] {
+        let mtime = std::fs::metadata(path.join(file))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(mtime, modified);
+    }
+}
+
+#[tokio::test]
+async fn test_no_clobber() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.no_clobber = true;
+
+    let file_content = "Hello, world!";
+
+    // Create a local copy of file1
+    create_tmp_file(&tmpdir.path().join("download/file1"), "Old").await;
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["file1", "file2"]);
+
+    // Configure the server to expect GET requests for the document and the missing file only
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_skipped();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!(
+            "INFO: Skipping {}: Local file is up to date",
+            server.url("/root/file1")
+        ),
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "INFO: Downloading {} to {}/download/file2 (size {})",
+            server.url("/root/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", "Old"),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_skip_existing_no_head() {
+    let (mut args, mut server, tmpdir) = test_setup("/file1");
+
+    args.skip_existing = true;
+
+    let file_content = "Hello, world!";
+    let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let modified = httpdate::parse_http_date(last_modified).unwrap();
+
+    // Create an up to date local copy of the file
+    let path = tmpdir.path().join("download/__file.dat");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, file_content).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    // Configure the server to refuse HEAD requests and respond to GET with the file
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/file1"))
+            .respond_with(status_code(405)),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file1")).respond_with(
+            status_code(200)
+                .append_header("Last-Modified", last_modified)
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_skipped();
+
+    let host = Url::parse(&server.url_str("/"))
+        .unwrap()
+        .host_str()
+        .unwrap()
+        .to_string();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file1")),
+        format!("INFO: Host {host} does not handle HEAD requests, using GET"),
+        format!(
+            "INFO: Skipping {}: Local file is up to date",
+            server.url("/file1")
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 1 skipped, 0 errored".to_string(),
+    ];
+
+    let hosts_json = format!("{{\n  \"{host}\": {{\n    \"head\": false\n  }}\n}}");
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.hosts.json", hosts_json.as_str()),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_keep_stale() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.manifest = true;
+
+    let good_content = "Good content";
+    let bad_content = "Hello, world!";
+    let good_sha256 = "9b2be01374fcb8349bdc0fd7be4c309c7ff7cf53bbf24c92b29a4cf4461eccdb";
+    let bad_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+
+    // Create a previously downloaded copy of the file
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+    std::fs::write(path.join("file1"), good_content).unwrap();
+
+    let url = server.url("/root/file1");
+
+    let manifest = format!(
+        "[\n  {{\n    \"url\": \"{url}\",\n    \"path\": \"file1\",\n    \"size\": {},\n    \"sha256\": \"{good_sha256}\",\n    \"timestamp\": 0\n  }}\n]",
+        good_content.len()
+    );
+    std::fs::write(path.join(".manifest.json"), &manifest).unwrap();
+
+    // Build document linking to the file with a hash fragment which the new copy doesn't match
+    let html_doc = build_html_anchors_doc(&[format!("file1#sha256={good_sha256}")]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(bad_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_errored();
+    expected_stats.add_kept_stale();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {url}"),
+        format!(
+            "INFO: Downloading {url} to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            bad_content.len()
+        ),
+        format!(
+            "ERROR: sha256 mismatch for {}/download/file1: expected {good_sha256}, got {bad_sha256}",
+            tmpdir.path().display()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
+        "INFO: 1 file kept from a previous download after a failed replacement".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    let stale_manifest = manifest.replace(
+        "\"timestamp\": 0\n",
+        "\"timestamp\": 0,\n    \"stale\": true\n",
+    );
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.manifest.json", stale_manifest.as_str()),
+            TmpFile::File("download/file1", good_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_multiple_urls() {
+    let (mut args, mut server, tmpdir) = test_setup("/pub/a/");
+
+    // Add a second URL from a URL file
+    let url_file = tmpdir.path().join("urls.txt");
+    let url_list = format!("# Extra URLs\n\n{}\n", server.url("/pub/b/"));
+    std::fs::write(&url_file, &url_list).unwrap();
+    args.url_file = Some(url_file.to_string_lossy().to_string());
+
+    let file_content = "Hello, world!";
+
+    // Build documents linking to the files
+    let html_doc_a = build_html_anchors_doc(&["file1", "../c/file3"]);
+    let html_doc_b = build_html_anchors_doc(&["file2"]);
+
+    // Configure the server to expect a GET request for each directory and file
+    for (path, doc) in [("/pub/a/", &html_doc_a), ("/pub/b/", &html_doc_b)] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path)).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "text/html")
+                    .body(doc.clone()),
+            ),
+        );
+    }
+
+    for path in ["/pub/a/file1", "/pub/b/file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc_a.len());
+    expected_stats.add_html(html_doc_b.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/pub/a/")),
+        format!("INFO: Fetching {}", server.url("/pub/b/")),
+        format!("INFO: Fetching {}", server.url("/pub/a/file1")),
+        format!("INFO: Fetching {}", server.url("/pub/b/file2")),
+        format!(
+            "INFO: Skipping {}: Path is outside the --only sub-trees",
+            server.url("/pub/c/file3")
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/a/file1 (size {})",
+            server.url("/pub/a/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/b/file2 (size {})",
+            server.url("/pub/b/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: 2 documents parsed ({} bytes)",
+            html_doc_a.len() + html_doc_b.len()
+        ),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/a"),
+            TmpFile::File("download/a/file1", file_content),
+            TmpFile::Dir("download/b"),
+            TmpFile::File("download/b/file2", file_content),
+            TmpFile::File("urls.txt", url_list.as_str()),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_min_age() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.min_age = Some(std::time::Duration::from_secs(60 * 60));
+
+    let file_content = "Hello, world!";
+    let old_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let new_modified = httpdate::fmt_http_date(std::time::SystemTime::now());
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["old", "new"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for (file, modified) in [("old", old_modified), ("new", new_modified.as_str())] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(
+                    status_code(200)
+                        .append_header("Last-Modified", modified.to_string())
+                        .body(file_content),
+                ),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/old")),
+        format!(
+            "INFO: Downloading {} to {}/download/old (size {})",
+            server.url("/root/old"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/new")),
+        format!(
+            "INFO: Skipping {}: File was modified more recently than --min-age",
+            server.url("/root/new")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/old", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_concurrent_per_host() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.concurrent_per_host = Some(1);
+
+    let file_content = "Hello, world!";
+    let files = ["file1", "file2", "file3"];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for file in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for file in files {
+        expected_stats.add_download(file_content.len());
+
+        expected_messages.push(format!(
+            "INFO: Fetching {}",
+            server.url(&format!("/root/{file}"))
+        ));
+        expected_messages.push(format!(
+            "INFO: Downloading {} to {}/download/{file} (size {})",
+            server.url(&format!("/root/{file}")),
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: {} files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        files.len(),
+        files.len() * file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+            TmpFile::File("download/file3", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_resume() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.resume = true;
+    args.max_size = Some(20);
+
+    let file_content = "Hello, world!";
+    let large_content = "Hello, world! Hello, world!";
+
+    // **** First process ****
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["file1", "file2", "file3"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to return the first file and fail the second
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2"))
+            .respond_with(status_code(500)),
+    );
+
+    // Configure the server to return a third file which is too large
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file3"))
+            .respond_with(status_code(200).body(large_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "ERROR: Status 500 Internal Server Error fetching {}",
+            server.url("/root/file2")
+        ),
+        format!("INFO: Fetching {}", server.url("/root/file3")),
+        format!(
+            "INFO: Skipping {}: File size {} is outside the size limits",
+            server.url("/root/file3"),
+            large_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 1 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Build expected crawl state. The skipped file is complete and isn't fetched again
+    let checkpoint_content = generate_checkpoint_json(
+        &[
+            server.url("/root/").to_string(),
+            server.url("/root/file1").to_string(),
+            server.url("/root/file3").to_string(),
+        ],
+        &[server.url("/root/file2").to_string()],
+    );
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File(
+                "download/.mirrorurl-state.json",
+                checkpoint_content.as_str(),
+            ),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // Configure the server to expect only the failed file to be fetched again
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "INFO: Downloading {} to {}/download/file2 (size {})",
+            server.url("/root/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_lock() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.lock = true;
+
+    let file_content = "Hello, world!";
+
+    // Create a lock held by another machine
+    let mut lock_path = tmpdir.path().to_path_buf();
+    lock_path.push("download");
+    std::fs::create_dir_all(&lock_path).unwrap();
+    lock_path.push(".mirrorurl.lock");
+
+    let lock = |heartbeat: u64| {
+        format!(
+            "{{\n  \"owner\": \"other\",\n  \"host\": \"otherhost\",\n  \"pid\": 1234,\n  \"heartbeat\": {heartbeat}\n}}"
+        )
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // **** First process ****
+
+    // The lock is still being updated
+    let live_lock = lock(now);
+    std::fs::write(&lock_path, &live_lock).unwrap();
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Err(format!("{} is locked by process 1234 on otherhost", args.target).into()),
+        &[] as &[&str; 0],
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.mirrorurl.lock", live_lock.as_str()),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // The lock has expired
+    let expired_lock = lock(now - 3600);
+    std::fs::write(&lock_path, &expired_lock).unwrap();
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Err(format!(
+            "{} is locked by process 1234 on otherhost which has stopped updating the lock (use --steal-lock to take it over)",
+            args.target
+        )
+        .into()),
+        &[] as &[&str; 0],
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.mirrorurl.lock", expired_lock.as_str()),
+        ],
+    )
+    .await;
+
+    // **** Third process ****
+
+    args.steal_lock = true;
+
+    // Configure the server to expect a single GET /file request and respond with the file content
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!(
+            "INFO: Taking over the lock on {} from process 1234 on otherhost",
+            args.target
+        ),
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results - the lock is released at the end of the run
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_slot_usage() {
+    let limiter = std::sync::Arc::new(Limiter::new(2, Some(1)));
+
+    let url1 = Url::parse("http://host1/").unwrap();
+    let url2 = Url::parse("http://host2/").unwrap();
+
+    let usage = |busy, waiting| SlotUsage {
+        busy,
+        total: 2,
+        waiting,
+    };
+
+    assert_eq!(limiter.usage(), usage(0, 0));
+
+    // Take a slot for each host
+    let slot1 = limiter.acquire(&url1, false).await.unwrap();
+    let slot2 = limiter.acquire(&url2, false).await.unwrap();
+
+    assert_eq!(limiter.usage(), usage(2, 0));
+    assert_eq!(limiter.usage().to_string(), "2/2 slots busy, 0 waiting");
+
+    // Another fetch from the first host has to wait
+    let waiter = tokio::spawn({
+        let limiter = limiter.clone();
+        let url1 = url1.clone();
+        async move { limiter.acquire(&url1, false).await.unwrap() }
+    });
+
+    while limiter.usage().waiting == 0 {
+        tokio::task::yield_now().await;
+    }
+
+    assert_eq!(limiter.usage(), usage(2, 1));
+
+    // Releasing the other host's slot doesn't let it through
+    drop(slot2);
+
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+
+    assert_eq!(limiter.usage(), usage(1, 1));
+
+    // Releasing the first host's slot does
+    drop(slot1);
+
+    let slot3 = waiter.await.unwrap();
+
+    assert_eq!(limiter.usage(), usage(1, 0));
+
+    drop(slot3);
+
+    assert_eq!(limiter.usage(), usage(0, 0));
+}
+
+/// Event sink recording the events received
+#[derive(Default)]
+struct RecordingSink {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl RecordingSink {
+    fn record(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl EventSink for RecordingSink {
+    fn on_fetch_start(&self, url: &Url) {
+        self.record(format!("fetch {url}"));
+    }
+
+    fn on_download_complete(&self, url: &Url, path: &std::path::Path, bytes: usize) {
+        self.record(format!("download {url} {} {bytes}", path.display()));
+    }
+
+    fn on_skip(&self, skip: &SkipReasonErr) {
+        self.record(format!("skip {skip}"));
+    }
+
+    fn on_error(&self, url: &Url, error: &dyn std::fmt::Display) {
+        self.record(format!("error {url} {error}"));
+    }
+}
+
+#[tokio::test]
+async fn test_event_sink() {
+    let (args, mut server, tmpdir) = test_setup("/root/");
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["file", "missing", "/other/"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/missing"))
+            .respond_with(status_code(404)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+    expected_stats.add_errored();
+
+    // Fetches, skips and errors go to the event sink instead of the log
+    let expected_messages = [
+        format!(
+            "INFO: Downloading {} to {}/download/file (size {})",
+            server.url("/root/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 1 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let sink = std::sync::Arc::new(RecordingSink::default());
+    let result = async_main_with_events(args, sink.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file", file_content),
+        ],
+    )
+    .await;
+
+    // Check events
+    let mut events = sink.events.lock().unwrap().clone();
+    events.sort();
+
+    let mut expected_events = vec![
+        format!("fetch {}", server.url("/root/")),
+        format!("fetch {}", server.url("/root/file")),
+        format!("fetch {}", server.url("/root/missing")),
+        format!(
+            "skip Skipping {}: URL is not relative to the base URL",
+            server.url("/other/")
+        ),
+        format!(
+            "download {} {}/download/file {}",
+            server.url("/root/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "error {} Status 404 Not Found fetching {}",
+            server.url("/root/missing"),
+            server.url("/root/missing")
+        ),
+    ];
+    expected_events.sort();
+
+    assert_eq!(events, expected_events);
+}
+
+#[tokio::test]
+async fn test_duplicate_resource() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.concurrent_fetch = 1;
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to a redirect, its target and the same file with different encodings
+    let html_doc = build_html_anchors_doc(&["before", "after", "file", "%66ile"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a single GET /root/before request and respond with a redirect
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/before"))
+            .respond_with(status_code(301).append_header("Location", "/root/after")),
+    );
+
+    // Configure the server to expect a single GET request for each file
+    for file in ["after", "file"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/before")),
+        format!(
+            "INFO: Downloading {} to {}/download/after (size {})",
+            server.url("/root/after"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: Fetching {}", server.url("/root/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/file (size {})",
+            server.url("/root/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/after", file_content),
+            TmpFile::File("download/file", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_content_disposition() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.allow_query = true;
+    args.content_disposition = true;
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to the downloads
+    let html_doc = build_html_anchors_doc(&["get?id=1", "get?id=2", "get?id=3", "get?id=4"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to respond to each download with a different disposition
+    for (id, disposition) in [
+        ("1", "attachment; filename=\"report.pdf\""),
+        (
+            "2",
+            "attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20file.txt",
+        ),
+        ("3", "attachment; filename=\"../../etc/passwd\""),
+        ("4", "inline"),
+    ] {
+        server.expect(
+            Expectation::matching(all_of!(
+                request::method_path("GET", "/root/get"),
+                request::query(url_decoded(contains(("id", id)))),
+            ))
+            .respond_with(
+                status_code(200)
+                    .append_header("Content-Disposition", disposition)
+                    .body(file_content),
+            ),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for (id, file) in [
+        ("1", "report.pdf"),
+        ("2", "naïve file.txt"),
+        ("3", "passwd"),
+        ("4", "get%3Fid=4"),
+    ] {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/get?id={id}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 4 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 4
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/get%3Fid=4", file_content),
+            TmpFile::File("download/naïve file.txt", file_content),
+            TmpFile::File("download/passwd", file_content),
+            TmpFile::File("download/report.pdf", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_non_utf8_headers() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+
+    args.content_disposition = true;
+
+    let file_content = "Hello, world!";
+
+    // ISO-8859-1 etag and raw UTF-8 file name
+    let etag_value: &[u8] = b"\"caf\xe9\"";
+    let disposition = "attachment; filename=\"café.txt\"";
+
+    let etags_content = generate_etags_json(
+        Some(&server.url("/file").to_string()),
+        vec![(
+            server.url("/file").to_string(),
+            "mirrorurl-raw:22636166e922".to_string(),
+        )],
+    );
+
+    // **** First process ****
+
+    // Configure the server to respond with the file, etag and disposition
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(not(contains(key("if-none-match")))),
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("ETag", etag_value)
+                .append_header("Content-Disposition", disposition.as_bytes())
+                .body(file_content),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/café.txt (size {})",
+            server.url("/file"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/café.txt", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process ****
+
+    // Configure the server to expect the etag to be sent back and respond with 304 not modified
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains(key("if-none-match"))),
+        ))
+        .respond_with(status_code(304)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_not_modified();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!("INFO: {} is not modified", server.url("/file")),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 1 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_content.as_str()),
+            TmpFile::File("download/café.txt", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_rules_command() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    // Generate skip list
+    let (skip_path, skip_content) = generate_skiplist_json(&tmpdir, vec!["private/"]).await;
+    args.skip_file = Some(skip_path.to_str().unwrap().to_string());
+
+    let items = [
+        "dir/file".to_string(),
+        "dir/".to_string(),
+        "page#section".to_string(),
+        "private/file".to_string(),
+        server.url("/other/file").to_string(),
+    ];
+
+    // Build expected messages
+    let expected_messages = [
+        format!(
+            "INFO: {}: saved to {}/download/dir/file",
+            server.url("/root/dir/file"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: {}: saved to {}/download/dir/index.html",
+            server.url("/root/dir/"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: Skipping {}#section: URL is a fragment",
+            server.url("/root/page")
+        ),
+        format!(
+            "INFO: Skipping {}: Path is in the skip list",
+            server.url("/root/private/file")
+        ),
+        format!(
+            "INFO: Skipping {}: URL is not relative to the base URL",
+            server.url("/other/file")
+        ),
+    ];
+
+    // Test the rules - nothing is fetched
+    let result = test_rules(args, &items, LOGGER.clone())
+        .await
+        .map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[TmpFile::File("skiplist.json", skip_content.as_str())],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_check() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    // **** Good configuration ****
+
+    // Configure the server to expect a single HEAD /root/ request
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/root/"))
+            .respond_with(status_code(200)),
+    );
+
+    // Check
+    let result = check(args.clone()).await.map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &["INFO: No problems found"],
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>],
+    )
+    .await;
+
+    // **** Bad configuration ****
+
+    // Write an invalid skip list and refer to a missing policy file
+    let skip_path = tmpdir.path().join("skiplist.json");
+    std::fs::write(&skip_path, "not json").unwrap();
+    args.skip_file = Some(skip_path.to_str().unwrap().to_string());
+
+    let policy_path = tmpdir.path().join("policy.json");
+    args.policy_file = Some(policy_path.to_str().unwrap().to_string());
+
+    // Configure the server to expect a single HEAD /root/ request and respond with not found
+    server.expect(
+        Expectation::matching(request::method_path("HEAD", "/root/"))
+            .respond_with(status_code(404)),
+    );
+
+    // Build expected messages
+    let expected_messages = [
+        format!(
+            "ERROR: Failed to load skip list file {}: expected ident at line 1 column 2",
+            skip_path.display()
+        ),
+        format!(
+            "ERROR: Failed to open policy file {}: No such file or directory (os error 2)",
+            policy_path.display()
+        ),
+        format!("ERROR: Status 404 Not Found from {}", server.url("/root/")),
+    ];
+
+    // Check
+    let result = check(args).await.map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Err("3 problems found".into()),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[TmpFile::File("skiplist.json", "not json")],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_decode_filenames() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.decode_filenames = true;
+
+    let items = [
+        "My%20File.pdf".to_string(),
+        "caf%C3%A9.txt".to_string(),
+        "dir%20one/file".to_string(),
+        "a%2Fb".to_string(),
+        "a%252Fb".to_string(),
+    ];
+
+    // Build expected messages
+    let expected_messages = [
+        format!(
+            "INFO: {}: saved to {}/download/My File.pdf",
+            server.url("/root/My%20File.pdf"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: {}: saved to {}/download/café.txt",
+            server.url("/root/caf%C3%A9.txt"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: {}: saved to {}/download/dir one/file",
+            server.url("/root/dir%20one/file"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: {}: saved to {}/download/a%2Fb",
+            server.url("/root/a%2Fb"),
+            tmpdir.path().display()
+        ),
+        format!(
+            "INFO: Skipping {}: Local file name is already used by {}",
+            server.url("/root/a%252Fb"),
+            server.url("/root/a%2Fb")
+        ),
+    ];
+
+    // Test the rules - nothing is fetched
+    let result = test_rules(args, &items, LOGGER.clone())
+        .await
+        .map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_exclude_from() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    // Write the exclude file
+    let exclude_content = "\
+# wget settings
+exclude_directories = /root/old,/root/tmp*
+reject = .iso,*.bak
+
+# rsync filter rules
++ keep/*.log
+- *.log
+- /private/
+cache/
+";
+
+    let exclude_path = tmpdir.path().join("exclude.txt");
+    std::fs::write(&exclude_path, exclude_content).unwrap();
+    args.exclude_from = Some(exclude_path.to_str().unwrap().to_string());
+
+    let saved = [
+        "keep/app.log",
+        "a/private/file",
+        "a/cache",
+        "docs/readme.txt",
+    ];
+
+    let excluded = [
+        "old/file",
+        "tmp1/file",
+        "image.iso",
+        "data.bak",
+        "logs/app.log",
+        "private/file",
+        "a/cache/file",
+    ];
+
+    // Build expected messages
+    let mut expected_messages = Vec::new();
+
+    for item in saved {
+        expected_messages.push(format!(
+            "INFO: {}: saved to {}/download/{item}",
+            server.url(&format!("/root/{item}")),
+            tmpdir.path().display()
+        ));
+    }
+
+    for item in excluded {
+        expected_messages.push(format!(
+            "INFO: Skipping {}: Path matches an exclude pattern",
+            server.url(&format!("/root/{item}"))
+        ));
+    }
+
+    let items: Vec<String> = saved
+        .iter()
+        .chain(excluded.iter())
+        .map(|item| item.to_string())
+        .collect();
+
+    // Test the rules - nothing is fetched
+    let result = test_rules(args, &items, LOGGER.clone())
+        .await
+        .map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[TmpFile::File("exclude.txt", exclude_content)],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_windows_names() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.windows_names = true;
+    args.decode_filenames = true;
+
+    let items = [
+        ("file.txt", "file.txt"),
+        ("dir/a:b.txt", "dir/a_b~1f0f1e95.txt"),
+        ("a%3Cb%3E", "a_b_~4e07dd89"),
+        ("CON", "CON~a3dbc4b6"),
+        ("con.txt", "con~d3bde286.txt"),
+        ("dir./file", "dir~22909fc1/file"),
+        ("name%20", "name~36d99a78"),
+    ];
+
+    // Build expected messages
+    let expected_messages: Vec<String> = items
+        .iter()
+        .map(|(item, local)| {
+            format!(
+                "INFO: {}: saved to {}/download/{local}",
+                server.url(&format!("/root/{item}")),
+                tmpdir.path().display()
+            )
+        })
+        .collect();
+
+    let items: Vec<String> = items.iter().map(|(item, _)| item.to_string()).collect();
+
+    // Test the rules - nothing is fetched
+    let result = test_rules(args, &items, LOGGER.clone())
+        .await
+        .map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_long_names() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.max_name_length = 20;
+    args.max_path_length = args.target.len() + 24;
+
+    let file_content = "Hello, world!";
+    let files = [
+        ("short.txt", "short.txt"),
+        ("a_very_long_file_name_indeed.txt", "a_very_~25688e5b.txt"),
+        ("dir1/dir2/dir3/dir4/file.txt", "dir1/dir2/~557ba0b4.txt"),
+    ];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files.map(|(file, _)| file));
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for (file, _) in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    // Build expected files
+    let mut expected_files = vec![
+        TmpFile::Dir("download".to_string()),
+        TmpFile::Dir("download/dir1".to_string()),
+        TmpFile::Dir("download/dir1/dir2".to_string()),
+    ];
+
+    for (file, local) in files {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{local} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{local}"),
+            file_content.to_string(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 3 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 3
+    ));
+
+    // The shortened paths are recorded in the name map
+    expected_files.push(TmpFile::File(
+        "download/.names.json".to_string(),
+        format!(
+            "{{\n  \"{}\": \"{}\",\n  \"{}\": \"{}\"\n}}",
+            files[1].1,
+            server.url(&format!("/root/{}", files[1].0)),
+            files[2].1,
+            server.url(&format!("/root/{}", files[2].0)),
+        ),
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_export_import_state() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    let url = server.url("/root/file1");
+    let sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+
+    // Create the state of an existing mirror
+    let mut path = tmpdir.path().to_path_buf();
+    path.push("download");
+    std::fs::create_dir_all(&path).unwrap();
+
+    let etags = generate_etags_json(None, vec![(url.to_string(), "\"etag1\"".to_string())]);
+    std::fs::write(path.join(".etags.json"), &etags).unwrap();
+
+    let manifest = format!(
+        "[\n  {{\n    \"url\": \"{url}\",\n    \"path\": \"file1\",\n    \"size\": 13,\n    \"sha256\": \"{sha256}\",\n    \"timestamp\": 0\n  }}\n]"
+    );
+    std::fs::write(path.join(".manifest.json"), &manifest).unwrap();
+
+    let state_file = tmpdir.path().join("state.json");
+    let state_file = state_file.to_str().unwrap();
+
+    // Export the state
+    let result = export_state(&args, state_file)
+        .await
+        .map(|()| Stats::default());
+
+    let state = format!(
+        "{{\n  \"version\": 1,\n  \"etags\": {{\n    \"{url}\": \"\\\"etag1\\\"\"\n  }},\n  \"manifest\": [\n    {{\n      \"url\": \"{url}\",\n      \"path\": \"file1\",\n      \"size\": 13,\n      \"sha256\": \"{sha256}\",\n      \"timestamp\": 0\n    }}\n  ]\n}}"
+    );
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &[format!(
+            "INFO: Exported 1 etags and 1 manifest entries to {state_file}"
+        )],
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags.as_str()),
+            TmpFile::File("download/.manifest.json", manifest.as_str()),
+            TmpFile::File("state.json", state.as_str()),
+        ],
+    )
+    .await;
+
+    // Import the state in to a new target directory
+    args.target = tmpdir.path().join("moved").to_str().unwrap().to_string();
+
+    let result = import_state(&args, state_file)
+        .await
+        .map(|()| Stats::default());
+
+    // Check results
+    check_results(
+        result,
+        Ok(Stats::default()),
+        &[format!(
+            "INFO: Imported 1 etags and 1 manifest entries from {state_file}"
+        )],
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags.as_str()),
+            TmpFile::File("download/.manifest.json", manifest.as_str()),
+            TmpFile::Dir("moved"),
+            TmpFile::File("moved/.etags.json", etags.as_str()),
+            TmpFile::File("moved/.manifest.json", manifest.as_str()),
+            TmpFile::File("state.json", state.as_str()),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_hot_slots() {
+    let url = Url::parse("https://example.com/file").unwrap();
+
+    let limiter = std::sync::Arc::new(Limiter::new(1, None));
+
+    // Take the only slot
+    let slot = limiter.acquire(&url, false).await.unwrap();
+
+    // Queue a normal fetch and then a high priority fetch
+    let order = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let mut handles = Vec::new();
+
+    for (name, hot) in [("normal", false), ("hot", true)] {
+        let task_limiter = limiter.clone();
+        let order = order.clone();
+        let url = url.clone();
+
+        handles.push(tokio::spawn(async move {
+            let _slot = task_limiter.acquire(&url, hot).await.unwrap();
+            order.lock().unwrap().push(name);
+        }));
+
+        // Wait for the fetch to queue
+        while limiter.usage().waiting < handles.len() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // Release the slot - the high priority fetch goes first
+    drop(slot);
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    assert_eq!(*order.lock().unwrap(), vec!["hot", "normal"]);
+}
+
+#[tokio::test]
+async fn test_hot_slots_aging() {
+    let url = Url::parse("https://example.com/file").unwrap();
+
+    let limiter = std::sync::Arc::new(Limiter::new(1, None));
+
+    // Take the only slot
+    let mut slot = limiter.acquire(&url, false).await.unwrap();
+
+    // Queue a normal fetch
+    let task_limiter = limiter.clone();
+    let task_url = url.clone();
+
+    let normal = tokio::spawn(async move {
+        let _slot = task_limiter.acquire(&task_url, false).await.unwrap();
+    });
+
+    while limiter.usage().waiting < 1 {
+        tokio::task::yield_now().await;
+    }
+
+    // Keep queueing high priority fetches until the normal fetch gets a slot
+    let mut rounds = 0;
+
+    while !normal.is_finished() && rounds <= MAX_GIVE_WAY * 2 {
+        let task_limiter = limiter.clone();
+        let task_url = url.clone();
+
+        let hot = tokio::spawn(async move { task_limiter.acquire(&task_url, true).await.unwrap() });
+
+        // Wait for the fetch to queue
+        while limiter.usage().waiting < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // Pass the slot on and take it back from the high priority fetch
+        drop(slot);
+        slot = hot.await.unwrap();
+
+        rounds += 1;
+    }
+
+    // The normal fetch keeps the slot once it has given way enough times
+    assert!(normal.is_finished());
+    assert_eq!(rounds, MAX_GIVE_WAY + 1);
+}
+
+#[cfg(feature = "self-update")]
+#[test]
+fn test_is_newer() {
+    use crate::update::is_newer;
+
+    assert!(is_newer("0.2.0", "0.1.0"));
+    assert!(is_newer("0.10.0", "0.9.1"));
+    assert!(is_newer("1.0.1", "1.0.0"));
+    assert!(!is_newer("0.1.0", "0.1.0"));
+    assert!(!is_newer("0.1.0", "0.2.0"));
+}
+
+#[cfg(feature = "self-update")]
+#[test]
+fn test_update_verify() {
+    use ed25519_dalek::{Signer, SigningKey};
+    use sha2::{Digest, Sha256};
+
+    use crate::update::{check_binary, check_manifest, verify};
+
+    let signing_key = SigningKey::from_bytes(&[7; 32]);
+    let key = signing_key
+        .verifying_key()
+        .as_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    let binary = b"new binary";
+    let manifest = |version: &str, name: &str| {
+        format!(
+            "{{\"version\": \"{version}\", \"name\": \"{name}\", \"sha256\": \"{:x}\"}}",
+            Sha256::digest(binary)
+        )
+    };
+    let sign = |message: &str| signing_key.sign(message.as_bytes()).to_bytes();
+
+    // Good signature
+    let message = manifest("0.2.0", "mirrorurl");
+    assert!(verify(&key, message.as_bytes(), &sign(&message)).is_ok());
+
+    // Tampered message, bad signature length and bad key
+    let err = verify(&key, b"other", &sign(&message)).unwrap_err();
+    assert_eq!(err.to_string(), "the signature does not match");
+    let err = verify(&key, message.as_bytes(), &[0; 10]).unwrap_err();
+    assert!(err.to_string().starts_with("the signature is invalid"));
+    let err = verify("abc", message.as_bytes(), &sign(&message)).unwrap_err();
+    assert_eq!(err.to_string(), "the release key is invalid");
+
+    // Newer manifest for the binary is accepted and describes it
+    let checked = check_manifest(
+        &key,
+        message.as_bytes(),
+        &sign(&message),
+        "mirrorurl",
+        "0.1.0",
+    )
+    .unwrap();
+    assert_eq!(checked.version, "0.2.0");
+    assert!(check_binary(binary, &checked).is_ok());
+    let err = check_binary(b"old binary", &checked).unwrap_err();
+    assert_eq!(err.to_string(), "the binary does not match the manifest");
+
+    // Signed older and same versions are refused
+    for (version, current) in [("0.1.0", "0.2.0"), ("0.2.0", "0.2.0")] {
+        let message = manifest(version, "mirrorurl");
+        let err = check_manifest(
+            &key,
+            message.as_bytes(),
+            &sign(&message),
+            "mirrorurl",
+            current,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("version {version} is not newer than {current}")
+        );
+    }
+
+    // Manifest for another binary is refused
+    let message = manifest("0.2.0", "other");
+    let err = check_manifest(
+        &key,
+        message.as_bytes(),
+        &sign(&message),
+        "mirrorurl",
+        "0.1.0",
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "the manifest describes other");
+}
+
+#[cfg(feature = "self-update")]
+#[test]
+fn test_replace_exe() {
+    use std::fs::{read, write};
+
+    use crate::update::replace_exe;
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let exe = tmpdir.path().join("mirrorurl");
+
+    write(&exe, b"old binary").unwrap();
+
+    replace_exe(&exe, b"new binary").unwrap();
+
+    // Binary replaced and the temporary file renamed away
+    assert_eq!(read(&exe).unwrap(), b"new binary");
+    assert!(!tmpdir.path().join("mirrorurl.new").exists());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = exe.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    // Replacing a binary in a missing directory fails
+    let err = replace_exe(&tmpdir.path().join("missing/mirrorurl"), b"new binary").unwrap_err();
+    assert!(err.to_string().starts_with("Error writing"));
+}
+
+#[test]
+fn test_verbosity() {
+    use log::{Level, Log, Record};
+
+    use crate::output::{Logger, Verbosity, PROGRESS_TARGET, VERBOSE_TARGET};
+
+    let logger = Logger::new();
+
+    // Logs a message of each kind, returning the messages output
+    let log_all = |verbosity| {
+        logger.set_verbosity(verbosity);
+
+        for (level, target, msg) in [
+            (Level::Error, "mirrorurl", "error"),
+            (Level::Info, "mirrorurl", "summary"),
+            (Level::Info, PROGRESS_TARGET, "progress"),
+            (Level::Info, VERBOSE_TARGET, "verbose"),
+        ] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{msg}"))
+                    .build(),
+            );
+        }
+
+        logger.get_messages()
+    };
+
+    assert_eq!(log_all(Verbosity::Quiet), ["ERROR: error"]);
+    assert_eq!(
+        log_all(Verbosity::NoProgress),
+        ["ERROR: error", "INFO: summary"]
+    );
+    assert_eq!(
+        log_all(Verbosity::Normal),
+        ["ERROR: error", "INFO: summary", "INFO: progress"]
+    );
+    assert_eq!(
+        log_all(Verbosity::Verbose),
+        [
+            "ERROR: error",
+            "INFO: summary",
+            "INFO: progress",
+            "INFO: verbose"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_cycle_debug_level() {
+    use crate::state::State;
+
+    let (args, _server, _tmpdir) = test_setup("/");
+
+    let state = State::new(args, LOGGER.clone(), None).await.unwrap();
+
+    // Each SIGHUP moves to the next level, wrapping back to zero after the maximum
+    assert_eq!(state.debug_level(), 1);
+
+    let levels = (0..4)
+        .map(|_| state.cycle_debug_level())
+        .collect::<Vec<_>>();
+
+    assert_eq!(levels, [2, 3, 0, 1]);
+    assert_eq!(state.debug_level(), 1);
+}
+
+#[test]
+fn test_debug_targets() {
+    use log::{Level, Log, Metadata};
+
+    use crate::output::Logger;
+
+    let logger = Logger::new();
+
+    // Returns true if a debug message from a target would be output
+    let enabled = |target| {
+        logger.enabled(
+            &Metadata::builder()
+                .level(Level::Debug)
+                .target(target)
+                .build(),
+        )
+    };
+
+    // Only the crate's own messages are output by default
+    assert!(enabled("mirrorurl::walk"));
+    assert!(!enabled("reqwest::connect"));
+
+    logger.set_debug_targets(&["walk".to_string(), "reqwest".to_string()]);
+
+    // Modules are matched by name within the crate, and crates by name
+    assert!(enabled("mirrorurl::walk"));
+    assert!(enabled("reqwest"));
+    assert!(enabled("reqwest::connect"));
+
+    // Other modules, and names which only share a prefix, are filtered out
+    assert!(!enabled("mirrorurl::download"));
+    assert!(!enabled("mirrorurl::walker"));
+    assert!(!enabled("reqwest_middleware"));
+    assert!(!enabled("hyper::client"));
+
+    // Messages other than debug and trace aren't filtered
+    assert!(logger.enabled(
+        &Metadata::builder()
+            .level(Level::Info)
+            .target("hyper::client")
+            .build()
+    ));
+}
+
+#[test]
+fn test_human_size() {
+    assert_eq!(human_size(0), None);
+    assert_eq!(human_size(1023), None);
+    assert_eq!(human_size(1024), Some("1.0 KiB".to_string()));
+    assert_eq!(human_size(1536), Some("1.5 KiB".to_string()));
+    assert_eq!(human_size(5 << 20), Some("5.0 MiB".to_string()));
+    assert_eq!(human_size(1234567890), Some("1.1 GiB".to_string()));
+    assert_eq!(human_size(3 << 50), Some("3.0 PiB".to_string()));
+    assert_eq!(human_size(u64::MAX), Some("16384.0 PiB".to_string()));
+}
+
+#[test]
+fn test_status_line() {
+    let usage = SlotUsage {
+        busy: 3,
+        total: 10,
+        waiting: 5,
+    };
+
+    let mut stats = Stats::default();
+    stats.add_download(100);
+    stats.add_html(50);
+    stats.add_errored();
+
+    assert_eq!(
+        status_line(&usage, &stats, 512, false),
+        "Status: 3 fetching, 5 waiting, 3 processed, 1 errored, 512 bytes/s"
+    );
+    assert_eq!(
+        status_line(&usage, &stats, 3 << 20, false),
+        "Status: 3 fetching, 5 waiting, 3 processed, 1 errored, 3.0 MiB/s"
+    );
+    assert_eq!(
+        status_line(&usage, &stats, 3 << 20, true),
+        "Status: 3 fetching, 5 waiting, 3 processed, 1 errored, 3145728 bytes/s"
+    );
+}
+
+#[test]
+fn test_histogram() {
+    let mut histogram = Histogram::default();
+
+    for _ in 0..89 {
+        histogram.add(std::time::Duration::from_millis(3));
+    }
+
+    for _ in 0..10 {
+        histogram.add(std::time::Duration::from_millis(150));
+    }
+
+    histogram.add(std::time::Duration::from_secs(1000));
+
+    assert_eq!(histogram.count(), 100);
+    assert_eq!(histogram.percentile(50), Some(5));
+    assert_eq!(histogram.percentile(90), Some(200));
+    assert_eq!(histogram.percentile(99), Some(200));
+    assert_eq!(histogram.percentile(100), None);
+    assert_eq!(histogram.to_string(), "p50 <=5ms, p90 <=200ms, p99 <=200ms");
+}
+
+#[tokio::test]
+async fn test_hot() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.concurrent_fetch = 1;
+    args.hot = vec!["dists/*/InRelease".to_string()];
+
+    let file_content = "Hello, world!";
+    let files = ["a.txt", "b.txt", "dists/stable/InRelease"];
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    // Configure the server to expect a GET request for each file
+    for file in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+
+    // Build expected messages (fetches are sent to the event sink)
+    let mut expected_messages = Vec::new();
+
+    // Build expected files
+    let mut expected_files = vec![
+        TmpFile::Dir("download".to_string()),
+        TmpFile::Dir("download/dists".to_string()),
+        TmpFile::Dir("download/dists/stable".to_string()),
+    ];
+
+    for file in files {
+        expected_stats.add_download(file_content.len());
+
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+
+        expected_files.push(TmpFile::File(
+            format!("download/{file}"),
+            file_content.to_string(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 3 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+        file_content.len() * 3
+    ));
+
+    // Process
+    let sink = std::sync::Arc::new(RecordingSink::default());
+    let result = async_main_with_events(args, sink.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+
+    // The hot file is fetched first
+    let fetches: Vec<String> = sink
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| event.starts_with("fetch "))
+        .cloned()
+        .collect();
+
+    let expected_fetches: Vec<String> = ["", "dists/stable/InRelease", "a.txt", "b.txt"]
+        .iter()
+        .map(|file| format!("fetch {}", server.url(&format!("/root/{file}"))))
+        .collect();
+
+    assert_eq!(fetches, expected_fetches);
+}
+
+#[tokio::test]
+async fn test_ftp_source() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    let file_content = "Hello, world!";
+
+    let ftp = FtpServer::run(
+        &[
+            ("/pub/file1", file_content),
+            ("/pub/sub/file2", file_content),
+        ],
+        true,
+    )
+    .await;
+
+    let source_url = ftp.url("/pub/");
+    args.urls = vec![source_url.clone()];
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+
+    for _ in 0..2 {
+        expected_stats.add_html(0);
+        expected_stats.add_download(file_content.len());
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {source_url}"),
+        format!("INFO: Fetching {source_url}file1"),
+        format!("INFO: Fetching {source_url}sub/"),
+        format!("INFO: Fetching {source_url}sub/file2"),
+        format!(
+            "INFO: Downloading {source_url}file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {source_url}sub/file2 to {}/download/sub/file2 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 2 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    let etags = tokio::fs::read_to_string(tmpdir.path().join("download/.etags.json"))
+        .await
+        .unwrap();
+
+    let expected_files = [
+        TmpFile::Dir("download"),
+        TmpFile::File("download/.etags.json", etags.as_str()),
+        TmpFile::File("download/file1", file_content),
+        TmpFile::Dir("download/sub"),
+        TmpFile::File("download/sub/file2", file_content),
+    ];
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+
+    // Files are recorded with a synthesized etag from the size and modification time
+    assert!(etags.contains(&format!(
+        "\"\\\"{}-20240102030405\\\"\"",
+        file_content.len()
+    )));
+
+    // **** Second run - the files are not modified ****
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+
+    for _ in 0..2 {
+        expected_stats.add_html(0);
+        expected_stats.add_not_modified();
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {source_url}"),
+        format!("INFO: Fetching {source_url}file1"),
+        format!("INFO: Fetching {source_url}sub/"),
+        format!("INFO: Fetching {source_url}sub/file2"),
+        format!("INFO: {source_url}file1 is not modified"),
+        format!("INFO: {source_url}sub/file2 is not modified"),
+        "INFO: 2 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 2 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &expected_files,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_ftp_verify() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.verify = Some(HashType::Md5);
+
+    let file_content = "Hello, world!";
+    let good_md5 = "6cd3556deb0da54bca060b4c39479839";
+    let bad_md5 = "00000000000000000000000000000000";
+    let sums = format!("{good_md5}  file1\n{bad_md5}  file2\n");
+
+    let ftp = FtpServer::run(
+        &[
+            ("/pub/MD5SUMS", sums.as_str()),
+            ("/pub/file1", file_content),
+            ("/pub/file2", file_content),
+        ],
+        true,
+    )
+    .await;
+
+    let source_url = ftp.url("/pub/");
+    args.urls = vec![source_url.clone()];
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(0);
+    expected_stats.add_download(sums.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {source_url}")];
+
+    for (file, size) in [
+        ("MD5SUMS", sums.len()),
+        ("file1", file_content.len()),
+        ("file2", file_content.len()),
+    ] {
+        expected_messages.push(format!("INFO: Fetching {source_url}{file}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {source_url}{file} to {}/download/{file} (size {size})",
+            tmpdir.path().display(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "ERROR: md5 mismatch for {}/download/file2: expected {bad_md5}, got {good_md5}",
+        tmpdir.path().display()
+    ));
+    expected_messages.push("INFO: 1 document parsed (0 bytes)".to_string());
+    expected_messages.push(format!(
+        "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+        sums.len() + file_content.len()
+    ));
+
+    // Process
+    let result = async_main(args).await;
+
+    let etags = tokio::fs::read_to_string(tmpdir.path().join("download/.etags.json"))
+        .await
+        .unwrap();
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags.as_str()),
+            TmpFile::File("download/MD5SUMS", sums.as_str()),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_ftp_list() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    let file_content = "Hello, world!";
+
+    // Server without MLSD support
+    let ftp = FtpServer::run(
+        &[
+            ("/pub/file 1", file_content),
+            ("/pub/sub/file2", file_content),
+        ],
+        false,
+    )
+    .await;
+
+    // Directory URL without a trailing slash
+    let source_url = ftp.url("/pub");
+    args.urls = vec![source_url.clone()];
+    args.no_etags = true;
+    args.concurrent_fetch = 1;
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+
+    for _ in 0..2 {
+        expected_stats.add_html(0);
+        expected_stats.add_download(file_content.len());
+    }
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {source_url}"),
+        format!("INFO: Fetching {source_url}/file%201"),
+        format!("INFO: Fetching {source_url}/sub/"),
+        format!("INFO: Fetching {source_url}/sub/file2"),
+        format!(
+            "INFO: Downloading {source_url}/file%201 to {}/download/file%201 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading {source_url}/sub/file2 to {}/download/sub/file2 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        "INFO: 2 documents parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file%201", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file2", file_content),
+        ],
+    )
+    .await;
+
+    // The control connection is reused for every URL
+    assert_eq!(ftp.sessions(), 1);
+}
+
+#[tokio::test]
+async fn test_ftp_control_chars() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    let ftp = FtpServer::run(&[("/pub/file1", "Hello, world!")], true).await;
+
+    // Path which would end the command and send another
+    let source_url = ftp.url("/pub/file1%0D%0ADELE%20file1");
+    args.urls = vec![source_url.clone()];
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("ERROR: Path of {source_url} contains control characters"),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 0 files downloaded (0 bytes), 0 not modified, 0 skipped, 1 errored".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[] as &[TmpFile<&str, &str>; 0],
+    )
+    .await;
+
+    // Nothing is sent to the server
+    assert_eq!(ftp.sessions(), 0);
+}
+
+#[tokio::test]
+async fn test_file_source_path() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    let file_content = "Hello, world!";
+
+    // Create a source directory tree
+    let mut source = tmpdir.path().to_path_buf();
+    source.push("source");
+    create_tmp_file(&source.join("file1"), file_content).await;
+    create_tmp_file(&source.join("private").join("file2"), file_content).await;
+
+    // Generate skip list
+    let (skip_path, skip_content) = generate_skiplist_json(&tmpdir, vec!["private/"]).await;
+    args.skip_file = Some(skip_path.to_str().unwrap().to_string());
+
+    // Give the local path rather than a file:// URL
+    args.urls = vec![source.to_str().unwrap().to_string()];
+    args.no_etags = true;
+
+    let source_url = Url::from_directory_path(source.canonicalize().unwrap()).unwrap();
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(0);
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {source_url}"),
+        format!("INFO: Fetching {source_url}file1"),
+        format!(
+            "INFO: Downloading {source_url}file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: Skipping {source_url}private/: Path is in the skip list"),
+        "INFO: 1 document parsed (0 bytes)".to_string(),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("skiplist.json", skip_content.as_str()),
+            TmpFile::Dir("source"),
+            TmpFile::File("source/file1", file_content),
+            TmpFile::Dir("source/private"),
+            TmpFile::File("source/private/file2", file_content),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_publish_data_first() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.publish_order = PublishOrder::DataFirst;
+
+    let old_content = "Old release";
+    let new_content = "New release";
+    let file_content = "Hello, world!";
+    let files = ["dists/stable/InRelease", "pool/a.deb", "pool/b.deb"];
+
+    // Create the previous copy of the metadata file
+    create_tmp_file(
+        &tmpdir.path().join("download/dists/stable/InRelease"),
+        old_content,
+    )
+    .await;
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&files);
+
+    let expect_run = |server: &mut Server, b_status: u16| {
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "text/html")
+                    .body(html_doc.clone()),
+            ),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/root/dists/stable/InRelease"))
+                .respond_with(status_code(200).body(new_content)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/root/pool/a.deb"))
+                .respond_with(status_code(200).body(file_content)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/root/pool/b.deb"))
+                .respond_with(status_code(b_status).body(file_content)),
+        );
+    };
+
+    let fetch_messages = |server: &Server| {
+        let mut messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+        for file in files {
+            messages.push(format!(
+                "INFO: Fetching {}",
+                server.url(&format!("/root/{file}"))
+            ));
+        }
+
+        messages
+    };
+
+    let downloading = |server: &Server, file: &str, len: usize| {
+        format!(
+            "INFO: Downloading {} to {}/download/{file} (size {len})",
+            server.url(&format!("/root/{file}")),
+            tmpdir.path().display(),
+        )
+    };
+
+    // First run - a data file fails so the new metadata is not published
+    expect_run(&mut server, 404);
+
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(new_content.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    let mut expected_messages = fetch_messages(&server);
+    expected_messages.extend([
+        downloading(&server, "dists/stable/InRelease", new_content.len()),
+        downloading(&server, "pool/a.deb", file_content.len()),
+        format!(
+            "ERROR: Status 404 Not Found fetching {}",
+            server.url("/root/pool/b.deb")
+        ),
+        "INFO: Not publishing 1 metadata files as not all data files were downloaded".to_string(),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+            new_content.len() + file_content.len()
+        ),
+    ]);
+
+    let result = async_main(args.clone()).await;
+
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/dists"),
+            TmpFile::Dir("download/dists/stable"),
+            TmpFile::File("download/dists/stable/InRelease", old_content),
+            TmpFile::Dir("download/pool"),
+            TmpFile::File("download/pool/a.deb", file_content),
+        ],
+    )
+    .await;
+
+    // Second run - all of the data files arrive so the metadata is published
+    expect_run(&mut server, 200);
+
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(new_content.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    let mut expected_messages = fetch_messages(&server);
+    expected_messages.extend([
+        downloading(&server, "dists/stable/InRelease", new_content.len()),
+        downloading(&server, "pool/a.deb", file_content.len()),
+        downloading(&server, "pool/b.deb", file_content.len()),
+        "INFO: Published 1 metadata files".to_string(),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 3 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            new_content.len() + file_content.len() * 2
+        ),
+    ]);
+
+    let result = async_main(args).await;
+
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/dists"),
+            TmpFile::Dir("download/dists/stable"),
+            TmpFile::File("download/dists/stable/InRelease", new_content),
+            TmpFile::Dir("download/pool"),
+            TmpFile::File("download/pool/a.deb", file_content),
+            TmpFile::File("download/pool/b.deb", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_stage() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.stage = true;
+    args.lock = true;
+
+    let old_content = "Old content";
+    let file_content = "Hello, world!";
+
+    // Create the live mirror
+    create_tmp_file(&tmpdir.path().join("download/file1"), old_content).await;
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["file1", "file2"]);
+
+    let expect_run = |server: &mut Server, file2_status: u16| {
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/")).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "text/html")
+                    .body(html_doc.clone()),
+            ),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/file1"))
+                .respond_with(status_code(200).body(file_content)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/file2"))
+                .respond_with(status_code(file2_status).body(file_content)),
+        );
+    };
+
+    let run_messages = |server: &Server, files: &[&str]| {
+        let mut messages = vec![
+            format!("INFO: Fetching {}", server.url("/")),
+            format!("INFO: Fetching {}", server.url("/file1")),
+            format!("INFO: Fetching {}", server.url("/file2")),
+            format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        ];
+
+        for file in files {
+            messages.push(format!(
+                "INFO: Downloading {} to {}/download.stage/{file} (size {})",
+                server.url(&format!("/{file}")),
+                tmpdir.path().display(),
+                file_content.len()
+            ));
+        }
+
+        messages
+    };
+
+    // First run - a file fails so the live mirror is left alone
+    expect_run(&mut server, 404);
+
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_errored();
+
+    let mut expected_messages = run_messages(&server, &["file1"]);
+    expected_messages.extend([
+        format!("ERROR: Status 404 Not Found fetching {}", server.url("/file2")),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 1 errored",
+            file_content.len()
+        ),
+        format!(
+            "INFO: Keeping staged mirror {}/download.stage for the next run as the run did not complete",
+            tmpdir.path().display()
+        ),
+    ]);
+
+    let result = async_main(args.clone()).await;
+
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", old_content),
+            TmpFile::Dir("download.stage"),
+            TmpFile::File("download.stage/file1", file_content),
+        ],
+    )
+    .await;
+
+    // Second and third runs complete and are swapped in
+    for generation in [1, 2] {
+        expect_run(&mut server, 200);
+
+        let mut expected_stats = Stats::default();
+        expected_stats.add_html(html_doc.len());
+        expected_stats.add_download(file_content.len());
+        expected_stats.add_download(file_content.len());
+
+        let mut expected_messages = run_messages(&server, &["file1", "file2"]);
+        expected_messages.extend([
+            format!(
+                "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+                file_content.len() * 2
+            ),
+            format!(
+                "INFO: Swapped in staged mirror {0}/download.{generation} as {0}/download",
+                tmpdir.path().display()
+            ),
+        ]);
+
+        if generation == 1 {
+            expected_messages.push(format!(
+                "INFO: Continuing staged mirror in {}/download.stage",
+                tmpdir.path().display()
+            ));
+        }
+
+        let result = async_main(args.clone()).await;
+
+        let gen_dir = format!("download.{generation}");
+
+        check_results(
+            result,
+            Ok(expected_stats),
+            &expected_messages,
+            &mut server,
+            &tmpdir,
+            &[
+                TmpFile::Link("download".to_string(), gen_dir.clone()),
+                TmpFile::Dir(gen_dir.clone()),
+                TmpFile::File(format!("{gen_dir}/file1"), file_content.to_string()),
+                TmpFile::File(format!("{gen_dir}/file2"), file_content.to_string()),
+            ],
+        )
+        .await;
+    }
+}
+
+/// Builds an S3 ListObjectsV2 result document
+fn build_bucket_listing(keys: &[&str], next_token: Option<&str>) -> String {
+    let mut doc = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name>
+"#,
+    );
+
+    for key in keys {
+        doc.push_str(&format!(
+            "  <Contents><Key>{key}</Key><Size>13</Size></Contents>\n"
+        ));
+    }
+
+    match next_token {
+        Some(token) => doc.push_str(&format!(
+            "  <IsTruncated>true</IsTruncated>\n  <NextContinuationToken>{token}</NextContinuationToken>\n"
+        )),
+        None => doc.push_str("  <IsTruncated>false</IsTruncated>\n"),
+    }
+
+    doc.push_str("</ListBucketResult>\n");
+
+    doc
+}
+
+#[tokio::test]
+async fn test_s3_source() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.urls = vec!["s3://bucket/pub/".to_string()];
+    args.s3_endpoint = Some(server.url("/").to_string().parse().unwrap());
+
+    let file_content = "Hello, world!";
+
+    // The listing is split over two pages and includes a folder marker
+    let page1 = build_bucket_listing(&["pub/file1", "pub/dir/"], Some("token&1"));
+    let page2 = build_bucket_listing(&["pub/dir/file 2"], None);
+
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/bucket/"),
+            request::query(url_decoded(contains(("list-type", "2")))),
+            request::query(url_decoded(contains(("prefix", "pub/")))),
+            request::query(url_decoded(not(contains(key("continuation-token"))))),
+        ])
+        .respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/xml")
+                .body(page1.clone()),
+        ),
+    );
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/bucket/"),
+            request::query(url_decoded(contains(("prefix", "pub/")))),
+            request::query(url_decoded(contains(("continuation-token", "token&1")))),
+        ])
+        .respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/xml")
+                .body(page2.clone()),
+        ),
+    );
+
+    for path in ["/bucket/pub/file1", "/bucket/pub/dir/file%202"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", path))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(page1.len() + page2.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        "INFO: Fetching s3://bucket/pub/".to_string(),
+        "INFO: Fetching s3://bucket/pub/file1".to_string(),
+        "INFO: Fetching s3://bucket/pub/dir/file%202".to_string(),
+        format!(
+            "INFO: Downloading s3://bucket/pub/file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Downloading s3://bucket/pub/dir/file%202 to {}/download/dir/file%202 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: 1 document parsed ({} bytes)",
+            page1.len() + page2.len()
+        ),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::Dir("download/dir"),
+            TmpFile::File("download/dir/file%202", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_s3_listing_url() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    args.urls = vec![server.url("/?list-type=2&prefix=pub/").to_string()];
+
+    let file_content = "Hello, world!";
+    let listing = build_bucket_listing(&["pub/a", "pub/b"], None);
+
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/"),
+            request::query(url_decoded(contains(("list-type", "2")))),
+        ])
+        .respond_with(
+            status_code(200)
+                .append_header("Content-Type", "application/xml")
+                .body(listing.clone()),
+        ),
+    );
+
+    for file in ["a", "b"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/pub/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(listing.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/?list-type=2&prefix=pub/")),
+        format!("INFO: 1 document parsed ({} bytes)", listing.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    for file in ["a", "b"] {
+        let url = server.url(&format!("/pub/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/pub/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::Dir("download/pub"),
+            TmpFile::File("download/pub/a", file_content),
+            TmpFile::File("download/pub/b", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_audit() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+
+    args.save_html = true;
+    args.audit = true;
+    args.reject = vec!["iso".to_string()];
+
+    // Generate skip list
+    let (skip_path, _) = generate_skiplist_json(&tmpdir, vec!["private/"]).await;
+    args.skip_file = Some(skip_path.to_str().unwrap().to_string());
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to the files, a directory and another site
+    let html_doc = build_html_anchors_doc(&[
+        "file1",
+        "file1#top",
+        "image.iso",
+        "private/file2",
+        "missing",
+        "http://example.com/file3",
+    ]);
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/missing"))
+            .respond_with(status_code(404)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+    expected_stats.add_errored();
+
+    let root = server.url("/root/");
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {root}"),
+        format!("INFO: Fetching {root}file1"),
+        format!("INFO: Fetching {root}missing"),
+        format!(
+            "INFO: Downloading {root} to {}/download/index.html (size {})",
+            tmpdir.path().display(),
+            html_doc.len()
+        ),
+        format!(
+            "INFO: Downloading {root}file1 to {}/download/file1 (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: Skipping {root}image.iso: File extension is not accepted"),
+        format!("INFO: Skipping {root}private/file2: Path is in the skip list"),
+        format!("INFO: Skipping {root}file1#top: URL is a fragment"),
+        "INFO: Skipping http://example.com/file3: URL is not relative to the base URL".to_string(),
+        format!("ERROR: Status 404 Not Found fetching {root}missing"),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 4 skipped, 1 errored",
+            file_content.len()
+        ),
+        format!(
+            "INFO: Audit: {root}image.iso linked from {root} is not mirrored: File extension is not accepted"
+        ),
+        format!(
+            "INFO: Audit: {root}missing linked from {root} is missing from the mirror: Not downloaded"
+        ),
+        format!(
+            "INFO: Audit: {root}private/file2 linked from {root} is not mirrored: Path is in the skip list"
+        ),
+        "INFO: Audit found 3 problems".to_string(),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("skiplist.json", "[\"private/\"]"),
+            TmpFile::Dir("download"),
+            TmpFile::File("download/index.html", &html_doc),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_saved_path_for_url() {
+    use crate::state::State;
+
+    let (mut args, server, tmpdir) = test_setup("/root/");
+
+    args.flatten = true;
+
+    let state = State::new(args, LOGGER.clone(), None).await.unwrap();
+
+    let root = Url::parse(&server.url("/root/").to_string()).unwrap();
+    let a = root.join("a/report.pdf").unwrap();
+    let b = root.join("b/report.pdf").unwrap();
+
+    let download = tmpdir.path().join("download");
+
+    // Looking up paths doesn't claim them
+    assert_eq!(
+        state.saved_path_for_url(&b).await.unwrap(),
+        Some(download.join("report.pdf"))
+    );
+    assert_eq!(
+        state.path_for_url(&a).await.unwrap(),
+        download.join("report.pdf")
+    );
+
+    // The plain name now belongs to the first URL
+    assert_eq!(
+        state.saved_path_for_url(&a).await.unwrap(),
+        Some(download.join("report.pdf"))
+    );
+    assert_eq!(state.saved_path_for_url(&b).await.unwrap(), None);
+
+    // Claiming a path for the second URL numbers it
+    assert_eq!(
+        state.path_for_url(&b).await.unwrap(),
+        download.join("report-1.pdf")
+    );
+    assert_eq!(
+        state.saved_path_for_url(&b).await.unwrap(),
+        Some(download.join("report-1.pdf"))
+    );
+}
+
+#[tokio::test]
+async fn test_flatten_non_ascii() {
+    use crate::state::State;
+
+    let (mut args, server, tmpdir) = test_setup("/root/");
+
+    args.flatten = true;
+    args.decode_filenames = true;
+
+    let state = State::new(args, LOGGER.clone(), None).await.unwrap();
+
+    let root = Url::parse(&server.url("/root/").to_string()).unwrap();
+    let download = tmpdir.path().join("download");
+
+    // Colliding names starting with a multibyte character are numbered before the extension
+    for (file, local) in [
+        ("a/%C3%A9.pdf", "é.pdf"),
+        ("b/%C3%A9.pdf", "é-1.pdf"),
+        ("c/%C3%A9.tar.gz", "é.tar.gz"),
+        ("d/%C3%A9.tar.gz", "é-1.tar.gz"),
+        ("e/%C3%A9", "é"),
+        ("f/%C3%A9", "é-1"),
+    ] {
+        assert_eq!(
+            state.path_for_url(&root.join(file).unwrap()).await.unwrap(),
+            download.join(local)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_blocklist_hashes() {
+    let (mut args, mut server, tmpdir) = test_setup("/");
+
+    let bad_content = "Hello, world!";
+    let bad_sha256 = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+    let good_content = "Good content";
+
+    // Write the blocklist in sha256sum format
+    let blocklist_content = format!("# Known bad files\n{bad_sha256}  bad.bin\n");
+    let blocklist_path = tmpdir.path().join("blocklist.txt");
+    std::fs::write(&blocklist_path, &blocklist_content).unwrap();
+
+    args.blocklist_hashes = Some(blocklist_path.to_str().unwrap().to_string());
+
+    let quarantine_path = tmpdir.path().join("quarantine");
+    args.quarantine_dir = Some(quarantine_path.to_str().unwrap().to_string());
+
+    // The previous copy of the bad file is left alone
+    create_tmp_file(&tmpdir.path().join("download/bad.bin"), good_content).await;
+
+    // Build document linking to the files
+    let html_doc = build_html_anchors_doc(&["good.bin", "bad.bin"]);
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(
+            status_code(200)
                 .append_header("Content-Type", "text/html")
-                .body(main_html_doc.clone()),
+                .body(html_doc.clone()),
+        ),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/good.bin"))
+            .respond_with(status_code(200).body(good_content)),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/bad.bin"))
+            .respond_with(status_code(200).body(bad_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(good_content.len());
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/")),
+        format!("INFO: Fetching {}", server.url("/good.bin")),
+        format!("INFO: Fetching {}", server.url("/bad.bin")),
+        format!(
+            "INFO: Downloading {} to {}/download/good.bin (size {})",
+            server.url("/good.bin"),
+            tmpdir.path().display(),
+            good_content.len()
+        ),
+        format!(
+            "INFO: Downloading {} to {}/download/bad.bin (size {})",
+            server.url("/bad.bin"),
+            tmpdir.path().display(),
+            bad_content.len()
+        ),
+        format!(
+            "INFO: Quarantined {} as {}/{bad_sha256}-bad.bin",
+            server.url("/bad.bin"),
+            quarantine_path.display()
+        ),
+        format!(
+            "INFO: Skipping {}: Content matches blocked hash {bad_sha256}",
+            server.url("/bad.bin")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            good_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::File("blocklist.txt".to_string(), blocklist_content.clone()),
+            TmpFile::Dir("download".to_string()),
+            TmpFile::File("download/good.bin".to_string(), good_content.to_string()),
+            TmpFile::File("download/bad.bin".to_string(), good_content.to_string()),
+            TmpFile::Dir("quarantine".to_string()),
+            TmpFile::File(
+                format!("quarantine/{bad_sha256}-bad.bin"),
+                bad_content.to_string(),
+            ),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_text_listing() {
+    let (args, mut server, tmpdir) = test_setup("/root/");
+
+    let file_content = "Hello, world!";
+    let listing = "# Files\nfile1\n\nsub/file2\n";
+    let files = ["file1", "sub/file2"];
+
+    // Configure the server to respond to the directory with a plain text listing
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/plain; charset=utf-8")
+                .body(listing),
+        ),
+    );
+
+    for file in files {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(
+                    status_code(200)
+                        .append_header("Content-Type", "text/plain")
+                        .body(file_content),
+                ),
+        );
+    }
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(listing.len());
+
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: 1 document parsed ({} bytes)", listing.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
+
+    for file in files {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_stats.add_download(file_content.len());
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
+    }
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results - text files which aren't directories are still downloaded
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::Dir("download/sub"),
+            TmpFile::File("download/sub/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_autoindex() {
+    let (args, mut server, tmpdir) = test_setup("/root/");
+
+    let file_content = "Hello, world!";
+
+    // Build an Apache fancy index page
+    let html_doc = r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 3.2 Final//EN">
+<html>
+ <head>
+  <title>Index of /root</title>
+ </head>
+ <body>
+<h1>Index of /root</h1>
+  <table>
+   <tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+   <tr><td><a href="/">Parent Directory</a></td><td>&nbsp;</td><td>-</td></tr>
+   <tr><td><a href="file1">file1</a></td><td>2024-01-01 12:00</td><td>13</td></tr>
+   <tr><td><a href="?page=2">More</a></td><td>&nbsp;</td><td>-</td></tr>
+  </table>
+</body></html>
+"#;
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc),
+        ),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
+    expected_stats.add_skipped();
+
+    // Build expected messages - only links which aren't sort links are reported
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!(
+            "INFO: Skipping {}: URL is not relative to the base URL",
+            server.url("/")
+        ),
+        format!(
+            "INFO: Skipping {}: URL has a query",
+            server.url("/root/?page=2")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 2 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_check_listed_sizes() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+    args.check_listed_sizes = true;
+
+    let file_content = "Hello, world!";
+    let large_content = "x".repeat(1200);
+
+    // Build an Apache fancy index page with a size which doesn't match the file and a rounded size
+    let html_doc = r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 3.2 Final//EN">
+<html>
+ <head>
+  <title>Index of /root</title>
+ </head>
+ <body>
+<h1>Index of /root</h1>
+  <table>
+   <tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+   <tr><td><a href="file1">file1</a></td><td>2024-01-01 12:00</td><td>13</td></tr>
+   <tr><td><a href="file2">file2</a></td><td>2024-01-01 12:00</td><td>100</td></tr>
+   <tr><td><a href="file3">file3</a></td><td>2024-01-01 12:00</td><td>1.2K</td></tr>
+  </table>
+</body></html>
+"#;
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc),
         ),
     );
 
-    expected_stats.add_html(main_html_doc.len());
-    expected_messages.push(format!("INFO: Fetching {}", server.url("/root/")));
+    for file in ["file1", "file2"] {
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
+        );
+    }
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file3"))
+            .respond_with(status_code(200).body(large_content.clone())),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(large_content.len());
+    expected_stats.add_errored();
+
+    // Build expected messages
+    let mut expected_messages = vec![format!("INFO: Fetching {}", server.url("/root/"))];
+
+    for (file, size) in [
+        ("file1", file_content.len().to_string()),
+        ("file2", file_content.len().to_string()),
+        ("file3", "1.2 KiB".to_string()),
+    ] {
+        let url = server.url(&format!("/root/{file}"));
+
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {size})",
+            tmpdir.path().display(),
+        ));
+    }
+
+    expected_messages.push(format!(
+        "ERROR: Size mismatch for {}/download/file2: listed as 100 bytes, got {} bytes",
+        tmpdir.path().display(),
+        file_content.len()
+    ));
+    expected_messages.push(format!(
+        "INFO: 1 document parsed ({} bytes)",
+        html_doc.len()
+    ));
+    expected_messages.push(
+        "INFO: 2 files downloaded (1.2 KiB), 0 not modified, 0 skipped, 1 errored".to_string(),
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file3", &large_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_json_listing() {
+    let (args, mut server, tmpdir) = test_setup("/root/");
+
+    let file_content = "Hello, world!";
+
+    // Build an nginx JSON listing
+    let listing = r#"[
+{ "name":"sub", "type":"directory", "mtime":"Mon, 01 Jan 2024 12:00:00 GMT" },
+{ "name":"file1", "type":"file", "mtime":"Mon, 01 Jan 2024 12:00:00 GMT", "size":13 }
+]"#;
+
+    for dir in ["/root/", "/root/sub/"] {
+        let body = if dir == "/root/" { listing } else { "[]" };
+
+        server.expect(
+            Expectation::matching(request::method_path("GET", dir)).respond_with(
+                status_code(200)
+                    .append_header("Content-Type", "application/json")
+                    .body(body),
+            ),
+        );
+    }
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file1"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(listing.len());
+    expected_stats.add_html(2);
+    expected_stats.add_download(file_content.len());
 
-    // Configure the server to serve sub pages
-    let html_doc = build_html_anchors_doc(&(0..SUB_PAGES).collect::<Vec<_>>());
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/sub/")),
+        format!("INFO: Fetching {}", server.url("/root/file1")),
+        format!(
+            "INFO: Downloading {} to {}/download/file1 (size {})",
+            server.url("/root/file1"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 2 documents parsed ({} bytes)", listing.len() + 2),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
 
-    for page in 0..SUB_PAGES {
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/file1", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_listing_dates() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
+    args.listing_dates = true;
+    args.no_etags = true;
+
+    let file_content = "Hello, world!";
+
+    // Build an nginx index page
+    let build_listing = |file2_date: &str| {
+        format!(
+            r#"<html>
+<head><title>Index of /root/</title></head>
+<body>
+<h1>Index of /root/</h1><hr><pre><a href="../">../</a>
+<a href="file1">file1</a>                                              01-Jan-2024 12:00                  13
+<a href="file2">file2</a>                                              {file2_date}                  13
+</pre><hr></body>
+</html>
+"#
+        )
+    };
+
+    let html_doc = build_listing("01-Jan-2024 12:00");
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    for file in ["file1", "file2"] {
         server.expect(
-            Expectation::matching(request::method_path("GET", format!("/root/{}/", page)))
-                .respond_with(
-                    status_code(200)
-                        .append_header("Content-Type", "text/html")
-                        .body(html_doc.clone()),
-                ),
+            Expectation::matching(request::method_path("GET", format!("/root/{file}")))
+                .respond_with(status_code(200).body(file_content)),
         );
+    }
 
-        expected_stats.add_html(html_doc.len());
-        expected_messages.push(format!("INFO: Fetching {}/{page}/", server.url("/root")));
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_skipped();
 
-        // Serve up the file content
-        for a in 0..SUB_PAGES {
-            server.expect(
-                Expectation::matching(request::method_path("GET", format!("/root/{page}/{a}")))
-                    .respond_with(status_code(200).body(file_content)),
-            );
+    // Build expected messages
+    let mut expected_messages = vec![
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!(
+            "INFO: Skipping {}: URL is not relative to the base URL",
+            server.url("/")
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 2 files downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
+            file_content.len() * 2
+        ),
+    ];
 
-            expected_contents.push(TmpFile::Dir(format!("download/{a}")));
-            expected_messages.push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
+    for file in ["file1", "file2"] {
+        let url = server.url(&format!("/root/{file}"));
 
-            expected_stats.add_download(file_content.len());
-            expected_contents.push(TmpFile::File(format!("download/{page}/{a}"), file_content));
-            expected_messages.push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
-            expected_messages.push(format!(
-                "INFO: Downloading {}/{page}/{a} to {}/download/{page}/{a} (size {})",
-                server.url("/root"),
-                tmpdir.path().display(),
-                file_content.len()
-            ));
-        }
+        expected_messages.push(format!("INFO: Fetching {url}"));
+        expected_messages.push(format!(
+            "INFO: Downloading {url} to {}/download/{file} (size {})",
+            tmpdir.path().display(),
+            file_content.len()
+        ));
     }
 
-    expected_messages.push(format!(
-        "INFO: {} documents parsed ({} bytes)",
-        SUB_PAGES + 1,
-        main_html_doc.len() + (SUB_PAGES * html_doc.len())
-    ));
-    expected_messages.push(format!(
-        "INFO: {} files downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
-        SUB_PAGES * SUB_PAGES,
-        SUB_PAGES * SUB_PAGES * file_content.len()
-    ));
+    let dates = |file2_date: &str| {
+        format!(
+            "{{\n  \"{}\": \"01-Jan-2024 12:00\",\n  \"{}\": \"{file2_date}\"\n}}",
+            server.url("/root/file1"),
+            server.url("/root/file2")
+        )
+    };
+    let first_dates = dates("01-Jan-2024 12:00");
+    let second_dates = dates("02-Jan-2024 09:30");
+
+    // Process
+    let result = async_main(args.clone()).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.listing-dates.json", first_dates.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+
+    // **** Second process - only the file with a new date is fetched ****
+
+    let html_doc = build_listing("02-Jan-2024 09:30");
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
+    );
+
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/file2"))
+            .respond_with(status_code(200).body(file_content)),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
+    expected_stats.add_not_modified();
+    expected_stats.add_skipped();
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!(
+            "INFO: Skipping {}: URL is not relative to the base URL",
+            server.url("/")
+        ),
+        format!("INFO: {} is not modified", server.url("/root/file1")),
+        format!("INFO: Fetching {}", server.url("/root/file2")),
+        format!(
+            "INFO: Downloading {} to {}/download/file2 (size {})",
+            server.url("/root/file2"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 1 not modified, 1 skipped, 0 errored",
+            file_content.len()
+        ),
+    ];
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.listing-dates.json", second_dates.as_str()),
+            TmpFile::File("download/file1", file_content),
+            TmpFile::File("download/file2", file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_segments() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+    args.segments = 2;
+
+    // Build a file large enough to be split in to two segments
+    let file_content: String = (0..2 << 20)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    let half = file_content.len() / 2;
+
+    // Configure the server to respond to the GET /file request with the whole file, accepting ranges
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            not(request::headers(contains(key("range")))),
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("Accept-Ranges", "bytes")
+                .append_header("ETag", "\"segments\"")
+                .body(file_content.clone()),
+        ),
+    );
+
+    // Configure the server to respond to a range request for the second half of the file
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains((
+                "range",
+                format!("bytes={half}-{}", file_content.len() - 1)
+            ))),
+            request::headers(contains(("if-range", "\"segments\""))),
+        ))
+        .respond_with(status_code(206).body(file_content[half..].to_string())),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size 2.0 MiB)",
+            server.url("/file"),
+            tmpdir.path().display(),
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 1 file downloaded (2.0 MiB), 0 not modified, 0 skipped, 0 errored".to_string(),
+    ];
+
+    // Build expected etags file
+    let file_url = server.url("/file").to_string();
+    let etags_json = generate_etags_json(
+        Some(&file_url),
+        vec![(file_url.clone(), "\"segments\"".to_string())],
+    );
+
+    // Process
+    let result = async_main(args).await;
+
+    // Check results
+    check_results(
+        result,
+        Ok(expected_stats),
+        &expected_messages,
+        &mut server,
+        &tmpdir,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_json.as_str()),
+            TmpFile::File("download/__file.dat", &file_content),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_segments_unvalidated() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+    args.segments = 2;
+
+    // Build a file large enough to be split in to two segments
+    let file_content: String = (0..2 << 20)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+
+    // Configure the server to respond to a single GET /file request with the whole file, accepting
+    // ranges but without an ETag or Last-Modified header to validate them with
+    server.expect(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            not(request::headers(contains(key("range")))),
+        ))
+        .respond_with(
+            status_code(200)
+                .append_header("Accept-Ranges", "bytes")
+                .body(file_content.clone()),
+        ),
+    );
+
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
+
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size 2.0 MiB)",
+            server.url("/file"),
+            tmpdir.path().display(),
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 1 file downloaded (2.0 MiB), 0 not modified, 0 skipped, 0 errored".to_string(),
+    ];
 
     // Process
     let result = async_main(args).await;
@@ -692,119 +11467,62 @@ async fn test_multi_html() {
         &expected_messages,
         &mut server,
         &tmpdir,
-        &expected_contents,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/__file.dat", file_content.as_str()),
+        ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_multi_html_skiplist() {
-    let (mut args, mut server, tmpdir) = test_setup("/root/");
-
-    const SUB_PAGES: usize = 4;
-
-    // Generate skip list
-    let (skip_path, skip_content) = generate_skiplist_json(&tmpdir, vec!["1", "2/", "3/1"]).await;
-    args.skip_file = Some(skip_path.to_str().unwrap().to_string());
-
-    // Start expected stats
-    let mut expected_stats = Stats::default();
-
-    // Start expected messages
-    let mut expected_messages = Vec::new();
-
-    // Start expected contents
-    let mut expected_contents = vec![
-        TmpFile::File("skiplist.json".to_string(), skip_content.as_str()),
-        TmpFile::Dir("download".to_string()),
-    ];
-
-    // File content
-    let file_content = "Hello, world!";
-
-    // Build main document with some anchors
-    let main_anchors = (1..=SUB_PAGES)
-        .map(|s| format!("{}/", s))
-        .collect::<Vec<_>>();
+async fn test_segments_per_host() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+    args.segments = 2;
+    args.concurrent_per_host = Some(1);
 
-    let main_html_doc = build_html_anchors_doc(&main_anchors);
+    // Build a file large enough to be split in to two segments
+    let file_content: String = (0..2 << 20)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
 
-    // Configure the server to expect a single GET /root request and respond with the main html document
+    // Configure the server to respond to a single GET /file request with the whole file. The
+    // only slot for the host is held by this request so no range requests can be made
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            not(request::headers(contains(key("range")))),
+        ))
+        .respond_with(
             status_code(200)
-                .append_header("Content-Type", "text/html")
-                .body(main_html_doc.clone()),
+                .append_header("Accept-Ranges", "bytes")
+                .append_header("ETag", "\"segments\"")
+                .body(file_content.clone()),
         ),
     );
 
-    expected_stats.add_html(main_html_doc.len());
-    expected_messages.push(format!("INFO: Fetching {}", server.url("/root/")));
-
-    // Configure the server to serve sub pages
-    let html_doc = build_html_anchors_doc(&(1..=SUB_PAGES).collect::<Vec<_>>());
-
-    for page in 1..=SUB_PAGES {
-        if page > 2 {
-            server.expect(
-                Expectation::matching(request::method_path("GET", format!("/root/{}/", page)))
-                    .respond_with(
-                        status_code(200)
-                            .append_header("Content-Type", "text/html")
-                            .body(html_doc.clone()),
-                    ),
-            );
-
-            expected_stats.add_html(html_doc.len());
-            expected_messages.push(format!("INFO: Fetching {}/{page}/", server.url("/root")));
-
-            // Serve up the file content
-            for a in 1..=SUB_PAGES {
-                if page != 3 || a != 1 {
-                    server.expect(
-                        Expectation::matching(request::method_path(
-                            "GET",
-                            format!("/root/{page}/{a}"),
-                        ))
-                        .respond_with(status_code(200).body(file_content)),
-                    );
-
-                    expected_contents.push(TmpFile::Dir(format!("download/{page}")));
-                    expected_messages
-                        .push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
+    // Build expected stats
+    let mut expected_stats = Stats::default();
+    expected_stats.add_download(file_content.len());
 
-                    expected_stats.add_download(file_content.len());
-                    expected_contents
-                        .push(TmpFile::File(format!("download/{page}/{a}"), file_content));
-                    expected_messages
-                        .push(format!("INFO: Fetching {}/{page}/{a}", server.url("/root")));
-                    expected_messages.push(format!(
-                        "INFO: Downloading {}/{page}/{a} to {}/download/{page}/{a} (size {})",
-                        server.url("/root"),
-                        tmpdir.path().display(),
-                        file_content.len()
-                    ));
-                } else {
-                    expected_stats.add_skipped();
-                    expected_messages.push(format!(
-                        "INFO: Skipping {}/{page}/{a}: Path is in the skip list",
-                        server.url("/root")
-                    ));
-                }
-            }
-        } else {
-            expected_stats.add_skipped();
-            expected_messages.push(format!(
-                "INFO: Skipping {}/{page}/: Path is in the skip list",
-                server.url("/root")
-            ));
-        }
-    }
+    // Build expected messages
+    let expected_messages = [
+        format!("INFO: Fetching {}", server.url("/file")),
+        format!(
+            "INFO: Downloading {} to {}/download/__file.dat (size 2.0 MiB)",
+            server.url("/file"),
+            tmpdir.path().display(),
+        ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 1 file downloaded (2.0 MiB), 0 not modified, 0 skipped, 0 errored".to_string(),
+    ];
 
-    expected_messages.push(format!("INFO: 3 documents parsed (626 bytes)"));
-    expected_messages.push(format!(
-        "INFO: 7 files downloaded (91 bytes), 0 not modified, 3 skipped, 0 errored"
-    ));
+    // Build expected etags file
+    let file_url = server.url("/file").to_string();
+    let etags_json = generate_etags_json(
+        Some(&file_url),
+        vec![(file_url.clone(), "\"segments\"".to_string())],
+    );
 
     // Process
     let result = async_main(args).await;
@@ -816,80 +11534,77 @@ async fn test_multi_html_skiplist() {
         &expected_messages,
         &mut server,
         &tmpdir,
-        &expected_contents,
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.etags.json", etags_json.as_str()),
+            TmpFile::File("download/__file.dat", &file_content),
+        ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_redirect() {
-    let (args, mut server, tmpdir) = test_setup("/root");
-
-    // Build document with some anchors
-    let html_doc = build_html_anchors_doc(&["beforefile", "extfile"]);
-
-    let file_content = "Hello, world!";
+async fn test_segments_throttled() {
+    let (mut args, mut server, tmpdir) = test_setup("/file");
+    args.segments = 2;
 
-    // Configure the server to expect a single GET /root request and respond with a redirect
-    server.expect(
-        Expectation::matching(request::method_path("GET", "/root"))
-            .respond_with(status_code(301).append_header("Location", "/root/")),
-    );
+    // Build a file large enough to be split in to two segments
+    let file_content: String = (0..2 << 20)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    let half = file_content.len() / 2;
 
-    // Configure the server to expect a single GET /root/ request and respond with the html document
+    // Configure the server to respond to the GET /file request with the whole file, accepting ranges
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            not(request::headers(contains(key("range")))),
+        ))
+        .respond_with(
             status_code(200)
-                .append_header("Content-Type", "text/html")
-                .body(html_doc.clone()),
+                .append_header("Accept-Ranges", "bytes")
+                .append_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+                .body(file_content.clone()),
         ),
     );
 
-    // Configure the server to expect a single GET /root/beforefile request and respond with a relative redirect
-    server.expect(
-        Expectation::matching(request::method_path("GET", "/root/beforefile"))
-            .respond_with(status_code(301).append_header("Location", "/root/afterfile")),
-    );
-
-    // Configure the server to expect a single GET /root/beforefile request and respond with a relative redirect
-    server.expect(
-        Expectation::matching(request::method_path("GET", "/root/afterfile"))
-            .respond_with(status_code(200).body(file_content)),
-    );
-
-    // Configure the server to expect a single GET /root/extfile request and respond with an non-relative redirect
+    // Configure the server to throttle the first range request and then respond with the range
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root/extfile"))
-            .respond_with(status_code(301).append_header("Location", "/other/extfile")),
+        Expectation::matching(all_of!(
+            request::method_path("GET", "/file"),
+            request::headers(contains((
+                "range",
+                format!("bytes={half}-{}", file_content.len() - 1)
+            ))),
+            request::headers(contains(("if-range", "Wed, 21 Oct 2015 07:28:00 GMT"))),
+        ))
+        .times(2)
+        .respond_with(cycle![
+            status_code(503).append_header("Retry-After", "0"),
+            status_code(206).body(file_content[half..].to_string()),
+        ]),
     );
 
     // Build expected stats
     let mut expected_stats = Stats::default();
-    expected_stats.add_html(html_doc.len());
+    expected_stats.add_throttled();
     expected_stats.add_download(file_content.len());
-    expected_stats.add_skipped();
 
     // Build expected messages
     let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/root")),
-        format!("INFO: Fetching {}", server.url("/root/beforefile")),
-        format!("INFO: Fetching {}", server.url("/root/extfile")),
-        format!(
-            "INFO: Skipping {}: Redirect to {} is not relative to the base URL",
-            server.url("/root/extfile"),
-            server.url("/other/extfile")
-        ),
+        format!("INFO: Fetching {}", server.url("/file")),
         format!(
-            "INFO: Downloading {} to {}/download/afterfile (size {})",
-            server.url("/root/afterfile"),
+            "INFO: Downloading {} to {}/download/__file.dat (size 2.0 MiB)",
+            server.url("/file"),
             tmpdir.path().display(),
-            file_content.len()
         ),
-        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
         format!(
-            "INFO: 1 file downloaded ({} bytes), 0 not modified, 1 skipped, 0 errored",
-            file_content.len()
+            "INFO: Status 503 Service Unavailable fetching {}, retrying in 0s",
+            server.url("/file")
         ),
+        "INFO: 0 documents parsed (0 bytes)".to_string(),
+        "INFO: 1 file downloaded (2.0 MiB), 0 not modified, 0 skipped, 0 errored".to_string(),
+        "INFO: 1 request throttled by the server and retried".to_string(),
     ];
 
     // Process
@@ -904,46 +11619,100 @@ async fn test_redirect() {
         &tmpdir,
         &[
             TmpFile::Dir("download"),
-            TmpFile::File("download/afterfile", file_content),
+            TmpFile::File("download/__file.dat", file_content.as_str()),
         ],
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_too_many_redirects() {
-    let (args, mut server, tmpdir) = test_setup("/root");
+async fn test_manifest_redirects() {
+    let (mut args, mut server, tmpdir) = test_setup("/root/");
 
-    // Configure the server to expect a single GET /root request and respond with a redirect
+    args.manifest = true;
+
+    let file_content = "Hello, world!";
+
+    // Build document linking to a file which is redirected twice
+    let html_doc = build_html_anchors_doc(&["old"]);
+
+    // Configure the server to expect a single GET /root/ request and respond with the html document
     server.expect(
-        Expectation::matching(request::method_path("GET", "/root"))
-            .respond_with(status_code(301).append_header("Location", "/root/1")),
+        Expectation::matching(request::method_path("GET", "/root/")).respond_with(
+            status_code(200)
+                .append_header("Content-Type", "text/html")
+                .body(html_doc.clone()),
+        ),
     );
 
-    for i in 1..=args.max_redirects {
-        server.expect(
-            Expectation::matching(request::method_path("GET", format!("/root/{}", i)))
-                .respond_with(
-                    status_code(301).append_header("Location", format!("/root/{}", i + 1)),
-                ),
-        );
-    }
+    // Configure the server to redirect /root/old to /root/moved and then to /root/new
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/old"))
+            .respond_with(status_code(301).append_header("Location", "/root/moved")),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/moved"))
+            .respond_with(status_code(302).append_header("Location", "/root/new")),
+    );
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/root/new"))
+            .respond_with(status_code(200).body(file_content)),
+    );
 
     // Build expected stats
     let mut expected_stats = Stats::default();
-    expected_stats.add_skipped();
+    expected_stats.add_html(html_doc.len());
+    expected_stats.add_download(file_content.len());
 
     // Build expected messages
     let expected_messages = [
-        format!("INFO: Fetching {}", server.url("/root")),
-        format!("INFO: Skipping {}: Too many redirects", server.url("/root")),
-        "INFO: 0 documents parsed (0 bytes)".to_string(),
-        "INFO: 0 files downloaded (0 bytes), 0 not modified, 1 skipped, 0 errored".to_string(),
+        format!("INFO: Fetching {}", server.url("/root/")),
+        format!("INFO: Fetching {}", server.url("/root/old")),
+        format!(
+            "INFO: Downloading {} to {}/download/new (size {})",
+            server.url("/root/new"),
+            tmpdir.path().display(),
+            file_content.len()
+        ),
+        format!("INFO: 1 document parsed ({} bytes)", html_doc.len()),
+        format!(
+            "INFO: 1 file downloaded ({} bytes), 0 not modified, 0 skipped, 0 errored",
+            file_content.len()
+        ),
     ];
 
     // Process
     let result = async_main(args).await;
 
+    // Check the manifest records each hop
+    let manifest = tokio::fs::read_to_string(tmpdir.path().join("download/.manifest.json"))
+        .await
+        .expect("Failed to read manifest");
+
+    let entries: serde_json::Value =
+        serde_json::from_str(&manifest).expect("Failed to parse manifest");
+
+    let entries = entries.as_array().expect("Manifest is not an array");
+    assert_eq!(entries.len(), 1);
+
+    let entry = &entries[0];
+    assert_eq!(entry["url"], server.url("/root/new").to_string());
+    assert_eq!(
+        entry["redirects"],
+        serde_json::json!([
+            {
+                "url": server.url("/root/old").to_string(),
+                "status": 301,
+                "location": server.url("/root/moved").to_string(),
+            },
+            {
+                "url": server.url("/root/moved").to_string(),
+                "status": 302,
+                "location": server.url("/root/new").to_string(),
+            },
+        ])
+    );
+
     // Check results
     check_results(
         result,
@@ -951,7 +11720,11 @@ async fn test_too_many_redirects() {
         &expected_messages,
         &mut server,
         &tmpdir,
-        &[] as &[TmpFile<&str, &str>; 0],
+        &[
+            TmpFile::Dir("download"),
+            TmpFile::File("download/.manifest.json", manifest.as_str()),
+            TmpFile::File("download/new", file_content),
+        ],
     )
     .await;
 }
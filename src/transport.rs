@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::file::FileTransport;
+use crate::ftp::FtpTransport;
+use crate::http::HttpTransport;
+use crate::limiter::Slot;
+use crate::outcome::Outcome;
+use crate::s3::S3Transport;
+use crate::skipreason::{SkipReason, SkipReasonErr};
+use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::url::Url;
+
+/// Fetches resources for one or more URL schemes
+pub trait Transport: Send + Sync {
+    /// Processes a URL - parsing documents and following links, or downloading the resource.
+    /// The download slot should be released as soon as the transfer is complete. Links which
+    /// couldn't be followed are added to the stats for the URL
+    fn walk<'a>(
+        &'a self,
+        state: &'a ArcState,
+        url: &'a Url,
+        sem: Slot,
+        stats: &'a mut Stats,
+    ) -> BoxFuture<'a, Result<Outcome, Box<dyn Error + Send + Sync>>>;
+}
+
+/// Registry of transports keyed by URL scheme
+pub struct Transports {
+    transports: HashMap<&'static str, Arc<dyn Transport>>,
+}
+
+impl Transports {
+    /// Creates the registry with all supported transports
+    pub fn new() -> Self {
+        let mut transports = Self {
+            transports: HashMap::new(),
+        };
+
+        let http = Arc::new(HttpTransport);
+        transports.register("http", http.clone());
+        transports.register("https", http);
+
+        transports.register("file", Arc::new(FileTransport));
+
+        transports.register("ftp", Arc::new(FtpTransport));
+
+        transports.register("s3", Arc::new(S3Transport));
+
+        transports
+    }
+
+    /// Registers a transport for a URL scheme
+    pub fn register(&mut self, scheme: &'static str, transport: Arc<dyn Transport>) {
+        self.transports.insert(scheme, transport);
+    }
+
+    /// Returns the transport for a URL or a skip reason if the scheme is not supported
+    pub fn get(&self, url: &Url) -> Result<&dyn Transport, SkipReasonErr> {
+        match self.transports.get(url.scheme()) {
+            Some(transport) => Ok(transport.as_ref()),
+            None => Err(SkipReasonErr::new(url.to_string(), SkipReason::Transport)),
+        }
+    }
+
+    /// Checks the URL scheme is supported
+    pub fn is_handled(&self, url: &Url) -> Result<(), SkipReasonErr> {
+        self.get(url).map(|_| ())
+    }
+}
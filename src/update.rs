@@ -0,0 +1,268 @@
+use std::env::consts::{ARCH, EXE_SUFFIX, OS};
+use std::env::current_exe;
+use std::error::Error;
+use std::fs::{remove_file, rename, write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::output::output;
+
+/// Release endpoint returning the latest release
+const RELEASE_URL: &str = "https://api.github.com/repos/andywarduk/mirrorurl/releases/latest";
+
+/// Hex encoded ed25519 public key the release binaries are signed with, set when release builds
+/// are made
+const RELEASE_KEY: Option<&str> = option_env!("MIRRORURL_RELEASE_KEY");
+
+/// Latest release details
+#[derive(Deserialize, Debug)]
+struct Release {
+    /// Release tag (eg. v0.2.0)
+    tag_name: String,
+    /// Files attached to the release
+    assets: Vec<Asset>,
+}
+
+/// File attached to a release
+#[derive(Deserialize, Debug)]
+struct Asset {
+    /// File name
+    name: String,
+    /// Download URL
+    browser_download_url: String,
+}
+
+/// Signed description of a release binary. The version is covered by the signature so an older
+/// signed binary can't be offered as an update
+#[derive(Deserialize, Debug)]
+pub struct ReleaseManifest {
+    /// Version of the binary (eg. 0.2.0)
+    pub version: String,
+    /// Binary file name
+    pub name: String,
+    /// Hex SHA-256 of the binary
+    pub sha256: String,
+}
+
+/// Checks for a newer release and, unless only checking, replaces the running binary with it
+/// once its signature has been verified
+pub async fn self_update(check_only: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current = env!("CARGO_PKG_VERSION");
+
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("mirrorurl/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+
+    // Fetch the latest release details
+    let body = fetch(&client, RELEASE_URL).await?;
+
+    let release: Release = serde_json::from_slice(&body)
+        .map_err(|e| format!("Error parsing release details from {RELEASE_URL}: {e}"))?;
+
+    let latest = release.tag_name.trim_start_matches('v');
+
+    if !is_newer(latest, current) {
+        output!("mirrorurl {current} is up to date");
+        return Ok(());
+    }
+
+    if check_only {
+        output!("mirrorurl {latest} is available (running {current})");
+        return Ok(());
+    }
+
+    // Find the binary for this platform and its signed manifest
+    let name = format!("mirrorurl-{ARCH}-{OS}{EXE_SUFFIX}");
+    let manifest_name = format!("{name}.manifest");
+    let sig_name = format!("{manifest_name}.sig");
+
+    let asset_url = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.browser_download_url.as_str())
+            .ok_or_else(|| format!("Release {latest} has no {name}"))
+    };
+
+    let binary_url = asset_url(&name)?;
+    let manifest_url = asset_url(&manifest_name)?;
+    let sig_url = asset_url(&sig_name)?;
+
+    // Download and verify the manifest
+    let manifest = fetch(&client, manifest_url).await?;
+    let signature = fetch(&client, sig_url).await?;
+
+    let key = RELEASE_KEY.ok_or("this build has no release key to check signatures with")?;
+
+    let manifest = check_manifest(key, &manifest, &signature, &name, current)
+        .map_err(|e| format!("Unable to verify {manifest_name}: {e}"))?;
+
+    // Download the binary and check it is the one described by the manifest
+    let binary = fetch(&client, binary_url).await?;
+
+    check_binary(&binary, &manifest).map_err(|e| format!("Unable to verify {name}: {e}"))?;
+
+    // Swap it in
+    let exe = current_exe().map_err(|e| format!("Unable to find the running binary: {e}"))?;
+
+    replace_exe(&exe, &binary)?;
+
+    output!(
+        "Updated {} from {current} to {}",
+        exe.display(),
+        manifest.version
+    );
+
+    Ok(())
+}
+
+/// Fetches a URL returning the body
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Unable to fetch {url}: {e}"))?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        Err(format!("Status {status} fetching {url}"))?
+    }
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Returns true if version a (eg. 0.10.1) is newer than version b
+pub fn is_newer(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.split(['.', '-'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    };
+
+    parse(a) > parse(b)
+}
+
+/// Checks the signature of a release manifest against the release key and that it describes a
+/// newer version of the named binary
+pub fn check_manifest(
+    key: &str,
+    manifest: &[u8],
+    signature: &[u8],
+    name: &str,
+    current: &str,
+) -> Result<ReleaseManifest, Box<dyn Error + Send + Sync>> {
+    verify(key, manifest, signature)?;
+
+    let manifest: ReleaseManifest =
+        serde_json::from_slice(manifest).map_err(|e| format!("the manifest is invalid: {e}"))?;
+
+    if manifest.name != name {
+        Err(format!("the manifest describes {}", manifest.name))?
+    }
+
+    // Refuse to roll back to an older signed release
+    if !is_newer(&manifest.version, current) {
+        Err(format!(
+            "version {} is not newer than {current}",
+            manifest.version
+        ))?
+    }
+
+    Ok(manifest)
+}
+
+/// Checks a downloaded binary matches the digest in its manifest
+pub fn check_binary(
+    binary: &[u8],
+    manifest: &ReleaseManifest,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sha256 = format!("{:x}", Sha256::digest(binary));
+
+    if !sha256.eq_ignore_ascii_case(&manifest.sha256) {
+        Err("the binary does not match the manifest")?
+    }
+
+    Ok(())
+}
+
+/// Checks a detached signature of a message against a hex encoded public key
+pub fn verify(
+    key: &str,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let key: [u8; 32] = decode_hex(key)
+        .and_then(|key| key.try_into().ok())
+        .ok_or("the release key is invalid")?;
+
+    let key =
+        VerifyingKey::from_bytes(&key).map_err(|e| format!("the release key is invalid: {e}"))?;
+
+    let signature =
+        Signature::from_slice(signature).map_err(|e| format!("the signature is invalid: {e}"))?;
+
+    key.verify(message, &signature)
+        .map_err(|_| "the signature does not match")?;
+
+    Ok(())
+}
+
+/// Decodes a hex string
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Writes the new binary next to the running one and renames it over it
+pub fn replace_exe(exe: &Path, binary: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let with_suffix = |suffix: &str| {
+        let mut path = exe.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+
+    let new = with_suffix(".new");
+
+    write(&new, binary).map_err(|e| format!("Error writing {}: {e}", new.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        set_permissions(&new, Permissions::from_mode(0o755))
+            .map_err(|e| format!("Error setting permissions on {}: {e}", new.display()))?;
+    }
+
+    // A running binary can't be replaced on Windows but it can be renamed out of the way
+    if cfg!(windows) {
+        let old = with_suffix(".old");
+        let _ = remove_file(&old);
+
+        rename(exe, &old).map_err(|e| format!("Error renaming {}: {e}", exe.display()))?;
+    }
+
+    rename(&new, exe).map_err(|e| {
+        let _ = remove_file(&new);
+        format!("Error replacing {}: {e}", exe.display())
+    })?;
+
+    Ok(())
+}
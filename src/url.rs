@@ -1,13 +1,9 @@
+use percent_encoding::{percent_decode_str, percent_encode_byte};
 use url::Position;
 pub use url::Url;
 
-use crate::skipreason::{SkipReason, SkipReasonErr};
-
 /// Extension trait for Url
 pub trait UrlExt {
-    /// Returns true if the URL can be handled
-    fn is_handled(&self) -> Result<(), SkipReasonErr>;
-
     /// Returns true if test URL is relative to a base URL
     fn is_relative_to(&self, base_url: &Url) -> bool;
 
@@ -16,25 +12,12 @@ pub trait UrlExt {
 
     /// Returns the full path of the URL including query and hash strings
     fn full_path(&self) -> &str;
+
+    /// Returns the URL in a normal form for matching URLs which refer to the same resource
+    fn normalised(&self) -> String;
 }
 
 impl UrlExt for Url {
-    /// Checks the passed URL can be handled
-    fn is_handled(&self) -> Result<(), SkipReasonErr> {
-        // Check scheme
-        match self.scheme() {
-            "http" | "https" => (),
-            _ => {
-                return Err(SkipReasonErr::new(
-                    self.to_string().clone(),
-                    SkipReason::Transport,
-                ))
-            }
-        }
-
-        Ok(())
-    }
-
     /// Checks a URL is relative to this one
     fn is_relative_to(&self, base_url: &Url) -> bool {
         self.relative_path(base_url).is_some()
@@ -63,4 +46,94 @@ impl UrlExt for Url {
     fn full_path(&self) -> &str {
         &self[Position::BeforePath..]
     }
+
+    /// Removes any fragment and empty query, and normalises percent encoding
+    fn normalised(&self) -> String {
+        let s = &self[..Position::AfterQuery];
+        let s = s.strip_suffix('?').unwrap_or(s);
+
+        normalise_escapes(s)
+    }
+}
+
+/// Decodes percent encoded unreserved characters and upper cases the remaining escapes
+fn normalise_escapes(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(pos) = rest.find('%') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        // An escape decodes to a single byte, anything else is left alone
+        let escaped = rest.get(..3).and_then(|escape| {
+            let mut bytes = percent_decode_str(escape);
+            bytes.next().filter(|_| bytes.next().is_none())
+        });
+
+        match escaped {
+            Some(b) if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => {
+                result.push(b as char);
+                rest = &rest[3..];
+            }
+            Some(b) => {
+                result.push_str(percent_encode_byte(b));
+                rest = &rest[3..];
+            }
+            None => {
+                result.push('%');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+
+    result
+}
+
+/// Set of URLs which may be crawled - those relative to the base URL plus any on allowed other hosts
+#[derive(Debug, Clone)]
+pub struct HostScope {
+    /// Base URL
+    base_url: Url,
+    /// Follow links to any other host
+    span_hosts: bool,
+    /// Other hosts to follow links to
+    allowed_hosts: Vec<String>,
+}
+
+impl HostScope {
+    /// Creates a new host scope
+    pub fn new(base_url: Url, span_hosts: bool, allowed_hosts: Vec<String>) -> Self {
+        Self {
+            base_url,
+            span_hosts,
+            allowed_hosts,
+        }
+    }
+
+    /// Returns true if the URL is relative to the base URL or is on an allowed other host
+    pub fn contains(&self, url: &Url) -> bool {
+        url.is_relative_to(&self.base_url) || self.is_allowed_host(url)
+    }
+
+    /// Returns the base URL
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Returns true if the URL is on an allowed host other than the base URL host
+    pub fn is_allowed_host(&self, url: &Url) -> bool {
+        match url.host_str() {
+            Some(host) if Some(host) != self.base_url.host_str() => {
+                self.span_hosts
+                    || self
+                        .allowed_hosts
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+            }
+            _ => false,
+        }
+    }
 }
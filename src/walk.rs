@@ -1,147 +1,139 @@
 use std::error::Error;
 
 use futures::future::{BoxFuture, FutureExt};
-use reqwest::header::{HeaderMap, HeaderValue};
 use tokio::spawn;
-use tokio::sync::OwnedSemaphorePermit;
 use tokio::task::JoinHandle;
 
-use crate::download::download;
-use crate::html::process_html;
-use crate::output::{debug, error, output};
-use crate::response::ResponseExt;
-use crate::skipreason::SkipReasonErr;
+use crate::hash::ExpectedHash;
+use crate::interstitial::expects_file;
+use crate::limiter::Slot;
+use crate::outcome::{check, ErrorKind, Outcome};
+use crate::output::{debug, error, output, verbose};
+use crate::skipreason::{SkipReason, SkipReasonErr};
 use crate::state::ArcState;
+use crate::stats::Stats;
+use crate::status::StatusErr;
 use crate::url::Url;
 
-/// Handle errors and update stats wrapper for walk_internal
-pub async fn walk(state: &ArcState, url: &Url, sem: OwnedSemaphorePermit) {
-    match walk_internal(state, url, sem).await {
-        Ok(()) => {}
-        Err(e) if e.is::<SkipReasonErr>() => {
-            output!("{e}");
-            state.update_stats(|mut stats| stats.add_skipped()).await;
-        }
-        Err(e) if matches!(e.source(), Some(e) if e.is::<SkipReasonErr>()) => {
-            // Error from the redirect policy
-            output!("{}", e.source().unwrap());
-            state.update_stats(|mut stats| stats.add_skipped()).await;
-        }
-        Err(e) => {
-            error!("{e}");
-            state.update_stats(|mut stats| stats.add_errored()).await;
-        }
-    }
-}
+/// Logging and update stats wrapper for walk_internal
+pub async fn walk(state: &ArcState, url: &Url, sem: Slot) {
+    // Stats for this URL, added to the totals once processing is complete
+    let mut stats = Stats::default();
 
-/// Loads data from a URL. If the data is HTML, parse the document and follow links.
-/// Otherwise download the file.
-/// Use loaded etags to determine if the resource has already been downloaded and skip if so.
-async fn walk_internal(
-    state: &ArcState,
-    url: &Url,
-    sem: OwnedSemaphorePermit,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Already seen this URL?
-    if !state.add_processed_url(url.clone()).await {
-        debug!(state, 1, "URL {url} has already been processed");
-        return Ok(());
+    let outcome = match walk_internal(state, url, sem, &mut stats).await {
+        Ok(outcome) => outcome,
+        Err(e) => apply_status_policy(state, url, Outcome::from(e)),
     };
 
-    // Check URL maps to a path
-    let _ = state.path_for_url(url).await?;
-
-    // Create additional HTTP headers
-    let mut headers = HeaderMap::new();
+    // Report the redirects followed
+    let redirects = state.take_redirects(url);
 
-    // Is there an etag for this URL?
-    let old_etag = state.find_etag(url);
-
-    if let Some(old_etag) = old_etag {
-        debug!(state, 2, "Previous etag value: {old_etag}");
+    for (i, hop) in redirects.iter().enumerate() {
+        verbose!("Redirect {} for {url}: {hop}", i + 1);
+    }
 
-        // Set the If-None-Match request header to the old etag
-        if let Ok(value) = HeaderValue::from_str(old_etag) {
-            headers.insert("If-None-Match", value);
-        } else {
-            error!("Previous etag value {old_etag} is not valid");
+    // Record the redirects followed to reach a downloaded file in the manifest
+    if matches!(outcome, Outcome::Downloaded { .. }) {
+        if let Some(final_url) = redirects.last().map(|hop| hop.location.clone()) {
+            state.set_manifest_redirects(&final_url, redirects).await;
         }
     }
 
-    // Fetch the URL
-    output!("Fetching {url}");
-
-    let response = state
-        .client()
-        .get(url.clone())
-        .headers(headers)
-        .send()
-        .await?;
-
-    // Get final URL after any redirects
-    let final_url = response.url().clone();
-
-    // Get status code
-    let status = response.status();
-
-    // Check status code
-    if !status.is_success() {
-        // Not OK - check status
-        match status.as_u16() {
-            304 if old_etag.is_some() => {
-                state
-                    .update_stats(|mut stats| stats.add_not_modified())
-                    .await;
-                output!("{url} is not modified");
+    match &outcome {
+        Outcome::Skipped(e) => {
+            state.events().on_skip(e);
+
+            if let Some(audit) = state.audit() {
+                audit.add_skip(e).await;
             }
-            _ => Err(format!("Status {status} fetching {final_url}"))?,
         }
+        Outcome::Errored(e) => state.events().on_error(url, e),
+        Outcome::Parsed { links, .. } => verbose!("Followed {links} links from {url}"),
+        _ => {}
+    }
 
-        return Ok(());
-    } else {
-        debug!(state, 2, "Status {status}");
+    // Fully processed and skipped URLs needn't be fetched again when resuming
+    if matches!(
+        outcome,
+        Outcome::Downloaded { .. }
+            | Outcome::NotModified
+            | Outcome::Parsed { .. }
+            | Outcome::Skipped(_)
+    ) {
+        state.checkpoint_complete(url).await;
     }
 
-    // Is the document HTML?
-    if response.is_html(state) {
-        // Get HTML body
-        let html = response.text().await?;
-
-        // Release the download slot
-        drop(sem);
-
-        // Add html stats
-        let html_bytes = html.len();
-        state
-            .update_stats(|mut stats| stats.add_html(html_bytes))
-            .await;
-
-        // Process HTML
-        let join_handles = process_html(state, &final_url, html).await;
-
-        // Join the threads
-        for j in join_handles {
-            match j.await {
-                Ok(()) => {}
-                Err(e) => {
-                    error!("Failed to join thread: {e}");
-                }
-            }
-        }
-    } else {
-        // Download the resource
-        let bytes = download(state, url, &final_url, response).await?;
+    stats.add_outcome(&outcome);
+    state.add_stats(&stats);
+}
 
-        // Release the download slot
-        drop(sem);
+/// Skips URLs returning a status in --ignore-status and aborts the run for a status in --fail-on
+fn apply_status_policy(state: &ArcState, url: &Url, outcome: Outcome) -> Outcome {
+    let Outcome::Errored(ErrorKind::Other(e)) = &outcome else {
+        return outcome;
+    };
+
+    let Some(status) = e.downcast_ref::<StatusErr>().map(|e| e.status()) else {
+        return outcome;
+    };
+
+    let args = state.args();
+
+    if args
+        .fail_on
+        .as_ref()
+        .is_some_and(|set| set.contains(status))
+    {
+        state.abort(format!("Run aborted: {e}"));
+    } else if args
+        .ignore_status
+        .as_ref()
+        .is_some_and(|set| set.contains(status))
+    {
+        return Outcome::Skipped(SkipReasonErr::new(
+            url.to_string(),
+            SkipReason::Status(status.as_u16()),
+        ));
+    }
+
+    outcome
+}
+
+/// Checks a URL hasn't already been processed and is allowed, then hands it to the transport for its scheme
+async fn walk_internal(
+    state: &ArcState,
+    url: &Url,
+    sem: Slot,
+    stats: &mut Stats,
+) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    // Don't start new fetches once interrupted
+    if state.is_interrupted() {
+        debug!(state, 1, "Interrupted - not fetching {url}");
+        return Ok(Outcome::Ignored);
+    }
+
+    // Already seen this URL?
+    if !state.add_processed_url(url).await {
+        debug!(state, 1, "URL {url} has already been processed");
+        return Ok(Outcome::Ignored);
+    };
 
-        // Add download stats
-        state
-            .update_stats(|mut stats| stats.add_download(bytes))
-            .await;
+    // Completed by the run being resumed?
+    if state.is_completed(url).await {
+        debug!(state, 1, "URL {url} was completed by a previous run");
+        return Ok(Outcome::Ignored);
     }
 
-    Ok(())
+    // Check URL maps to a path
+    let _ = check!(state.path_for_url(url).await);
+
+    // Check robots.txt allows the URL
+    check!(state.check_robots(url));
+
+    // Look up the transport for the URL scheme and process the URL
+    check!(state.transports().get(url))
+        .walk(state, url, sem, stats)
+        .await
 }
 
 pub fn walk_recurse(
@@ -152,11 +144,110 @@ pub fn walk_recurse(
         // Clone state
         let state = state.clone();
 
+        // Record the URL in the crawl state
+        state.checkpoint_queue(&url).await;
+
         // Acquire a download slot
-        let sem = state.acquire_slot().await?;
+        let sem = state.acquire_slot(&url).await?;
 
         // Spawn a task to process the url
         Ok(spawn(async move { walk(&state, &url, sem).await }))
     }
     .boxed()
 }
+
+/// Follows a list of links returning a list of join handles for spawned download tasks.
+/// Links which can't be followed are added to the stats for the page
+pub async fn follow_links(
+    state: &ArcState,
+    mut links: Vec<Result<Url, SkipReasonErr>>,
+    stats: &mut Stats,
+) -> Vec<JoinHandle<()>> {
+    let mut join_handles = Vec::new();
+
+    // Follow links matching the --hot patterns first
+    if !state.args().hot.is_empty() {
+        links.sort_by_key(|link| !link.as_ref().is_ok_and(|url| state.is_hot(url)));
+    }
+
+    // Process each link
+    for link in links {
+        // Stop following links once interrupted
+        if state.is_interrupted() {
+            break;
+        }
+
+        let url = link.as_ref().ok().cloned();
+
+        match follow_link(state, link).await {
+            Err(outcome) => {
+                match &outcome {
+                    Outcome::Skipped(skip) => {
+                        state.events().on_skip(skip);
+
+                        if let Some(audit) = state.audit() {
+                            audit.add_skip(skip).await;
+                        }
+                    }
+                    Outcome::Errored(e) => match &url {
+                        Some(url) => state.events().on_error(url, e),
+                        None => output!("{e}"),
+                    },
+                    _ => {}
+                }
+
+                stats.add_outcome(&outcome);
+            }
+            Ok(join) => join_handles.push(join),
+        }
+    }
+
+    join_handles
+}
+
+/// Checks a link against the crawl policy and spawns a task to process it, returning the outcome
+/// for the link if it isn't followed
+async fn follow_link(
+    state: &ArcState,
+    link: Result<Url, SkipReasonErr>,
+) -> Result<JoinHandle<()>, Outcome> {
+    let mut url = link?;
+
+    // A hash fragment gives the digest of the target rather than a location in it
+    let expected_hash = ExpectedHash::from_fragment(&url);
+
+    if expected_hash.is_some() {
+        url.set_fragment(None);
+    }
+
+    // Check the URL transport and against the crawl policy
+    let url = state.check_link(url)?;
+
+    // Don't fetch links to files in another shard
+    if expects_file(&url) {
+        state.check_shard(&url)?;
+    }
+
+    if let Some(expected_hash) = expected_hash {
+        debug!(
+            state,
+            1, "Expecting {} {} for {url}", expected_hash.hash_type, expected_hash.hex
+        );
+        state.add_expected_hash(&url, expected_hash).await;
+    }
+
+    // Recurse in to this URL
+    Ok(walk_recurse(state, url).await?)
+}
+
+/// Waits for a list of spawned tasks to finish
+pub async fn join_tasks(join_handles: Vec<JoinHandle<()>>) {
+    for j in join_handles {
+        match j.await {
+            Ok(()) => {}
+            Err(e) => {
+                error!("Failed to join thread: {e}");
+            }
+        }
+    }
+}
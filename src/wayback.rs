@@ -0,0 +1,90 @@
+use std::error::Error;
+
+use crate::args::Args;
+use crate::url::Url;
+
+/// Wayback Machine address used when no endpoint is given
+const WAYBACK_URL: &str = "https://web.archive.org/";
+
+/// Rewrites URLs to and from Wayback Machine snapshot URLs so a snapshot of a site can be mirrored
+/// as though it was the live site
+#[derive(Debug, Clone)]
+pub struct Wayback {
+    /// Wayback Machine address
+    endpoint: Url,
+    /// Snapshot timestamp (YYYYMMDDhhmmss or a prefix of it)
+    timestamp: String,
+}
+
+impl Wayback {
+    /// Creates the URL rewriter if mirroring via the Wayback Machine
+    pub fn from_args(args: &Args) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        match &args.via_wayback {
+            Some(timestamp) => Ok(Some(Self::new(args.wayback_endpoint.as_ref(), timestamp)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates a new URL rewriter for the snapshot nearest a timestamp
+    pub fn new(
+        endpoint: Option<&Url>,
+        timestamp: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut endpoint = match endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => Url::parse(WAYBACK_URL)?,
+        };
+
+        if !endpoint.path().ends_with('/') {
+            endpoint.set_path(&format!("{}/", endpoint.path()));
+        }
+
+        Ok(Self {
+            endpoint,
+            timestamp: timestamp.to_string(),
+        })
+    }
+
+    /// Returns the snapshot URL to fetch a URL through. The id_ flag asks for the content as it was
+    /// archived rather than with its links rewritten
+    pub fn snapshot_url(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+        url.set_fragment(None);
+
+        match self
+            .endpoint
+            .join(&format!("web/{}id_/{url}", self.timestamp))
+        {
+            Ok(snapshot_url) => snapshot_url,
+            Err(_) => url,
+        }
+    }
+
+    /// Returns the original URL of a snapshot URL, or None if it isn't a snapshot URL. Snapshots
+    /// are redirected to the nearest timestamp and may carry any of the archive's flags
+    pub fn original_url(&self, url: &Url) -> Option<Url> {
+        if url.origin() != self.endpoint.origin() {
+            return None;
+        }
+
+        let rest = url
+            .path()
+            .strip_prefix(self.endpoint.path())?
+            .strip_prefix("web/")?;
+
+        let (stamp, original) = rest.split_once('/')?;
+
+        let digits = stamp.trim_end_matches(|c: char| c.is_ascii_lowercase() || c == '_');
+
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let original = match url.query() {
+            Some(query) => format!("{original}?{query}"),
+            None => original.to_string(),
+        };
+
+        Url::parse(&original).ok()
+    }
+}